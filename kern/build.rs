@@ -0,0 +1,154 @@
+//! Builds the user programs in `../user` and packs them, together with
+//! the files under `../rootfs`, into the cpio newc archive that
+//! `initramfs` embeds (`$OUT_DIR/initramfs.cpio`).
+//!
+//! Archive layout: `user/src/bin/init.rs` becomes `/init`, every other
+//! program `/bin/<name>`, and `rootfs/<path>` becomes `/<path>`.
+//! Host builds (e.g. the `sim` crate) get an empty archive.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const USER_TARGET: &str = "riscv64gc-unknown-none-elf";
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// Names of the programs in `user/src/bin`, sorted.
+fn user_programs(user_dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(user_dir.join("src/bin"))
+        .expect("Could not read user/src/bin")
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != "rs" {
+                return None;
+            }
+            Some(path.file_stem()?.to_str()?.to_owned())
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+fn build_user(user_dir: &Path, target_dir: &Path, profile: &str) {
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned());
+    let mut cmd = Command::new(cargo);
+    cmd.current_dir(user_dir)
+        .args(["build", "--bins", "--target", USER_TARGET])
+        .arg("--target-dir").arg(target_dir);
+    if profile == "release" {
+        cmd.arg("--release");
+    }
+    // the kernel's flags (linker script ..) must not leak into the user build
+    for var in ["CARGO_ENCODED_RUSTFLAGS", "RUSTFLAGS", "CARGO_BUILD_TARGET",
+                "CARGO_TARGET_DIR", "CARGO_MAKEFLAGS", "MAKEFLAGS", "MFLAGS"] {
+        cmd.env_remove(var);
+    }
+
+    let status = cmd.status().expect("Could not run cargo for the user programs");
+    if !status.success() {
+        panic!("Building the user programs failed");
+    }
+}
+
+/// Writer for cpio newc archives.
+struct Cpio {
+    out     : Vec<u8>,
+    next_ino: u32,
+}
+
+impl Cpio {
+    fn new() -> Self {
+        Self { out: Vec::new(), next_ino: 1 }
+    }
+
+    fn pad4(&mut self) {
+        while !self.out.len().is_multiple_of(4) {
+            self.out.push(0);
+        }
+    }
+
+    fn entry(&mut self, name: &str, mode: u32, data: &[u8]) {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        let nlink = if mode & S_IFDIR != 0 { 2 } else { 1 };
+        let fields = [ino, mode, 0, 0, nlink, 0, data.len() as u32, 0, 0, 0, 0,
+                      name.len() as u32 + 1, 0];
+
+        self.out.extend_from_slice(b"070701");
+        for field in fields {
+            self.out.extend_from_slice(format!("{:08x}", field).as_bytes());
+        }
+        self.out.extend_from_slice(name.as_bytes());
+        self.out.push(0);
+        self.pad4();
+        self.out.extend_from_slice(data);
+        self.pad4();
+    }
+
+    fn dir(&mut self, name: &str) {
+        self.entry(name, S_IFDIR | 0o755, &[]);
+    }
+
+    fn file(&mut self, name: &str, mode: u32, data: &[u8]) {
+        self.entry(name, S_IFREG | mode, data);
+    }
+
+    /// Add `dir` and everything below it as `prefix/...`.
+    fn tree(&mut self, dir: &Path, prefix: &str) {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        paths.sort();
+        for path in paths {
+            let name = path.file_name().unwrap().to_str().unwrap();
+            let name = if prefix.is_empty() { name.to_owned() } else { format!("{}/{}", prefix, name) };
+            if path.is_dir() {
+                self.dir(&name);
+                self.tree(&path, &name);
+            } else {
+                self.file(&name, 0o644, &fs::read(&path).unwrap());
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.entry("TRAILER!!!", 0, &[]);
+        self.out
+    }
+}
+
+fn main() {
+    let out_dir      = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let user_dir     = manifest_dir.join("../user");
+    let rootfs_dir   = manifest_dir.join("../rootfs");
+
+    println!("cargo:rerun-if-changed=../user/src");
+    println!("cargo:rerun-if-changed=../user/Cargo.toml");
+    println!("cargo:rerun-if-changed=../user/user.ld");
+    println!("cargo:rerun-if-changed=../rootfs");
+
+    let mut cpio = Cpio::new();
+    if env::var("CARGO_CFG_TARGET_ARCH").as_deref() == Ok("riscv64") {
+        let profile    = env::var("PROFILE").unwrap();
+        let target_dir = out_dir.join("user");
+        build_user(&user_dir, &target_dir, &profile);
+
+        let bin_dir = target_dir.join(USER_TARGET).join(&profile);
+        cpio.dir(".");
+        cpio.dir("bin");
+        for name in user_programs(&user_dir) {
+            let image = fs::read(bin_dir.join(&name)).unwrap();
+            let path  = if name == "init" { name } else { format!("bin/{}", name) };
+            cpio.file(&path, 0o755, &image);
+        }
+        if rootfs_dir.is_dir() {
+            cpio.tree(&rootfs_dir, "");
+        }
+    }
+
+    fs::write(out_dir.join("initramfs.cpio"), cpio.finish()).unwrap();
+}
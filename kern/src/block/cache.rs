@@ -0,0 +1,370 @@
+//! Buffer cache: `NBUF` block sized buffers shared by every filesystem,
+//! keyed by `(DevId, block)`.
+//!
+//! `bread` returns the block locked: one user at a time, others sleep
+//! on the buffer's `SleepLock`. A reference count keeps a buffer from
+//! being recycled while someone holds or waits for it; once the last
+//! reference is gone the least recently released buffer is reused,
+//! written back first if it is dirty.
+//!
+//! Changes reach the disk either right away (`BufGuard::write_through`)
+//! or when the buffer is recycled or `sync`ed (`BufGuard::write_back`).
+//! Pinned buffers (`BufGuard::pin`) are never recycled.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{BlockErr, DevId, SECTOR_SIZE};
+use crate::sched;
+use crate::sync::{SleepLock, SleepLockGuard, SpinLock};
+
+/// Buffers in the cache.
+pub const NBUF: usize = 64;
+
+/// `Meta::dev` of a buffer that caches nothing.
+const NO_DEV: DevId = DevId::MAX;
+
+/// Identity of a buffer, guarded by the `CACHE` lock.
+#[derive(Debug, Clone, Copy)]
+struct Meta {
+    dev   : DevId,
+    block : u64,
+    refcnt: usize,  // holders and waiters
+    stamp : u64,    // when it was last released, for LRU
+}
+
+struct Cache {
+    meta : [Meta; NBUF],
+    clock: u64,
+}
+
+/// Contents of a buffer, guarded by its sleep lock.
+struct BufData {
+    data : Vec<u8>,
+    valid: bool,    // `data` holds the block
+    dirty: bool,    // `data` is newer than the disk
+}
+
+static CACHE: SpinLock<Cache> = SpinLock::new(Cache {
+    meta : [Meta { dev: NO_DEV, block: 0, refcnt: 0, stamp: 0 }; NBUF],
+    clock: 0,
+});
+
+static BUFS: [SleepLock<BufData>; NBUF] = [const {
+    SleepLock::new(BufData { data: Vec::new(), valid: false, dirty: false })
+}; NBUF];
+
+static HITS     : AtomicU64 = AtomicU64::new(0);
+static MISSES   : AtomicU64 = AtomicU64::new(0);
+static READS    : AtomicU64 = AtomicU64::new(0);
+static WRITES   : AtomicU64 = AtomicU64::new(0);
+static EVICTIONS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub buffers  : usize,
+    pub in_use   : usize,   // referenced right now
+    pub cached   : usize,   // holding some block
+    pub hits     : u64,
+    pub misses   : u64,
+    pub reads    : u64,     // blocks read from devices
+    pub writes   : u64,     // blocks written to devices
+    pub evictions: u64,
+}
+
+/// Channel for processes waiting for a buffer to become free.
+fn cache_chan() -> usize {
+    &CACHE as *const _ as usize
+}
+
+fn read_block(dev: DevId, block: u64, data: &mut [u8]) -> Result<(), BlockErr> {
+    let sector = block * (data.len() / SECTOR_SIZE) as u64;
+    super::device(dev)?.read_sectors(sector, data)?;
+    READS.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+fn write_block(dev: DevId, block: u64, data: &[u8]) -> Result<(), BlockErr> {
+    let sector = block * (data.len() / SECTOR_SIZE) as u64;
+    super::device(dev)?.write_sectors(sector, data)?;
+    WRITES.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Drop a reference to buffer `idx`.
+fn release(idx: usize) {
+    let mut guard = CACHE.lock();
+    let cache = guard.get_mut();
+    cache.clock += 1;
+    let meta = &mut cache.meta[idx];
+    meta.refcnt -= 1;
+    meta.stamp = cache.clock;
+    if meta.refcnt == 0 {
+        sched::wakeup(cache_chan());
+    }
+}
+
+/// A locked buffer from `bread`. Dropping it unlocks and releases it.
+pub struct BufGuard {
+    idx  : usize,
+    dev  : DevId,
+    block: u64,
+    data : Option<SleepLockGuard<'static, BufData>>,
+}
+
+impl BufGuard {
+    fn buf(&self) -> &BufData {
+        self.data.as_ref().unwrap()
+    }
+
+    fn buf_mut(&mut self) -> &mut BufData {
+        self.data.as_mut().unwrap()
+    }
+
+    pub fn dev(&self) -> DevId {
+        self.dev
+    }
+
+    pub fn block(&self) -> u64 {
+        self.block
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.buf().data
+    }
+
+    /// The block for changing. Follow up with `write_back` or
+    /// `write_through`, or the change may be lost when the buffer is
+    /// recycled.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.buf_mut().data
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.buf().dirty
+    }
+
+    /// Write the block out later: on `sync` or when it is recycled.
+    pub fn write_back(&mut self) {
+        self.buf_mut().dirty = true;
+    }
+
+    /// Write the block out now.
+    pub fn write_through(&mut self) -> Result<(), BlockErr> {
+        write_block(self.dev, self.block, &self.buf().data)?;
+        self.buf_mut().dirty = false;
+        Ok(())
+    }
+
+    /// Keep the block cached after the guard is gone, until `unpin`.
+    /// A log pins the blocks a transaction changed so they are not
+    /// recycled, and with that written home, before it commits.
+    pub fn pin(&self) {
+        CACHE.lock().get_mut().meta[self.idx].refcnt += 1;
+    }
+}
+
+/// Undo one `BufGuard::pin` of `(dev, block)`.
+pub fn unpin(dev: DevId, block: u64) {
+    let idx = CACHE.lock().get().meta.iter().position(|m| m.dev == dev && m.block == block);
+    match idx {
+        Some(idx) => release(idx),
+        None => panic!("bcache: unpin of block {} on device {} which is not cached", block, dev),
+    }
+}
+
+impl Drop for BufGuard {
+    fn drop(&mut self) {
+        drop(self.data.take());
+        release(self.idx);
+    }
+}
+
+/// A locked buffer for `(dev, block)`, whatever it holds right now.
+fn get(dev: DevId, block: u64) -> Result<BufGuard, BlockErr> {
+    loop {
+        let (idx, recycle) = {
+            let mut guard = CACHE.lock();
+            loop {
+                let cache = guard.get_mut();
+                if let Some(idx) = cache.meta.iter().position(|m| m.dev == dev && m.block == block) {
+                    cache.meta[idx].refcnt += 1;
+                    HITS.fetch_add(1, Ordering::Relaxed);
+                    break (idx, false);
+                }
+                // unused buffers first, then the least recently released
+                let victim = cache.meta.iter().enumerate()
+                    .filter(|(_, m)| m.refcnt == 0)
+                    .min_by_key(|(_, m)| (m.dev != NO_DEV, m.stamp))
+                    .map(|(idx, _)| idx);
+                if let Some(idx) = victim {
+                    cache.meta[idx].refcnt = 1;
+                    MISSES.fetch_add(1, Ordering::Relaxed);
+                    break (idx, true);
+                }
+                guard = sched::sleep(cache_chan(), guard);
+            }
+        };
+
+        let mut data = BUFS[idx].lock();
+        if !recycle {
+            return Ok(BufGuard { idx, dev, block, data: Some(data) });
+        }
+
+        // write back what the victim holds; until its identity changes
+        // below, users of the old block may still find and share it
+        let old = CACHE.lock().get().meta[idx];
+        if old.dev != NO_DEV && data.valid && data.dirty {
+            if let Err(err) = write_block(old.dev, old.block, &data.data) {
+                drop(data);
+                release(idx);
+                return Err(err);
+            }
+        }
+        data.dirty = false;
+
+        let mut guard = CACHE.lock();
+        let cache = guard.get_mut();
+        let taken = cache.meta.iter().any(|m| m.dev == dev && m.block == block);
+        if taken || cache.meta[idx].refcnt > 1 {
+            // someone cached our block meanwhile, or wants the old one
+            drop(guard);
+            drop(data);
+            release(idx);
+            continue;
+        }
+        if old.dev != NO_DEV {
+            EVICTIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        cache.meta[idx].dev   = dev;
+        cache.meta[idx].block = block;
+        drop(guard);
+        data.valid = false;
+        return Ok(BufGuard { idx, dev, block, data: Some(data) });
+    }
+}
+
+/// Block `block` of `dev`, locked, read from the device unless cached.
+pub fn bread(dev: DevId, block: u64) -> Result<BufGuard, BlockErr> {
+    let size = super::block_size(dev)?;
+    if block >= super::blocks(dev)? {
+        return Err(BlockErr::OutOfRange);
+    }
+    let mut buf = get(dev, block)?;
+    let data = buf.buf_mut();
+    if !data.valid {
+        if data.data.len() != size {
+            data.data.clear();
+            data.data.try_reserve_exact(size).map_err(|_| BlockErr::NoMemory)?;
+            data.data.resize(size, 0);
+        }
+        read_block(dev, block, &mut data.data)?;
+        data.valid = true;
+    }
+    Ok(buf)
+}
+
+/// Block `block` of `dev`, locked and zeroed without reading it: for
+/// blocks that are about to be overwritten as a whole.
+pub fn bzero(dev: DevId, block: u64) -> Result<BufGuard, BlockErr> {
+    let size = super::block_size(dev)?;
+    if block >= super::blocks(dev)? {
+        return Err(BlockErr::OutOfRange);
+    }
+    let mut buf = get(dev, block)?;
+    let data = buf.buf_mut();
+    data.data.clear();
+    data.data.try_reserve_exact(size).map_err(|_| BlockErr::NoMemory)?;
+    data.data.resize(size, 0);
+    data.valid = true;
+    Ok(buf)
+}
+
+/// Write every dirty buffer of `dev` back and flush the device.
+pub fn sync(dev: DevId) -> Result<(), BlockErr> {
+    for (idx, buf) in BUFS.iter().enumerate() {
+        let block = {
+            let mut guard = CACHE.lock();
+            let meta = &mut guard.get_mut().meta[idx];
+            if meta.dev != dev {
+                continue;
+            }
+            meta.refcnt += 1;
+            meta.block
+        };
+        let mut data = buf.lock();
+        let written = match data.valid && data.dirty {
+            true => write_block(dev, block, &data.data),
+            false => Ok(()),
+        };
+        if written.is_ok() {
+            data.dirty = false;
+        }
+        drop(data);
+        release(idx);
+        written?;
+    }
+    super::device(dev)?.flush()
+}
+
+/// Is any block of `dev` held or waited for?
+pub fn in_use(dev: DevId) -> bool {
+    CACHE.lock().get().meta.iter().any(|meta| meta.dev == dev && meta.refcnt != 0)
+}
+
+/// Forget every block cached for `dev`, dirty or not.
+/// Fails with `Busy` if one of them is in use.
+pub fn invalidate(dev: DevId) -> Result<(), BlockErr> {
+    let mut guard = CACHE.lock();
+    let cache = guard.get_mut();
+    let mine = |meta: &&mut Meta| meta.dev == dev;
+    if cache.meta.iter_mut().filter(mine).any(|meta| meta.refcnt != 0) {
+        return Err(BlockErr::Busy);
+    }
+    for meta in cache.meta.iter_mut().filter(mine) {
+        meta.dev = NO_DEV;
+    }
+    Ok(())
+}
+
+/// Free the data of the clean buffers nobody uses, when memory runs
+/// out. Never sleeps; dirty buffers stay, writing them could. Returns
+/// the buffers freed.
+pub fn shrink() -> usize {
+    let mut freed = 0;
+    for (idx, buf) in BUFS.iter().enumerate() {
+        // unreferenced buffers are not locked, and stay unreferenced
+        // while we hold `CACHE`
+        let mut guard = CACHE.lock();
+        let meta = &mut guard.get_mut().meta[idx];
+        if meta.refcnt != 0 {
+            continue;
+        }
+        let Some(mut data) = buf.try_lock() else {
+            continue;
+        };
+        if (data.valid && data.dirty) || data.data.capacity() == 0 {
+            continue;
+        }
+        meta.dev   = NO_DEV;
+        data.valid = false;
+        data.data  = Vec::new();
+        freed += 1;
+    }
+    freed
+}
+
+pub fn stats() -> CacheStats {
+    let guard = CACHE.lock();
+    let meta = &guard.get().meta;
+    CacheStats {
+        buffers  : NBUF,
+        in_use   : meta.iter().filter(|m| m.refcnt != 0).count(),
+        cached   : meta.iter().filter(|m| m.dev != NO_DEV).count(),
+        hits     : HITS.load(Ordering::Relaxed),
+        misses   : MISSES.load(Ordering::Relaxed),
+        reads    : READS.load(Ordering::Relaxed),
+        writes   : WRITES.load(Ordering::Relaxed),
+        evictions: EVICTIONS.load(Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,202 @@
+//! Block devices and the buffer cache in front of them.
+//!
+//! Drivers implement `BlockDevice` in 512 byte sectors and `register`
+//! the device, which gives it a `DevId`. Filesystems never talk to the
+//! device directly: they go through `cache`, in blocks of whatever size
+//! they picked with `set_block_size`.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::sync::SpinLock;
+
+pub mod cache;
+
+pub use cache::{bread, sync, BufGuard, CacheStats};
+
+pub const SECTOR_SIZE   : usize = 512;
+/// Largest block size the cache handles.
+pub const MAX_BLOCK_SIZE: usize = 4096;
+
+/// Index of a registered device.
+pub type DevId = u32;
+
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockErr {
+    NoDevice,
+    OutOfRange,     // past the end of the device
+    BadLength,      // not a whole number of sectors
+    BadBlockSize,   // not a multiple of `SECTOR_SIZE` up to `MAX_BLOCK_SIZE`
+    ReadOnly,
+    Busy,           // buffers of the device are still in use
+    NoMemory,
+    Io,
+}
+
+/// A disk, addressed in `SECTOR_SIZE` byte sectors.
+pub trait BlockDevice: Send + Sync {
+    /// Size in sectors.
+    fn sectors(&self) -> u64;
+
+    /// Read `buf.len() / SECTOR_SIZE` sectors starting at `sector`.
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockErr>;
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), BlockErr>;
+
+    /// Make earlier writes durable.
+    fn flush(&self) -> Result<(), BlockErr> {
+        Ok(())
+    }
+
+    fn read_only(&self) -> bool {
+        false
+    }
+}
+
+struct DevEntry {
+    device    : Arc<dyn BlockDevice>,
+    block_size: usize,
+}
+
+static DEVICES: SpinLock<Vec<Option<DevEntry>>> = SpinLock::new(Vec::new());
+
+/// Make `device` available to the cache, with `SECTOR_SIZE` blocks.
+pub fn register(device: Arc<dyn BlockDevice>) -> DevId {
+    let mut guard = DEVICES.lock();
+    let devices = guard.get_mut();
+    let entry = Some(DevEntry { device, block_size: SECTOR_SIZE });
+    match devices.iter().position(Option::is_none) {
+        Some(idx) => {
+            devices[idx] = entry;
+            idx as DevId
+        }
+        None => {
+            devices.push(entry);
+            (devices.len() - 1) as DevId
+        }
+    }
+}
+
+/// Write back and forget everything cached for `dev`, then drop it.
+pub fn unregister(dev: DevId) -> Result<(), BlockErr> {
+    if cache::in_use(dev) {
+        return Err(BlockErr::Busy);
+    }
+    cache::sync(dev)?;
+    cache::invalidate(dev)?;
+    let mut guard = DEVICES.lock();
+    let slot = guard.get_mut().get_mut(dev as usize).ok_or(BlockErr::NoDevice)?;
+    slot.take().ok_or(BlockErr::NoDevice).map(|_| ())
+}
+
+pub fn device(dev: DevId) -> Result<Arc<dyn BlockDevice>, BlockErr> {
+    let guard = DEVICES.lock();
+    guard.get().get(dev as usize)
+        .and_then(Option::as_ref)
+        .map(|entry| entry.device.clone())
+        .ok_or(BlockErr::NoDevice)
+}
+
+pub fn block_size(dev: DevId) -> Result<usize, BlockErr> {
+    let guard = DEVICES.lock();
+    guard.get().get(dev as usize)
+        .and_then(Option::as_ref)
+        .map(|entry| entry.block_size)
+        .ok_or(BlockErr::NoDevice)
+}
+
+/// Address `dev` in blocks of `size` bytes from now on. Cached blocks
+/// of the old size are written back and dropped, which fails with
+/// `Busy` while any of them is in use.
+pub fn set_block_size(dev: DevId, size: usize) -> Result<(), BlockErr> {
+    if size == 0 || !size.is_multiple_of(SECTOR_SIZE) || size > MAX_BLOCK_SIZE {
+        return Err(BlockErr::BadBlockSize);
+    }
+    if block_size(dev)? == size {
+        return Ok(());
+    }
+    if cache::in_use(dev) {
+        return Err(BlockErr::Busy);
+    }
+    cache::sync(dev)?;
+    cache::invalidate(dev)?;
+    let mut guard = DEVICES.lock();
+    let entry = guard.get_mut().get_mut(dev as usize)
+        .and_then(Option::as_mut)
+        .ok_or(BlockErr::NoDevice)?;
+    entry.block_size = size;
+    Ok(())
+}
+
+/// Number of `block_size(dev)` blocks on `dev`.
+pub fn blocks(dev: DevId) -> Result<u64, BlockErr> {
+    let sectors_per_block = (block_size(dev)? / SECTOR_SIZE) as u64;
+    Ok(device(dev)?.sectors() / sectors_per_block)
+}
+
+/// Check a sector range against a device of `sectors` sectors.
+pub fn check_range(sectors: u64, sector: u64, len: usize) -> Result<(), BlockErr> {
+    if !len.is_multiple_of(SECTOR_SIZE) {
+        return Err(BlockErr::BadLength);
+    }
+    let count = (len / SECTOR_SIZE) as u64;
+    if sector.checked_add(count).is_none_or(|end| end > sectors) {
+        return Err(BlockErr::OutOfRange);
+    }
+    Ok(())
+}
+
+/// A disk in kernel memory, e.g. an image from the initrd or a test.
+pub struct RamDisk {
+    data     : SpinLock<Vec<u8>>,
+    read_only: bool,
+}
+
+impl RamDisk {
+    /// A disk holding `data`, rounded up to whole sectors.
+    pub fn new(mut data: Vec<u8>) -> Self {
+        data.resize(data.len().next_multiple_of(SECTOR_SIZE), 0);
+        Self { data: SpinLock::new(data), read_only: false }
+    }
+
+    pub fn new_read_only(data: Vec<u8>) -> Self {
+        Self { read_only: true, ..Self::new(data) }
+    }
+
+    /// A copy of the whole disk.
+    pub fn contents(&self) -> Vec<u8> {
+        self.data.lock().get().clone()
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn sectors(&self) -> u64 {
+        (self.data.lock().get().len() / SECTOR_SIZE) as u64
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockErr> {
+        let guard = self.data.lock();
+        let data = guard.get();
+        check_range((data.len() / SECTOR_SIZE) as u64, sector, buf.len())?;
+        let start = sector as usize * SECTOR_SIZE;
+        buf.copy_from_slice(&data[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), BlockErr> {
+        if self.read_only {
+            return Err(BlockErr::ReadOnly);
+        }
+        let mut guard = self.data.lock();
+        let data = guard.get_mut();
+        check_range((data.len() / SECTOR_SIZE) as u64, sector, buf.len())?;
+        let start = sector as usize * SECTOR_SIZE;
+        data[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+}
@@ -1,23 +1,130 @@
 use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::fs::FsErr;
 use crate::sync::{SpinLock, SpinLockGuard};
 use crate::uart;
 
 pub const CONS_BUFF_SIZE: usize = 1024;
 
+/// Ctrl-T: print the kernel heap report.
+const CTRL_T: u8 = b'\x14';
+/// Ctrl-O: print the outstanding heap allocations (`alloc-track`).
+#[cfg(feature = "alloc-track")]
+const CTRL_O: u8 = b'\x0f';
+
 pub struct KConsole <'a> {
-    buffer: &'static SpinLock<uart::UartBuff>,
     spinl_guard: SpinLockGuard<'a, uart::UartBuff>
 }
 
 impl <'a> KConsole <'a> {
     pub fn new(buffer: &'static SpinLock<uart::UartBuff>) -> Self {
-        let spinl_guard = buffer.lock();
-        KConsole{ buffer, spinl_guard}
+        KConsole{ spinl_guard: buffer.lock() }
     }
 }
 
 pub struct UConsole { }
 
+/// Input typed on the console, waiting for `cons_read`.
+struct ConsInput {
+    buffer: [u8; CONS_BUFF_SIZE],
+    rd:     usize,
+    wt:     usize,
+}
+
+/// A heap report was asked for on the console, see `cons_commands`.
+static HEAP_REPORT: AtomicBool = AtomicBool::new(false);
+#[cfg(feature = "alloc-track")]
+static ALLOC_DUMP : AtomicBool = AtomicBool::new(false);
+
+static CONS_INPUT: SpinLock<ConsInput> = SpinLock::new(ConsInput {
+    buffer: [0; CONS_BUFF_SIZE],
+    rd:     0,
+    wt:     0,
+});
+
+/// Queue a received character. Called from the UART interrupt.
+pub fn cons_intr(c: u8) {
+    let mut guard = CONS_INPUT.lock();
+    let input = guard.get_mut();
+    match c {
+        CTRL_T => HEAP_REPORT.store(true, Ordering::Relaxed),
+        #[cfg(feature = "alloc-track")]
+        CTRL_O => ALLOC_DUMP.store(true, Ordering::Relaxed),
+        b'\x08' | b'\x7f' => {
+            // erase the last character of the unread line
+            if input.wt != input.rd {
+                let last = (input.wt + CONS_BUFF_SIZE - 1) % CONS_BUFF_SIZE;
+                if input.buffer[last] != b'\n' {
+                    input.wt = last;
+                }
+            }
+        }
+        _ => {
+            let nxt = (input.wt + 1) % CONS_BUFF_SIZE;
+            if nxt != input.rd {
+                input.buffer[input.wt] = c;
+                input.wt = nxt;
+            }
+        }
+    }
+}
+
+/// Run the commands typed as control keys. The UART interrupt only
+/// flags them: they print and walk the heap, so the scheduler runs
+/// them, with no lock held.
+pub fn cons_commands() {
+    if HEAP_REPORT.swap(false, Ordering::Relaxed) {
+        crate::mem::alloc::heap_report();
+    }
+    #[cfg(feature = "alloc-track")]
+    if ALLOC_DUMP.swap(false, Ordering::Relaxed) {
+        crate::mem::alloc::track::dump(0);
+    }
+}
+
+/// Wait channel of `cons_read`, woken by the UART interrupt.
+pub fn cons_chan() -> usize {
+    core::ptr::addr_of!(CONS_INPUT) as usize
+}
+
+/// Read typed input into `buf`, sleeping until there is some. Returns
+/// what is queued, up to and including a newline, or `Interrupted` if
+/// the process was killed meanwhile.
+pub fn cons_read(buf: &mut [u8]) -> Result<usize, FsErr> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    let mut guard = CONS_INPUT.lock();
+    loop {
+        let input = guard.get_mut();
+        let mut count = 0;
+        while input.rd != input.wt && count < buf.len() {
+            let c = input.buffer[input.rd];
+            input.rd = (input.rd + 1) % CONS_BUFF_SIZE;
+            buf[count] = c;
+            count += 1;
+            if c == b'\n' {
+                break;
+            }
+        }
+        if count > 0 {
+            return Ok(count);
+        }
+        if crate::proc::myproc().is_some_and(|proc| proc.killed) {
+            return Err(FsErr::Interrupted);
+        }
+        guard = crate::sched::sleep(cons_chan(), guard);
+    }
+}
+
+/// Write raw bytes to the console, e.g. on behalf of a user program.
+pub fn cons_write(bytes: &[u8]) {
+    let guard = uart::UART_RX_BUFF.lock();
+    for c in bytes {
+        guard.uart_putc_block(*c);
+    }
+}
+
 impl <'a> Write for KConsole<'a> {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         if !s.is_empty() {
@@ -0,0 +1,144 @@
+//! Reader for cpio "newc" archives (the format used for initramfs).
+//!
+//! Each entry is a 110 byte ASCII header (`070701` followed by 13 hex
+//! fields of 8 digits), the NUL terminated name padded to 4 bytes and
+//! the file data padded to 4 bytes. The archive ends with an entry named
+//! `TRAILER!!!`.
+
+const NEWC_MAGIC      : &[u8] = b"070701";
+const NEWC_HEADER_SIZE: usize = 110;
+const NEWC_TRAILER    : &str  = "TRAILER!!!";
+
+/// File type bits of `mode`.
+pub const S_IFMT : u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum CpioErr {
+    BadMagic,
+    BadHeader,      // a header field is not hex
+    BadName,        // the name is not NUL terminated UTF-8
+    Truncated,      // name or data run past the end of the archive
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CpioEntry<'a> {
+    /// Path inside the archive, without a leading `/` or `./`.
+    pub name : &'a str,
+    pub ino  : u32,
+    pub mode : u32,
+    pub nlink: u32,
+    pub mtime: u32,
+    /// Device the file came from; `(dev, ino)` identifies hard links.
+    pub devmajor: u32,
+    pub devminor: u32,
+    pub data : &'a [u8],
+}
+
+impl CpioEntry<'_> {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+}
+
+/// Iterates over the entries of an archive, stopping at the trailer.
+/// A malformed entry is returned as an error and ends the iteration.
+pub struct CpioIter<'a> {
+    archive: &'a [u8],
+    off    : usize,
+    done   : bool,
+}
+
+pub fn entries(archive: &[u8]) -> CpioIter<'_> {
+    CpioIter { archive, off: 0, done: false }
+}
+
+const fn align4(off: usize) -> usize {
+    (off + 3) & !3
+}
+
+fn hex_field(header: &[u8], idx: usize) -> Result<u32, CpioErr> {
+    let start = NEWC_MAGIC.len() + idx * 8;
+    let digits = core::str::from_utf8(&header[start..start + 8])
+        .map_err(|_| CpioErr::BadHeader)?;
+    u32::from_str_radix(digits, 16).map_err(|_| CpioErr::BadHeader)
+}
+
+/// Strip the leading `./` or `/` archivers put in front of names.
+pub fn normalize(name: &str) -> &str {
+    let name = name.strip_prefix("./").unwrap_or(name);
+    let name = name.trim_start_matches('/');
+    name.trim_end_matches('/')
+}
+
+impl<'a> CpioIter<'a> {
+    fn parse(&mut self) -> Result<Option<CpioEntry<'a>>, CpioErr> {
+        let header = self.archive.get(self.off..self.off + NEWC_HEADER_SIZE)
+            .ok_or(CpioErr::Truncated)?;
+        if &header[..NEWC_MAGIC.len()] != NEWC_MAGIC {
+            return Err(CpioErr::BadMagic);
+        }
+
+        let ino       = hex_field(header, 0)?;
+        let mode      = hex_field(header, 1)?;
+        let nlink     = hex_field(header, 4)?;
+        let mtime     = hex_field(header, 5)?;
+        let file_size = hex_field(header, 6)? as usize;
+        let devmajor  = hex_field(header, 7)?;
+        let devminor  = hex_field(header, 8)?;
+        let name_size = hex_field(header, 11)? as usize;
+
+        let name_start = self.off + NEWC_HEADER_SIZE;
+        let name = self.archive.get(name_start..name_start + name_size)
+            .ok_or(CpioErr::Truncated)?;
+        let (&nul, name) = name.split_last().ok_or(CpioErr::BadName)?;
+        if nul != 0 {
+            return Err(CpioErr::BadName);
+        }
+        let name = core::str::from_utf8(name).map_err(|_| CpioErr::BadName)?;
+
+        let data_start = align4(name_start + name_size);
+        let data = self.archive.get(data_start..data_start + file_size)
+            .ok_or(CpioErr::Truncated)?;
+        self.off = align4(data_start + file_size);
+
+        if name == NEWC_TRAILER {
+            return Ok(None);
+        }
+        Ok(Some(CpioEntry {
+            name: normalize(name), ino, mode, nlink, mtime, devmajor, devminor, data,
+        }))
+    }
+}
+
+impl<'a> Iterator for CpioIter<'a> {
+    type Item = Result<CpioEntry<'a>, CpioErr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.parse() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
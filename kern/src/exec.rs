@@ -0,0 +1,171 @@
+//! Loading ELF executables into a process' address space.
+
+use elf::ElfBytes;
+use elf::abi::{EM_RISCV, ET_EXEC, PF_R, PF_W, PF_X, PT_LOAD};
+use elf::endian::LittleEndian;
+use elf::file::Class;
+use elf::ParseError;
+
+use crate::ktrap::TrapFrame;
+use crate::proc::{self, Proc, USR_LIMIT, USR_STACK_TOP};
+use crate::virtm::{self, PTEPerms, PAGE_SIZE};
+
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum LoadErr {
+    Parse(ParseError),  // malformed ELF file
+    WrongClass,         // not ELF64
+    WrongMachine,       // not RISC-V
+    NotExecutable,      // not `ET_EXEC` (e.g. a relocatable object)
+    NoLoadSegments,     // nothing to map
+    BadSegment,         // filesz > memsz, outside of the user range or overlapping another
+    OutOfMemory,
+}
+
+impl From<ParseError> for LoadErr {
+    fn from(err: ParseError) -> Self {
+        LoadErr::Parse(err)
+    }
+}
+
+#[inline]
+fn page_down(addr: usize) -> usize {
+    addr & !(PAGE_SIZE - 1)
+}
+
+#[inline]
+fn page_up(addr: usize) -> usize {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// PTE permissions for a segment's `p_flags`.
+fn segment_perms(p_flags: u32) -> u64 {
+    let mut perms = PTEPerms::USER;
+    if (p_flags & PF_R) != 0 { perms |= PTEPerms::READ; }
+    if (p_flags & PF_W) != 0 { perms |= PTEPerms::WRITE; }
+    if (p_flags & PF_X) != 0 { perms |= PTEPerms::EXEC; }
+    perms
+}
+
+/// Map every page of `[vaddr, vaddr + memsz)` and copy `data` to `vaddr`.
+/// The rest of the range (the `.bss` part) stays zeroed. A page that is
+/// shared with a previous segment keeps its frame and gains `perms`.
+fn load_segment(proc: &mut Proc, vaddr: usize, memsz: usize, data: &[u8], perms: u64)
+    -> Result<(), LoadErr>
+{
+    let mut page = page_down(vaddr);
+    while page < vaddr + memsz {
+        let frame = match virtm::pt_walk(proc.page_table, page) {
+            Some(entry) if unsafe { *entry & PTEPerms::VALID } != 0 => {
+                unsafe { *entry |= perms };
+                virtm::pt_translate(proc.page_table, page, 0).ok_or(LoadErr::BadSegment)?
+            },
+            _ => {
+                let frame = virtm::pg_alloc().ok_or(LoadErr::OutOfMemory)? as usize;
+                if !virtm::pt_map(proc.page_table, frame, page, PAGE_SIZE, perms, "usr segment") {
+                    virtm::pg_free(frame as *mut u8);
+                    return Err(LoadErr::OutOfMemory);
+                }
+                frame
+            }
+        };
+
+        // the part of `data` that lands in this page
+        let copy_start = page.max(vaddr);
+        let copy_end   = (page + PAGE_SIZE).min(vaddr + data.len());
+        if copy_start < copy_end {
+            let src = &data[(copy_start - vaddr)..(copy_end - vaddr)];
+            let dst = (frame + (copy_start - page)) as *mut u8;
+            virtm::memcpy(dst, src.as_ptr(), src.len());
+        }
+        page += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+/// Load the `PT_LOAD` segments of `image` into `proc`'s page table.
+/// The heap starts on the page after the highest segment.
+/// Returns the entry point.
+pub fn load_elf(proc: &mut Proc, image: &[u8]) -> Result<usize, LoadErr> {
+    let elf = ElfBytes::<LittleEndian>::minimal_parse(image)?;
+
+    if elf.ehdr.class != Class::ELF64 {
+        return Err(LoadErr::WrongClass);
+    }
+    if elf.ehdr.e_machine != EM_RISCV {
+        return Err(LoadErr::WrongMachine);
+    }
+    if elf.ehdr.e_type != ET_EXEC {
+        return Err(LoadErr::NotExecutable);
+    }
+
+    let segments = elf.segments().ok_or(LoadErr::NoLoadSegments)?;
+    let mut image_end = 0;
+    for (idx, phdr) in segments.iter().enumerate().filter(|(_, phdr)| phdr.p_type == PT_LOAD) {
+        let vaddr = phdr.p_vaddr as usize;
+        let memsz = phdr.p_memsz as usize;
+        if phdr.p_filesz > phdr.p_memsz {
+            return Err(LoadErr::BadSegment);
+        }
+        let end = vaddr.checked_add(memsz).ok_or(LoadErr::BadSegment)?;
+        if vaddr < PAGE_SIZE || end > USR_LIMIT {
+            return Err(LoadErr::BadSegment);
+        }
+        // segments may share a page, not bytes; the earlier ones are in range
+        let overlaps = segments.iter().take(idx)
+            .filter(|other| other.p_type == PT_LOAD && other.p_memsz != 0)
+            .any(|other| (other.p_vaddr as usize) < end && vaddr < (other.p_vaddr + other.p_memsz) as usize);
+        if memsz != 0 && overlaps {
+            return Err(LoadErr::BadSegment);
+        }
+
+        let data = elf.segment_data(&phdr)?;
+        load_segment(proc, vaddr, memsz, data, segment_perms(phdr.p_flags))?;
+        image_end = image_end.max(end);
+    }
+    if image_end == 0 {
+        return Err(LoadErr::NoLoadSegments);
+    }
+
+    let entry = elf.ehdr.e_entry as usize;
+    if virtm::pt_translate(proc.page_table, entry, PTEPerms::EXEC).is_none() {
+        return Err(LoadErr::BadSegment);
+    }
+
+    proc.brk = page_up(image_end);
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        core::arch::asm!("fence.i", options(nostack, nomem, preserves_flags));
+    };
+    Ok(entry)
+}
+
+/// Replace the program running in `proc` with `image`. The old address
+/// space is only released once the new program is fully loaded, so on
+/// failure `proc` is left untouched.
+pub fn exec(proc: &mut Proc, image: &[u8]) -> Result<(), LoadErr> {
+    let page_table = proc::proc_page_table(proc.trap_frame).ok_or(LoadErr::OutOfMemory)?;
+    if !proc::proc_map_stack(page_table) {
+        virtm::pt_destroy(page_table, true);
+        return Err(LoadErr::OutOfMemory);
+    }
+
+    let old_table = core::mem::replace(&mut proc.page_table, page_table);
+    let old_brk   = proc.brk;
+    let entry = match load_elf(proc, image) {
+        Ok(entry) => entry,
+        Err(err) => {
+            proc.page_table = old_table;
+            proc.brk        = old_brk;
+            virtm::pt_destroy(page_table, true);
+            return Err(err);
+        }
+    };
+    virtm::pt_destroy(old_table, true);
+
+    let tf = proc.trap_frame();
+    *tf = TrapFrame::new();
+    tf.sepc = entry;
+    tf.sp   = USR_STACK_TOP;
+    Ok(())
+}
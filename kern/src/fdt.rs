@@ -0,0 +1,310 @@
+//! Flattened device tree (DTB) access.
+//!
+//! With `-bios none` QEMU hands the DTB address to the kernel in `a1`.
+//! Properties can be looked up by node path, or the nodes walked in
+//! order with `Fdt::nodes`. See `platform` for what the kernel takes
+//! from the tree.
+
+const FDT_MAGIC      : u32 = 0xd00dfeed;
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE  : u32 = 2;
+const FDT_PROP      : u32 = 3;
+const FDT_NOP       : u32 = 4;
+const FDT_END       : u32 = 9;
+
+/// Deepest node nesting `Fdt::nodes` follows.
+const MAX_DEPTH: usize = 16;
+
+/// Physical address of the DTB passed by the boot loader (0 if none).
+pub static mut BOOT_DTB: usize = 0;
+
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum FdtErr {
+    BadMagic,
+    BadVersion,
+    Truncated,     // the header points past the end of the blob
+}
+
+pub struct Fdt<'a> {
+    blob   : &'a [u8],
+    structs: &'a [u8],
+    strings: &'a [u8],
+}
+
+fn be32(bytes: &[u8], off: usize) -> Option<u32> {
+    let word = bytes.get(off..off + 4)?;
+    Some(u32::from_be_bytes([word[0], word[1], word[2], word[3]]))
+}
+
+/// NUL terminated string starting at `off`.
+fn cstr(bytes: &[u8], off: usize) -> Option<&str> {
+    let rest = bytes.get(off..)?;
+    let len  = rest.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&rest[..len]).ok()
+}
+
+const fn align4(off: usize) -> usize {
+    (off + 3) & !3
+}
+
+/// Big endian value spread over any number of cells.
+fn read_cells(bytes: &[u8]) -> usize {
+    bytes.chunks_exact(4)
+        .fold(0, |acc, cell| (acc << 32) | be32(cell, 0).unwrap() as usize)
+}
+
+/// Read a 1 or 2 cell (big endian) property value.
+pub fn prop_usize(value: &[u8]) -> Option<usize> {
+    match value.len() {
+        4 => be32(value, 0).map(|v| v as usize),
+        8 => Some(((be32(value, 0)? as usize) << 32) | be32(value, 4)? as usize),
+        _ => None,
+    }
+}
+
+impl<'a> Fdt<'a> {
+    pub fn from_bytes(blob: &'a [u8]) -> Result<Self, FdtErr> {
+        if be32(blob, 0) != Some(FDT_MAGIC) {
+            return Err(FdtErr::BadMagic);
+        }
+        let header = |idx: usize| be32(blob, idx * 4).ok_or(FdtErr::Truncated);
+        let total_size    = header(1)? as usize;
+        let off_struct    = header(2)? as usize;
+        let off_strings   = header(3)? as usize;
+        let last_comp     = header(6)?;
+        let size_strings  = header(8)? as usize;
+        let size_struct   = header(9)? as usize;
+        if last_comp > 17 {
+            return Err(FdtErr::BadVersion);
+        }
+
+        let blob = blob.get(..total_size).ok_or(FdtErr::Truncated)?;
+        Ok(Self {
+            blob,
+            structs: blob.get(off_struct..off_struct + size_struct).ok_or(FdtErr::Truncated)?,
+            strings: blob.get(off_strings..off_strings + size_strings).ok_or(FdtErr::Truncated)?,
+        })
+    }
+
+    /// # Safety
+    /// `addr` must point to a readable DTB that outlives the returned `Fdt`.
+    pub unsafe fn from_addr(addr: usize) -> Result<Self, FdtErr> {
+        if addr == 0 {
+            return Err(FdtErr::BadMagic);
+        }
+        let header = core::slice::from_raw_parts(addr as *const u8, FDT_HEADER_SIZE);
+        if be32(header, 0) != Some(FDT_MAGIC) {
+            return Err(FdtErr::BadMagic);
+        }
+        let total_size = be32(header, 4).unwrap() as usize;
+        Self::from_bytes(core::slice::from_raw_parts(addr as *const u8, total_size))
+    }
+
+    /// Size of the whole blob in bytes.
+    pub fn total_size(&self) -> usize {
+        self.blob.len()
+    }
+
+    /// Every node of the tree, depth first, the root included.
+    pub fn nodes(&self) -> FdtNodes<'a> {
+        FdtNodes {
+            structs: self.structs,
+            strings: self.strings,
+            off    : 0,
+            depth  : 0,
+            cells  : [(2, 1); MAX_DEPTH],
+        }
+    }
+
+    /// First enabled node compatible with one of `compat`.
+    pub fn find_compatible(&self, compat: &[&str]) -> Option<FdtNode<'a>> {
+        self.nodes().find(|node| node.is_enabled() && compat.iter().any(|c| node.is_compatible(c)))
+    }
+
+    /// Value of property `name` on the node at `path` (e.g. `/chosen`).
+    /// Path components without a unit address match any unit address.
+    pub fn prop(&self, path: &str, name: &str) -> Option<&'a [u8]> {
+        let component = |idx: usize| path.split('/').filter(|c| !c.is_empty()).nth(idx);
+        let target = path.split('/').filter(|c| !c.is_empty()).count();
+        let mut depth   = 0;    // nesting level of the current node, root is 1
+        let mut matched = 0;    // number of leading path components matched
+        let mut off     = 0;
+
+        loop {
+            let token = be32(self.structs, off)?;
+            off += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let node = cstr(self.structs, off)?;
+                    off = align4(off + node.len() + 1);
+                    depth += 1;
+                    if depth > 1 && matched == depth - 2 {
+                        let hit = component(matched).is_some_and(|want| {
+                            node == want || (!want.contains('@')
+                                && node.split('@').next() == Some(want))
+                        });
+                        if hit {
+                            matched += 1;
+                        }
+                    }
+                }
+                FDT_END_NODE => {
+                    if depth > 1 && matched == depth - 1 {
+                        if matched == target {
+                            return None;
+                        }
+                        matched -= 1;
+                    }
+                    depth -= 1;
+                }
+                FDT_PROP => {
+                    let len     = be32(self.structs, off)? as usize;
+                    let nameoff = be32(self.structs, off + 4)? as usize;
+                    let value   = self.structs.get(off + 8..off + 8 + len)?;
+                    off = align4(off + 8 + len);
+                    if matched == target && depth == target + 1
+                        && cstr(self.strings, nameoff)? == name {
+                        return Some(value);
+                    }
+                }
+                FDT_NOP => {}
+                FDT_END => return None,
+                _ => return None,       // unknown token, corrupt blob
+            }
+        }
+    }
+}
+
+/// A node met while walking the tree.
+#[derive(Clone, Copy)]
+pub struct FdtNode<'a> {
+    structs   : &'a [u8],
+    strings   : &'a [u8],
+    props     : usize,      // offset of the first token after the name
+    name      : &'a str,
+    depth     : usize,      // the root is 1
+    addr_cells: usize,      // the parent's `#address-cells`, `reg` is in these
+    size_cells: usize,
+}
+
+impl<'a> FdtNode<'a> {
+    /// Full name, with the unit address (e.g. `serial@10000000`).
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    /// Nesting level, the root is 1.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn props(&self) -> impl Iterator<Item = (&'a str, &'a [u8])> {
+        let (structs, strings) = (self.structs, self.strings);
+        let mut off = self.props;
+        core::iter::from_fn(move || loop {
+            match be32(structs, off)? {
+                FDT_PROP => {
+                    let len     = be32(structs, off + 4)? as usize;
+                    let nameoff = be32(structs, off + 8)? as usize;
+                    let value   = structs.get(off + 12..off + 12 + len)?;
+                    off = align4(off + 12 + len);
+                    return Some((cstr(strings, nameoff)?, value));
+                }
+                FDT_NOP => off += 4,
+                _ => return None,       // properties come before subnodes
+            }
+        })
+    }
+
+    pub fn prop(&self, name: &str) -> Option<&'a [u8]> {
+        self.props().find(|&(prop, _)| prop == name).map(|(_, value)| value)
+    }
+
+    /// Does the `compatible` string list hold `compat`?
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        self.prop("compatible").is_some_and(|list| {
+            list.split(|&b| b == 0).any(|entry| entry == compat.as_bytes())
+        })
+    }
+
+    /// Is the `device_type` property `kind` (e.g. `memory`, `cpu`)?
+    pub fn is_type(&self, kind: &str) -> bool {
+        self.prop("device_type").and_then(|value| cstr(value, 0)) == Some(kind)
+    }
+
+    /// Nodes without a `status`, or with `okay`, are usable.
+    pub fn is_enabled(&self) -> bool {
+        self.prop("status").and_then(|value| cstr(value, 0)).is_none_or(|s| s == "okay" || s == "ok")
+    }
+
+    /// `(address, size)` pairs of the `reg` property.
+    pub fn reg(&self) -> impl Iterator<Item = (usize, usize)> + 'a {
+        let (addr, size) = (self.addr_cells * 4, self.size_cells * 4);
+        let value = self.prop("reg").unwrap_or(&[]);
+        value.chunks_exact((addr + size).max(4))
+            .map(move |entry| (read_cells(&entry[..addr]), read_cells(&entry[addr..])))
+    }
+
+    /// First entry of `interrupts`, for devices with one interrupt cell.
+    pub fn irq(&self) -> Option<u32> {
+        self.prop("interrupts").and_then(|value| be32(value, 0))
+    }
+}
+
+/// Depth first walk over the nodes, see `Fdt::nodes`.
+pub struct FdtNodes<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+    off    : usize,
+    depth  : usize,
+    cells  : [(usize, usize); MAX_DEPTH],   // cells each open node gives its children
+}
+
+impl<'a> Iterator for FdtNodes<'a> {
+    type Item = FdtNode<'a>;
+
+    fn next(&mut self) -> Option<FdtNode<'a>> {
+        loop {
+            let token = be32(self.structs, self.off)?;
+            self.off += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = cstr(self.structs, self.off)?;
+                    self.off = align4(self.off + name.len() + 1);
+                    if self.depth >= MAX_DEPTH {
+                        return None;
+                    }
+                    let (addr_cells, size_cells) = match self.depth {
+                        0     => (2, 1),
+                        depth => self.cells[depth - 1],
+                    };
+                    self.depth += 1;
+                    let node = FdtNode {
+                        structs: self.structs,
+                        strings: self.strings,
+                        props  : self.off,
+                        name,
+                        depth  : self.depth,
+                        addr_cells,
+                        size_cells,
+                    };
+                    let cells = |name, default| {
+                        node.prop(name).and_then(|value| be32(value, 0)).map_or(default, |v| v as usize)
+                    };
+                    self.cells[self.depth - 1] = (cells("#address-cells", 2), cells("#size-cells", 1));
+                    return Some(node);
+                }
+                FDT_END_NODE => self.depth = self.depth.checked_sub(1)?,
+                FDT_PROP => {
+                    let len = be32(self.structs, self.off)? as usize;
+                    self.off = align4(self.off + 8 + len);
+                }
+                FDT_NOP => {}
+                _ => return None,       // FDT_END, or a corrupt blob
+            }
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Device filesystem mounted at `/dev`. Holds the console.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+
+use super::{DirEntry, FileSystem, FileType, FsErr, Inode, InodeRef, Stat};
+use crate::console;
+
+const DEVFS_DEV: u32 = 2;
+
+const ROOT_INO   : u64 = 1;
+const CONSOLE_INO: u64 = 2;
+
+pub struct DevFs {
+    root: Arc<DevDir>,
+}
+
+struct DevDir {
+    console: Arc<Console>,
+}
+
+/// `/dev/console`: reads wait for a line from the UART, writes go
+/// straight out.
+pub struct Console;
+
+impl DevFs {
+    pub fn new() -> Self {
+        Self { root: Arc::new(DevDir { console: Arc::new(Console) }) }
+    }
+}
+
+impl Default for DevFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for DevFs {
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+}
+
+impl Inode for DevDir {
+    fn stat(&self) -> Stat {
+        Stat { ino: ROOT_INO, size: 0, dev: DEVFS_DEV, nlink: 2, kind: FileType::Directory, mode: 0o755 }
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsErr> {
+        match name {
+            "console" => Ok(self.console.clone()),
+            _ => Err(FsErr::NotFound),
+        }
+    }
+
+    fn create(&self, _name: &str, _kind: FileType) -> Result<InodeRef, FsErr> {
+        Err(FsErr::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsErr> {
+        Err(FsErr::ReadOnly)
+    }
+
+    fn dirent(&self, index: usize) -> Result<Option<DirEntry>, FsErr> {
+        Ok(match index {
+            0 => Some(DirEntry { ino: CONSOLE_INO, kind: FileType::CharDevice, name: String::from("console") }),
+            _ => None,
+        })
+    }
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+}
+
+impl Inode for Console {
+    fn stat(&self) -> Stat {
+        Stat { ino: CONSOLE_INO, size: 0, dev: DEVFS_DEV, nlink: 1, kind: FileType::CharDevice, mode: 0o620 }
+    }
+
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize, FsErr> {
+        console::cons_read(buf)
+    }
+
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize, FsErr> {
+        console::cons_write(buf);
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, _size: usize) -> Result<(), FsErr> {
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+}
@@ -0,0 +1,407 @@
+//! ext2, read-only, e.g. images made with `mke2fs -d`.
+//!
+//! The cache block size is set to the filesystem's block size at mount,
+//! so a filesystem block is one cache block. Inodes are read from disk
+//! on lookup and never change afterwards: nothing writes to the volume.
+//!
+//! Features that change the on-disk layout (extents, 64 bit, meta block
+//! groups, compression) refuse the mount; a volume that needs journal
+//! recovery is refused as well.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::{DirEntry, FileSystem, FileType, FsErr, Inode, InodeRef, Stat};
+use crate::block::{self, DevId};
+
+const SUPERBLOCK_OFFSET: usize = 1024;
+const EXT2_MAGIC       : u16 = 0xef53;
+const ROOT_INO         : u32 = 2;
+
+/// Block pointers in an inode: 12 direct, then single, double and
+/// triple indirect.
+const N_DIRECT   : usize = 12;
+const IND_BLOCK  : usize = 12;
+const DIND_BLOCK : usize = 13;
+const TIND_BLOCK : usize = 14;
+const N_BLOCKS   : usize = 15;
+
+/// Symlinks shorter than this keep their target in the block pointers.
+const FAST_SYMLINK_MAX: usize = N_BLOCKS * 4;
+
+/// `s_feature_incompat` bits.
+pub struct Ext2Incompat;
+impl Ext2Incompat {
+    pub const COMPRESSION: u32 = 0x0001;
+    pub const FILETYPE   : u32 = 0x0002;    // directory entries carry the file type
+    pub const RECOVER    : u32 = 0x0004;    // journal needs recovery
+    pub const JOURNAL_DEV: u32 = 0x0008;
+    pub const META_BG    : u32 = 0x0010;
+    pub const EXTENTS    : u32 = 0x0040;
+    pub const BIT64      : u32 = 0x0080;
+}
+
+/// Incompatible features this driver reads correctly.
+const INCOMPAT_SUPPORTED: u32 = Ext2Incompat::FILETYPE;
+
+/// File type bits of `i_mode`.
+struct Ext2Mode;
+impl Ext2Mode {
+    const TYPE_MASK: u16 = 0xf000;
+    const FIFO     : u16 = 0x1000;
+    const CHR      : u16 = 0x2000;
+    const DIR      : u16 = 0x4000;
+    const BLK      : u16 = 0x6000;
+    const REG      : u16 = 0x8000;
+    const LNK      : u16 = 0xa000;
+    const SOCK     : u16 = 0xc000;
+}
+
+pub struct Ext2Fs {
+    vol : Arc<Volume>,
+    root: InodeRef,
+}
+
+struct Volume {
+    dev             : DevId,
+    stat_dev        : u32,
+    block_size      : usize,
+    inodes_count    : u32,
+    inodes_per_group: u32,
+    inode_size      : usize,
+    inode_tables    : Vec<u64>,     // first block of each group's inode table
+    filetype        : bool,         // `Ext2Incompat::FILETYPE`
+}
+
+pub struct Ext2Inode {
+    vol    : Arc<Volume>,
+    ino    : u32,
+    mode   : u16,
+    nlink  : u16,
+    size   : u64,
+    sectors: u32,               // `i_blocks`, in 512 byte units
+    blocks : [u32; N_BLOCKS],
+}
+
+fn le16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn le32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn file_type(mode: u16) -> FileType {
+    match mode & Ext2Mode::TYPE_MASK {
+        Ext2Mode::DIR => FileType::Directory,
+        Ext2Mode::LNK => FileType::Symlink,
+        Ext2Mode::CHR => FileType::CharDevice,
+        // block devices, fifos and sockets show up as files that
+        // cannot be read
+        _ => FileType::Regular,
+    }
+}
+
+impl Volume {
+    /// Read `buf.len()` bytes of block `block` from `offset`.
+    fn read(&self, block: u64, offset: usize, buf: &mut [u8]) -> Result<(), FsErr> {
+        let data = block::bread(self.dev, block)?;
+        buf.copy_from_slice(&data.data()[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn read_u32(&self, block: u64, index: usize) -> Result<u32, FsErr> {
+        let mut raw = [0u8; 4];
+        self.read(block, index * 4, &mut raw)?;
+        Ok(u32::from_le_bytes(raw))
+    }
+
+    fn inode(self: &Arc<Self>, ino: u32) -> Result<Arc<Ext2Inode>, FsErr> {
+        if ino == 0 || ino > self.inodes_count {
+            return Err(FsErr::Corrupt);
+        }
+        let group = ((ino - 1) / self.inodes_per_group) as usize;
+        let index = ((ino - 1) % self.inodes_per_group) as usize;
+        let table = *self.inode_tables.get(group).ok_or(FsErr::Corrupt)?;
+        let byte  = index * self.inode_size;
+
+        let mut raw = [0u8; 128];
+        self.read(table + (byte / self.block_size) as u64, byte % self.block_size, &mut raw)?;
+        let mode = le16(&raw, 0);
+        // the high half of the size is `i_dir_acl` for directories
+        let high = match mode & Ext2Mode::TYPE_MASK {
+            Ext2Mode::REG => le32(&raw, 108) as u64,
+            _ => 0,
+        };
+        let mut blocks = [0u32; N_BLOCKS];
+        for (n, block) in blocks.iter_mut().enumerate() {
+            *block = le32(&raw, 40 + n * 4);
+        }
+        Ok(Arc::new(Ext2Inode {
+            vol    : self.clone(),
+            ino,
+            mode,
+            nlink  : le16(&raw, 26),
+            size   : (high << 32) | le32(&raw, 4) as u64,
+            sectors: le32(&raw, 28),
+            blocks,
+        }))
+    }
+}
+
+impl Ext2Fs {
+    /// Mount the ext2 volume on `dev`, read-only.
+    pub fn mount(dev: DevId) -> Result<Arc<Self>, FsErr> {
+        block::set_block_size(dev, 1024)?;
+        let mut sb = [0u8; 1024];
+        sb.copy_from_slice(block::bread(dev, (SUPERBLOCK_OFFSET / 1024) as u64)?.data());
+
+        if le16(&sb, 56) != EXT2_MAGIC {
+            return Err(FsErr::Invalid);
+        }
+        let inodes_count     = le32(&sb, 0);
+        let blocks_count     = le32(&sb, 4);
+        let first_data_block = le32(&sb, 20);
+        let log_block_size   = le32(&sb, 24);
+        let blocks_per_group = le32(&sb, 32);
+        let inodes_per_group = le32(&sb, 40);
+        let rev_level        = le32(&sb, 76);
+        let (inode_size, incompat) = match rev_level {
+            0 => (128, 0),
+            _ => (le16(&sb, 88) as usize, le32(&sb, 96)),
+        };
+
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(FsErr::Unsupported);
+        }
+        if log_block_size > 2 {
+            return Err(FsErr::Unsupported);     // larger than the cache handles
+        }
+        let block_size = 1024usize << log_block_size;
+        if blocks_per_group == 0 || inodes_per_group == 0 || blocks_count <= first_data_block
+            || inode_size < 128 || !inode_size.is_power_of_two() || inode_size > block_size {
+            return Err(FsErr::Corrupt);
+        }
+
+        block::set_block_size(dev, block_size)?;
+        let groups = (blocks_count - first_data_block).div_ceil(blocks_per_group) as usize;
+        let desc_block = first_data_block as u64 + 1;
+        let mut inode_tables = Vec::new();
+        inode_tables.try_reserve_exact(groups).map_err(|_| FsErr::NoSpace)?;
+        for group in 0..groups {
+            let byte = group * 32;
+            let data = block::bread(dev, desc_block + (byte / block_size) as u64)?;
+            inode_tables.push(le32(data.data(), byte % block_size + 8) as u64);
+        }
+
+        let vol = Arc::new(Volume {
+            dev,
+            stat_dev: super::new_dev(),
+            block_size,
+            inodes_count,
+            inodes_per_group,
+            inode_size,
+            inode_tables,
+            filetype: incompat & Ext2Incompat::FILETYPE != 0,
+        });
+        let root = vol.inode(ROOT_INO)?;
+        if root.mode & Ext2Mode::TYPE_MASK != Ext2Mode::DIR {
+            return Err(FsErr::Corrupt);
+        }
+        Ok(Arc::new(Self { vol, root }))
+    }
+
+    pub fn dev(&self) -> DevId {
+        self.vol.dev
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+}
+
+impl Ext2Inode {
+    fn kind(&self) -> FileType {
+        file_type(self.mode)
+    }
+
+    fn is_dir(&self) -> bool {
+        self.mode & Ext2Mode::TYPE_MASK == Ext2Mode::DIR
+    }
+
+    /// Disk block holding block `index` of the file, `None` for a hole.
+    fn map(&self, index: usize) -> Result<Option<u64>, FsErr> {
+        if index < N_DIRECT {
+            return Ok(Some(self.blocks[index] as u64).filter(|&block| block != 0));
+        }
+        let per_block = self.vol.block_size / 4;
+        let mut rest = index - N_DIRECT;
+        let mut span = per_block;
+        for (levels, slot) in [(1, IND_BLOCK), (2, DIND_BLOCK), (3, TIND_BLOCK)] {
+            if rest < span {
+                return self.walk(self.blocks[slot], levels, rest);
+            }
+            rest -= span;
+            span *= per_block;
+        }
+        Err(FsErr::Corrupt)
+    }
+
+    /// Follow `levels` of indirect blocks from `block` to entry `index`.
+    fn walk(&self, mut block: u32, levels: u32, index: usize) -> Result<Option<u64>, FsErr> {
+        let per_block = self.vol.block_size / 4;
+        for level in (0..levels).rev() {
+            if block == 0 {
+                return Ok(None);
+            }
+            block = self.vol.read_u32(block as u64, index / per_block.pow(level) % per_block)?;
+        }
+        Ok(Some(block as u64).filter(|&block| block != 0))
+    }
+
+    /// Read file contents at `offset`; holes read as zeros.
+    fn read_data(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsErr> {
+        let size = self.size as usize;
+        if offset >= size {
+            return Ok(0);
+        }
+        let count = buf.len().min(size - offset);
+        let block_size = self.vol.block_size;
+        let mut done = 0;
+        while done < count {
+            let pos = offset + done;
+            let at  = pos % block_size;
+            let len = (block_size - at).min(count - done);
+            let out = &mut buf[done..done + len];
+            match self.map(pos / block_size)? {
+                Some(block) => self.vol.read(block, at, out)?,
+                None => out.fill(0),
+            }
+            done += len;
+        }
+        Ok(count)
+    }
+
+    /// The directory's live entries, without "." and "..".
+    fn entries(&self) -> Result<Vec<DirEntry>, FsErr> {
+        if !self.is_dir() {
+            return Err(FsErr::NotDir);
+        }
+        let block_size = self.vol.block_size;
+        let mut entries = Vec::new();
+        let mut data = alloc::vec![0u8; block_size];
+        for index in 0..(self.size as usize).div_ceil(block_size) {
+            let Some(block) = self.map(index)? else {
+                continue;
+            };
+            self.vol.read(block, 0, &mut data)?;
+            let mut at = 0;
+            while at + 8 <= block_size {
+                let ino      = le32(&data, at);
+                let rec_len  = le16(&data, at + 4) as usize;
+                let name_len = data[at + 6] as usize;
+                if rec_len < 8 || at + rec_len > block_size || 8 + name_len > rec_len {
+                    return Err(FsErr::Corrupt);
+                }
+                let name = &data[at + 8..at + 8 + name_len];
+                if ino != 0 && name != b"." && name != b".." {
+                    let name = String::from(core::str::from_utf8(name).map_err(|_| FsErr::Corrupt)?);
+                    let kind = match self.vol.filetype {
+                        true => match data[at + 7] {
+                            2 => FileType::Directory,
+                            3 => FileType::CharDevice,
+                            7 => FileType::Symlink,
+                            _ => FileType::Regular,
+                        },
+                        false => self.vol.inode(ino)?.kind(),
+                    };
+                    entries.push(DirEntry { ino: ino as u64, kind, name });
+                }
+                at += rec_len;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Directory operations fail with `ReadOnly` on directories.
+    fn read_only_dir(&self) -> FsErr {
+        if self.is_dir() { FsErr::ReadOnly } else { FsErr::NotDir }
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn stat(&self) -> Stat {
+        Stat {
+            ino  : self.ino as u64,
+            size : self.size,
+            dev  : self.vol.stat_dev,
+            nlink: self.nlink as u32,
+            kind : self.kind(),
+            mode : (self.mode & 0o7777) as u32,
+        }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsErr> {
+        match self.mode & Ext2Mode::TYPE_MASK {
+            Ext2Mode::REG => self.read_data(offset, buf),
+            Ext2Mode::DIR => Err(FsErr::IsDir),
+            Ext2Mode::LNK => Err(FsErr::InvalidPath),
+            Ext2Mode::CHR | Ext2Mode::BLK | Ext2Mode::FIFO | Ext2Mode::SOCK => Err(FsErr::Unsupported),
+            _ => Err(FsErr::Corrupt),
+        }
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize, FsErr> {
+        if self.is_dir() { Err(FsErr::IsDir) } else { Err(FsErr::ReadOnly) }
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsErr> {
+        let entry = self.entries()?.into_iter()
+            .find(|entry| entry.name == name)
+            .ok_or(FsErr::NotFound)?;
+        Ok(self.vol.inode(entry.ino as u32)?)
+    }
+
+    fn create(&self, _name: &str, _kind: FileType) -> Result<InodeRef, FsErr> {
+        Err(self.read_only_dir())
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<InodeRef, FsErr> {
+        Err(self.read_only_dir())
+    }
+
+    fn link(&self, _name: &str, _inode: &InodeRef) -> Result<(), FsErr> {
+        Err(self.read_only_dir())
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsErr> {
+        Err(self.read_only_dir())
+    }
+
+    fn dirent(&self, index: usize) -> Result<Option<DirEntry>, FsErr> {
+        Ok(self.entries()?.into_iter().nth(index))
+    }
+
+    fn read_link(&self) -> Result<String, FsErr> {
+        if self.kind() != FileType::Symlink {
+            return Err(FsErr::InvalidPath);
+        }
+        let size = self.size as usize;
+        let mut target = alloc::vec![0u8; size];
+        if size < FAST_SYMLINK_MAX && self.sectors == 0 {
+            // fast symlink: the target sits where the block pointers go
+            let raw: Vec<u8> = self.blocks.iter().flat_map(|b| b.to_le_bytes()).collect();
+            target.copy_from_slice(&raw[..size]);
+        } else {
+            self.read_data(0, &mut target)?;
+        }
+        String::from_utf8(target).map_err(|_| FsErr::Corrupt)
+    }
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+}
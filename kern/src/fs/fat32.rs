@@ -0,0 +1,945 @@
+//! FAT32 over the buffer cache, with long file names.
+//!
+//! Everything is addressed in sectors: the cache block size is set to
+//! the volume's sector size at mount. One sleep lock per volume
+//! serialises operations; inodes are shared through a table keyed by
+//! the position of their directory entry, so two opens of a file see
+//! the same size and cluster chain.
+//!
+//! FAT has no hard or symbolic links, ownership or permissions beyond
+//! a read-only bit. Names are compared without regard to ASCII case.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use super::{DirEntry, FileSystem, FileType, FsErr, Inode, InodeRef, Stat};
+use crate::block::{self, DevId};
+use crate::sync::{SleepLock, SpinLock};
+
+/// Attribute bits of a directory entry.
+pub struct FatAttr;
+impl FatAttr {
+    pub const READ_ONLY: u8 = 0x01;
+    pub const HIDDEN   : u8 = 0x02;
+    pub const SYSTEM   : u8 = 0x04;
+    pub const VOLUME_ID: u8 = 0x08;
+    pub const DIRECTORY: u8 = 0x10;
+    pub const ARCHIVE  : u8 = 0x20;
+    pub const LFN      : u8 = 0x0f;    // READ_ONLY | HIDDEN | SYSTEM | VOLUME_ID
+}
+
+const DIR_ENTRY_SIZE: usize = 32;
+/// First name byte of a deleted entry, and of the entries after the last.
+const ENTRY_FREE: u8 = 0xe5;
+const ENTRY_END : u8 = 0x00;
+
+const FAT_MASK   : u32 = 0x0fff_ffff;
+const FAT_EOC    : u32 = 0x0fff_ffff;
+/// Values from here up end a cluster chain.
+const FAT_EOC_MIN: u32 = 0x0fff_fff8;
+
+/// Set in the order byte of the last (first stored) long name entry.
+const LFN_LAST : u8 = 0x40;
+const LFN_CHARS: usize = 13;
+/// Offsets of the 13 UTF-16 units in a long name entry.
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Flags in byte 12 of a short entry: show base / extension lower case.
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXT : u8 = 0x10;
+
+const ROOT_INO: u64 = 1;
+
+/// Source for zero filling, one sector at a time.
+static ZEROS: [u8; block::MAX_BLOCK_SIZE] = [0; block::MAX_BLOCK_SIZE];
+
+/// Where a directory entry lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct EntryLoc {
+    sector: u64,
+    offset: usize,
+}
+
+pub struct Fat32Fs {
+    vol : Arc<Volume>,
+    root: Arc<FatInode>,
+}
+
+struct Volume {
+    dev         : DevId,
+    stat_dev    : u32,
+    sector_size : usize,
+    cluster_secs: u64,
+    fat_start   : u64,
+    fat_sectors : u64,
+    num_fats    : u64,
+    data_start  : u64,
+    clusters    : u32,      // valid cluster numbers are 2..clusters + 2
+    root_cluster: u32,
+    fsinfo      : u64,      // sector of the FSInfo block, 0 if none
+    read_only   : bool,
+    op          : SleepLock<()>,
+    alloc_hint  : SpinLock<u32>,
+    inodes      : SpinLock<BTreeMap<u64, Weak<FatInode>>>,
+}
+
+pub struct FatInode {
+    vol : Arc<Volume>,
+    ino : u64,
+    dir : bool,
+    node: SpinLock<FatNode>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FatNode {
+    first: u32,                 // first cluster, 0 for an empty file
+    size : u32,
+    attr : u8,
+    entry: Option<EntryLoc>,    // short entry; `None` for the root or once unlinked
+}
+
+/// A parsed directory entry.
+struct DirItem {
+    name      : String,
+    short     : [u8; DIR_ENTRY_SIZE],
+    slot      : usize,      // index of the short entry
+    first_slot: usize,      // index of its first long name entry
+    loc       : EntryLoc,
+}
+
+impl DirItem {
+    fn attr(&self) -> u8 {
+        self.short[11]
+    }
+
+    fn first_cluster(&self) -> u32 {
+        let high = u16::from_le_bytes([self.short[20], self.short[21]]) as u32;
+        let low  = u16::from_le_bytes([self.short[26], self.short[27]]) as u32;
+        (high << 16) | low
+    }
+
+    fn size(&self) -> u32 {
+        u32::from_le_bytes(self.short[28..32].try_into().unwrap())
+    }
+}
+
+fn le16(bytes: &[u8], at: usize) -> u64 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]]) as u64
+}
+
+fn le32(bytes: &[u8], at: usize) -> u64 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as u64
+}
+
+/// Checksum of an 8.3 name, stored in its long name entries.
+fn lfn_checksum(short: &[u8]) -> u8 {
+    short[..11].iter().fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+/// The 8.3 name as shown, e.g. "README.TXT".
+fn short_display(short: &[u8]) -> String {
+    let mut base: Vec<u8> = short[..8].to_vec();
+    if base[0] == 0x05 {
+        base[0] = ENTRY_FREE;
+    }
+    let lower = |bytes: &[u8], flag: u8| -> String {
+        let text: String = bytes.iter().map(|&b| b as char).collect();
+        let text = String::from(text.trim_end_matches(' '));
+        if short[12] & flag != 0 { text.to_ascii_lowercase() } else { text }
+    };
+    let mut name = lower(&base, NT_LOWER_BASE);
+    let ext = lower(&short[8..11], NT_LOWER_EXT);
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&ext);
+    }
+    name
+}
+
+/// Characters a short name may hold besides letters and digits.
+fn short_char_ok(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "$%'-_@~`!(){}^#&".contains(c)
+}
+
+/// Check a new long name.
+fn check_name(name: &str) -> Result<(), FsErr> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsErr::InvalidPath);
+    }
+    // FAT allows 255 UTF-16 units, the VFS `NAME_MAX` bytes
+    if name.len() > super::NAME_MAX {
+        return Err(FsErr::NameTooLong);
+    }
+    if name.chars().any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c))
+        || name.ends_with('.') || name.ends_with(' ') {
+        return Err(FsErr::InvalidPath);
+    }
+    Ok(())
+}
+
+/// The 8.3 form of `name` if it is one exactly (upper case), which
+/// needs no long name entries.
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    let fits = |part: &str, max: usize| {
+        part.len() <= max && part.chars().all(|c| short_char_ok(c) && !c.is_ascii_lowercase())
+    };
+    if base.is_empty() || !fits(base, 8) || !fits(ext, 3) || base.starts_with('.') {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(short)
+}
+
+/// A unique 8.3 alias for `name`, "BASE~N.EXT" style.
+fn make_short_name(name: &str, taken: &[[u8; 11]]) -> Result<[u8; 11], FsErr> {
+    let squash = |part: &str, max: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| if short_char_ok(c) { c.to_ascii_uppercase() as u8 } else { b'_' })
+            .take(max)
+            .collect()
+    };
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rsplit_once('.') {
+        Some((base, ext)) if !base.is_empty() => (squash(base, 8), squash(ext, 3)),
+        _ => (squash(trimmed, 8), Vec::new()),
+    };
+    let base = if base.is_empty() { Vec::from(*b"_") } else { base };
+
+    for n in 1..1_000_000u32 {
+        let tail = alloc::format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+        let mut short = [b' '; 11];
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        short[8..8 + ext.len()].copy_from_slice(&ext);
+        if !taken.contains(&short) {
+            return Ok(short);
+        }
+    }
+    Err(FsErr::NoSpace)
+}
+
+impl Volume {
+    fn cluster_bytes(&self) -> usize {
+        self.cluster_secs as usize * self.sector_size
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.cluster_secs
+    }
+
+    fn valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.clusters + 2
+    }
+
+    fn read(&self, sector: u64, offset: usize, buf: &mut [u8]) -> Result<(), FsErr> {
+        let block = block::bread(self.dev, sector)?;
+        buf.copy_from_slice(&block.data()[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, offset: usize, data: &[u8]) -> Result<(), FsErr> {
+        let mut block = block::bread(self.dev, sector)?;
+        block.data_mut()[offset..offset + data.len()].copy_from_slice(data);
+        block.write_back();
+        Ok(())
+    }
+
+    fn fat_pos(&self, cluster: u32) -> (u64, usize) {
+        let byte = cluster as u64 * 4;
+        (self.fat_start + byte / self.sector_size as u64, (byte % self.sector_size as u64) as usize)
+    }
+
+    fn fat_get(&self, cluster: u32) -> Result<u32, FsErr> {
+        let (sector, offset) = self.fat_pos(cluster);
+        let mut raw = [0u8; 4];
+        self.read(sector, offset, &mut raw)?;
+        Ok(u32::from_le_bytes(raw) & FAT_MASK)
+    }
+
+    /// Set a FAT entry in every copy of the FAT.
+    fn fat_set(&self, cluster: u32, value: u32) -> Result<(), FsErr> {
+        let (sector, offset) = self.fat_pos(cluster);
+        for fat in 0..self.num_fats {
+            let sector = sector + fat * self.fat_sectors;
+            let mut raw = [0u8; 4];
+            self.read(sector, offset, &mut raw)?;
+            // the top 4 bits are reserved and kept
+            let entry = (u32::from_le_bytes(raw) & !FAT_MASK) | (value & FAT_MASK);
+            self.write(sector, offset, &entry.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster`, `None` at the end of the chain.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FsErr> {
+        match self.fat_get(cluster)? {
+            next if next >= FAT_EOC_MIN => Ok(None),
+            next if self.valid_cluster(next) => Ok(Some(next)),
+            _ => Err(FsErr::Corrupt),
+        }
+    }
+
+    /// Allocate a zeroed cluster, linked after `prev` if given.
+    fn alloc_cluster(&self, prev: Option<u32>) -> Result<u32, FsErr> {
+        let hint = *self.alloc_hint.lock().get();
+        for step in 0..self.clusters {
+            let cluster = 2 + (hint.saturating_sub(2) + step) % self.clusters;
+            if self.fat_get(cluster)? != 0 {
+                continue;
+            }
+            self.fat_set(cluster, FAT_EOC)?;
+            for sector in 0..self.cluster_secs {
+                let mut block = block::cache::bzero(self.dev, self.cluster_sector(cluster) + sector)?;
+                block.write_back();
+            }
+            if let Some(prev) = prev {
+                self.fat_set(prev, cluster)?;
+            }
+            *self.alloc_hint.lock().get_mut() = cluster + 1;
+            return Ok(cluster);
+        }
+        Err(FsErr::NoSpace)
+    }
+
+    /// Free the chain starting at `first`.
+    fn free_chain(&self, first: u32) -> Result<(), FsErr> {
+        let mut cluster = first;
+        while self.valid_cluster(cluster) {
+            let next = self.fat_get(cluster)?;
+            self.fat_set(cluster, 0)?;
+            if next >= FAT_EOC_MIN {
+                break;
+            }
+            cluster = next;
+        }
+        Ok(())
+    }
+
+    /// Cluster number `index` of the chain at `first`, if the chain is that long.
+    fn nth_cluster(&self, first: u32, index: usize) -> Result<Option<u32>, FsErr> {
+        if !self.valid_cluster(first) {
+            return Ok(None);
+        }
+        let mut cluster = first;
+        for _ in 0..index {
+            match self.next_cluster(cluster)? {
+                Some(next) => cluster = next,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(cluster))
+    }
+
+    /// Every cluster of the chain at `first`.
+    fn chain(&self, first: u32) -> Result<Vec<u32>, FsErr> {
+        let mut chain = Vec::new();
+        let mut next = self.valid_cluster(first).then_some(first);
+        while let Some(cluster) = next {
+            if chain.len() > self.clusters as usize {
+                return Err(FsErr::Corrupt);     // a loop
+            }
+            chain.push(cluster);
+            next = self.next_cluster(cluster)?;
+        }
+        Ok(chain)
+    }
+
+    /// Locations of all entry slots of the directory at `first`.
+    fn dir_slots(&self, first: u32) -> Result<Vec<EntryLoc>, FsErr> {
+        let per_sector = self.sector_size / DIR_ENTRY_SIZE;
+        let mut slots = Vec::new();
+        for cluster in self.chain(first)? {
+            for sector in 0..self.cluster_secs {
+                let sector = self.cluster_sector(cluster) + sector;
+                slots.extend((0..per_sector).map(|i| EntryLoc { sector, offset: i * DIR_ENTRY_SIZE }));
+            }
+        }
+        Ok(slots)
+    }
+
+    fn read_slot(&self, loc: EntryLoc) -> Result<[u8; DIR_ENTRY_SIZE], FsErr> {
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        self.read(loc.sector, loc.offset, &mut raw)?;
+        Ok(raw)
+    }
+
+    /// The entries of the directory at `first`, without "." and "..".
+    fn read_dir(&self, first: u32) -> Result<(Vec<DirItem>, Vec<EntryLoc>), FsErr> {
+        let slots = self.dir_slots(first)?;
+        let mut items = Vec::new();
+
+        // long name being collected: UTF-16 units, checksum, first slot, next order
+        let mut lfn: Option<(Vec<u16>, u8, usize, u8)> = None;
+        for (idx, &loc) in slots.iter().enumerate() {
+            let raw = self.read_slot(loc)?;
+            match raw[0] {
+                ENTRY_END => break,
+                ENTRY_FREE => {
+                    lfn = None;
+                    continue;
+                }
+                _ => {}
+            }
+            if raw[11] & 0x3f == FatAttr::LFN {
+                let order = raw[0] & 0x1f;
+                if raw[0] & LFN_LAST != 0 && order != 0 {
+                    lfn = Some((alloc::vec![0xffff; order as usize * LFN_CHARS], raw[13], idx, order));
+                }
+                match &mut lfn {
+                    Some((units, sum, _, next)) if *next == order && *sum == raw[13] && order != 0 => {
+                        let at = (order as usize - 1) * LFN_CHARS;
+                        for (i, &off) in LFN_OFFSETS.iter().enumerate() {
+                            units[at + i] = u16::from_le_bytes([raw[off], raw[off + 1]]);
+                        }
+                        *next = order - 1;
+                    }
+                    _ => lfn = None,
+                }
+                continue;
+            }
+            if raw[11] & FatAttr::VOLUME_ID != 0 {
+                lfn = None;
+                continue;
+            }
+
+            let (name, first_slot) = match lfn.take() {
+                Some((units, sum, start, 0)) if sum == lfn_checksum(&raw) => {
+                    let len = units.iter().position(|&u| u == 0 || u == 0xffff).unwrap_or(units.len());
+                    let name: String = char::decode_utf16(units[..len].iter().copied())
+                        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                        .collect();
+                    // up to 255 UTF-16 units can be longer than `NAME_MAX`
+                    // bytes in UTF-8: such a file goes by its 8.3 name
+                    if name.len() > super::NAME_MAX {
+                        (short_display(&raw), start)
+                    } else {
+                        (name, start)
+                    }
+                }
+                _ => (short_display(&raw), idx),
+            };
+            if name == "." || name == ".." {
+                continue;
+            }
+            items.push(DirItem { name, short: raw, slot: idx, first_slot, loc });
+        }
+        Ok((items, slots))
+    }
+
+    fn find(&self, dir: u32, name: &str) -> Result<DirItem, FsErr> {
+        let (items, _) = self.read_dir(dir)?;
+        items.into_iter()
+            .find(|item| item.name.eq_ignore_ascii_case(name) || short_display(&item.short).eq_ignore_ascii_case(name))
+            .ok_or(FsErr::NotFound)
+    }
+
+    fn ino_of(&self, loc: EntryLoc) -> u64 {
+        (loc.sector * self.sector_size as u64 + loc.offset as u64) / DIR_ENTRY_SIZE as u64
+    }
+
+    /// The shared inode for a directory entry.
+    fn inode(self: &Arc<Self>, item: &DirItem) -> Arc<FatInode> {
+        let ino = self.ino_of(item.loc);
+        let mut guard = self.inodes.lock();
+        let inodes = guard.get_mut();
+        if let Some(inode) = inodes.get(&ino).and_then(Weak::upgrade) {
+            return inode;
+        }
+        inodes.retain(|_, inode| inode.strong_count() > 0);
+        let inode = Arc::new(FatInode {
+            vol : self.clone(),
+            ino,
+            dir : item.attr() & FatAttr::DIRECTORY != 0,
+            node: SpinLock::new(FatNode {
+                first: item.first_cluster(),
+                size : item.size(),
+                attr : item.attr(),
+                entry: Some(item.loc),
+            }),
+        });
+        inodes.insert(ino, Arc::downgrade(&inode));
+        inode
+    }
+
+    /// Write `node`'s first cluster and size back to its directory entry.
+    fn update_entry(&self, node: &FatNode) -> Result<(), FsErr> {
+        let Some(loc) = node.entry else {
+            return Ok(());
+        };
+        let mut raw = self.read_slot(loc)?;
+        raw[20..22].copy_from_slice(&((node.first >> 16) as u16).to_le_bytes());
+        raw[26..28].copy_from_slice(&(node.first as u16).to_le_bytes());
+        let size = if node.attr & FatAttr::DIRECTORY != 0 { 0 } else { node.size };
+        raw[28..32].copy_from_slice(&size.to_le_bytes());
+        self.write(loc.sector, loc.offset, &raw)
+    }
+
+    /// Read or write (`data` given) or zero (`data` empty, `len` bytes) a
+    /// range of `node`, growing the chain as needed for writes.
+    fn file_io(&self, node: &mut FatNode, pos: usize, buf: IoBuf) -> Result<(), FsErr> {
+        let cluster_bytes = self.cluster_bytes();
+        let len = buf.len();
+        if len == 0 {
+            return Ok(());
+        }
+        let writing = !matches!(buf, IoBuf::Read(_));
+
+        let index = pos / cluster_bytes;
+        let mut cluster = match self.nth_cluster(node.first, index)? {
+            Some(cluster) => cluster,
+            None if writing => self.grow_to(node, index)?,
+            None => return Err(FsErr::Corrupt),
+        };
+        let mut buf = buf;
+        let mut done = 0;
+        while done < len {
+            let at = (pos + done) % cluster_bytes;
+            if done > 0 && at == 0 {
+                cluster = match self.next_cluster(cluster)? {
+                    Some(next) => next,
+                    None if writing => self.alloc_cluster(Some(cluster))?,
+                    None => return Err(FsErr::Corrupt),
+                };
+            }
+            let sector = self.cluster_sector(cluster) + (at / self.sector_size) as u64;
+            let offset = at % self.sector_size;
+            let count  = (self.sector_size - offset).min(len - done);
+            match &mut buf {
+                IoBuf::Read(out) => self.read(sector, offset, &mut out[done..done + count])?,
+                IoBuf::Write(data) => self.write(sector, offset, &data[done..done + count])?,
+                IoBuf::Zero(_) => self.write(sector, offset, &ZEROS[..count])?,
+            }
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Extend `node`'s chain to cover cluster `index`, returning it.
+    fn grow_to(&self, node: &mut FatNode, index: usize) -> Result<u32, FsErr> {
+        let mut last = match self.valid_cluster(node.first) {
+            true => *self.chain(node.first)?.last().unwrap(),
+            false => {
+                node.first = self.alloc_cluster(None)?;
+                node.first
+            }
+        };
+        let mut have = self.chain(node.first)?.len();
+        while have <= index {
+            last = self.alloc_cluster(Some(last))?;
+            have += 1;
+        }
+        Ok(last)
+    }
+
+    /// Write the free cluster hint back to FSInfo; the free count
+    /// becomes unknown, which tools recompute.
+    fn sync_fsinfo(&self) -> Result<(), FsErr> {
+        if self.fsinfo == 0 || self.read_only {
+            return Ok(());
+        }
+        let hint = *self.alloc_hint.lock().get();
+        self.write(self.fsinfo, 488, &u32::MAX.to_le_bytes())?;
+        self.write(self.fsinfo, 492, &hint.to_le_bytes())
+    }
+}
+
+enum IoBuf<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+    Zero(usize),
+}
+
+impl IoBuf<'_> {
+    fn len(&self) -> usize {
+        match self {
+            IoBuf::Read(buf) => buf.len(),
+            IoBuf::Write(data) => data.len(),
+            IoBuf::Zero(len) => *len,
+        }
+    }
+}
+
+impl Fat32Fs {
+    /// Mount the FAT32 volume on `dev`.
+    pub fn mount(dev: DevId) -> Result<Arc<Self>, FsErr> {
+        block::set_block_size(dev, block::SECTOR_SIZE)?;
+        let mut boot = [0u8; block::SECTOR_SIZE];
+        boot.copy_from_slice(block::bread(dev, 0)?.data());
+
+        if boot[510] != 0x55 || boot[511] != 0xaa {
+            return Err(FsErr::Invalid);
+        }
+        let sector_size  = le16(&boot, 11) as usize;
+        let cluster_secs = boot[13] as u64;
+        let reserved     = le16(&boot, 14);
+        let num_fats     = boot[16] as u64;
+        let root_entries = le16(&boot, 17);
+        let fat16_size   = le16(&boot, 22);
+        let total16      = le16(&boot, 19);
+        let total32      = le32(&boot, 32);
+        let fat_sectors  = le32(&boot, 36);
+        let root_cluster = le32(&boot, 44) as u32;
+        let fsinfo       = le16(&boot, 48);
+
+        let sector_ok = matches!(sector_size, 512 | 1024 | 2048 | 4096);
+        if !sector_ok || !cluster_secs.is_power_of_two() || reserved == 0 || num_fats == 0 {
+            return Err(FsErr::Invalid);
+        }
+        // FAT12/16 keep a fixed root directory and a 16 bit FAT size
+        if root_entries != 0 || fat16_size != 0 || fat_sectors == 0 {
+            return Err(FsErr::Unsupported);
+        }
+        let total = if total16 != 0 { total16 } else { total32 };
+        let data_start = reserved + num_fats * fat_sectors;
+        if total <= data_start {
+            return Err(FsErr::Corrupt);
+        }
+        let fat_entries = fat_sectors * sector_size as u64 / 4;
+        let clusters = ((total - data_start) / cluster_secs).min(fat_entries - 2) as u32;
+
+        block::set_block_size(dev, sector_size)?;
+        let device = block::device(dev)?;
+        let vol = Arc::new(Volume {
+            dev,
+            stat_dev    : super::new_dev(),
+            sector_size,
+            cluster_secs,
+            fat_start   : reserved,
+            fat_sectors,
+            num_fats,
+            data_start,
+            clusters,
+            root_cluster,
+            fsinfo      : if fsinfo != 0 && fsinfo != 0xffff { fsinfo } else { 0 },
+            read_only   : device.read_only(),
+            op          : SleepLock::new(()),
+            alloc_hint  : SpinLock::new(2),
+            inodes      : SpinLock::new(BTreeMap::new()),
+        });
+        if !vol.valid_cluster(root_cluster) {
+            return Err(FsErr::Corrupt);
+        }
+        if vol.fsinfo != 0 {
+            let hint = {
+                let info = block::bread(dev, vol.fsinfo)?;
+                let data = info.data();
+                let signed = le32(data, 0) == 0x41615252 && le32(data, 484) == 0x61417272;
+                if signed { le32(data, 492) as u32 } else { 2 }
+            };
+            if vol.valid_cluster(hint) {
+                *vol.alloc_hint.lock().get_mut() = hint;
+            }
+        }
+
+        let root = Arc::new(FatInode {
+            vol : vol.clone(),
+            ino : ROOT_INO,
+            dir : true,
+            node: SpinLock::new(FatNode { first: root_cluster, size: 0, attr: FatAttr::DIRECTORY, entry: None }),
+        });
+        Ok(Arc::new(Self { vol, root }))
+    }
+
+    /// Block device the volume lives on.
+    pub fn dev(&self) -> DevId {
+        self.vol.dev
+    }
+}
+
+impl FileSystem for Fat32Fs {
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+
+    fn sync(&self) -> Result<(), FsErr> {
+        let _op = self.vol.op.lock();
+        self.vol.sync_fsinfo()?;
+        Ok(block::sync(self.vol.dev)?)
+    }
+}
+
+impl FatInode {
+    fn node(&self) -> FatNode {
+        *self.node.lock().get()
+    }
+
+    fn set_node(&self, node: FatNode) {
+        *self.node.lock().get_mut() = node;
+    }
+
+    fn check_writable(&self) -> Result<(), FsErr> {
+        if self.vol.read_only {
+            return Err(FsErr::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// The directory's cluster, for directory operations.
+    fn dir_cluster(&self) -> Result<u32, FsErr> {
+        if !self.dir {
+            return Err(FsErr::NotDir);
+        }
+        Ok(self.node().first)
+    }
+
+    /// Find `count` free consecutive slots, growing the directory if
+    /// needed. Returns the slots.
+    fn free_slots(&self, count: usize) -> Result<Vec<EntryLoc>, FsErr> {
+        let vol = &self.vol;
+        loop {
+            let first = self.node().first;
+            let slots = vol.dir_slots(first)?;
+            let mut run = 0;
+            for (idx, &loc) in slots.iter().enumerate() {
+                let raw = vol.read_slot(loc)?;
+                if raw[0] == ENTRY_FREE || raw[0] == ENTRY_END {
+                    run += 1;
+                    if run == count {
+                        return Ok(slots[idx + 1 - count..=idx].to_vec());
+                    }
+                } else {
+                    run = 0;
+                }
+            }
+            let last = *vol.chain(first)?.last().ok_or(FsErr::Corrupt)?;
+            vol.alloc_cluster(Some(last))?;
+        }
+    }
+}
+
+/// A short entry for `short` pointing at `cluster`.
+fn short_entry(short: &[u8; 11], attr: u8, cluster: u32) -> [u8; DIR_ENTRY_SIZE] {
+    let mut raw = [0u8; DIR_ENTRY_SIZE];
+    raw[..11].copy_from_slice(short);
+    raw[11] = attr;
+    // 1980-01-01 00:00 for creation, access and write time
+    for at in [16, 18, 24] {
+        raw[at..at + 2].copy_from_slice(&0x0021u16.to_le_bytes());
+    }
+    raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    raw
+}
+
+impl Inode for FatInode {
+    fn stat(&self) -> Stat {
+        let node = self.node();
+        let mode = match (self.dir, node.attr & FatAttr::READ_ONLY != 0) {
+            (true, _)      => 0o755,
+            (false, true)  => 0o444,
+            (false, false) => 0o644,
+        };
+        Stat {
+            ino  : self.ino,
+            size : if self.dir { 0 } else { node.size as u64 },
+            dev  : self.vol.stat_dev,
+            nlink: if node.entry.is_some() || self.ino == ROOT_INO { 1 } else { 0 },
+            kind : if self.dir { FileType::Directory } else { FileType::Regular },
+            mode,
+        }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsErr> {
+        if self.dir {
+            return Err(FsErr::IsDir);
+        }
+        let _op = self.vol.op.lock();
+        let mut node = self.node();
+        let size = node.size as usize;
+        if offset >= size {
+            return Ok(0);
+        }
+        let count = buf.len().min(size - offset);
+        self.vol.file_io(&mut node, offset, IoBuf::Read(&mut buf[..count]))?;
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, FsErr> {
+        if self.dir {
+            return Err(FsErr::IsDir);
+        }
+        self.check_writable()?;
+        let end = offset.checked_add(buf.len()).filter(|&end| end <= u32::MAX as usize)
+            .ok_or(FsErr::NoSpace)?;
+        let _op = self.vol.op.lock();
+        let mut node = self.node();
+        // a write past the end leaves zeros in between
+        let size = node.size as usize;
+        let result = match offset > size {
+            true => self.vol.file_io(&mut node, size, IoBuf::Zero(offset - size)),
+            false => Ok(()),
+        }.and_then(|_| self.vol.file_io(&mut node, offset, IoBuf::Write(buf)));
+
+        if result.is_ok() {
+            node.size = node.size.max(end as u32);
+        }
+        // clusters allocated before a failure stay with the file
+        self.set_node(node);
+        self.vol.update_entry(&node)?;
+        result.map(|_| buf.len())
+    }
+
+    fn truncate(&self, size: usize) -> Result<(), FsErr> {
+        if self.dir {
+            return Err(FsErr::IsDir);
+        }
+        self.check_writable()?;
+        if size > u32::MAX as usize {
+            return Err(FsErr::NoSpace);
+        }
+        let _op = self.vol.op.lock();
+        let vol = &self.vol;
+        let mut node = self.node();
+        let old = node.size as usize;
+        if size > old {
+            vol.file_io(&mut node, old, IoBuf::Zero(size - old))?;
+        } else if size < old {
+            let keep = size.div_ceil(vol.cluster_bytes());
+            if keep == 0 {
+                vol.free_chain(node.first)?;
+                node.first = 0;
+            } else if let Some(last) = vol.nth_cluster(node.first, keep - 1)? {
+                if let Some(rest) = vol.next_cluster(last)? {
+                    vol.fat_set(last, FAT_EOC)?;
+                    vol.free_chain(rest)?;
+                }
+            }
+        }
+        node.size = size as u32;
+        self.set_node(node);
+        vol.update_entry(&node)
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsErr> {
+        let dir = self.dir_cluster()?;
+        let _op = self.vol.op.lock();
+        let item = self.vol.find(dir, name)?;
+        Ok(self.vol.inode(&item))
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<InodeRef, FsErr> {
+        let dir = self.dir_cluster()?;
+        self.check_writable()?;
+        check_name(name)?;
+        let attr = match kind {
+            FileType::Regular   => FatAttr::ARCHIVE,
+            FileType::Directory => FatAttr::DIRECTORY,
+            _ => return Err(FsErr::Unsupported),
+        };
+        let _op = self.vol.op.lock();
+        let vol = &self.vol;
+        let (items, _) = vol.read_dir(dir)?;
+        if items.iter().any(|item| item.name.eq_ignore_ascii_case(name)) {
+            return Err(FsErr::Exists);
+        }
+        let taken: Vec<[u8; 11]> = items.iter().map(|item| item.short[..11].try_into().unwrap()).collect();
+
+        let (short, units) = match exact_short_name(name) {
+            Some(short) if !taken.contains(&short) => (short, Vec::new()),
+            _ => (make_short_name(name, &taken)?, name.encode_utf16().collect::<Vec<u16>>()),
+        };
+        let lfn_entries = units.len().div_ceil(LFN_CHARS);
+        let slots = self.free_slots(lfn_entries + 1)?;
+
+        let cluster = match kind {
+            FileType::Directory => {
+                let cluster = vol.alloc_cluster(None)?;
+                let parent = if dir == vol.root_cluster { 0 } else { dir };
+                let sector = vol.cluster_sector(cluster);
+                vol.write(sector, 0, &short_entry(b".          ", FatAttr::DIRECTORY, cluster))?;
+                vol.write(sector, DIR_ENTRY_SIZE, &short_entry(b"..         ", FatAttr::DIRECTORY, parent))?;
+                cluster
+            }
+            _ => 0,
+        };
+
+        let sum = lfn_checksum(&short);
+        for (i, loc) in slots[..lfn_entries].iter().enumerate() {
+            // stored last part first
+            let order = (lfn_entries - i) as u8;
+            let mut raw = [0u8; DIR_ENTRY_SIZE];
+            raw[0]  = order | if i == 0 { LFN_LAST } else { 0 };
+            raw[11] = FatAttr::LFN;
+            raw[13] = sum;
+            for (j, &off) in LFN_OFFSETS.iter().enumerate() {
+                let at = (order as usize - 1) * LFN_CHARS + j;
+                let unit = match at.cmp(&units.len()) {
+                    core::cmp::Ordering::Less => units[at],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xffff,
+                };
+                raw[off..off + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            vol.write(loc.sector, loc.offset, &raw)?;
+        }
+        let raw = short_entry(&short, attr, cluster);
+        let loc = slots[lfn_entries];
+        vol.write(loc.sector, loc.offset, &raw)?;
+
+        let item = DirItem {
+            name: String::from(name),
+            short: raw,
+            slot: 0,
+            first_slot: 0,
+            loc,
+        };
+        Ok(vol.inode(&item))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsErr> {
+        let dir = self.dir_cluster()?;
+        self.check_writable()?;
+        let _op = self.vol.op.lock();
+        let vol = &self.vol;
+        let (items, slots) = vol.read_dir(dir)?;
+        let item = items.iter()
+            .find(|item| item.name.eq_ignore_ascii_case(name))
+            .ok_or(FsErr::NotFound)?;
+        if item.attr() & FatAttr::DIRECTORY != 0 && !vol.read_dir(item.first_cluster())?.0.is_empty() {
+            return Err(FsErr::NotEmpty);
+        }
+
+        for loc in &slots[item.first_slot..=item.slot] {
+            vol.write(loc.sector, loc.offset, &[ENTRY_FREE])?;
+        }
+        vol.free_chain(item.first_cluster())?;
+
+        // open files keep an inode, now empty and without an entry
+        let ino = vol.ino_of(item.loc);
+        let inode = vol.inodes.lock().get_mut().remove(&ino).and_then(|inode| inode.upgrade());
+        if let Some(inode) = inode {
+            let mut guard = inode.node.lock();
+            let node = guard.get_mut();
+            node.first = 0;
+            node.size  = 0;
+            node.entry = None;
+        }
+        Ok(())
+    }
+
+    fn link(&self, _name: &str, _inode: &InodeRef) -> Result<(), FsErr> {
+        Err(FsErr::Unsupported)
+    }
+
+    fn dirent(&self, index: usize) -> Result<Option<DirEntry>, FsErr> {
+        let dir = self.dir_cluster()?;
+        let _op = self.vol.op.lock();
+        let (items, _) = self.vol.read_dir(dir)?;
+        Ok(items.get(index).map(|item| DirEntry {
+            ino : self.vol.ino_of(item.loc),
+            kind: if item.attr() & FatAttr::DIRECTORY != 0 { FileType::Directory } else { FileType::Regular },
+            name: item.name.clone(),
+        }))
+    }
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+}
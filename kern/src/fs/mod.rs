@@ -0,0 +1,599 @@
+//! Virtual filesystem.
+//!
+//! Filesystems implement `FileSystem` and hand out `Inode`s; the VFS
+//! resolves paths across the mount table and wraps opened inodes in
+//! `File`s, which processes keep in their fd table.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::initramfs::RamNode;
+use crate::sync::{SleepLock, SpinLock};
+
+pub mod devfs;
+pub mod ext2;
+pub mod fat32;
+pub mod ramfs;
+pub mod tmpfs;
+pub mod xv6fs;
+
+/// Longest file name a directory entry can hold.
+pub const NAME_MAX: usize = 255;
+/// Longest path accepted from user space.
+pub const PATH_MAX: usize = 256;
+
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsErr {
+    NotFound,
+    NotDir,
+    IsDir,
+    Exists,
+    NotEmpty,       // removing a directory that still has entries
+    ReadOnly,
+    NoSpace,
+    NameTooLong,
+    InvalidPath,
+    Busy,           // e.g. unlinking a mount point
+    Invalid,        // bad argument: unknown `whence`, negative offset ..
+    Io,             // the device failed
+    Corrupt,        // on-disk structures make no sense
+    Unsupported,
+    Interrupted,    // the process was killed while it waited
+}
+
+impl From<crate::block::BlockErr> for FsErr {
+    fn from(err: crate::block::BlockErr) -> Self {
+        use crate::block::BlockErr;
+        match err {
+            BlockErr::ReadOnly   => FsErr::ReadOnly,
+            BlockErr::NoMemory   => FsErr::NoSpace,
+            BlockErr::Busy       => FsErr::Busy,
+            BlockErr::OutOfRange => FsErr::Corrupt,
+            _                    => FsErr::Io,
+        }
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular    = 1,
+    Directory  = 2,
+    Symlink    = 3,
+    CharDevice = 4,
+}
+
+/// File metadata, as copied out by the `stat` system call.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Stat {
+    pub ino  : u64,
+    pub size : u64,
+    pub dev  : u32,     // filesystem the inode lives on
+    pub nlink: u32,
+    pub kind : FileType,
+    pub mode : u32,     // permission bits
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub ino : u64,
+    pub kind: FileType,
+    pub name: String,
+}
+
+pub type InodeRef = Arc<dyn Inode>;
+
+/// A file, directory or device on some filesystem.
+///
+/// Offsets and sizes are in bytes. Operations a node does not support
+/// fail with the error its type would give (`NotDir` for directory
+/// operations on files, `ReadOnly` for writes ..).
+pub trait Inode: Send + Sync {
+    fn stat(&self) -> Stat;
+
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize, FsErr> {
+        Err(FsErr::IsDir)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize, FsErr> {
+        Err(FsErr::ReadOnly)
+    }
+
+    fn truncate(&self, _size: usize) -> Result<(), FsErr> {
+        Err(FsErr::ReadOnly)
+    }
+
+    /// The entry `name` of this directory.
+    fn lookup(&self, _name: &str) -> Result<InodeRef, FsErr> {
+        Err(FsErr::NotDir)
+    }
+
+    /// Create an empty file or directory `name` in this directory.
+    fn create(&self, _name: &str, _kind: FileType) -> Result<InodeRef, FsErr> {
+        Err(FsErr::NotDir)
+    }
+
+    /// Create a symbolic link `name` pointing at `target` in this directory.
+    fn symlink(&self, _name: &str, _target: &str) -> Result<InodeRef, FsErr> {
+        Err(FsErr::NotDir)
+    }
+
+    /// Add a hard link `name` to `inode`, which must be on the same filesystem.
+    fn link(&self, _name: &str, _inode: &InodeRef) -> Result<(), FsErr> {
+        Err(FsErr::NotDir)
+    }
+
+    /// Remove the entry `name`. Directories must be empty.
+    fn unlink(&self, _name: &str) -> Result<(), FsErr> {
+        Err(FsErr::NotDir)
+    }
+
+    /// Entry number `index` of this directory, `None` past the end.
+    fn dirent(&self, _index: usize) -> Result<Option<DirEntry>, FsErr> {
+        Err(FsErr::NotDir)
+    }
+
+    /// Target of a symbolic link.
+    fn read_link(&self) -> Result<String, FsErr> {
+        Err(FsErr::InvalidPath)
+    }
+
+    /// Lets filesystems get their own inode type back from an `InodeRef`
+    /// (e.g. to check a `link` target lives on the same filesystem).
+    fn as_any(&self) -> &dyn core::any::Any;
+}
+
+pub trait FileSystem: Send + Sync {
+    fn root(&self) -> InodeRef;
+
+    /// Write back everything cached in memory.
+    fn sync(&self) -> Result<(), FsErr> {
+        Ok(())
+    }
+}
+
+/// Device numbers below this are fixed (ramfs, devfs); the rest are
+/// handed out to filesystems as they are created.
+static NEXT_DEV: AtomicU32 = AtomicU32::new(16);
+
+/// A fresh device number for `Stat::dev`.
+pub fn new_dev() -> u32 {
+    NEXT_DEV.fetch_add(1, Ordering::Relaxed)
+}
+
+struct Mount {
+    path: String,           // normalised, e.g. "/" or "/dev"
+    fs  : Arc<dyn FileSystem>,
+}
+
+static MOUNTS: SpinLock<Vec<Mount>> = SpinLock::new(Vec::new());
+
+/// Symbolic links followed while resolving one path.
+const MAX_SYMLINKS: usize = 8;
+
+/// Split `path` into its components, dropping `.` and applying `..`.
+/// Relative paths start at the root.
+pub fn normalize(path: &str) -> Result<Vec<&str>, FsErr> {
+    if path.is_empty() {
+        return Err(FsErr::NotFound);
+    }
+    if path.len() > PATH_MAX {
+        return Err(FsErr::NameTooLong);
+    }
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => { components.pop(); }
+            name if name.len() > NAME_MAX => return Err(FsErr::NameTooLong),
+            name => components.push(name),
+        }
+    }
+    Ok(components)
+}
+
+/// Canonical form of a mount point, e.g. "/" or "/dev".
+fn mount_path(path: &str) -> Result<String, FsErr> {
+    let mut mount_path = String::new();
+    for component in normalize(path)? {
+        mount_path.push('/');
+        mount_path.push_str(component);
+    }
+    if mount_path.is_empty() {
+        mount_path.push('/');
+    }
+    Ok(mount_path)
+}
+
+/// Mount `fs` at `path`. A later mount on the same path hides the earlier one.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsErr> {
+    let path = mount_path(path)?;
+    let mut guard = MOUNTS.lock();
+    guard.get_mut().push(Mount { path, fs });
+    Ok(())
+}
+
+/// Remove the latest mount on `path`, syncing it first.
+pub fn umount(path: &str) -> Result<(), FsErr> {
+    let path = mount_path(path)?;
+    let fs = {
+        let mut guard = MOUNTS.lock();
+        let mounts = guard.get_mut();
+        let idx = mounts.iter().rposition(|mount| mount.path == path)
+            .ok_or(FsErr::InvalidPath)?;
+        mounts.remove(idx).fs
+    };
+    fs.sync()
+}
+
+/// Number of leading `components` covered by `mount_path`, if it is a prefix.
+fn mount_depth(mount_path: &str, components: &[&str]) -> Option<usize> {
+    let mut depth = 0;
+    for part in mount_path.split('/').filter(|c| !c.is_empty()) {
+        if components.get(depth) != Some(&part) {
+            return None;
+        }
+        depth += 1;
+    }
+    Some(depth)
+}
+
+/// The filesystem mounted deepest along `components`, and how many
+/// components its mount point covers.
+fn find_mount(components: &[&str]) -> Result<(Arc<dyn FileSystem>, usize), FsErr> {
+    let guard = MOUNTS.lock();
+    let mut best: Option<(&Mount, usize)> = None;
+    for mount in guard.get().iter() {
+        if let Some(depth) = mount_depth(&mount.path, components) {
+            if best.is_none_or(|(_, best_depth)| depth >= best_depth) {
+                best = Some((mount, depth));
+            }
+        }
+    }
+    best.map(|(mount, depth)| (mount.fs.clone(), depth)).ok_or(FsErr::NotFound)
+}
+
+/// Resolve `path` to its inode, following symbolic links.
+pub fn resolve(path: &str) -> Result<InodeRef, FsErr> {
+    resolve_with(path, MAX_SYMLINKS)
+}
+
+fn resolve_with(path: &str, links_left: usize) -> Result<InodeRef, FsErr> {
+    let components = normalize(path)?;
+    let (fs, depth) = find_mount(&components)?;
+    let mut inode = fs.root();
+    for (idx, name) in components.iter().enumerate().skip(depth) {
+        inode = inode.lookup(name)?;
+        if inode.stat().kind == FileType::Symlink {
+            if links_left == 0 {
+                return Err(FsErr::InvalidPath);
+            }
+            // relative targets start at the directory holding the link
+            let target = inode.read_link()?;
+            let mut next = String::new();
+            if !target.starts_with('/') {
+                for dir in &components[..idx] {
+                    next.push('/');
+                    next.push_str(dir);
+                }
+                next.push('/');
+            }
+            next.push_str(&target);
+            for rest in &components[idx + 1..] {
+                next.push('/');
+                next.push_str(rest);
+            }
+            return resolve_with(&next, links_left - 1);
+        }
+    }
+    Ok(inode)
+}
+
+/// Resolve everything but the last component of `path`. Returns the
+/// parent directory and the final name.
+pub fn resolve_parent(path: &str) -> Result<(InodeRef, String), FsErr> {
+    let components = normalize(path)?;
+    let (name, dirs) = components.split_last().ok_or(FsErr::InvalidPath)?;
+
+    let mut parent = String::from("/");
+    for dir in dirs {
+        parent.push_str(dir);
+        parent.push('/');
+    }
+    let dir = resolve(&parent)?;
+    if dir.stat().kind != FileType::Directory {
+        return Err(FsErr::NotDir);
+    }
+    Ok((dir, String::from(*name)))
+}
+
+/// Is something mounted exactly on `path`?
+fn is_mount_point(path: &str) -> Result<bool, FsErr> {
+    let path = mount_path(path)?;
+    let guard = MOUNTS.lock();
+    Ok(guard.get().iter().any(|mount| mount.path == path))
+}
+
+pub fn stat(path: &str) -> Result<Stat, FsErr> {
+    Ok(resolve(path)?.stat())
+}
+
+pub fn mkdir(path: &str) -> Result<InodeRef, FsErr> {
+    let (dir, name) = resolve_parent(path)?;
+    dir.create(&name, FileType::Directory)
+}
+
+pub fn unlink(path: &str) -> Result<(), FsErr> {
+    if is_mount_point(path)? {
+        return Err(FsErr::Busy);
+    }
+    let (dir, name) = resolve_parent(path)?;
+    dir.unlink(&name)
+}
+
+/// Hard link `new` to the file at `old`.
+pub fn link(old: &str, new: &str) -> Result<(), FsErr> {
+    let inode = resolve(old)?;
+    let (dir, name) = resolve_parent(new)?;
+    dir.link(&name, &inode)
+}
+
+/// Write back every mounted filesystem.
+pub fn sync() -> Result<(), FsErr> {
+    let filesystems: Vec<Arc<dyn FileSystem>> = {
+        let guard = MOUNTS.lock();
+        guard.get().iter().map(|mount| mount.fs.clone()).collect()
+    };
+    filesystems.iter().try_for_each(|fs| fs.sync())
+}
+
+/// Flags for `open`, same values as Linux.
+pub struct OpenFlags;
+impl OpenFlags {
+    pub const RDONLY   : usize = 0;
+    pub const WRONLY   : usize = 1;
+    pub const RDWR     : usize = 2;
+    pub const ACCMODE  : usize = 3;
+    pub const CREAT    : usize = 0o100;
+    pub const EXCL     : usize = 0o200;
+    pub const TRUNC    : usize = 0o1000;
+    pub const APPEND   : usize = 0o2000;
+    pub const DIRECTORY: usize = 0o200000;
+}
+
+/// `whence` values for `File::seek`.
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+/// An open file: an inode, the access mode and a position. Shared by
+/// every fd that refers to it. The position stays locked for the whole
+/// of a read or write, so fds sharing the file never reuse an offset.
+pub struct File {
+    inode : InodeRef,
+    flags : usize,
+    offset: SleepLock<usize>,
+}
+
+impl File {
+    pub fn new(inode: InodeRef, flags: usize) -> Self {
+        Self { inode, flags, offset: SleepLock::new(0) }
+    }
+
+    pub fn inode(&self) -> &InodeRef {
+        &self.inode
+    }
+
+    fn readable(&self) -> bool {
+        self.flags & OpenFlags::ACCMODE != OpenFlags::WRONLY
+    }
+
+    fn writable(&self) -> bool {
+        self.flags & OpenFlags::ACCMODE != OpenFlags::RDONLY
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FsErr> {
+        if !self.readable() {
+            return Err(FsErr::Invalid);
+        }
+        let mut offset = self.offset.lock();
+        let count = self.inode.read_at(*offset.get(), buf)?;
+        *offset.get_mut() += count;
+        Ok(count)
+    }
+
+    pub fn write(&self, buf: &[u8]) -> Result<usize, FsErr> {
+        if !self.writable() {
+            return Err(FsErr::Invalid);
+        }
+        let mut offset = self.offset.lock();
+        if self.flags & OpenFlags::APPEND != 0 {
+            *offset.get_mut() = self.inode.stat().size as usize;
+        }
+        let count = self.inode.write_at(*offset.get(), buf)?;
+        *offset.get_mut() += count;
+        Ok(count)
+    }
+
+    /// Move the position, returns the new one.
+    pub fn seek(&self, offset: isize, whence: usize) -> Result<usize, FsErr> {
+        let mut guard = self.offset.lock();
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => *guard.get(),
+            SEEK_END => self.inode.stat().size as usize,
+            _ => return Err(FsErr::Invalid),
+        };
+        let pos = base.checked_add_signed(offset).ok_or(FsErr::Invalid)?;
+        *guard.get_mut() = pos;
+        Ok(pos)
+    }
+
+    /// Next directory entry; the position counts entries.
+    pub fn next_dirent(&self) -> Result<Option<DirEntry>, FsErr> {
+        let mut index = self.offset.lock();
+        let entry = self.inode.dirent(*index.get())?;
+        if entry.is_some() {
+            *index.get_mut() += 1;
+        }
+        Ok(entry)
+    }
+
+    /// Step back one directory entry (it did not fit the caller's buffer).
+    pub fn unread_dirent(&self) {
+        let mut guard = self.offset.lock();
+        let index = guard.get_mut();
+        *index = index.saturating_sub(1);
+    }
+}
+
+/// Open (and with `OpenFlags::CREAT`, create) the file at `path`.
+pub fn open(path: &str, flags: usize) -> Result<Arc<File>, FsErr> {
+    let inode = match resolve(path) {
+        Ok(_) if flags & OpenFlags::CREAT != 0 && flags & OpenFlags::EXCL != 0 => {
+            return Err(FsErr::Exists);
+        }
+        Ok(inode) => inode,
+        Err(FsErr::NotFound) if flags & OpenFlags::CREAT != 0 => {
+            let (dir, name) = resolve_parent(path)?;
+            dir.create(&name, FileType::Regular)?
+        }
+        Err(err) => return Err(err),
+    };
+
+    let kind = inode.stat().kind;
+    if kind == FileType::Directory && flags & OpenFlags::ACCMODE != OpenFlags::RDONLY {
+        return Err(FsErr::IsDir);
+    }
+    if kind != FileType::Directory && flags & OpenFlags::DIRECTORY != 0 {
+        return Err(FsErr::NotDir);
+    }
+    if kind == FileType::Regular && flags & OpenFlags::TRUNC != 0
+        && flags & OpenFlags::ACCMODE != OpenFlags::RDONLY {
+        inode.truncate(0)?;
+    }
+    Ok(Arc::new(File::new(inode, flags)))
+}
+
+/// Copy the initramfs nodes into the filesystem mounted at `/`. Hard
+/// links stay links; device nodes and FIFOs are skipped, devices live in
+/// `/dev`. Returns the entries created.
+pub fn unpack_initramfs(nodes: &[RamNode]) -> Result<usize, FsErr> {
+    use crate::cpio::{S_IFDIR, S_IFLNK, S_IFMT, S_IFREG};
+
+    // the first name of each file with more than one link
+    let mut linked: Vec<((u32, u32, u32), InodeRef)> = Vec::new();
+    let mut count = 0;
+    for node in nodes {
+        if node.path.is_empty() || resolve(node.path).is_ok() {
+            continue;
+        }
+        let kind = node.mode & S_IFMT;
+        if ![S_IFDIR, S_IFLNK, S_IFREG].contains(&kind) {
+            continue;
+        }
+        // archives need not list every parent directory
+        if let Some((parent, _)) = node.path.rsplit_once('/') {
+            let mut path = String::new();
+            for dir in parent.split('/') {
+                path.push('/');
+                path.push_str(dir);
+                if resolve(&path).is_err() {
+                    mkdir(&path)?;
+                }
+            }
+        }
+        let (dir, name) = resolve_parent(node.path)?;
+        let id = (node.devmajor, node.devminor, node.ino);
+        match kind {
+            S_IFDIR => { dir.create(&name, FileType::Directory)?; }
+            S_IFLNK => {
+                let target = core::str::from_utf8(node.data).map_err(|_| FsErr::Corrupt)?;
+                dir.symlink(&name, target)?;
+            }
+            _ => {
+                let first = linked.iter().find(|(other, _)| node.nlink > 1 && *other == id);
+                if let Some((_, inode)) = first {
+                    dir.link(&name, inode)?;
+                } else {
+                    let inode = dir.create(&name, FileType::Regular)?;
+                    if inode.write_at(0, node.data)? != node.data.len() {
+                        return Err(FsErr::NoSpace);
+                    }
+                    if node.nlink > 1 {
+                        linked.try_reserve(1).map_err(|_| FsErr::NoSpace)?;
+                        linked.push((id, inode));
+                    }
+                }
+            }
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Mount the boot filesystems: a tmpfs at `/` holding a copy of the
+/// initramfs, devices at `/dev` and a scratch tmpfs at `/tmp`. If the
+/// initramfs does not fit in the heap, `/` falls back to the read-only
+/// view of the archive.
+pub fn fs_init() {
+    let root = Arc::new(tmpfs::TmpFs::new());
+    let mut mounted = mount("/", root.clone());
+    match unpack_initramfs(crate::initramfs::nodes()) {
+        Ok(count) => kprintln!("VFS: unpacked {} initramfs entries into tmpfs", count),
+        Err(err) => {
+            kprintln!("VFS: unpacking initramfs failed: {:?}, using it read-only", err);
+            mounted = umount("/").and_then(|_| mount("/", Arc::new(ramfs::RamFs::new())));
+        }
+    }
+    for dir in ["/dev", "/tmp"] {
+        if resolve(dir).is_err() {
+            let _ = mkdir(dir);
+        }
+    }
+    let mounted = mounted
+        .and_then(|_| mount("/dev", Arc::new(devfs::DevFs::new())))
+        .and_then(|_| mount("/tmp", Arc::new(tmpfs::TmpFs::new())));
+    match mounted {
+        Ok(()) => kprintln!("VFS: /, /dev and /tmp mounted"),
+        Err(err) => kprintln!("VFS: mount failed: {:?}", err),
+    }
+
+    if let Some(dev) = unsafe { crate::virtio::VIRTIO_BLK_DEV } {
+        let mounted = mount_disk(dev).and_then(|(kind, fs)| {
+            if resolve("/mnt").is_err() {
+                mkdir("/mnt")?;
+            }
+            mount("/mnt", fs).map(|_| kind)
+        });
+        match mounted {
+            Ok(kind) => kprintln!("VFS: {} on block device {} mounted at /mnt", kind, dev),
+            Err(err) => kprintln!("VFS: no filesystem mounted from block device {}: {:?}", dev, err),
+        }
+    }
+}
+
+/// Mount whichever filesystem `dev` holds. Returns its type and the
+/// filesystem.
+pub fn mount_disk(dev: crate::block::DevId) -> Result<(&'static str, Arc<dyn FileSystem>), FsErr> {
+    match fat32::Fat32Fs::mount(dev) {
+        Ok(fs) => return Ok(("fat32", fs)),
+        Err(FsErr::Invalid | FsErr::Unsupported) => {}
+        Err(err) => return Err(err),
+    }
+    match ext2::Ext2Fs::mount(dev) {
+        Ok(fs) => return Ok(("ext2", fs)),
+        Err(FsErr::Invalid | FsErr::Unsupported) => {}
+        Err(err) => return Err(err),
+    }
+    match xv6fs::Xv6Fs::mount(dev) {
+        Ok(fs) => return Ok(("xv6fs", fs)),
+        Err(FsErr::Invalid | FsErr::Unsupported) => {}
+        Err(err) => return Err(err),
+    }
+    Err(FsErr::Unsupported)
+}
@@ -0,0 +1,122 @@
+//! Read-only filesystem over the initramfs archive.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+
+use super::{DirEntry, FileSystem, FileType, FsErr, Inode, InodeRef, Stat};
+use crate::cpio;
+use crate::initramfs::{self, RamNode};
+
+const RAMFS_DEV: u32 = 1;
+
+pub struct RamFs {
+    root: Arc<RamInode>,
+}
+
+pub struct RamInode {
+    node: RamNode,
+}
+
+/// Node type from the cpio mode bits.
+fn node_kind(node: &RamNode) -> FileType {
+    match node.mode & cpio::S_IFMT {
+        cpio::S_IFDIR => FileType::Directory,
+        cpio::S_IFLNK => FileType::Symlink,
+        _ => FileType::Regular,
+    }
+}
+
+impl RamFs {
+    pub fn new() -> Self {
+        // archives do not always carry an entry for the root itself
+        let node = initramfs::lookup("").copied().unwrap_or(RamNode {
+            path: "",
+            ino : 0,
+            mode: cpio::S_IFDIR | 0o755,
+            nlink: 2,
+            devmajor: 0,
+            devminor: 0,
+            data: &[],
+        });
+        Self { root: Arc::new(RamInode { node }) }
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for RamFs {
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+}
+
+impl Inode for RamInode {
+    fn stat(&self) -> Stat {
+        Stat {
+            ino  : self.node.ino as u64,
+            size : self.node.data.len() as u64,
+            dev  : RAMFS_DEV,
+            nlink: 1,
+            kind : node_kind(&self.node),
+            mode : self.node.mode & 0o7777,
+        }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsErr> {
+        if self.node.is_dir() {
+            return Err(FsErr::IsDir);
+        }
+        let data = self.node.data.get(offset..).unwrap_or(&[]);
+        let count = data.len().min(buf.len());
+        buf[..count].copy_from_slice(&data[..count]);
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsErr> {
+        if !self.node.is_dir() {
+            return Err(FsErr::NotDir);
+        }
+        let node = initramfs::read_dir(self.node.path)
+            .find(|node| node.name() == name)
+            .ok_or(FsErr::NotFound)?;
+        Ok(Arc::new(RamInode { node: *node }))
+    }
+
+    fn create(&self, _name: &str, _kind: FileType) -> Result<InodeRef, FsErr> {
+        Err(FsErr::ReadOnly)
+    }
+
+    fn link(&self, _name: &str, _inode: &InodeRef) -> Result<(), FsErr> {
+        Err(FsErr::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsErr> {
+        Err(FsErr::ReadOnly)
+    }
+
+    fn dirent(&self, index: usize) -> Result<Option<DirEntry>, FsErr> {
+        if !self.node.is_dir() {
+            return Err(FsErr::NotDir);
+        }
+        Ok(initramfs::read_dir(self.node.path).nth(index).map(|node| DirEntry {
+            ino : node.ino as u64,
+            kind: node_kind(node),
+            name: String::from(node.name()),
+        }))
+    }
+
+    fn read_link(&self) -> Result<String, FsErr> {
+        if node_kind(&self.node) != FileType::Symlink {
+            return Err(FsErr::InvalidPath);
+        }
+        core::str::from_utf8(self.node.data).map(String::from).map_err(|_| FsErr::Corrupt)
+    }
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+}
@@ -0,0 +1,331 @@
+//! Writable in-memory filesystem. File data lives in page sized chunks
+//! from the kernel heap, so files grow without ever needing one large
+//! contiguous buffer; holes left by `truncate` or seeking past the end
+//! are not allocated until written.
+
+use alloc::alloc::{alloc_zeroed, Layout};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{DirEntry, FileSystem, FileType, FsErr, Inode, InodeRef, Stat};
+use crate::sync::SpinLock;
+
+/// Bytes of file data per heap chunk.
+const CHUNK_SIZE: usize = 4096;
+
+type Chunk = [u8; CHUNK_SIZE];
+
+pub struct TmpFs {
+    root  : Arc<TmpInode>,
+    super_: Arc<TmpSuper>,
+}
+
+/// State shared by every inode of one tmpfs.
+struct TmpSuper {
+    dev     : u32,
+    next_ino: AtomicU64,
+}
+
+pub struct TmpInode {
+    ino   : u64,
+    kind  : FileType,
+    super_: Arc<TmpSuper>,
+    inner : SpinLock<TmpInner>,
+}
+
+struct TmpInner {
+    nlink: u32,
+    mode : u32,
+    data : TmpData,
+}
+
+enum TmpData {
+    File { size: usize, chunks: Vec<Option<Box<Chunk>>> },
+    Dir(Vec<(String, InodeRef)>),
+    Symlink(String),
+}
+
+/// A zeroed chunk, `None` when the heap is exhausted.
+fn chunk_alloc() -> Option<Box<Chunk>> {
+    let layout = Layout::new::<Chunk>();
+    let ptr = unsafe { alloc_zeroed(layout) } as *mut Chunk;
+    if ptr.is_null() {
+        return None;
+    }
+    Some(unsafe { Box::from_raw(ptr) })
+}
+
+impl TmpSuper {
+    fn new_inode(self: &Arc<Self>, kind: FileType, data: TmpData) -> Arc<TmpInode> {
+        let (nlink, mode) = match kind {
+            FileType::Directory => (2, 0o755),
+            FileType::Symlink   => (1, 0o777),
+            _                   => (1, 0o644),
+        };
+        Arc::new(TmpInode {
+            ino   : self.next_ino.fetch_add(1, Ordering::Relaxed),
+            kind,
+            super_: self.clone(),
+            inner : SpinLock::new(TmpInner { nlink, mode, data }),
+        })
+    }
+}
+
+impl TmpFs {
+    pub fn new() -> Self {
+        let super_ = Arc::new(TmpSuper { dev: super::new_dev(), next_ino: AtomicU64::new(1) });
+        let root = super_.new_inode(FileType::Directory, TmpData::Dir(Vec::new()));
+        Self { root, super_ }
+    }
+
+    /// Device number reported in `Stat::dev`.
+    pub fn dev(&self) -> u32 {
+        self.super_.dev
+    }
+}
+
+impl Default for TmpFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+}
+
+impl TmpInode {
+    /// Create `name` in this directory holding `data`.
+    fn add_entry(&self, name: &str, kind: FileType, data: TmpData) -> Result<InodeRef, FsErr> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(FsErr::InvalidPath);
+        }
+        if name.len() > super::NAME_MAX {
+            return Err(FsErr::NameTooLong);
+        }
+        let mut guard = self.inner.lock();
+        let inner = guard.get_mut();
+        let TmpData::Dir(entries) = &mut inner.data else {
+            return Err(FsErr::NotDir);
+        };
+        if entries.iter().any(|(entry, _)| entry == name) {
+            return Err(FsErr::Exists);
+        }
+        entries.try_reserve(1).map_err(|_| FsErr::NoSpace)?;
+
+        let inode: InodeRef = self.super_.new_inode(kind, data);
+        entries.push((String::from(name), inode.clone()));
+        if kind == FileType::Directory {
+            inner.nlink += 1;
+        }
+        Ok(inode)
+    }
+
+    fn is_empty_dir(&self) -> bool {
+        matches!(&self.inner.lock().get().data, TmpData::Dir(entries) if entries.is_empty())
+    }
+}
+
+impl Inode for TmpInode {
+    fn stat(&self) -> Stat {
+        let guard = self.inner.lock();
+        let inner = guard.get();
+        let size = match &inner.data {
+            TmpData::File { size, .. } => *size,
+            TmpData::Dir(entries)      => entries.len(),
+            TmpData::Symlink(target)   => target.len(),
+        };
+        Stat {
+            ino  : self.ino,
+            size : size as u64,
+            dev  : self.super_.dev,
+            nlink: inner.nlink,
+            kind : self.kind,
+            mode : inner.mode,
+        }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsErr> {
+        let guard = self.inner.lock();
+        let TmpData::File { size, chunks } = &guard.get().data else {
+            return Err(FsErr::IsDir);
+        };
+        let end = (*size).min(offset.saturating_add(buf.len()));
+        let mut pos = offset;
+        while pos < end {
+            let at    = pos % CHUNK_SIZE;
+            let count = (CHUNK_SIZE - at).min(end - pos);
+            let dst   = &mut buf[pos - offset..pos - offset + count];
+            match &chunks[pos / CHUNK_SIZE] {
+                Some(chunk) => dst.copy_from_slice(&chunk[at..at + count]),
+                None => dst.fill(0),
+            }
+            pos += count;
+        }
+        Ok(end.saturating_sub(offset))
+    }
+
+    /// Writes past the end grow the file; if the heap runs out part way
+    /// the bytes written so far are kept and counted.
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, FsErr> {
+        let mut guard = self.inner.lock();
+        let TmpData::File { size, chunks } = &mut guard.get_mut().data else {
+            return Err(FsErr::IsDir);
+        };
+        let end = offset.checked_add(buf.len()).ok_or(FsErr::Invalid)?;
+        let needed = end.div_ceil(CHUNK_SIZE);
+        if needed > chunks.len() {
+            chunks.try_reserve(needed - chunks.len()).map_err(|_| FsErr::NoSpace)?;
+            chunks.resize_with(needed, || None);
+        }
+
+        let mut pos = offset;
+        while pos < end {
+            let at    = pos % CHUNK_SIZE;
+            let count = (CHUNK_SIZE - at).min(end - pos);
+            let slot  = &mut chunks[pos / CHUNK_SIZE];
+            if slot.is_none() {
+                match chunk_alloc() {
+                    Some(chunk) => *slot = Some(chunk),
+                    None => break,
+                }
+            }
+            if let Some(chunk) = slot {
+                chunk[at..at + count].copy_from_slice(&buf[pos - offset..pos - offset + count]);
+            }
+            pos += count;
+        }
+
+        if pos > *size {
+            *size = pos;
+        }
+        // drop the chunk slots reserved for a write that did not happen
+        chunks.truncate(size.div_ceil(CHUNK_SIZE));
+        match pos - offset {
+            0 if !buf.is_empty() => Err(FsErr::NoSpace),
+            written => Ok(written),
+        }
+    }
+
+    fn truncate(&self, new_size: usize) -> Result<(), FsErr> {
+        let mut guard = self.inner.lock();
+        let TmpData::File { size, chunks } = &mut guard.get_mut().data else {
+            return Err(FsErr::IsDir);
+        };
+        let needed = new_size.div_ceil(CHUNK_SIZE);
+        if needed > chunks.len() {
+            chunks.try_reserve(needed - chunks.len()).map_err(|_| FsErr::NoSpace)?;
+        }
+        chunks.resize_with(needed, || None);
+        // bytes past the new end must read back as zero if the file grows again
+        let tail = new_size % CHUNK_SIZE;
+        if tail != 0 && new_size < *size {
+            if let Some(Some(chunk)) = chunks.last_mut() {
+                chunk[tail..].fill(0);
+            }
+        }
+        *size = new_size;
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsErr> {
+        let guard = self.inner.lock();
+        let TmpData::Dir(entries) = &guard.get().data else {
+            return Err(FsErr::NotDir);
+        };
+        entries.iter()
+            .find(|(entry, _)| entry == name)
+            .map(|(_, inode)| inode.clone())
+            .ok_or(FsErr::NotFound)
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<InodeRef, FsErr> {
+        let data = match kind {
+            FileType::Regular   => TmpData::File { size: 0, chunks: Vec::new() },
+            FileType::Directory => TmpData::Dir(Vec::new()),
+            _ => return Err(FsErr::Unsupported),
+        };
+        self.add_entry(name, kind, data)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<InodeRef, FsErr> {
+        self.add_entry(name, FileType::Symlink, TmpData::Symlink(String::from(target)))
+    }
+
+    fn link(&self, name: &str, inode: &InodeRef) -> Result<(), FsErr> {
+        let target = inode.as_any().downcast_ref::<TmpInode>()
+            .filter(|target| Arc::ptr_eq(&target.super_, &self.super_))
+            .ok_or(FsErr::Invalid)?;
+        if target.kind == FileType::Directory {
+            return Err(FsErr::IsDir);
+        }
+        if name.len() > super::NAME_MAX {
+            return Err(FsErr::NameTooLong);
+        }
+
+        let mut guard = self.inner.lock();
+        let TmpData::Dir(entries) = &mut guard.get_mut().data else {
+            return Err(FsErr::NotDir);
+        };
+        if entries.iter().any(|(entry, _)| entry == name) {
+            return Err(FsErr::Exists);
+        }
+        entries.try_reserve(1).map_err(|_| FsErr::NoSpace)?;
+        entries.push((String::from(name), inode.clone()));
+        target.inner.lock().get_mut().nlink += 1;
+        Ok(())
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsErr> {
+        let mut guard = self.inner.lock();
+        let inner = guard.get_mut();
+        let TmpData::Dir(entries) = &mut inner.data else {
+            return Err(FsErr::NotDir);
+        };
+        let idx = entries.iter().position(|(entry, _)| entry == name).ok_or(FsErr::NotFound)?;
+        let inode = entries[idx].1.clone();
+        let child = inode.as_any().downcast_ref::<TmpInode>().ok_or(FsErr::Corrupt)?;
+        if child.kind == FileType::Directory {
+            if !child.is_empty_dir() {
+                return Err(FsErr::NotEmpty);
+            }
+            inner.nlink -= 1;
+        }
+        entries.remove(idx);
+        // data is freed when the last open file drops its reference
+        let mut child_guard = child.inner.lock();
+        let child_inner = child_guard.get_mut();
+        child_inner.nlink = match child.kind {
+            FileType::Directory => 0,
+            _ => child_inner.nlink.saturating_sub(1),
+        };
+        Ok(())
+    }
+
+    fn dirent(&self, index: usize) -> Result<Option<DirEntry>, FsErr> {
+        let guard = self.inner.lock();
+        let TmpData::Dir(entries) = &guard.get().data else {
+            return Err(FsErr::NotDir);
+        };
+        Ok(entries.get(index).map(|(name, inode)| {
+            let stat = inode.stat();
+            DirEntry { ino: stat.ino, kind: stat.kind, name: name.clone() }
+        }))
+    }
+
+    fn read_link(&self) -> Result<String, FsErr> {
+        match &self.inner.lock().get().data {
+            TmpData::Symlink(target) => Ok(target.clone()),
+            _ => Err(FsErr::InvalidPath),
+        }
+    }
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+}
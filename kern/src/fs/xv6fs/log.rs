@@ -0,0 +1,179 @@
+//! Write-ahead log: makes the block writes of a filesystem operation
+//! atomic across crashes, as in xv6.
+//!
+//! An operation runs between `begin_op` and `end_op` and hands every
+//! block it changes to `write` instead of writing it out. The blocks stay
+//! pinned in the buffer cache. When the last outstanding operation ends,
+//! the transaction commits:
+//!
+//! 1. the changed blocks are copied into the log area,
+//! 2. the header is written with their home block numbers: the commit,
+//! 3. the blocks are written home,
+//! 4. the header is cleared.
+//!
+//! A crash before 2 loses the transaction, one after it is replayed by
+//! `Log::recover` at the next mount.
+//!
+//! On disk the log is a header block followed by `LOG_SIZE` blocks; the
+//! header holds the number of logged blocks and their home block numbers.
+
+use alloc::vec::Vec;
+
+use super::BSIZE;
+use crate::block::{self, cache, DevId};
+use crate::fs::FsErr;
+use crate::sched;
+use crate::sync::SpinLock;
+
+/// Blocks one transaction may change.
+pub const LOG_SIZE: usize = 30;
+/// Most distinct blocks a single operation writes.
+pub const MAX_OP_BLOCKS: usize = 10;
+
+const _: () = assert!(LOG_SIZE < cache::NBUF, "logged blocks are pinned in the cache");
+const _: () = assert!(4 + 4 * LOG_SIZE <= BSIZE, "the log header fits a block");
+
+pub struct Log {
+    dev  : DevId,
+    start: u64,     // the header block
+    state: SpinLock<LogState>,
+}
+
+struct LogState {
+    outstanding: usize,     // operations between `begin_op` and `end_op`
+    committing : bool,
+    blocks     : Vec<u64>,  // home blocks changed by the open transaction
+}
+
+fn read_head(dev: DevId, start: u64) -> Result<Vec<u64>, FsErr> {
+    let head = block::bread(dev, start)?;
+    let data = head.data();
+    let word = |n: usize| u32::from_le_bytes(data[4 * n..4 * n + 4].try_into().unwrap());
+    let count = word(0) as usize;
+    if count > LOG_SIZE {
+        return Err(FsErr::Corrupt);
+    }
+    Ok((1..=count).map(|n| word(n) as u64).collect())
+}
+
+/// Write the header; with a non-empty `blocks` this is the commit point.
+fn write_head(dev: DevId, start: u64, blocks: &[u64]) -> Result<(), FsErr> {
+    let mut head = cache::bzero(dev, start)?;
+    let data = head.data_mut();
+    data[..4].copy_from_slice(&(blocks.len() as u32).to_le_bytes());
+    for (n, &home) in blocks.iter().enumerate() {
+        data[4 * (n + 1)..4 * (n + 2)].copy_from_slice(&(home as u32).to_le_bytes());
+    }
+    head.write_through()?;
+    Ok(block::device(dev)?.flush()?)
+}
+
+/// Copy logged block `n` or the cached block to the home location.
+fn install(dev: DevId, start: u64, blocks: &[u64], from_log: bool) -> Result<(), FsErr> {
+    for (n, &home) in blocks.iter().enumerate() {
+        let mut to = match from_log {
+            true => {
+                let from = block::bread(dev, start + 1 + n as u64)?;
+                let mut to = cache::bzero(dev, home)?;
+                to.data_mut().copy_from_slice(from.data());
+                to
+            }
+            false => block::bread(dev, home)?,
+        };
+        to.write_through()?;
+    }
+    Ok(block::device(dev)?.flush()?)
+}
+
+impl Log {
+    /// Replay a committed transaction left in the log at `start`, if any,
+    /// and return the log ready for use.
+    pub fn recover(dev: DevId, start: u64) -> Result<Self, FsErr> {
+        let blocks = read_head(dev, start)?;
+        if !blocks.is_empty() {
+            install(dev, start, &blocks, true)?;
+            write_head(dev, start, &[])?;
+        }
+        Ok(Self {
+            dev,
+            start,
+            state: SpinLock::new(LogState { outstanding: 0, committing: false, blocks: Vec::new() }),
+        })
+    }
+
+    fn chan(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Start an operation, waiting until the log has room for it.
+    pub fn begin_op(&self) {
+        let mut guard = self.state.lock();
+        loop {
+            let state = guard.get_mut();
+            let reserved = state.blocks.len() + (state.outstanding + 1) * MAX_OP_BLOCKS;
+            if !state.committing && reserved <= LOG_SIZE {
+                state.outstanding += 1;
+                return;
+            }
+            guard = sched::sleep(self.chan(), guard);
+        }
+    }
+
+    /// End an operation; the last one out commits the transaction.
+    pub fn end_op(&self) -> Result<(), FsErr> {
+        let commit = {
+            let mut guard = self.state.lock();
+            let state = guard.get_mut();
+            state.outstanding -= 1;
+            if state.outstanding == 0 {
+                state.committing = true;
+                core::mem::take(&mut state.blocks)
+            } else {
+                // one fewer reservation, someone may fit now
+                sched::wakeup(self.chan());
+                return Ok(());
+            }
+        };
+
+        let result = self.commit(&commit);
+        for &home in &commit {
+            cache::unpin(self.dev, home);
+        }
+        self.state.lock().get_mut().committing = false;
+        sched::wakeup(self.chan());
+        result
+    }
+
+    fn commit(&self, blocks: &[u64]) -> Result<(), FsErr> {
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        for (n, &home) in blocks.iter().enumerate() {
+            let from = block::bread(self.dev, home)?;
+            let mut to = cache::bzero(self.dev, self.start + 1 + n as u64)?;
+            to.data_mut().copy_from_slice(from.data());
+            to.write_through()?;
+        }
+        block::device(self.dev)?.flush()?;
+        write_head(self.dev, self.start, blocks)?;
+        install(self.dev, self.start, blocks, false)?;
+        write_head(self.dev, self.start, &[])
+    }
+
+    /// Record that the current operation changed `buf`. Use this instead
+    /// of writing the buffer out.
+    pub fn write(&self, buf: &block::BufGuard) -> Result<(), FsErr> {
+        let mut guard = self.state.lock();
+        let state = guard.get_mut();
+        assert!(state.outstanding > 0, "log: write outside of an operation");
+        if state.blocks.contains(&buf.block()) {
+            return Ok(());      // absorbed into the transaction
+        }
+        if state.blocks.len() >= LOG_SIZE {
+            return Err(FsErr::NoSpace);
+        }
+        state.blocks.push(buf.block());
+        buf.pin();
+        Ok(())
+    }
+}
@@ -0,0 +1,967 @@
+//! The native on-disk filesystem, laid out as in xv6:
+//! ```text
+//! [ boot | super | log header, log blocks | inodes | free bitmap | data ]
+//! ```
+//! Inodes have 12 direct block pointers and one indirect block, so files
+//! reach 268 KiB. Directories are files of fixed size entries. Every
+//! change goes through the write-ahead `log`, so an operation such as
+//! create, unlink or one chunk of a write either fully happens or not at
+//! all, whenever the machine stops.
+//!
+//! Inodes whose last link went away while they were open are freed
+//! when the last reference is dropped; after a crash, `mount` frees any
+//! left behind.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use super::{DirEntry, FileSystem, FileType, FsErr, Inode, InodeRef, Stat};
+use crate::block::{self, cache, DevId};
+use crate::sync::{SleepLock, SleepLockGuard, SpinLock};
+
+pub mod log;
+
+use log::{Log, LOG_SIZE, MAX_OP_BLOCKS};
+
+pub const BSIZE: usize = 1024;
+const FS_MAGIC: u32 = 0x10203040;
+const SUPER_BLOCK: u64 = 1;
+const ROOT_INO: u32 = 1;
+
+const NDIRECT  : usize = 12;
+const NINDIRECT: usize = BSIZE / 4;
+/// Largest file, in blocks.
+const MAX_FILE : usize = NDIRECT + NINDIRECT;
+
+const DINODE_SIZE: usize = 64;
+/// Inodes per block.
+const IPB        : usize = BSIZE / DINODE_SIZE;
+/// Bitmap bits per block.
+const BPB        : usize = BSIZE * 8;
+
+const DIRENT_SIZE: usize = 32;
+/// Longest name a directory entry holds.
+pub const DIRSIZ : usize = DIRENT_SIZE - 4;
+
+/// Bytes of a write that fit one operation: the inode, an indirect
+/// block and a bitmap block, plus data blocks that each may need a
+/// bitmap block of their own.
+const MAX_WRITE: usize = (MAX_OP_BLOCKS - 4) / 2 * BSIZE;
+
+/// `DInode::kind` values.
+pub struct Xv6Type;
+impl Xv6Type {
+    pub const FREE   : u16 = 0;
+    pub const DIR    : u16 = 1;
+    pub const FILE   : u16 = 2;
+    pub const SYMLINK: u16 = 4;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SuperBlock {
+    pub size       : u32,   // blocks in the filesystem
+    pub nblocks    : u32,   // data blocks
+    pub ninodes    : u32,
+    pub nlog       : u32,   // log blocks, the header included
+    pub log_start  : u32,
+    pub inode_start: u32,
+    pub bmap_start : u32,
+}
+
+impl SuperBlock {
+    fn parse(data: &[u8]) -> Result<Self, FsErr> {
+        let word = |n: usize| u32::from_le_bytes(data[4 * n..4 * n + 4].try_into().unwrap());
+        if word(0) != FS_MAGIC {
+            return Err(FsErr::Invalid);
+        }
+        let sb = Self {
+            size       : word(1),
+            nblocks    : word(2),
+            ninodes    : word(3),
+            nlog       : word(4),
+            log_start  : word(5),
+            inode_start: word(6),
+            bmap_start : word(7),
+        };
+        let inode_blocks = (sb.ninodes as usize).div_ceil(IPB) as u32;
+        let bitmap_blocks = (sb.size as usize).div_ceil(BPB) as u32;
+        let layout_ok = sb.nlog as usize == LOG_SIZE + 1
+            && sb.log_start >= 2
+            && sb.inode_start >= sb.log_start + sb.nlog
+            && sb.bmap_start >= sb.inode_start + inode_blocks
+            && sb.bmap_start + bitmap_blocks + sb.nblocks == sb.size
+            && sb.ninodes > ROOT_INO;
+        if !layout_ok {
+            return Err(FsErr::Corrupt);
+        }
+        Ok(sb)
+    }
+
+    fn encode(&self, data: &mut [u8]) {
+        let words = [FS_MAGIC, self.size, self.nblocks, self.ninodes, self.nlog,
+                     self.log_start, self.inode_start, self.bmap_start];
+        for (n, word) in words.iter().enumerate() {
+            data[4 * n..4 * n + 4].copy_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    /// First data block.
+    fn data_start(&self) -> u32 {
+        self.size - self.nblocks
+    }
+}
+
+/// An inode as stored on disk.
+#[derive(Debug, Clone, Copy, Default)]
+struct DInode {
+    kind : u16,
+    nlink: u16,
+    size : u32,
+    addrs: [u32; NDIRECT + 1],
+}
+
+impl DInode {
+    fn parse(raw: &[u8]) -> Self {
+        let mut addrs = [0u32; NDIRECT + 1];
+        for (n, addr) in addrs.iter_mut().enumerate() {
+            *addr = u32::from_le_bytes(raw[12 + 4 * n..16 + 4 * n].try_into().unwrap());
+        }
+        Self {
+            kind : u16::from_le_bytes([raw[0], raw[1]]),
+            nlink: u16::from_le_bytes([raw[6], raw[7]]),
+            size : u32::from_le_bytes(raw[8..12].try_into().unwrap()),
+            addrs,
+        }
+    }
+
+    /// Bytes 2..6 hold a device number in xv6 and stay zero here.
+    fn encode(&self, raw: &mut [u8]) {
+        raw.fill(0);
+        raw[0..2].copy_from_slice(&self.kind.to_le_bytes());
+        raw[6..8].copy_from_slice(&self.nlink.to_le_bytes());
+        raw[8..12].copy_from_slice(&self.size.to_le_bytes());
+        for (n, addr) in self.addrs.iter().enumerate() {
+            raw[12 + 4 * n..16 + 4 * n].copy_from_slice(&addr.to_le_bytes());
+        }
+    }
+}
+
+fn dirent_encode(inum: u32, name: &str) -> [u8; DIRENT_SIZE] {
+    let mut raw = [0u8; DIRENT_SIZE];
+    raw[..4].copy_from_slice(&inum.to_le_bytes());
+    raw[4..4 + name.len()].copy_from_slice(name.as_bytes());
+    raw
+}
+
+fn dirent_parse(raw: &[u8]) -> (u32, &[u8]) {
+    let inum = u32::from_le_bytes(raw[..4].try_into().unwrap());
+    let name = &raw[4..];
+    let len = name.iter().position(|&b| b == 0).unwrap_or(DIRSIZ);
+    (inum, &name[..len])
+}
+
+fn check_name(name: &str) -> Result<(), FsErr> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        return Err(FsErr::InvalidPath);
+    }
+    if name.len() > DIRSIZ {
+        return Err(FsErr::NameTooLong);
+    }
+    Ok(())
+}
+
+/// Write a fresh, empty filesystem with room for `ninodes` inodes over
+/// the whole of `dev`.
+pub fn mkfs(dev: DevId, ninodes: u32) -> Result<SuperBlock, FsErr> {
+    block::set_block_size(dev, BSIZE)?;
+    let size = block::blocks(dev)?.min(u32::MAX as u64) as u32;
+    let ninodes = ninodes.max(ROOT_INO + 1);
+    let nlog = (LOG_SIZE + 1) as u32;
+    let inode_blocks = (ninodes as usize).div_ceil(IPB) as u32;
+    let bitmap_blocks = (size as usize).div_ceil(BPB) as u32;
+    let meta = 2 + nlog + inode_blocks + bitmap_blocks;
+    if size <= meta {
+        return Err(FsErr::NoSpace);
+    }
+    let sb = SuperBlock {
+        size,
+        nblocks    : size - meta,
+        ninodes,
+        nlog,
+        log_start  : 2,
+        inode_start: 2 + nlog,
+        bmap_start : 2 + nlog + inode_blocks,
+    };
+
+    let write = |blockno: u64, fill: &dyn Fn(&mut [u8])| -> Result<(), FsErr> {
+        let mut buf = cache::bzero(dev, blockno)?;
+        fill(buf.data_mut());
+        Ok(buf.write_through()?)
+    };
+    for blockno in 0..meta as u64 {
+        write(blockno, &|_| {})?;
+    }
+    write(SUPER_BLOCK, &|data| sb.encode(data))?;
+
+    // the root directory, in the first data block
+    let root_block = sb.data_start();
+    let mut root = DInode { kind: Xv6Type::DIR, nlink: 1, size: 2 * DIRENT_SIZE as u32, ..DInode::default() };
+    root.addrs[0] = root_block;
+    let (iblock, ioffset) = inode_pos(&sb, ROOT_INO);
+    write(iblock, &|data| root.encode(&mut data[ioffset..ioffset + DINODE_SIZE]))?;
+    write(root_block as u64, &|data| {
+        data[..DIRENT_SIZE].copy_from_slice(&dirent_encode(ROOT_INO, "."));
+        data[DIRENT_SIZE..2 * DIRENT_SIZE].copy_from_slice(&dirent_encode(ROOT_INO, ".."));
+    })?;
+
+    // everything up to and including the root's block is in use
+    let used = root_block as usize + 1;
+    for n in 0..bitmap_blocks as usize {
+        write(sb.bmap_start as u64 + n as u64, &|data| {
+            for bit in n * BPB..used.min((n + 1) * BPB) {
+                data[(bit % BPB) / 8] |= 1 << (bit % 8);
+            }
+        })?;
+    }
+    block::sync(dev)?;
+    Ok(sb)
+}
+
+fn inode_pos(sb: &SuperBlock, inum: u32) -> (u64, usize) {
+    let block = sb.inode_start as u64 + (inum as usize / IPB) as u64;
+    (block, (inum as usize % IPB) * DINODE_SIZE)
+}
+
+pub struct Xv6Fs {
+    vol : Arc<Volume>,
+    root: Arc<Xv6Inode>,
+}
+
+struct Volume {
+    dev     : DevId,
+    stat_dev: u32,
+    sb      : SuperBlock,
+    log     : Log,
+    inodes  : SpinLock<BTreeMap<u32, Weak<Xv6Inode>>>,
+}
+
+pub struct Xv6Inode {
+    vol : Arc<Volume>,
+    inum: u32,
+    data: SleepLock<DInode>,
+}
+
+impl Volume {
+    /// Run `f` as one logged operation.
+    fn op<R>(&self, f: impl FnOnce() -> Result<R, FsErr>) -> Result<R, FsErr> {
+        self.log.begin_op();
+        let result = f();
+        let committed = self.log.end_op();
+        let value = result?;
+        committed?;
+        Ok(value)
+    }
+
+    fn check_inum(&self, inum: u32) -> Result<(), FsErr> {
+        if inum == 0 || inum >= self.sb.ninodes {
+            return Err(FsErr::Corrupt);
+        }
+        Ok(())
+    }
+
+    fn check_block(&self, blockno: u32) -> Result<u64, FsErr> {
+        if blockno < self.sb.data_start() || blockno >= self.sb.size {
+            return Err(FsErr::Corrupt);
+        }
+        Ok(blockno as u64)
+    }
+
+    fn read_dinode(&self, inum: u32) -> Result<DInode, FsErr> {
+        self.check_inum(inum)?;
+        let (blockno, offset) = inode_pos(&self.sb, inum);
+        let buf = block::bread(self.dev, blockno)?;
+        Ok(DInode::parse(&buf.data()[offset..offset + DINODE_SIZE]))
+    }
+
+    /// Write `din` back to its inode block.
+    fn iupdate(&self, inum: u32, din: &DInode) -> Result<(), FsErr> {
+        let (blockno, offset) = inode_pos(&self.sb, inum);
+        let mut buf = block::bread(self.dev, blockno)?;
+        din.encode(&mut buf.data_mut()[offset..offset + DINODE_SIZE]);
+        self.log.write(&buf)
+    }
+
+    /// The shared in-memory inode `inum`.
+    fn iget(self: &Arc<Self>, inum: u32) -> Result<Arc<Xv6Inode>, FsErr> {
+        if let Some(inode) = self.inodes.lock().get().get(&inum).and_then(Weak::upgrade) {
+            return Ok(inode);
+        }
+        let din = self.read_dinode(inum)?;
+        if din.kind == Xv6Type::FREE {
+            return Err(FsErr::Corrupt);
+        }
+        let mut guard = self.inodes.lock();
+        let inodes = guard.get_mut();
+        // someone may have loaded it meanwhile
+        if let Some(inode) = inodes.get(&inum).and_then(Weak::upgrade) {
+            return Ok(inode);
+        }
+        let inode = Arc::new(Xv6Inode { vol: self.clone(), inum, data: SleepLock::new(din) });
+        inodes.insert(inum, Arc::downgrade(&inode));
+        Ok(inode)
+    }
+
+    /// Allocate an inode of `kind`, with no links yet.
+    fn ialloc(&self, kind: u16) -> Result<u32, FsErr> {
+        for inum in ROOT_INO..self.sb.ninodes {
+            let (blockno, offset) = inode_pos(&self.sb, inum);
+            let mut buf = block::bread(self.dev, blockno)?;
+            let raw = &mut buf.data_mut()[offset..offset + DINODE_SIZE];
+            if DInode::parse(raw).kind == Xv6Type::FREE {
+                DInode { kind, ..DInode::default() }.encode(raw);
+                self.log.write(&buf)?;
+                return Ok(inum);
+            }
+        }
+        Err(FsErr::NoSpace)
+    }
+
+    /// Allocate a zeroed data block.
+    fn balloc(&self) -> Result<u32, FsErr> {
+        let sb = &self.sb;
+        for base in (0..sb.size as usize).step_by(BPB) {
+            let mut bitmap = block::bread(self.dev, (sb.bmap_start as usize + base / BPB) as u64)?;
+            let Some(bit) = (0..BPB.min(sb.size as usize - base))
+                .find(|bit| bitmap.data()[bit / 8] & (1 << (bit % 8)) == 0) else {
+                continue;
+            };
+            bitmap.data_mut()[bit / 8] |= 1 << (bit % 8);
+            self.log.write(&bitmap)?;
+            drop(bitmap);
+
+            let blockno = (base + bit) as u32;
+            let zero = cache::bzero(self.dev, self.check_block(blockno)?)?;
+            self.log.write(&zero)?;
+            return Ok(blockno);
+        }
+        Err(FsErr::NoSpace)
+    }
+
+    fn bfree(&self, blockno: u32) -> Result<(), FsErr> {
+        let bit = self.check_block(blockno)? as usize;
+        let mut bitmap = block::bread(self.dev, (self.sb.bmap_start as usize + bit / BPB) as u64)?;
+        let byte = &mut bitmap.data_mut()[(bit % BPB) / 8];
+        if *byte & (1 << (bit % 8)) == 0 {
+            return Err(FsErr::Corrupt);     // freeing a free block
+        }
+        *byte &= !(1 << (bit % 8));
+        self.log.write(&bitmap)
+    }
+
+    /// Disk block holding block `bn` of the inode, allocating it (and the
+    /// indirect block) if `alloc` is set; `None` for a hole otherwise.
+    fn bmap(&self, din: &mut DInode, bn: usize, alloc: bool) -> Result<Option<u64>, FsErr> {
+        if bn >= MAX_FILE {
+            return Err(FsErr::NoSpace);
+        }
+        let slot = bn.min(NDIRECT);
+        if din.addrs[slot] == 0 {
+            if !alloc {
+                return Ok(None);
+            }
+            din.addrs[slot] = self.balloc()?;
+        }
+        let addr = self.check_block(din.addrs[slot])?;
+        if bn < NDIRECT {
+            return Ok(Some(addr));
+        }
+
+        let at = (bn - NDIRECT) * 4;
+        let mut indirect = block::bread(self.dev, addr)?;
+        let mut entry = u32::from_le_bytes(indirect.data()[at..at + 4].try_into().unwrap());
+        if entry == 0 {
+            if !alloc {
+                return Ok(None);
+            }
+            entry = self.balloc()?;
+            indirect.data_mut()[at..at + 4].copy_from_slice(&entry.to_le_bytes());
+            self.log.write(&indirect)?;
+        }
+        Ok(Some(self.check_block(entry)?))
+    }
+
+    fn readi(&self, din: &mut DInode, offset: usize, buf: &mut [u8]) -> Result<usize, FsErr> {
+        let size = din.size as usize;
+        if offset >= size {
+            return Ok(0);
+        }
+        let count = buf.len().min(size - offset);
+        let mut done = 0;
+        while done < count {
+            let pos = offset + done;
+            let len = (BSIZE - pos % BSIZE).min(count - done);
+            let out = &mut buf[done..done + len];
+            match self.bmap(din, pos / BSIZE, false)? {
+                Some(blockno) => {
+                    let data = block::bread(self.dev, blockno)?;
+                    out.copy_from_slice(&data.data()[pos % BSIZE..pos % BSIZE + len]);
+                }
+                None => out.fill(0),
+            }
+            done += len;
+        }
+        Ok(count)
+    }
+
+    /// Write `data` at `offset` within the current operation.
+    fn writei(&self, inum: u32, din: &mut DInode, offset: usize, data: &[u8]) -> Result<(), FsErr> {
+        let end = offset.checked_add(data.len()).filter(|&end| end <= MAX_FILE * BSIZE)
+            .ok_or(FsErr::NoSpace)?;
+        let mut done = 0;
+        let result = (|| {
+            while done < data.len() {
+                let pos = offset + done;
+                let len = (BSIZE - pos % BSIZE).min(data.len() - done);
+                let blockno = self.bmap(din, pos / BSIZE, true)?.ok_or(FsErr::Corrupt)?;
+                let mut buf = block::bread(self.dev, blockno)?;
+                buf.data_mut()[pos % BSIZE..pos % BSIZE + len].copy_from_slice(&data[done..done + len]);
+                self.log.write(&buf)?;
+                done += len;
+            }
+            Ok(())
+        })();
+        // blocks allocated before a failure stay with the inode
+        if result.is_ok() {
+            din.size = din.size.max(end as u32);
+        }
+        self.iupdate(inum, din)?;
+        result
+    }
+
+    /// Free the blocks from block `keep` on.
+    fn itrunc(&self, din: &mut DInode, keep: usize) -> Result<(), FsErr> {
+        for slot in keep.min(NDIRECT)..NDIRECT {
+            if din.addrs[slot] != 0 {
+                self.bfree(din.addrs[slot])?;
+                din.addrs[slot] = 0;
+            }
+        }
+        if din.addrs[NDIRECT] != 0 {
+            let first = keep.saturating_sub(NDIRECT);
+            let indirect_addr = self.check_block(din.addrs[NDIRECT])?;
+            let mut indirect = block::bread(self.dev, indirect_addr)?;
+            let mut changed = false;
+            for at in (first * 4..BSIZE).step_by(4) {
+                let entry = u32::from_le_bytes(indirect.data()[at..at + 4].try_into().unwrap());
+                if entry != 0 {
+                    self.bfree(entry)?;
+                    changed = true;
+                    if first > 0 {
+                        indirect.data_mut()[at..at + 4].fill(0);
+                    }
+                }
+            }
+            if first == 0 {
+                // the block itself goes, its contents do not matter
+                drop(indirect);
+                self.bfree(din.addrs[NDIRECT])?;
+                din.addrs[NDIRECT] = 0;
+            } else if changed {
+                self.log.write(&indirect)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Free an inode with no links left, and its blocks.
+    fn ifree(&self, inum: u32, din: &mut DInode) -> Result<(), FsErr> {
+        self.itrunc(din, 0)?;
+        *din = DInode::default();
+        self.iupdate(inum, din)
+    }
+
+    /// Live entries of a directory: inode, name and offset.
+    fn dir_entries(&self, din: &mut DInode) -> Result<Vec<(u32, String, usize)>, FsErr> {
+        let mut entries = Vec::new();
+        let mut raw = [0u8; DIRENT_SIZE];
+        for offset in (0..din.size as usize).step_by(DIRENT_SIZE) {
+            if self.readi(din, offset, &mut raw)? != DIRENT_SIZE {
+                return Err(FsErr::Corrupt);
+            }
+            let (inum, name) = dirent_parse(&raw);
+            if inum != 0 {
+                let name = core::str::from_utf8(name).map_err(|_| FsErr::Corrupt)?;
+                entries.push((inum, String::from(name), offset));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn dir_lookup(&self, din: &mut DInode, name: &str) -> Result<Option<(u32, usize)>, FsErr> {
+        Ok(self.dir_entries(din)?.into_iter()
+            .find(|(_, entry, _)| entry == name)
+            .map(|(inum, _, offset)| (inum, offset)))
+    }
+
+    /// Add the entry `name` for `target` in the first free slot.
+    fn dir_link(&self, inum: u32, din: &mut DInode, name: &str, target: u32) -> Result<(), FsErr> {
+        let mut raw = [0u8; DIRENT_SIZE];
+        let mut slot = din.size as usize;
+        for offset in (0..din.size as usize).step_by(DIRENT_SIZE) {
+            self.readi(din, offset, &mut raw)?;
+            if dirent_parse(&raw).0 == 0 {
+                slot = offset;
+                break;
+            }
+        }
+        self.writei(inum, din, slot, &dirent_encode(target, name))
+    }
+
+    /// Create an inode of `kind` and its entry `name` in `dir`, which
+    /// must be locked, within the current operation.
+    fn create_in(self: &Arc<Self>, dir: &Xv6Inode, ddin: &mut DInode, name: &str, kind: u16)
+        -> Result<(u32, DInode), FsErr>
+    {
+        if self.dir_lookup(ddin, name)?.is_some() {
+            return Err(FsErr::Exists);
+        }
+        let inum = self.ialloc(kind)?;
+        let mut din = DInode { kind, nlink: 1, ..DInode::default() };
+        let made = self.iupdate(inum, &din).and_then(|_| {
+            if kind == Xv6Type::DIR {
+                self.dir_link(inum, &mut din, ".", inum)?;
+                self.dir_link(inum, &mut din, "..", dir.inum)?;
+            }
+            self.dir_link(dir.inum, ddin, name, inum)
+        });
+        if let Err(err) = made {
+            // give the inode back within the same operation
+            let _ = self.ifree(inum, &mut din);
+            return Err(err);
+        }
+        if kind == Xv6Type::DIR {
+            ddin.nlink += 1;    // for ".."
+            self.iupdate(dir.inum, ddin)?;
+        }
+        Ok((inum, din))
+    }
+
+    /// Free inodes left without links by a crash, e.g. files that were
+    /// unlinked while open.
+    fn reclaim_orphans(&self) -> Result<usize, FsErr> {
+        let mut reclaimed = 0;
+        for inum in ROOT_INO + 1..self.sb.ninodes {
+            let mut din = self.read_dinode(inum)?;
+            if din.kind != Xv6Type::FREE && din.nlink == 0 {
+                self.op(|| self.ifree(inum, &mut din))?;
+                reclaimed += 1;
+            }
+        }
+        Ok(reclaimed)
+    }
+}
+
+impl Xv6Fs {
+    /// Mount the filesystem on `dev`, replaying the log first.
+    pub fn mount(dev: DevId) -> Result<Arc<Self>, FsErr> {
+        block::set_block_size(dev, BSIZE)?;
+        let sb = SuperBlock::parse(block::bread(dev, SUPER_BLOCK)?.data())?;
+        if (sb.size as u64) > block::blocks(dev)? {
+            return Err(FsErr::Corrupt);
+        }
+        let log = Log::recover(dev, sb.log_start as u64)?;
+        let vol = Arc::new(Volume {
+            dev,
+            stat_dev: super::new_dev(),
+            sb,
+            log,
+            inodes  : SpinLock::new(BTreeMap::new()),
+        });
+        vol.reclaim_orphans()?;
+        let root = vol.iget(ROOT_INO)?;
+        if root.data.lock().kind != Xv6Type::DIR {
+            return Err(FsErr::Corrupt);
+        }
+        Ok(Arc::new(Self { vol, root }))
+    }
+
+    pub fn dev(&self) -> DevId {
+        self.vol.dev
+    }
+
+    pub fn super_block(&self) -> SuperBlock {
+        self.vol.sb
+    }
+
+    /// Check the filesystem is consistent: every block belongs to one
+    /// inode and is marked in the bitmap, link counts match directory
+    /// entries and every inode in use is reachable from the root. Only
+    /// meaningful while no operation runs and no unlinked file is open.
+    pub fn fsck(&self) -> Result<FsckStats, FsckErr> {
+        let vol = &self.vol;
+        let sb = &vol.sb;
+        // the root's own entry is implicit
+        let mut refs: BTreeMap<u32, u32> = BTreeMap::from([(ROOT_INO, 1)]);
+        let mut owned: BTreeSet<u32> = BTreeSet::new();
+        let mut stats = FsckStats::default();
+
+        let mut pending = alloc::vec![(ROOT_INO, ROOT_INO)];
+        let mut seen = BTreeSet::new();
+        while let Some((inum, parent)) = pending.pop() {
+            if !seen.insert(inum) {
+                continue;
+            }
+            let mut din = vol.read_dinode(inum)?;
+            if din.kind == Xv6Type::FREE {
+                return Err(FsckErr::FreeInodeLinked(inum));
+            }
+            stats.inodes += 1;
+
+            // blocks: direct, the indirect block and what it points at
+            let mut blocks: Vec<u32> = din.addrs[..NDIRECT].iter().copied().filter(|&b| b != 0).collect();
+            if din.addrs[NDIRECT] != 0 {
+                let indirect = din.addrs[NDIRECT];
+                vol.check_block(indirect).map_err(|_| FsckErr::BadBlock { inum, block: indirect })?;
+                blocks.push(indirect);
+                let buf = block::bread(vol.dev, indirect as u64).map_err(FsErr::from)?;
+                blocks.extend(buf.data().chunks(4)
+                    .map(|raw| u32::from_le_bytes(raw.try_into().unwrap()))
+                    .filter(|&b| b != 0));
+            }
+            for block in blocks {
+                if vol.check_block(block).is_err() {
+                    return Err(FsckErr::BadBlock { inum, block });
+                }
+                if !owned.insert(block) {
+                    return Err(FsckErr::DuplicateBlock(block));
+                }
+            }
+
+            if din.kind == Xv6Type::DIR {
+                stats.dirs += 1;
+                for (child, name, _) in vol.dir_entries(&mut din)? {
+                    match name.as_str() {
+                        "." if child != inum => return Err(FsckErr::BadDot(inum)),
+                        ".." if child != parent => return Err(FsckErr::BadDot(inum)),
+                        // a subdirectory's ".." links its parent
+                        ".." if inum != ROOT_INO => *refs.entry(parent).or_default() += 1,
+                        "." | ".." => {}
+                        _ => {
+                            vol.check_inum(child).map_err(|_| FsckErr::FreeInodeLinked(child))?;
+                            *refs.entry(child).or_default() += 1;
+                            pending.push((child, inum));
+                        }
+                    }
+                }
+            }
+        }
+
+        for inum in ROOT_INO..sb.ninodes {
+            let din = vol.read_dinode(inum)?;
+            let linked = refs.get(&inum).copied().unwrap_or(0);
+            if din.kind == Xv6Type::FREE {
+                continue;
+            }
+            if linked == 0 {
+                return Err(FsckErr::Unreachable(inum));
+            }
+            if din.nlink as u32 != linked {
+                return Err(FsckErr::LinkCount { inum, nlink: din.nlink as u32, linked });
+            }
+        }
+
+        for blockno in 0..sb.size {
+            let buf = block::bread(vol.dev, (sb.bmap_start as usize + blockno as usize / BPB) as u64)
+                .map_err(FsErr::from)?;
+            let bit = blockno as usize % BPB;
+            let marked = buf.data()[bit / 8] & (1 << (bit % 8)) != 0;
+            let used = blockno < sb.data_start() || owned.contains(&blockno);
+            if marked != used {
+                return Err(FsckErr::Bitmap(blockno));
+            }
+        }
+        stats.blocks = owned.len();
+        Ok(stats)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FsckStats {
+    pub inodes: usize,      // reachable from the root
+    pub dirs  : usize,
+    pub blocks: usize,      // data blocks in use
+}
+
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsckErr {
+    Fs(FsErr),
+    FreeInodeLinked(u32),               // a directory entry points at a free inode
+    BadBlock { inum: u32, block: u32 }, // outside the data area
+    DuplicateBlock(u32),                // in two files, or twice in one
+    BadDot(u32),                        // "." or ".." of a directory is wrong
+    Unreachable(u32),                   // in use but in no directory
+    LinkCount { inum: u32, nlink: u32, linked: u32 },
+    Bitmap(u32),                        // bitmap disagrees with the inodes
+}
+
+impl From<FsErr> for FsckErr {
+    fn from(err: FsErr) -> Self {
+        FsckErr::Fs(err)
+    }
+}
+
+impl FileSystem for Xv6Fs {
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+
+    /// Operations commit as they end; this only flushes the device.
+    fn sync(&self) -> Result<(), FsErr> {
+        Ok(block::sync(self.vol.dev)?)
+    }
+}
+
+impl Xv6Inode {
+    fn kind(&self) -> u16 {
+        self.data.lock().kind
+    }
+
+    fn dir_locked(&self) -> Result<SleepLockGuard<'_, DInode>, FsErr> {
+        let guard = self.data.lock();
+        if guard.kind != Xv6Type::DIR {
+            return Err(FsErr::NotDir);
+        }
+        Ok(guard)
+    }
+
+    /// Create `name` of `kind`, with `contents` for symlinks.
+    fn make(&self, name: &str, kind: u16, contents: &[u8]) -> Result<InodeRef, FsErr> {
+        check_name(name)?;
+        let vol = &self.vol;
+        let inum = vol.op(|| {
+            let mut ddin = self.dir_locked()?;
+            if ddin.nlink == 0 {
+                return Err(FsErr::NotFound);    // removed while we held it
+            }
+            let (inum, mut din) = vol.create_in(self, &mut ddin, name, kind)?;
+            if !contents.is_empty() {
+                vol.writei(inum, &mut din, 0, contents)?;
+            }
+            Ok(inum)
+        })?;
+        Ok(vol.iget(inum)?)
+    }
+}
+
+impl Drop for Xv6Inode {
+    fn drop(&mut self) {
+        let vol = self.vol.clone();
+        {
+            let mut guard = vol.inodes.lock();
+            let inodes = guard.get_mut();
+            if inodes.get(&self.inum).is_some_and(|inode| inode.strong_count() == 0) {
+                inodes.remove(&self.inum);
+            }
+        }
+        // the last reference to an unlinked inode: free it now; if this
+        // fails, the next mount does
+        let mut din = self.data.lock();
+        if din.kind != Xv6Type::FREE && din.nlink == 0 {
+            let _ = vol.op(|| vol.ifree(self.inum, &mut din));
+        }
+    }
+}
+
+impl Inode for Xv6Inode {
+    fn stat(&self) -> Stat {
+        let din = *self.data.lock();
+        let (kind, mode) = match din.kind {
+            Xv6Type::DIR     => (FileType::Directory, 0o755),
+            Xv6Type::SYMLINK => (FileType::Symlink, 0o777),
+            _                => (FileType::Regular, 0o644),
+        };
+        Stat {
+            ino  : self.inum as u64,
+            size : din.size as u64,
+            dev  : self.vol.stat_dev,
+            nlink: din.nlink as u32,
+            kind,
+            mode,
+        }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsErr> {
+        let mut din = self.data.lock();
+        match din.kind {
+            Xv6Type::FILE => self.vol.readi(&mut din, offset, buf),
+            Xv6Type::DIR => Err(FsErr::IsDir),
+            _ => Err(FsErr::InvalidPath),
+        }
+    }
+
+    /// Written in chunks of `MAX_WRITE` bytes, one operation each: a
+    /// crash may keep some of the chunks.
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, FsErr> {
+        match self.kind() {
+            Xv6Type::FILE => {}
+            Xv6Type::DIR => return Err(FsErr::IsDir),
+            _ => return Err(FsErr::InvalidPath),
+        }
+        let mut done = 0;
+        for chunk in buf.chunks(MAX_WRITE) {
+            let written = self.vol.op(|| {
+                let mut din = self.data.lock();
+                self.vol.writei(self.inum, &mut din, offset + done, chunk)
+            });
+            match written {
+                Ok(()) => done += chunk.len(),
+                Err(_) if done > 0 => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(done)
+    }
+
+    fn truncate(&self, size: usize) -> Result<(), FsErr> {
+        match self.kind() {
+            Xv6Type::FILE => {}
+            Xv6Type::DIR => return Err(FsErr::IsDir),
+            _ => return Err(FsErr::InvalidPath),
+        }
+        if size > MAX_FILE * BSIZE {
+            return Err(FsErr::NoSpace);
+        }
+        let vol = &self.vol;
+        vol.op(|| {
+            let mut din = self.data.lock();
+            if size < din.size as usize {
+                vol.itrunc(&mut din, size.div_ceil(BSIZE))?;
+                // zero the tail of the last block, growing later reads zeros
+                if !size.is_multiple_of(BSIZE) {
+                    if let Some(blockno) = vol.bmap(&mut din, size / BSIZE, false)? {
+                        let mut buf = block::bread(vol.dev, blockno)?;
+                        buf.data_mut()[size % BSIZE..].fill(0);
+                        vol.log.write(&buf)?;
+                    }
+                }
+            }
+            // growing leaves a hole
+            din.size = size as u32;
+            vol.iupdate(self.inum, &din)
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, FsErr> {
+        let inum = {
+            let mut ddin = self.dir_locked()?;
+            let found = self.vol.dir_lookup(&mut ddin, name)?;
+            found.ok_or(FsErr::NotFound)?.0
+        };
+        Ok(self.vol.iget(inum)?)
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<InodeRef, FsErr> {
+        match kind {
+            FileType::Regular   => self.make(name, Xv6Type::FILE, &[]),
+            FileType::Directory => self.make(name, Xv6Type::DIR, &[]),
+            _ => Err(FsErr::Unsupported),
+        }
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<InodeRef, FsErr> {
+        if target.is_empty() {
+            return Err(FsErr::InvalidPath);
+        }
+        if target.len() > super::PATH_MAX {
+            return Err(FsErr::NameTooLong);
+        }
+        self.make(name, Xv6Type::SYMLINK, target.as_bytes())
+    }
+
+    fn link(&self, name: &str, inode: &InodeRef) -> Result<(), FsErr> {
+        check_name(name)?;
+        let target = inode.as_any().downcast_ref::<Xv6Inode>()
+            .filter(|target| Arc::ptr_eq(&target.vol, &self.vol))
+            .ok_or(FsErr::Invalid)?;
+        let vol = &self.vol;
+        vol.op(|| {
+            let mut ddin = self.dir_locked()?;
+            let mut din = target.data.lock();
+            if din.kind == Xv6Type::DIR {
+                return Err(FsErr::IsDir);
+            }
+            if din.nlink == 0 {
+                return Err(FsErr::NotFound);
+            }
+            if vol.dir_lookup(&mut ddin, name)?.is_some() {
+                return Err(FsErr::Exists);
+            }
+            vol.dir_link(self.inum, &mut ddin, name, target.inum)?;
+            din.nlink += 1;
+            vol.iupdate(target.inum, &din)
+        })
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsErr> {
+        if name == "." || name == ".." {
+            return Err(FsErr::InvalidPath);
+        }
+        let vol = &self.vol;
+        // dropped after the operation ends: freeing it is one of its own
+        let mut victim = None;
+        let result = vol.op(|| {
+            let mut ddin = self.dir_locked()?;
+            let (inum, offset) = vol.dir_lookup(&mut ddin, name)?.ok_or(FsErr::NotFound)?;
+            let inode = vol.iget(inum)?;
+            let mut din = inode.data.lock();
+            if din.kind == Xv6Type::DIR {
+                let empty = vol.dir_entries(&mut din)?.iter().all(|(_, name, _)| name == "." || name == "..");
+                if !empty {
+                    return Err(FsErr::NotEmpty);
+                }
+                ddin.nlink -= 1;    // its ".."
+            }
+            vol.writei(self.inum, &mut ddin, offset, &[0u8; DIRENT_SIZE])?;
+            din.nlink = din.nlink.saturating_sub(1);
+            vol.iupdate(inum, &din)?;
+            drop(din);
+            victim = Some(inode);
+            Ok(())
+        });
+        drop(victim);
+        result
+    }
+
+    fn dirent(&self, index: usize) -> Result<Option<DirEntry>, FsErr> {
+        let entries = {
+            let mut ddin = self.dir_locked()?;
+            self.vol.dir_entries(&mut ddin)?
+        };
+        let Some((inum, name, _)) = entries.into_iter().filter(|(_, name, _)| name != "." && name != "..").nth(index) else {
+            return Ok(None);
+        };
+        let kind = match self.vol.read_dinode(inum)?.kind {
+            Xv6Type::DIR     => FileType::Directory,
+            Xv6Type::SYMLINK => FileType::Symlink,
+            _                => FileType::Regular,
+        };
+        Ok(Some(DirEntry { ino: inum as u64, kind, name }))
+    }
+
+    fn read_link(&self) -> Result<String, FsErr> {
+        let mut din = self.data.lock();
+        if din.kind != Xv6Type::SYMLINK {
+            return Err(FsErr::InvalidPath);
+        }
+        let mut target = alloc::vec![0u8; din.size as usize];
+        self.vol.readi(&mut din, 0, &mut target)?;
+        String::from_utf8(target).map_err(|_| FsErr::Corrupt)
+    }
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+}
@@ -1,21 +1,30 @@
+#[cfg(target_arch = "riscv64")]
 use core::arch::naked_asm;
 
-#[naked]
+#[cfg(target_arch = "riscv64")]
+#[unsafe(naked)]
 #[link_section=".init"]
 #[export_name ="_entry_2"]
 pub unsafe extern "C" fn _entry()
 {
     naked_asm!(
         "
+        # a0 (hartid) and a1 (DTB) are passed on to sys_init
+        csrr t1, mhartid
+        li t2, {ncpu}
+        bgeu t1, t2, 2f              # no stack or `Cpu` for this hart
         la sp, stack
-        li a0, 1024*1024*4           # 4MB stack
-        csrr a1, mhartid
-        addi a1, a1, 1
-        mul a0, a0, a1
-        add sp, sp, a0
+        li t0, 1024*1024*4           # 4MB stack
+        addi t1, t1, 1
+        mul t0, t0, t1
+        add sp, sp, t0
         call sys_init
         1:
             j 1b
-        "
+        2:
+            wfi
+            j 2b
+        ",
+        ncpu = const crate::proc::NCPU,
     );
-}
\ No newline at end of file
+}
@@ -0,0 +1,155 @@
+//! Initial RAM filesystem.
+//!
+//! The root tree comes from a cpio newc archive: the one QEMU loads with
+//! `-initrd` (found through `/chosen` in the DTB) or, without one, the
+//! archive `build.rs` packs from the user programs and `rootfs/`.
+//! The archive stays where it is; the filesystem is a read-only table of
+//! nodes pointing into it.
+
+use alloc::vec::Vec;
+
+use crate::cpio::{self, CpioErr};
+use crate::fdt::{self, Fdt};
+
+/// Archive built into the kernel image.
+static EMBEDDED: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initramfs.cpio"));
+
+/// Physical `[start, end)` of the archive loaded with `-initrd`, if any.
+pub static mut INITRD: (usize, usize) = (0, 0);
+
+#[derive(Debug, Clone, Copy)]
+pub struct RamNode {
+    /// Full path without the leading `/`. The root is `""`.
+    pub path : &'static str,
+    pub ino  : u32,
+    pub mode : u32,
+    pub nlink: u32,
+    pub devmajor: u32,
+    pub devminor: u32,
+    pub data : &'static [u8],
+}
+
+impl RamNode {
+    /// Last path component.
+    pub fn name(&self) -> &'static str {
+        self.path.rsplit('/').next().unwrap_or(self.path)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & cpio::S_IFMT == cpio::S_IFDIR
+    }
+
+    /// Whether `other` is another name for the same file.
+    fn same_file(&self, other: &RamNode) -> bool {
+        self.nlink > 1 && other.nlink > 1
+            && (self.devmajor, self.devminor, self.ino) == (other.devmajor, other.devminor, other.ino)
+    }
+}
+
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum InitramfsErr {
+    Cpio(CpioErr),
+    NoMemory,
+}
+
+impl From<CpioErr> for InitramfsErr {
+    fn from(err: CpioErr) -> Self {
+        InitramfsErr::Cpio(err)
+    }
+}
+
+/// Set once by `initramfs_init`, read-only after.
+static mut NODES: Vec<RamNode> = Vec::new();
+
+/// Every node, in archive order (parents before their entries).
+pub fn nodes() -> &'static [RamNode] {
+    unsafe { &*core::ptr::addr_of!(NODES) }
+}
+
+/// Look for an `-initrd` archive in the DTB and remember where it is,
+/// so the page allocator can keep its hands off it. Runs before
+/// `kern_vm_init`.
+pub fn initramfs_probe(dtb: usize) {
+    let Ok(fdt) = (unsafe { Fdt::from_addr(dtb) }) else {
+        return;
+    };
+    let start = fdt.prop("/chosen", "linux,initrd-start").and_then(fdt::prop_usize);
+    let end   = fdt.prop("/chosen", "linux,initrd-end").and_then(fdt::prop_usize);
+    if let (Some(start), Some(end)) = (start, end) {
+        if start < end {
+            unsafe { INITRD = (start, end) };
+        }
+    }
+}
+
+/// The archive the root tree is built from.
+fn archive() -> &'static [u8] {
+    let (start, end) = unsafe { INITRD };
+    if start != 0 {
+        unsafe { core::slice::from_raw_parts(start as *const u8, end - start) }
+    } else {
+        EMBEDDED
+    }
+}
+
+/// Index the archive. Returns the number of nodes. Needs the heap.
+pub fn initramfs_init() -> Result<usize, InitramfsErr> {
+    let nodes = index(archive())?;
+    let count = nodes.len();
+    unsafe { *core::ptr::addr_of_mut!(NODES) = nodes };
+    Ok(count)
+}
+
+/// The nodes of `archive`, in archive order.
+pub fn index(archive: &'static [u8]) -> Result<Vec<RamNode>, InitramfsErr> {
+    let mut nodes = Vec::new();
+    for entry in cpio::entries(archive) {
+        let entry = entry?;
+        nodes.try_reserve(1).map_err(|_| InitramfsErr::NoMemory)?;
+        nodes.push(RamNode {
+            path : entry.name,
+            ino  : entry.ino,
+            mode : entry.mode,
+            nlink: entry.nlink,
+            devmajor: entry.devmajor,
+            devminor: entry.devminor,
+            data : entry.data,
+        });
+    }
+
+    // hard links: newc stores the data only with the last link. Only
+    // entries that say they have other links are joined; archivers
+    // reuse inode numbers for unrelated files (often all zero).
+    for idx in 0..nodes.len() {
+        let node = nodes[idx];
+        if node.data.is_empty() && !node.is_dir() && node.nlink > 1 {
+            let data = nodes.iter()
+                .find(|other| node.same_file(other) && !other.data.is_empty())
+                .map(|other| other.data);
+            if let Some(data) = data {
+                nodes[idx].data = data;
+            }
+        }
+    }
+
+    Ok(nodes)
+}
+
+/// Find the node at `path` (absolute or relative to the root).
+pub fn lookup(path: &str) -> Option<&'static RamNode> {
+    let path = cpio::normalize(path);
+    nodes().iter().find(|node| node.path == path)
+}
+
+/// Nodes directly inside the directory `path`.
+pub fn read_dir(path: &str) -> impl Iterator<Item = &'static RamNode> + '_ {
+    let dir = cpio::normalize(path);
+    nodes().iter().filter(move |node| {
+        if node.path.is_empty() {
+            return false;
+        }
+        let parent = node.path.rsplit_once('/').map_or("", |(parent, _)| parent);
+        parent == dir
+    })
+}
@@ -1,106 +1,342 @@
 use crate::{plic_sclaim_r, plic_sclaim_w};
-use crate::riscv::{RegSCause, RegSEPC, Register}; 
-use crate::uart::{uart_isr, uart_puts, UART0_IRQ};
-use crate::kprintln;
+use crate::uart::{uart_irq, uart_isr, uart_puts};
+use crate::{sched, timer, virtio};
+#[cfg(target_arch = "riscv64")]
+use crate::riscv::{self, RegSATP, RegSEPC, RegSStatus, RegSTVec, RegTP, Register};
+#[cfg(target_arch = "riscv64")]
+use crate::{kprintln, proc, syscall, trampoline};
 
-#[naked]
+/// Size of the `TrapFrame` as laid out by the trap entry code.
+/// Kept as a multiple of 16 so that `sp` stays aligned while the
+/// frame lives on the stack.
+pub const TRAP_FRAME_SIZE: usize = 320;
+
+/// The interrupted context.
+///
+/// Holds every general purpose register (`x1`..`x31`) followed by
+/// the supervisor trap CSRs. The field order is part of the ABI of
+/// `kern_trap` and of the trampoline, which address the fields by offset.
+///
+/// The `kernel_*` fields are only used on the user trap path: they are
+/// filled in by `usr_trap_ret` and read back by `uservec` to get onto
+/// the kernel stack and page table.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TrapFrame {
+    pub ra           : usize,  //   0
+    pub sp           : usize,  //   8
+    pub gp           : usize,  //  16
+    pub tp           : usize,  //  24
+    pub t0           : usize,  //  32
+    pub t1           : usize,  //  40
+    pub t2           : usize,  //  48
+    pub s0           : usize,  //  56
+    pub s1           : usize,  //  64
+    pub a0           : usize,  //  72
+    pub a1           : usize,  //  80
+    pub a2           : usize,  //  88
+    pub a3           : usize,  //  96
+    pub a4           : usize,  // 104
+    pub a5           : usize,  // 112
+    pub a6           : usize,  // 120
+    pub a7           : usize,  // 128
+    pub s2           : usize,  // 136
+    pub s3           : usize,  // 144
+    pub s4           : usize,  // 152
+    pub s5           : usize,  // 160
+    pub s6           : usize,  // 168
+    pub s7           : usize,  // 176
+    pub s8           : usize,  // 184
+    pub s9           : usize,  // 192
+    pub s10          : usize,  // 200
+    pub s11          : usize,  // 208
+    pub t3           : usize,  // 216
+    pub t4           : usize,  // 224
+    pub t5           : usize,  // 232
+    pub t6           : usize,  // 240
+    pub sepc         : usize,  // 248
+    pub sstatus      : usize,  // 256
+    pub scause       : usize,  // 264
+    pub stval        : usize,  // 272
+    pub kernel_satp  : usize,  // 280
+    pub kernel_sp    : usize,  // 288
+    pub kernel_trap  : usize,  // 296
+    pub kernel_hartid: usize,  // 304
+    _pad             : usize,  // 312
+}
+
+const _: () = assert!(core::mem::size_of::<TrapFrame>() == TRAP_FRAME_SIZE);
+const _: () = assert!(core::mem::offset_of!(TrapFrame, sepc) == 248);
+const _: () = assert!(core::mem::offset_of!(TrapFrame, kernel_satp) == 280);
+
+impl TrapFrame {
+    pub const fn new() -> Self {
+        Self {
+            ra: 0, sp: 0, gp: 0, tp: 0,
+            t0: 0, t1: 0, t2: 0,
+            s0: 0, s1: 0,
+            a0: 0, a1: 0, a2: 0, a3: 0, a4: 0, a5: 0, a6: 0, a7: 0,
+            s2: 0, s3: 0, s4: 0, s5: 0, s6: 0, s7: 0, s8: 0, s9: 0, s10: 0, s11: 0,
+            t3: 0, t4: 0, t5: 0, t6: 0,
+            sepc: 0, sstatus: 0, scause: 0, stval: 0,
+            kernel_satp: 0, kernel_sp: 0, kernel_trap: 0, kernel_hartid: 0,
+            _pad: 0,
+        }
+    }
+
+    #[inline]
+    pub fn is_intr(&self) -> bool {
+        self.scause >> 63 != 0
+    }
+
+    #[inline]
+    pub fn code(&self) -> usize {
+        self.scause & 0xffff
+    }
+}
+
+/// Supervisor trap vector.
+///
+/// Spills the complete register state into a `TrapFrame` on the
+/// current stack and hands a pointer to it to `ktrap_isr`. On return
+/// the (possibly modified) frame is written back, including `sepc`
+/// and `sstatus`, before `sret`.
+///
+/// # Safety
+/// Only ever entered by the hardware through `stvec`, never called.
+#[cfg(target_arch = "riscv64")]
+#[unsafe(naked)]
 #[export_name = "kern_trap"]
 pub unsafe extern "C" fn kern_trap()
 {
-    unsafe {
-        core::arch::naked_asm!(
-            "
-            addi sp, sp, -256
-            sd ra, 0(sp)
-            sd sp, 8(sp)
-            sd gp, 16(sp)
-            sd tp, 24(sp)
-            sd t0, 32(sp)
-            sd t1, 40(sp)
-            sd t2, 48(sp)
-            sd a0, 72(sp)
-            sd a1, 80(sp)
-            sd a2, 88(sp)
-            sd a3, 96(sp)
-            sd a4, 104(sp)
-            sd a5, 112(sp)
-            sd a6, 120(sp)
-            sd a7, 128(sp)
-            sd t3, 216(sp)
-            sd t4, 224(sp)
-            sd t5, 232(sp)
-            sd t6, 240(sp)
-
-            call ktrap_isr
-
-            ld ra, 0(sp)
-            ld sp, 8(sp)
-            ld gp, 16(sp)
-            ld t0, 32(sp)
-            ld t1, 40(sp)
-            ld t2, 48(sp)
-            ld a0, 72(sp)
-            ld a1, 80(sp)
-            ld a2, 88(sp)
-            ld a3, 96(sp)
-            ld a4, 104(sp)
-            ld a5, 112(sp)
-            ld a6, 120(sp)
-            ld a7, 128(sp)
-            ld t3, 216(sp)
-            ld t4, 224(sp)
-            ld t5, 232(sp)
-            ld t6, 240(sp)
-            addi sp, sp, 256
-            sret
-            "
-        );
-    };
+    core::arch::naked_asm!(
+        "
+        addi sp, sp, -320
+        sd ra, 0(sp)
+        sd gp, 16(sp)
+        sd tp, 24(sp)
+        sd t0, 32(sp)
+        addi t0, sp, 320
+        sd t0, 8(sp)
+        sd t1, 40(sp)
+        sd t2, 48(sp)
+        sd s0, 56(sp)
+        sd s1, 64(sp)
+        sd a0, 72(sp)
+        sd a1, 80(sp)
+        sd a2, 88(sp)
+        sd a3, 96(sp)
+        sd a4, 104(sp)
+        sd a5, 112(sp)
+        sd a6, 120(sp)
+        sd a7, 128(sp)
+        sd s2, 136(sp)
+        sd s3, 144(sp)
+        sd s4, 152(sp)
+        sd s5, 160(sp)
+        sd s6, 168(sp)
+        sd s7, 176(sp)
+        sd s8, 184(sp)
+        sd s9, 192(sp)
+        sd s10, 200(sp)
+        sd s11, 208(sp)
+        sd t3, 216(sp)
+        sd t4, 224(sp)
+        sd t5, 232(sp)
+        sd t6, 240(sp)
+
+        csrr t0, sepc
+        sd t0, 248(sp)
+        csrr t0, sstatus
+        sd t0, 256(sp)
+        csrr t0, scause
+        sd t0, 264(sp)
+        csrr t0, stval
+        sd t0, 272(sp)
+
+        mv a0, sp
+        call ktrap_isr
+
+        ld t0, 248(sp)
+        csrw sepc, t0
+        ld t0, 256(sp)
+        csrw sstatus, t0
+
+        ld ra, 0(sp)
+        ld gp, 16(sp)
+        ld tp, 24(sp)
+        ld t0, 32(sp)
+        ld t1, 40(sp)
+        ld t2, 48(sp)
+        ld s0, 56(sp)
+        ld s1, 64(sp)
+        ld a0, 72(sp)
+        ld a1, 80(sp)
+        ld a2, 88(sp)
+        ld a3, 96(sp)
+        ld a4, 104(sp)
+        ld a5, 112(sp)
+        ld a6, 120(sp)
+        ld a7, 128(sp)
+        ld s2, 136(sp)
+        ld s3, 144(sp)
+        ld s4, 152(sp)
+        ld s5, 160(sp)
+        ld s6, 168(sp)
+        ld s7, 176(sp)
+        ld s8, 184(sp)
+        ld s9, 192(sp)
+        ld s10, 200(sp)
+        ld s11, 208(sp)
+        ld t3, 216(sp)
+        ld t4, 224(sp)
+        ld t5, 232(sp)
+        ld t6, 240(sp)
+        ld sp, 8(sp)
+        sret
+        "
+    );
+}
+
+/// Handle a device (or timer/software) interrupt.
+/// Returns `true` for a timer tick.
+fn dev_intr(code: usize) -> bool
+{
+    #[allow(unused_variables)]
+    match code {
+        1 => uart_puts("--Software Intr\n"),
+        5 => {
+            timer::timer_intr();
+            return true;
+        },
+        9 => {
+            let intr_id = plic_sclaim_r!(0);
+            match intr_id {
+                0 => {},    // claimed by another hart
+                id if id == uart_irq() => uart_isr(),
+                id if virtio::is_virtio_irq(id) => virtio::virtio_intr(id),
+                _ => uart_puts("--Unknown PLIC Intr\n"),
+            }
+            if intr_id != 0 {
+                plic_sclaim_w!(0, intr_id);
+            }
+        },
+        _ => uart_puts("--Unkwown Intr\n"),
+    }
+    false
 }
 
 #[export_name = "ktrap_isr"]
-pub extern "C" fn ktrap_isr()
+pub extern "C" fn ktrap_isr(tf: &mut TrapFrame)
 {
-    let sepc    = RegSEPC::read();
-    let cause   = RegSCause::read();
-    let is_intr  = cause >> 63 != 0;
-    let code    = cause & 0xffff;
-    let intr_id   = plic_sclaim_r!(0);
+    let code = tf.code();
 
-    #[allow(unused_variables)]
-    if is_intr {
-        match code {
-            1 => uart_puts("--Software Intr\n"),
-            5 => uart_puts("--Timer Intr\n"),
-            9 => {
-                let uart_intr = UART0_IRQ as u32;
-                match intr_id {
-                    uart_intr => uart_isr(),
-                }
-            },
-            _ => uart_puts("--Unkwown Intr\n"),
+    if tf.is_intr() {
+        // preempt kernel code running on behalf of a process too,
+        // `tf` keeps the interrupted `sepc`/`sstatus` across the switch
+        if dev_intr(code) {
+            sched::tick();
         }
     }else {
-        uart_puts("An Exception Occured\n");
+        // nothing in the kernel is expected to fault, and returning would
+        // only retry the faulting instruction
+        panic!("ktrap_isr: {} (scause: {:#x}, sepc: {:#x}, stval: {:#x})\n{:#x?}",
+               exception_name(code), tf.scause, tf.sepc, tf.stval, tf);
+    }
+}
+
+/// What exception `code` (`scause` without the interrupt bit) is.
+fn exception_name(code: usize) -> &'static str
+{
+    match code {
+        0  => "instruction address misaligned",
+        1  => "instruction access fault",
+        2  => "illegal instruction",
+        3  => "breakpoint",
+        4  => "load address misaligned",
+        5  => "load access fault",
+        6  => "store/AMO address misaligned",
+        7  => "store/AMO access fault",
+        8  => "environment call from U-mode",
+        9  => "environment call from S-mode",
+        12 => "instruction page fault",
+        13 => "load page fault",
+        15 => "store/AMO page fault",
+        _  => "unknown exception",
+    }
+}
+
+/// Entered from `uservec` on the kernel stack, with the kernel page
+/// table installed and the user registers saved in the trap frame.
+#[cfg(target_arch = "riscv64")]
+#[export_name = "usr_trap"]
+pub extern "C" fn usr_trap() -> !
+{
+    if (RegSStatus::read() & RegSStatus::SSTATUS_SPP) != 0 {
+        panic!("usr_trap: not from user mode");
+    }
+
+    // traps taken while in the kernel go to `kern_trap`
+    RegSTVec::write(kern_trap as *const () as usize);
+
+    let proc = proc::myproc().expect("usr_trap: no process");
+    let tf = proc.trap_frame();
+    let code = tf.code();
+
+    let mut is_tick = false;
+    if tf.is_intr() {
+        is_tick = dev_intr(code);
+    }else {
         match code {
-            0 => uart_puts("Instruction address misaligned"),
-            1 => uart_puts("Instruction access fault"),
-            2 => uart_puts("Illegal instruction"),
-            4..=7 => {
-                uart_puts("Illegal Memory Access");
-                loop {
-                    1;
-                }
+            8 => {
+                // ecall: resume after the instruction
+                tf.sepc += 4;
+                riscv::intr_on();
+                syscall::syscall(tf);
             },
             _ => {
-                uart_puts("Unknown/unhandled exception: ");
-                kprintln!("code: {}", code);
-                loop {
-                    1;
-                }
+                kprintln!("User exception, code: {}, sepc: {:#x}, stval: {:#x}",
+                            code, tf.sepc, tf.stval);
+                proc.killed = true;
             },
         }
     }
-    plic_sclaim_w!(0, intr_id);
-    RegSEPC::write(sepc);
-}
\ No newline at end of file
+
+    if is_tick {
+        sched::tick();
+    }
+    if proc.killed {
+        proc::proc_exit(-1);
+    }
+
+    usr_trap_ret()
+}
+
+/// Return to user space through the trampoline.
+#[cfg(target_arch = "riscv64")]
+pub fn usr_trap_ret() -> !
+{
+    // no traps until we are back in user space, `stvec` is about to
+    // point at the user vector.
+    riscv::intr_off();
+    RegSTVec::write(trampoline::uservec_va());
+
+    let proc = proc::myproc().expect("usr_trap_ret: no process");
+    let kstack_top = proc.kstack_top();
+    let tf = proc.trap_frame();
+    tf.kernel_satp   = RegSATP::read();
+    tf.kernel_sp     = kstack_top;
+    tf.kernel_trap   = usr_trap as *const () as usize;
+    tf.kernel_hartid = RegTP::read();
+
+    let mut sstatus = RegSStatus::read();
+    sstatus &= !RegSStatus::SSTATUS_SPP;  // U-mode
+    sstatus |= RegSStatus::SSTATUS_SPIE;  // interrupts on in U-mode
+    RegSStatus::write(sstatus);
+    RegSEPC::write(tf.sepc);
+
+    let satp = RegSATP::sv39(proc.page_table as u64) as usize;
+    let userret: extern "C" fn(usize) -> ! = 
+            unsafe { core::mem::transmute(trampoline::userret_va()) };
+    userret(satp)
+}
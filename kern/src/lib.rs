@@ -1,14 +1,30 @@
 #![no_std]
 #![feature(naked_functions)]
 
+extern crate alloc;
+
 pub mod uart;
 pub mod riscv;
 pub mod plic;
 pub mod init;
 pub mod ktrap;
+pub mod trampoline;
 #[macro_use]
 pub mod console;
 pub mod sync;
 pub mod virtm;
 pub mod usr;
-pub mod mem;
\ No newline at end of file
+pub mod exec;
+pub mod syscall;
+pub mod sysfile;
+pub mod fs;
+pub mod proc;
+pub mod sched;
+pub mod timer;
+pub mod fdt;
+pub mod platform;
+pub mod cpio;
+pub mod initramfs;
+pub mod mem;
+pub mod virtio;
+pub mod block;
\ No newline at end of file
@@ -2,47 +2,70 @@
 #![no_main]
 #![feature(naked_functions)]
 #![feature(custom_test_frameworks)]
+#![feature(alloc_error_handler)]
 
 extern crate alloc;
-use alloc::string::String;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use kernel::*;
 use crate::riscv::Register; 
 use crate::virtm;
 use crate::usr;
 
-const NCPU: u32         = 2;
-const CSTACKSIZE: usize = (NCPU * (1024 * 1024 * 4)) as usize; // cpu stack size
+const CSTACKSIZE: usize = proc::NCPU * (1024 * 1024 * 4); // cpu stack size
 
 #[allow(non_upper_case_globals)]
 #[no_mangle]
 static mut stack: [u8; CSTACKSIZE] = [0; CSTACKSIZE];
 
 
+static KERN_STARTED: AtomicBool = AtomicBool::new(false);
+
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn kern_exec() -> ! {
     let cpu_first = riscv::RegTP::read() == 0;
     if cpu_first {
         let kern_end = virtm::get_data_end();
 
-        let heap_str = String::from("Heap Alloc String");
-
         kprintln!("System Initialised.");
         kprintln!("Kern End: {:#x}, VA Max: {:#x}", kern_end, virtm::MEM_MAX);
-        // usr::usr_load_and_exec();
+        let machine = platform::platform();
+        for range in machine.ram() {
+            kprintln!("RAM: {:#x}..{:#x} ({} MiB)", range.start, range.end(), range.size >> 20);
+        }
+        kprintln!("CPUs: {} (running on {}), timebase: {} Hz", machine.cpus,
+                  machine.cpus.min(proc::NCPU), machine.timebase);
+        mem::mem_init();
+        kprintln!("Free frames: {} ({} MiB)", mem::frame::free_frames(),
+                  mem::frame::free_frames() * virtm::PAGE_SIZE >> 20);
+        match initramfs::initramfs_init() {
+            Ok(count) => kprintln!("Initramfs: {} entries", count),
+            Err(err) => kprintln!("Initramfs: {:?}", err),
+        }
+        virtio::virtio_init();
+        fs::fs_init();
+        proc::proc_init();
+        usr::usr_spawn("/init");
+        KERN_STARTED.store(true, Ordering::SeqCst);
+    }else {
+        while !KERN_STARTED.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+        // pick up the kernel stacks mapped by the first cpu
+        riscv::sfence_vma();
     }
 
-    loop {
-        1;
-    }
+    sched::scheduler()
+}
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: core::alloc::Layout) -> ! {
+    mem::oom::out_of_memory(layout)
 }
 
 #[panic_handler]
 fn panic_handler(info: &core::panic::PanicInfo) -> !{
-    let message =  info.message();
-    if let Some(message) = message.as_str() {
-        kprintln!("{}", message);
-    }
+    kprintln!("{}", info.message());
     let loc = info.location();
     if let Some(loc) = loc {
         kprint!("file: {}, line: {}", loc.file(), loc.line())
@@ -1,10 +1,30 @@
 use core::alloc::Layout;
 use core::ptr::NonNull;
 use core::sync::atomic::{AtomicUsize, Ordering};
-use super::{Allocatable, AllocatableConfig, AllocatableErr};
+use super::{Allocatable, AllocatableConfig, AllocatableErr, HeapInfo};
 
 pub struct  PageTable;
 
+/// Filled into freed blocks (`heap-debug`).
+pub const POISON_BYTE : u8    = 0x6b;
+/// Filled into the redzones around allocations (`heap-debug`).
+pub const REDZONE_BYTE: u8    = 0xbb;
+/// Least redzone on either side of an allocation (`heap-debug`).
+pub const REDZONE     : usize = 16;
+
+/// A broken invariant found by `BuddyAllocator::check`.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuddyErr {
+    OutOfRange      { addr: usize, order: usize },  // free block outside the heap
+    Misaligned      { addr: usize, order: usize },
+    MarkedAllocated { addr: usize, order: usize },  // free block with allocated bits set
+    Overlap         { addr: usize, order: usize },  // free block inside another one
+    Uncoalesced     { addr: usize, order: usize },  // free block with a free buddy
+    SplitBit        { addr: usize, order: usize },  // split bits disagree with a free block
+    LostMemory      { blocks: usize },              // clear bits no free block covers
+}
+
 
 #[repr(C)]
 struct FreeBlock {
@@ -15,8 +35,8 @@ pub struct BuddyAllocator {
     base            : usize,
     size            : usize,
     free_lists      : [Option<NonNull<FreeBlock>>; BuddyAllocator::NUM_ORDERS],
-    split_bitmap    : [u8; BuddyAllocator::BITMAP_SIZE],
-    allocated_bitmap: [u8; BuddyAllocator::BITMAP_SIZE],
+    split_bitmap    : &'static mut [u8],
+    allocated_bitmap: &'static mut [u8],
     stats           : AllocatorStats,
 }
 
@@ -24,6 +44,8 @@ struct AllocatorStats {
     allocated_bytes     : AtomicUsize,
     total_allocations   : AtomicUsize,
     total_deallocations : AtomicUsize,
+    peak_bytes          : AtomicUsize,
+    failed_allocations  : AtomicUsize,
 }
 
 impl BuddyAllocator {
@@ -31,8 +53,7 @@ impl BuddyAllocator {
     pub const MIN_ORDER     : usize = 5;                // 32 bytes min block size
     pub const NUM_ORDERS    : usize = BuddyAllocator::MAX_ORDER - BuddyAllocator::MIN_ORDER + 1;
     pub const MIN_BLOCK_SIZE: usize = 1 << BuddyAllocator::MIN_ORDER;
-    pub const MAX_MEMORY    : usize = 1024 * 1024 * 150; // 150MB maximum managed memory
-    pub const BITMAP_SIZE   : usize = (BuddyAllocator::MAX_MEMORY / BuddyAllocator::MIN_BLOCK_SIZE + 7) / 8;
+    pub const MAX_BLOCK_SIZE: usize = 1 << BuddyAllocator::MAX_ORDER;
 
     fn init(&mut self) {
         let mut remaining_size = self.size;
@@ -45,6 +66,11 @@ impl BuddyAllocator {
                 let num_min_blocks = block_size / BuddyAllocator::MIN_BLOCK_SIZE;
                 let start_index    = self.block_to_index(current_addr);
                 self.set_allocated_range(start_index, num_min_blocks, false);
+                // the tail of the heap hangs off split blocks reaching past the end
+                for parent in order + 1..=BuddyAllocator::MAX_ORDER {
+                    let parent_addr = self.base + ((current_addr - self.base) & !((1 << parent) - 1));
+                    self.set_split_bit(self.split_index(parent_addr, parent), true);
+                }
                 
                 unsafe {
                     let block_ptr     = current_addr as *mut FreeBlock;
@@ -62,10 +88,21 @@ impl BuddyAllocator {
         (addr - self.base) / BuddyAllocator::MIN_BLOCK_SIZE
     }
 
+    /// Bit of the block of `order` at `addr` in `split_bitmap`, set while
+    /// the block is split in two. Each `MAX_BLOCK_SIZE` block owns the
+    /// bits of its tree of blocks, numbered top down as in a binary heap.
+    fn split_index(&self, addr: usize, order: usize) -> usize {
+        let leaves = BuddyAllocator::MAX_BLOCK_SIZE / BuddyAllocator::MIN_BLOCK_SIZE;
+        let index  = self.block_to_index(addr);
+        let depth  = BuddyAllocator::MAX_ORDER - order;
+        let pos    = (index % leaves) >> (order - BuddyAllocator::MIN_ORDER);
+        (index / leaves) * leaves + (1 << depth) - 1 + pos
+    }
+
     fn set_allocated_range(&mut self, start_index: usize, num_blocks: usize, value: bool) {
         for index in start_index..start_index + num_blocks {
             // self.set_bit(&mut self.allocated_bitmap, index, value);
-            if index >= BuddyAllocator::MAX_MEMORY / BuddyAllocator::MIN_BLOCK_SIZE {
+            if index >= self.allocated_bitmap.len() * 8 {
                 return;
             }
             let byte_index = index / 8;
@@ -80,7 +117,7 @@ impl BuddyAllocator {
 
     fn set_split_bit(&mut self, index: usize, value: bool) {
         // self.set_bit(&mut self.split_bitmap, index, value);
-        if index >= BuddyAllocator::MAX_MEMORY / BuddyAllocator::MIN_BLOCK_SIZE {
+        if index >= self.split_bitmap.len() * 8 {
             return;
         }
         let byte_index = index / 8;
@@ -106,7 +143,7 @@ impl BuddyAllocator {
     }
 
     fn set_bit(&mut self, bitmap: &mut [u8], index: usize, value: bool) {
-        if index >= BuddyAllocator::MAX_MEMORY / BuddyAllocator::MIN_BLOCK_SIZE {
+        if index >= bitmap.len() * 8 {
             return;
         }
         let byte_index = index / 8;
@@ -119,7 +156,7 @@ impl BuddyAllocator {
     }
 
     fn get_bit(&self, bitmap: &[u8], index: usize) -> bool {
-        if index >= BuddyAllocator::MAX_MEMORY / BuddyAllocator::MIN_BLOCK_SIZE {
+        if index >= bitmap.len() * 8 {
             return false;
         }
         let byte_index = index / 8;
@@ -147,20 +184,17 @@ impl BuddyAllocator {
         let block_size     = 1 << order;
         let num_min_blocks = block_size / BuddyAllocator::MIN_BLOCK_SIZE;
 
-        if let Some(block)  = self.free_lists[index].take() {
+        if let Some(block)  = self.free_lists[index] {
+            self.free_lists[index] = unsafe { block.as_ref().next };
             let block_addr  = block.as_ptr() as usize;
             let start_index = self.block_to_index(block_addr);
             self.set_allocated_range(start_index, num_min_blocks, true);
-            
-            self.stats.allocated_bytes.fetch_add(block_size, Ordering::Relaxed);
-            self.stats.total_allocations.fetch_add(1, Ordering::Relaxed);
             return Some(unsafe { NonNull::new_unchecked(block.as_ptr() as *mut u8) });
         }
 
         if let Some(larger_block) = self.allocate_order(order + 1) {
             let larger_block_addr = larger_block.as_ptr() as usize;
-            let parent_index      = self.block_to_index(larger_block_addr) / 2;
-            self.set_split_bit(parent_index, true);
+            self.set_split_bit(self.split_index(larger_block_addr, order + 1), true);
 
             let buddy_addr  = larger_block_addr + (1 << order);
             let buddy_index = self.block_to_index(buddy_addr);
@@ -192,11 +226,7 @@ impl BuddyAllocator {
 
         self.set_allocated_range(start_index, num_min_blocks, false);
 
-        self.stats.allocated_bytes.fetch_sub(block_size, Ordering::Relaxed);
-        self.stats.total_deallocations.fetch_add(1, Ordering::Relaxed);
-
         if order < BuddyAllocator::MAX_ORDER {
-            let parent_index = start_index / (2 * num_min_blocks);
             let buddy_addr = if (start_index / num_min_blocks) % 2 == 0 {
                 block_addr + block_size
             } else {
@@ -207,8 +237,8 @@ impl BuddyAllocator {
             
             if self.is_range_free(buddy_start_index, num_min_blocks) {
                 self.remove_from_free_list(buddy_addr, index);
-                self.set_split_bit(parent_index, false);
                 let merged_addr = block_addr.min(buddy_addr);
+                self.set_split_bit(self.split_index(merged_addr, order + 1), false);
                 let merged_ptr  = unsafe { NonNull::new_unchecked(merged_addr as *mut u8) };
                 self.deallocate_order(merged_ptr, order + 1);
                 return;
@@ -240,58 +270,297 @@ impl BuddyAllocator {
         }
     }
 
-    pub fn stats(&self) -> (usize, usize, usize) {
-        (
-            self.stats.allocated_bytes.load(Ordering::Relaxed),
-            self.stats.total_allocations.load(Ordering::Relaxed),
-            self.stats.total_deallocations.load(Ordering::Relaxed),
-        )
+    /// Order of the block serving `layout`. Blocks are aligned to their
+    /// size, so an alignment above the size is met by taking a block as
+    /// large as the alignment. With `heap-debug` the block also holds the
+    /// redzones.
+    fn layout_order(&self, layout: &Layout) -> Option<usize> {
+        #[cfg(not(feature = "heap-debug"))]
+        return self.size_to_order(layout.size().max(layout.align()));
+        #[cfg(feature = "heap-debug")]
+        return self.size_to_order(BuddyAllocator::front_redzone(layout) + layout.size() + REDZONE);
+    }
+
+    /// Size of the block `allocate` takes for `layout`.
+    pub fn block_size(&self, layout: &Layout) -> Option<usize> {
+        self.layout_order(layout).map(|order| 1 << order)
+    }
+
+    /// Number of free blocks of `order`.
+    pub fn free_blocks(&self, order: usize) -> usize {
+        let mut count   = 0;
+        let mut current = self.free_lists[order - BuddyAllocator::MIN_ORDER];
+        while let Some(node) = current {
+            count  += 1;
+            current = unsafe { node.as_ref().next };
+        }
+        count
+    }
+
+    /// Take over `region` for the heap, bitmaps included.
+    ///
+    /// # Safety
+    /// `region` must outlive the allocator and not be used otherwise.
+    pub unsafe fn from_region(region: &mut [u8]) -> Result<Self, AllocatableErr> {
+        Self::new(AllocatableConfig::new(region.as_mut_ptr() as usize, region.len()))
+    }
+
+    /// Check the free lists against the bitmaps: every free block lies
+    /// in the heap, is aligned, is clear in `allocated_bitmap`, overlaps
+    /// no other, has no free buddy and sits under a split parent; and
+    /// every clear bit of `allocated_bitmap` belongs to a free block.
+    ///
+    /// The free blocks are marked in a copy of `allocated_bitmap` in
+    /// `scratch`, at least `scratch_len()` bytes, so the heap is left as it
+    /// was and a caller that cannot allocate can still check it.
+    pub fn check(&self, scratch: &mut [u8]) -> Result<(), BuddyErr> {
+        let end = self.base + self.size;
+        let max_blocks = self.size / BuddyAllocator::MIN_BLOCK_SIZE;
+        let mut listed = 0;
+
+        for order in BuddyAllocator::MIN_ORDER..=BuddyAllocator::MAX_ORDER {
+            let block_size     = 1 << order;
+            let num_min_blocks = block_size / BuddyAllocator::MIN_BLOCK_SIZE;
+            let mut current    = self.free_lists[order - BuddyAllocator::MIN_ORDER];
+            while let Some(node) = current {
+                let addr = node.as_ptr() as usize;
+                if addr < self.base || addr + block_size > end {
+                    return Err(BuddyErr::OutOfRange { addr, order });
+                }
+                if !(addr - self.base).is_multiple_of(block_size) {
+                    return Err(BuddyErr::Misaligned { addr, order });
+                }
+                let index = self.block_to_index(addr);
+                if !self.is_range_free(index, num_min_blocks) {
+                    return Err(BuddyErr::MarkedAllocated { addr, order });
+                }
+                if order < BuddyAllocator::MAX_ORDER {
+                    let buddy_addr = self.base + ((addr - self.base) ^ block_size);
+                    if self.is_range_free(self.block_to_index(buddy_addr), num_min_blocks) {
+                        return Err(BuddyErr::Uncoalesced { addr, order });
+                    }
+                    let parent_addr = addr.min(buddy_addr);
+                    if !self.get_split_bit(self.split_index(parent_addr, order + 1)) {
+                        return Err(BuddyErr::SplitBit { addr, order });
+                    }
+                }
+                if order > BuddyAllocator::MIN_ORDER && self.get_split_bit(self.split_index(addr, order)) {
+                    return Err(BuddyErr::SplitBit { addr, order });
+                }
+                listed += num_min_blocks;
+                if listed > max_blocks {
+                    return Err(BuddyErr::Overlap { addr, order });      // or a loop
+                }
+                current = unsafe { node.as_ref().next };
+            }
+        }
+
+        // mark the listed blocks, a block found marked overlaps another
+        let scratch = &mut scratch[..self.scratch_len()];
+        scratch.copy_from_slice(self.allocated_bitmap);
+        for order in BuddyAllocator::MIN_ORDER..=BuddyAllocator::MAX_ORDER {
+            let num_min_blocks = (1 << order) / BuddyAllocator::MIN_BLOCK_SIZE;
+            let mut current    = self.free_lists[order - BuddyAllocator::MIN_ORDER];
+            while let Some(node) = current {
+                let addr  = node.as_ptr() as usize;
+                let index = self.block_to_index(addr);
+                for index in index..index + num_min_blocks {
+                    if self.get_bit(scratch, index) {
+                        return Err(BuddyErr::Overlap { addr, order });
+                    }
+                    scratch[index / 8] |= 1 << (index % 8);
+                }
+                current = unsafe { node.as_ref().next };
+            }
+        }
+        let free = (0..max_blocks).filter(|&index| !self.get_bit(scratch, index)).count();
+        if free != 0 {
+            return Err(BuddyErr::LostMemory { blocks: free });
+        }
+        Ok(())
+    }
+
+    /// Bytes of scratch space `check` needs.
+    pub fn scratch_len(&self) -> usize {
+        self.allocated_bitmap.len()
     }
+
 }
 
 
 
 
 
+/// Checks of `heap-debug`. An allocation sits after a redzone at the
+/// start of its block, a multiple of its alignment, and is followed by
+/// at least `REDZONE` bytes of redzone up to the end of the block.
+#[cfg(feature = "heap-debug")]
+impl BuddyAllocator {
+    fn front_redzone(layout: &Layout) -> usize {
+        layout.align().max(REDZONE)
+    }
+
+    /// Fill the redzones of the new `block`, returns the allocation.
+    fn fill_redzones(block: NonNull<u8>, order: usize, layout: &Layout) -> NonNull<u8> {
+        let front = BuddyAllocator::front_redzone(layout);
+        let end   = front + layout.size();
+        unsafe {
+            let bytes = core::slice::from_raw_parts_mut(block.as_ptr(), 1 << order);
+            bytes[..front].fill(REDZONE_BYTE);
+            bytes[end..].fill(REDZONE_BYTE);
+            NonNull::new_unchecked(block.as_ptr().add(front))
+        }
+    }
+
+    /// Make sure `ptr` is a live allocation of `layout`, with its
+    /// redzones intact, and poison its block. Returns the block.
+    /// Panics with a report otherwise.
+    fn check_free(&mut self, ptr: NonNull<u8>, order: usize, layout: &Layout) -> NonNull<u8> {
+        let report = |what: core::fmt::Arguments| -> ! {
+            panic!("heap: {} (ptr {:p}, size {}, align {})", what, ptr, layout.size(), layout.align())
+        };
+        let front      = BuddyAllocator::front_redzone(layout);
+        let block_size = 1 << order;
+        let addr       = (ptr.as_ptr() as usize).wrapping_sub(front);
+        if addr < self.base || addr >= self.base + self.size {
+            report(format_args!("invalid free, not a heap allocation"));
+        }
+        if (addr - self.base) % block_size != 0 || addr + block_size > self.base + self.size {
+            report(format_args!("invalid free, no allocation of this layout starts here"));
+        }
+
+        let index      = self.block_to_index(addr);
+        let num_blocks = block_size / BuddyAllocator::MIN_BLOCK_SIZE;
+        if self.is_range_free(index, num_blocks) {
+            report(format_args!("double free"));
+        }
+        if (index..index + num_blocks).any(|index| !self.get_bit(&self.allocated_bitmap, index)) {
+            report(format_args!("double free, or freed with a larger layout than allocated"));
+        }
+        // allocated blocks are whole: not split, and their parent is
+        if order > BuddyAllocator::MIN_ORDER && self.get_split_bit(self.split_index(addr, order)) {
+            report(format_args!("invalid free, freed with a larger layout than allocated"));
+        }
+        if order < BuddyAllocator::MAX_ORDER {
+            let parent = self.base + ((addr - self.base) & !((2 << order) - 1));
+            if !self.get_split_bit(self.split_index(parent, order + 1)) {
+                report(format_args!("invalid free, freed with a smaller layout than allocated"));
+            }
+        }
+
+        let bytes = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, block_size) };
+        if let Some(offset) = bytes[..front].iter().rposition(|&byte| byte != REDZONE_BYTE) {
+            report(format_args!("redzone overwritten {} bytes before the allocation", front - offset));
+        }
+        let end = front + layout.size();
+        if let Some(offset) = bytes[end..].iter().position(|&byte| byte != REDZONE_BYTE) {
+            report(format_args!("redzone overwritten {} bytes past the allocation", offset));
+        }
+        bytes.fill(POISON_BYTE);
+        unsafe { NonNull::new_unchecked(addr as *mut u8) }
+    }
+}
+
 impl Allocatable for BuddyAllocator {
+    #[cfg(not(feature = "heap-debug"))]
+    const MAX_LAYOUT: usize = BuddyAllocator::MAX_BLOCK_SIZE;
+    // leaves room for the redzones
+    #[cfg(feature = "heap-debug")]
+    const MAX_LAYOUT: usize = BuddyAllocator::MAX_BLOCK_SIZE / 4;
+
+    /// The bitmaps live at the start of the managed memory; the heap
+    /// proper starts at the next `MAX_BLOCK_SIZE` boundary after them, so
+    /// buddies computed from `base` are buddies in absolute addresses too.
     fn new(config: AllocatableConfig) -> Result<Self, AllocatableErr> {
-        let aligned_start = (config.start + BuddyAllocator::MIN_BLOCK_SIZE - 1) & !(BuddyAllocator::MIN_BLOCK_SIZE - 1);
-        let adjusted_size = config.size - (aligned_start - config.start);
-        let usable_size   = adjusted_size & !(BuddyAllocator::MIN_BLOCK_SIZE - 1);
-
-        let exceeds_allocator_limit = usable_size > BuddyAllocator::MAX_MEMORY;
-        if exceeds_allocator_limit {
-            return Err(AllocatableErr::ExceedsAllocatorMaxCap);
+        let mem_end = config.start + config.size;
+        let blocks  = config.size.div_ceil(BuddyAllocator::MAX_BLOCK_SIZE)
+                        * (BuddyAllocator::MAX_BLOCK_SIZE / BuddyAllocator::MIN_BLOCK_SIZE);
+        let bitmap_size = blocks.div_ceil(8);
+
+        let aligned_start = (config.start + bitmap_size * 2 + BuddyAllocator::MAX_BLOCK_SIZE - 1)
+                                & !(BuddyAllocator::MAX_BLOCK_SIZE - 1);
+        if aligned_start >= mem_end {
+            return Err(AllocatableErr::ExceedsAllocatableLimit);
         }
+        let usable_size = (mem_end - aligned_start) & !(BuddyAllocator::MIN_BLOCK_SIZE - 1);
+
+        let (split_bitmap, allocated_bitmap) = unsafe {
+            let bitmaps = config.start as *mut u8;
+            core::ptr::write_bytes(bitmaps, 0, bitmap_size * 2);
+            (core::slice::from_raw_parts_mut(bitmaps, bitmap_size),
+             core::slice::from_raw_parts_mut(bitmaps.add(bitmap_size), bitmap_size))
+        };
 
         let mut allocator = BuddyAllocator {
             base            : aligned_start,
             size            : usable_size,
             free_lists      : [None; BuddyAllocator::NUM_ORDERS],
-            split_bitmap    : [0; BuddyAllocator::BITMAP_SIZE],
-            allocated_bitmap: [0; BuddyAllocator::BITMAP_SIZE],
+            split_bitmap,
+            allocated_bitmap,
             stats: AllocatorStats {
                 allocated_bytes    : AtomicUsize::new(0),
                 total_allocations  : AtomicUsize::new(0),
                 total_deallocations: AtomicUsize::new(0),
+                peak_bytes         : AtomicUsize::new(0),
+                failed_allocations : AtomicUsize::new(0),
             },
         };
 
+        // memory past the end is never free, so nothing merges into it
+        let first_missing = usable_size / BuddyAllocator::MIN_BLOCK_SIZE;
+        let missing = allocator.allocated_bitmap.len() * 8 - first_missing;
+        allocator.set_allocated_range(first_missing, missing, true);
+
         allocator.init();
         Ok(allocator)
     }
 
     fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
-        if let Some(order) = self.size_to_order(layout.size()){
-            return self.allocate_order(order);
-        }
-        None
+        let order = self.layout_order(&layout)?;
+        let Some(block) = self.allocate_order(order) else {
+            self.stats.failed_allocations.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let allocated = self.stats.allocated_bytes.fetch_add(1 << order, Ordering::Relaxed) + (1 << order);
+        self.stats.peak_bytes.fetch_max(allocated, Ordering::Relaxed);
+        self.stats.total_allocations.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "heap-debug")]
+        let block = BuddyAllocator::fill_redzones(block, order, &layout);
+        Some(block)
     }
 
     fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
-        if let Some(order) = self.size_to_order(layout.size()) {
+        if let Some(order) = self.layout_order(&layout) {
+            #[cfg(feature = "heap-debug")]
+            let ptr = self.check_free(ptr, order, &layout);
             self.deallocate_order(ptr, order);
+            self.stats.allocated_bytes.fetch_sub(1 << order, Ordering::Relaxed);
+            self.stats.total_deallocations.fetch_add(1, Ordering::Relaxed);
         }
     }
+
+    fn heap_info(&self) -> HeapInfo {
+        let mut info = HeapInfo {
+            free_bytes  : [0; BuddyAllocator::NUM_ORDERS],
+            largest_free: 0,
+            allocated   : self.stats.allocated_bytes.load(Ordering::Relaxed),
+            allocs      : self.stats.total_allocations.load(Ordering::Relaxed),
+            frees       : self.stats.total_deallocations.load(Ordering::Relaxed),
+            peak        : self.stats.peak_bytes.load(Ordering::Relaxed),
+            failed      : self.stats.failed_allocations.load(Ordering::Relaxed),
+        };
+        for order in BuddyAllocator::MIN_ORDER..=BuddyAllocator::MAX_ORDER {
+            let blocks = self.free_blocks(order);
+            info.free_bytes[order - BuddyAllocator::MIN_ORDER] = blocks << order;
+            if blocks != 0 {
+                info.largest_free = 1 << order;
+            }
+        }
+        info
+    }
+
+    fn allocated(&self) -> usize {
+        self.stats.allocated_bytes.load(Ordering::Relaxed)
+    }
 }
 
@@ -1,25 +1,32 @@
-mod alloc_buddy;
+pub mod alloc_buddy;
+pub mod slab;
+#[cfg(feature = "alloc-track")]
+pub mod track;
 use core::{
     alloc::{GlobalAlloc, Layout}, 
     ptr::{NonNull, null_mut},
     cell::UnsafeCell,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 // use crate::sync::SpinLock;
 use alloc_buddy::BuddyAllocator;
+use slab::{SlabCache, SlabSource, SlabStats, GENERAL_CACHES};
 
 use crate::sync::{self, SpinLock};
+use crate::mem::{frame, oom};
+use crate::virtm::PAGE_SIZE;
 
 pub struct  AllocatableConfig {
     start: usize,
     size : usize,
 }
 
-const PAGE_SIZE   : usize = 4096;
-
-pub const KERN_START  : usize = 0x80000000;
-pub const KERN_RESERV : usize = 128 * (1024 * 1024);
-pub const MEM_MAX : usize = 1usize << (9 + 9 + 9 + 12 - 1);
+impl AllocatableConfig {
+    pub const fn new(start: usize, size: usize) -> Self {
+        Self { start, size }
+    }
+}
 
 #[non_exhaustive]
 #[derive(Debug)]
@@ -31,23 +38,81 @@ pub enum AllocatableErr{
 /// Similar to `core::alloc::GlobalAlloc` except the
 /// `allocate` and `deallocate` take a mutable ref to self.
 pub trait Allocatable{
+    /// Largest size and alignment `allocate` serves.
+    const MAX_LAYOUT: usize;
+
     fn new(config: AllocatableConfig) -> Result<Self, AllocatableErr> where Self: Sized;
     fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>>;
     fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout);
+    fn heap_info(&self) -> HeapInfo;
+    /// Bytes in allocated blocks, as in `heap_info`, but cheap.
+    fn allocated(&self) -> usize;
+}
+
+/// A snapshot of the kernel heap, see `heap_info`. Laid out for the
+/// `heapinfo` system call.
+///
+/// `allocs`, `frees` and `failed` count the heap's callers, whichever
+/// of slabs, blocks or frame runs served them; `allocated` and `peak`
+/// count the memory backing them.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapInfo {
+    pub free_bytes  : [usize; BuddyAllocator::NUM_ORDERS],  // in free blocks of `1 << (MIN_ORDER + n)` bytes
+    pub largest_free: usize,    // largest free block
+    pub allocated   : usize,    // bytes in allocated blocks and frame runs, slabs included
+    pub allocs      : usize,
+    pub frees       : usize,
+    pub peak        : usize,    // most bytes allocated at once
+    pub failed      : usize,    // allocations that returned null
+}
+
+impl HeapInfo {
+    pub fn free(&self) -> usize {
+        self.free_bytes.iter().sum()
+    }
 }
 
+/// The kernel heap: layouts up to `slab::SLAB_MAX` bytes come from the
+/// general slab caches, larger ones straight from `T`, which also
+/// provides the slabs. Layouts beyond `T::MAX_LAYOUT` get a run of
+/// frames of their own (see `large_alloc`).
 pub struct Allocator <T: Allocatable> {
     allocator: Option<SpinLock<UnsafeCell<T>>>,
+    slabs    : SpinLock<[SlabCache; GENERAL_CACHES]>,
+    stats    : HeapStats,
+}
+
+/// Counters for what the slab caches do not count themselves.
+struct HeapStats {
+    allocs     : AtomicUsize,   // blocks and frame runs handed out
+    frees      : AtomicUsize,
+    large_bytes: AtomicUsize,   // in frame runs
+    peak       : AtomicUsize,
+    failed     : AtomicUsize,
 }
 
 impl <T: Allocatable> Allocator <T> {
-    pub fn get_allocator(&self) -> Option<&mut T> {
-        if let Some(lock) = &self.allocator {
-            let mut guard = lock.lock();
-            let data = unsafe {&mut *guard.get_mut().get()};
-            return Some(data);
+    /// A heap without memory until `set_allocator`.
+    pub const fn new() -> Self {
+        Self {
+            allocator: None,
+            slabs    : SpinLock::new(slab::general_caches()),
+            stats    : HeapStats {
+                allocs     : AtomicUsize::new(0),
+                frees      : AtomicUsize::new(0),
+                large_bytes: AtomicUsize::new(0),
+                peak       : AtomicUsize::new(0),
+                failed     : AtomicUsize::new(0),
+            },
         }
-        None
+    }
+
+    /// Run `f` on the allocator with its lock held, `None` before
+    /// `set_allocator`.
+    pub fn with_allocator<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        let mut guard = self.allocator.as_ref()?.lock();
+        Some(f(guard.get_mut().get_mut()))
     }
 
     pub fn set_allocator(&mut self, allocator: T){
@@ -55,60 +120,252 @@ impl <T: Allocatable> Allocator <T> {
                             sync::SpinLock::new(UnsafeCell::new(allocator));
         self.allocator = Some(alloc);
     }
+
+    /// The heap right now, `None` before `set_allocator`.
+    pub fn heap_info(&self) -> Option<HeapInfo> {
+        let mut info = self.with_allocator(|allocator| allocator.heap_info())?;
+        let (slab_allocs, slab_frees) = self.slabs.lock().get().iter()
+            .map(|cache| cache.stats())
+            .fold((0, 0), |(allocs, frees), stats| (allocs + stats.allocs, frees + stats.frees));
+        info.allocated += self.stats.large_bytes.load(Ordering::Relaxed);
+        info.allocs     = slab_allocs + self.stats.allocs.load(Ordering::Relaxed);
+        info.frees      = slab_frees + self.stats.frees.load(Ordering::Relaxed);
+        info.peak       = self.stats.peak.load(Ordering::Relaxed);
+        info.failed     = self.stats.failed.load(Ordering::Relaxed);
+        Some(info)
+    }
+
+    /// Allocate from `T`, keeping `peak` up to date.
+    fn block_alloc(&self, layout: Layout) -> Option<NonNull<u8>> {
+        self.with_allocator(|allocator| {
+            let block = allocator.allocate(layout);
+            if block.is_some() {
+                self.note_peak(allocator.allocated());
+            }
+            block
+        }).flatten()
+    }
+
+    /// `blocks` bytes are allocated from `T`.
+    fn note_peak(&self, blocks: usize) {
+        let used = blocks + self.stats.large_bytes.load(Ordering::Relaxed);
+        self.stats.peak.fetch_max(used, Ordering::Relaxed);
+    }
+}
+
+impl <T: Allocatable> Default for Allocator<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl <T: Allocatable> SlabSource for Allocator<T> {
+    fn alloc_slab(&self, size: usize) -> Option<NonNull<u8>> {
+        let layout = Layout::from_size_align(size, size).ok()?;
+        self.block_alloc(layout)
+    }
+
+    fn free_slab(&self, slab: NonNull<u8>, size: usize) {
+        if let Ok(layout) = Layout::from_size_align(size, size) {
+            self.with_allocator(|allocator| allocator.deallocate(slab, layout));
+        }
+    }
+}
+
+impl <T: Allocatable> Allocator <T> {
+    unsafe fn heap_alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(class) = slab::size_class(&layout) {
+            let object = self.slabs.lock().get_mut()[class].allocate(self);
+            return object.map_or(null_mut(), |object| object.as_ptr());
+        }
+        let block = if is_large::<T>(&layout) {
+            large_alloc(layout).inspect(|_| {
+                self.stats.large_bytes.fetch_add(run_bytes(&layout), Ordering::Relaxed);
+                self.note_peak(self.with_allocator(|allocator| allocator.allocated()).unwrap_or(0));
+            })
+        } else {
+            self.block_alloc(layout)
+        };
+        if block.is_some() {
+            self.stats.allocs.fetch_add(1, Ordering::Relaxed);
+        }
+        block.map_or(null_mut(), |block| block.as_ptr())
+    }
+
+    unsafe fn heap_dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(class) = slab::size_class(&layout) {
+            self.slabs.lock().get_mut()[class].deallocate(NonNull::new_unchecked(ptr), self);
+            return;
+        }
+        self.stats.frees.fetch_add(1, Ordering::Relaxed);
+        if is_large::<T>(&layout) {
+            large_dealloc(NonNull::new_unchecked(ptr), layout);
+            self.stats.large_bytes.fetch_sub(run_bytes(&layout), Ordering::Relaxed);
+            return;
+        }
+        let ptr = NonNull::new_unchecked(ptr);
+        self.with_allocator(|allocator| allocator.deallocate(ptr, layout));
+    }
 }
 
 /// TODO: Add better error & param checking.
 unsafe impl <T: Allocatable> GlobalAlloc for Allocator<T> {
+    // kept out of line, the backtrace starts with its return address:
+    // in `__rust_alloc`, or in the code allocating once that is inlined
+    #[cfg_attr(feature = "alloc-track", inline(never))]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        if let Some(allocator) = self.get_allocator(){
-            if let Some(address) = allocator.allocate(layout){
-                return address.as_ptr();
-            }
+        #[cfg(feature = "alloc-track")]
+        let callers = track::backtrace(0);
+        let mut ptr = self.heap_alloc(layout);
+        if ptr.is_null() {
+            ptr = oom::retry(|| self.heap_alloc(layout));
+        }
+        if ptr.is_null() {
+            self.stats.failed.fetch_add(1, Ordering::Relaxed);
         }
-        null_mut()
+        #[cfg(feature = "alloc-track")]
+        if !ptr.is_null() {
+            track::record(ptr, layout, callers);
+        }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        if let Some(allocator) = self.get_allocator(){
-            let ptr = NonNull::new_unchecked(ptr);
-            allocator.deallocate(ptr, layout);
-        }
+        #[cfg(feature = "alloc-track")]
+        track::forget(ptr);
+        self.heap_dealloc(ptr, layout);
     }
 }
 
-#[inline]
-fn get_end() -> usize {
-    let x: usize;
+fn is_large<T: Allocatable>(layout: &Layout) -> bool {
+    layout.size() > T::MAX_LAYOUT || layout.align() > T::MAX_LAYOUT
+}
+
+/// Frames in the run `large_alloc` gives `layout`, with `heap-debug`
+/// room for a redzone after it too.
+fn run_pages(layout: &Layout) -> usize {
+    #[cfg(not(feature = "heap-debug"))]
+    let size = layout.size();
+    #[cfg(feature = "heap-debug")]
+    let size = layout.size() + alloc_buddy::REDZONE;
+    size.div_ceil(PAGE_SIZE).max(1)
+}
+
+fn run_bytes(layout: &Layout) -> usize {
+    run_pages(layout) * PAGE_SIZE
+}
+
+/// Whole frames for `layout`, aligned to at least a page.
+pub fn large_alloc(layout: Layout) -> Option<NonNull<u8>> {
+    let run = frame::frame_alloc_run(run_pages(&layout), layout.align())?;
+    #[cfg(feature = "heap-debug")]
     unsafe {
-        core::arch::asm!(
-            " la {}, end", out(reg) x,)};
-    x
+        core::slice::from_raw_parts_mut(run as *mut u8, run_bytes(&layout))[layout.size()..]
+            .fill(alloc_buddy::REDZONE_BYTE);
+    }
+    NonNull::new(run as *mut u8)
 }
 
-fn create_allocator<T: Allocatable>() -> T {
+/// # Safety
+/// `ptr` must come from `large_alloc` with the same `layout`.
+pub unsafe fn large_dealloc(ptr: NonNull<u8>, layout: Layout) {
+    #[cfg(feature = "heap-debug")]
+    large_check_free(ptr, &layout);
+    frame::frame_free_run(ptr.as_ptr() as usize, run_pages(&layout));
+}
 
-    let mut kern_end = get_end();
-    kern_end = (kern_end + (PAGE_SIZE - 1)) & !(PAGE_SIZE - 1);
-    let mem_size = KERN_RESERV - (kern_end - KERN_START);
-    let config = AllocatableConfig{start: kern_end, size: mem_size};
+/// Make sure `ptr` is a live run of `layout`, with the redzone after it
+/// intact, and poison it. Panics with a report otherwise.
+#[cfg(feature = "heap-debug")]
+fn large_check_free(ptr: NonNull<u8>, layout: &Layout) {
+    let report = |what: core::fmt::Arguments| -> ! {
+        panic!("heap: {} (ptr {:p}, size {}, align {})", what, ptr, layout.size(), layout.align())
+    };
+    let addr = ptr.as_ptr() as usize;
+    if !addr.is_multiple_of(PAGE_SIZE) {
+        report(format_args!("invalid free, not a run of frames"));
+    }
+    if !(0..run_pages(layout)).all(|page| frame::frame_is_allocated(addr + page * PAGE_SIZE)) {
+        report(format_args!("double free, or not a run of frames"));
+    }
+    let bytes = unsafe { core::slice::from_raw_parts_mut(ptr.as_ptr(), run_bytes(layout)) };
+    if let Some(offset) = bytes[layout.size()..].iter().position(|&byte| byte != alloc_buddy::REDZONE_BYTE) {
+        report(format_args!("redzone overwritten {} bytes past the allocation", offset));
+    }
+    bytes.fill(alloc_buddy::POISON_BYTE);
+}
+
+/// Kernel heap size. The heap is one run of frames from `mem::frame`.
+pub const KERN_HEAP_SIZE: usize = 16 * 1024 * 1024;
+
+fn create_allocator<T: Allocatable>() -> T {
+    let heap_start = frame::frame_alloc_run(KERN_HEAP_SIZE / PAGE_SIZE, BuddyAllocator::MAX_BLOCK_SIZE)
+        .expect("Could Not Allocate The Kernel Heap");
+    let config = AllocatableConfig{start: heap_start, size: KERN_HEAP_SIZE};
 
-    let allocator = T::new(config).expect("Could Not Initialise Memory Allocator");
-    allocator
+    T::new(config).expect("Could Not Initialise Memory Allocator")
 }
 
-#[global_allocator]
-static mut GLOB_ALLOCATOR: Allocator<BuddyAllocator> = Allocator{ allocator: None};
+#[cfg_attr(target_arch = "riscv64", global_allocator)]
+static mut GLOB_ALLOCATOR: Allocator<BuddyAllocator> = Allocator::new();
 
 /// Initialisation
 /// - This function gets run once by the CPU that initialised the
-///     kernel.
+///   kernel.
 pub fn allocator_init(){
     unsafe {
         let allocator = create_allocator::<BuddyAllocator>();
-        GLOB_ALLOCATOR.set_allocator(allocator);
+        (*core::ptr::addr_of_mut!(GLOB_ALLOCATOR)).set_allocator(allocator);
     }
     kprintln!("Memory Allocator initialsed");
 }
 
 
 
+
+/// Stats of every slab cache, the general ones first.
+pub fn slab_stats() -> alloc::vec::Vec<SlabStats> {
+    // copied out first: collecting into a `Vec` allocates from the slabs
+    let general: [SlabStats; GENERAL_CACHES] = unsafe {
+        let slabs = (*core::ptr::addr_of!(GLOB_ALLOCATOR)).slabs.lock();
+        core::array::from_fn(|idx| slabs.get()[idx].stats())
+    };
+    let mut stats = general.to_vec();
+    stats.extend(slab::cache_stats());
+    stats
+}
+
+/// Print `slab_stats` as a table.
+pub fn slab_report() {
+    kprintln!("{:<16} {:>6} {:>7} {:>6} {:>8} {:>8} {:>5}", "cache", "size", "slab", "slabs", "objects", "inuse", "use%");
+    for stats in slab_stats() {
+        kprintln!("{:<16} {:>6} {:>7} {:>6} {:>8} {:>8} {:>4}%", stats.name, stats.object_size,
+                  stats.slab_size, stats.slabs, stats.objects, stats.inuse, stats.utilisation());
+    }
+}
+
+/// The kernel heap right now, `None` before `allocator_init`.
+pub fn heap_info() -> Option<HeapInfo> {
+    unsafe { (*core::ptr::addr_of!(GLOB_ALLOCATOR)).heap_info() }
+}
+
+/// Print `heap_info` and the slab caches.
+pub fn heap_report() {
+    heap_summary();
+    slab_report();
+}
+
+/// Print `heap_info`. Does not allocate, for when memory ran out.
+pub fn heap_summary() {
+    let Some(info) = heap_info() else {
+        kprintln!("heap: not initialised");
+        return;
+    };
+    kprintln!("heap: {} KiB free, {} KiB allocated (peak {} KiB), largest free block {} KiB",
+              info.free() / 1024, info.allocated / 1024, info.peak / 1024, info.largest_free / 1024);
+    kprintln!("heap: {} allocations, {} frees, {} failed", info.allocs, info.frees, info.failed);
+    for (n, bytes) in info.free_bytes.iter().enumerate().filter(|(_, &bytes)| bytes != 0) {
+        let block = 1usize << (BuddyAllocator::MIN_ORDER + n);
+        kprintln!("  {:>7} B blocks: {:>5} free", block, bytes / block);
+    }
+}
@@ -0,0 +1,382 @@
+//! Slab allocator: caches of equally sized objects on top of the buddy
+//! allocator.
+//!
+//! A slab is one naturally aligned buddy block: a `Slab` header followed
+//! by as many objects as fit. Free objects are linked through their
+//! first word, so an allocation is a list pop. Objects are found back to
+//! their slab by rounding the address down to the slab size.
+//!
+//! `Allocator`'s `GlobalAlloc` routes layouts up to `SLAB_MAX` bytes to
+//! the general caches; `create_cache` makes a cache for one object type.
+//!
+//! With `heap-debug` every object is followed by a redzone, and frees are
+//! checked and poison the object, as in the buddy allocator.
+
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use crate::sync::SpinLock;
+#[cfg(feature = "heap-debug")]
+use super::alloc_buddy::{POISON_BYTE, REDZONE, REDZONE_BYTE};
+
+/// Smallest slab, a page.
+pub const SLAB_MIN: usize = 4096;
+/// Largest slab, the largest layout the buddy allocator serves.
+pub const SLAB_MAX_SIZE: usize = <super::alloc_buddy::BuddyAllocator as super::Allocatable>::MAX_LAYOUT;
+/// Slabs are sized for this many objects, one fewer fits with the header.
+const SLAB_OBJECTS: usize = 8;
+
+/// Object sizes of the general caches.
+pub const GENERAL_SIZES: [usize; GENERAL_CACHES] = [16, 32, 64, 96, 128, 192, 256, 512, 1024, 2048];
+pub const GENERAL_CACHES: usize = 10;
+/// Largest layout served by a general cache.
+pub const SLAB_MAX: usize = GENERAL_SIZES[GENERAL_CACHES - 1];
+
+/// Where slabs come from.
+pub trait SlabSource {
+    /// A block of `size` bytes aligned to `size`.
+    fn alloc_slab(&self, size: usize) -> Option<NonNull<u8>>;
+    fn free_slab(&self, slab: NonNull<u8>, size: usize);
+}
+
+#[repr(C)]
+struct FreeObj {
+    next: Option<NonNull<FreeObj>>,
+}
+
+#[repr(C)]
+struct Slab {
+    next : Option<NonNull<Slab>>,
+    prev : Option<NonNull<Slab>>,
+    free : Option<NonNull<FreeObj>>,
+    inuse: usize,
+}
+
+/// Utilisation of one cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabStats {
+    pub name       : &'static str,
+    pub object_size: usize,     // bytes per object, padding included
+    pub slab_size  : usize,
+    pub slabs      : usize,
+    pub objects    : usize,     // room in all slabs
+    pub inuse      : usize,
+    pub allocs     : usize,
+    pub frees      : usize,
+}
+
+impl SlabStats {
+    /// Bytes of slab memory holding live objects, in percent.
+    pub fn utilisation(&self) -> usize {
+        match self.slabs {
+            0 => 0,
+            slabs => self.inuse * self.object_size * 100 / (slabs * self.slab_size),
+        }
+    }
+}
+
+pub struct SlabCache {
+    name     : &'static str,
+    size     : usize,       // object stride
+    #[cfg(feature = "heap-debug")]
+    object   : usize,       // object size, the redzone follows
+    slab_size: usize,
+    offset   : usize,       // of the first object in a slab
+    capacity : usize,       // objects per slab
+    ctor     : Option<fn(*mut u8)>,
+    partial  : Option<NonNull<Slab>>,   // slabs with free objects
+    full     : Option<NonNull<Slab>>,
+    slabs    : usize,
+    inuse    : usize,
+    allocs   : usize,
+    frees    : usize,
+}
+
+// the slabs are only reached through the cache, which sits behind a lock
+unsafe impl Send for SlabCache {}
+unsafe impl Sync for SlabCache {}
+
+const fn round_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+fn push(list: &mut Option<NonNull<Slab>>, slab: NonNull<Slab>) {
+    unsafe {
+        (*slab.as_ptr()).prev = None;
+        (*slab.as_ptr()).next = *list;
+        if let Some(head) = *list {
+            (*head.as_ptr()).prev = Some(slab);
+        }
+    }
+    *list = Some(slab);
+}
+
+fn unlink(list: &mut Option<NonNull<Slab>>, slab: NonNull<Slab>) {
+    unsafe {
+        let Slab { next, prev, .. } = *slab.as_ptr();
+        match prev {
+            Some(prev) => (*prev.as_ptr()).next = next,
+            None       => *list = next,
+        }
+        if let Some(next) = next {
+            (*next.as_ptr()).prev = prev;
+        }
+    }
+}
+
+impl SlabCache {
+    /// A cache of `size` byte objects aligned to `align` (a power of
+    /// two). `ctor`, if any, initialises every object handed out.
+    pub const fn new(name: &'static str, size: usize, align: usize, ctor: Option<fn(*mut u8)>) -> Self {
+        let align     = if align < 8 { 8 } else { align };
+        let object    = if size < 8 { 8 } else { size };
+        #[cfg(not(feature = "heap-debug"))]
+        let size      = round_up(object, align);
+        #[cfg(feature = "heap-debug")]
+        let size      = round_up(object + REDZONE, align);
+        let offset    = round_up(core::mem::size_of::<Slab>(), align);
+        let wanted    = (SLAB_OBJECTS * size).next_power_of_two();
+        let slab_size = if wanted < SLAB_MIN { SLAB_MIN } else { wanted };
+        Self {
+            name,
+            size,
+            #[cfg(feature = "heap-debug")]
+            object,
+            slab_size,
+            offset,
+            capacity: slab_size.saturating_sub(offset) / size,
+            ctor,
+            partial : None,
+            full    : None,
+            slabs   : 0,
+            inuse   : 0,
+            allocs  : 0,
+            frees   : 0,
+        }
+    }
+
+    /// Can slabs of this cache come from the buddy allocator?
+    pub fn is_valid(&self) -> bool {
+        self.capacity != 0 && self.slab_size <= SLAB_MAX_SIZE
+    }
+
+    fn grow(&mut self, src: &dyn SlabSource) -> Option<NonNull<Slab>> {
+        let slab = src.alloc_slab(self.slab_size)?.cast::<Slab>();
+        let base = slab.as_ptr() as usize;
+        let mut free = None;
+        for idx in (0..self.capacity).rev() {
+            let obj = (base + self.offset + idx * self.size) as *mut FreeObj;
+            unsafe { (*obj).next = free };
+            free = NonNull::new(obj);
+        }
+        unsafe {
+            slab.as_ptr().write(Slab { next: None, prev: None, free, inuse: 0 });
+        }
+        push(&mut self.partial, slab);
+        self.slabs += 1;
+        Some(slab)
+    }
+
+    pub fn allocate(&mut self, src: &dyn SlabSource) -> Option<NonNull<u8>> {
+        let slab = match self.partial {
+            Some(slab) => slab,
+            None       => self.grow(src)?,
+        };
+        let obj = unsafe {
+            let slab_ref = &mut *slab.as_ptr();
+            let obj = slab_ref.free.expect("slab: partial slab without free objects");
+            slab_ref.free = (*obj.as_ptr()).next;
+            slab_ref.inuse += 1;
+            if slab_ref.free.is_none() {
+                unlink(&mut self.partial, slab);
+                push(&mut self.full, slab);
+            }
+            obj.cast::<u8>()
+        };
+        self.inuse  += 1;
+        self.allocs += 1;
+        #[cfg(feature = "heap-debug")]
+        self.fill_redzone(obj);
+        if let Some(ctor) = self.ctor {
+            ctor(obj.as_ptr());
+        }
+        Some(obj)
+    }
+
+    /// Give back an object from `allocate`. Slabs left empty go back to
+    /// `src`, except the last one.
+    ///
+    /// # Safety
+    /// `obj` must come from `allocate` on this cache and not be freed yet.
+    pub unsafe fn deallocate(&mut self, obj: NonNull<u8>, src: &dyn SlabSource) {
+        let slab = NonNull::new_unchecked((obj.as_ptr() as usize & !(self.slab_size - 1)) as *mut Slab);
+        let slab_ref = &mut *slab.as_ptr();
+        #[cfg(feature = "heap-debug")]
+        self.check_free(slab_ref, obj);
+        let was_full = slab_ref.free.is_none();
+        let obj = obj.cast::<FreeObj>();
+        (*obj.as_ptr()).next = slab_ref.free;
+        slab_ref.free = Some(obj);
+        slab_ref.inuse -= 1;
+        self.inuse -= 1;
+        self.frees += 1;
+
+        if was_full {
+            unlink(&mut self.full, slab);
+            push(&mut self.partial, slab);
+        }
+        if slab_ref.inuse == 0 && self.slabs > 1 {
+            unlink(&mut self.partial, slab);
+            src.free_slab(slab.cast(), self.slab_size);
+            self.slabs -= 1;
+        }
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        SlabStats {
+            name       : self.name,
+            object_size: self.size,
+            slab_size  : self.slab_size,
+            slabs      : self.slabs,
+            objects    : self.slabs * self.capacity,
+            inuse      : self.inuse,
+            allocs     : self.allocs,
+            frees      : self.frees,
+        }
+    }
+}
+
+/// Checks of `heap-debug`. The redzone runs from the end of the object
+/// to the end of its stride, at least `REDZONE` bytes.
+#[cfg(feature = "heap-debug")]
+impl SlabCache {
+    fn fill_redzone(&self, obj: NonNull<u8>) {
+        unsafe {
+            core::slice::from_raw_parts_mut(obj.as_ptr(), self.size)[self.object..].fill(REDZONE_BYTE);
+        }
+    }
+
+    /// Make sure `obj` is a live object of `slab`, with its redzone
+    /// intact, and poison it. Panics with a report otherwise.
+    fn check_free(&self, slab: &Slab, obj: NonNull<u8>) {
+        let report = |what: core::fmt::Arguments| -> ! {
+            panic!("heap: {} (ptr {:p}, cache {}, size {})", what, obj, self.name, self.object)
+        };
+        // a slab given back is poisoned by the buddy allocator
+        if slab.inuse == 0 || slab.inuse > self.capacity {
+            report(format_args!("double free, or not in a live slab"));
+        }
+        let base   = slab as *const Slab as usize + self.offset;
+        let offset = (obj.as_ptr() as usize).wrapping_sub(base);
+        if offset >= self.capacity * self.size || !offset.is_multiple_of(self.size) {
+            report(format_args!("invalid free, not an object of this cache"));
+        }
+        let mut free = slab.free;
+        while let Some(free_obj) = free {
+            if free_obj.cast::<u8>() == obj {
+                report(format_args!("double free"));
+            }
+            free = unsafe { (*free_obj.as_ptr()).next };
+        }
+
+        let bytes = unsafe { core::slice::from_raw_parts_mut(obj.as_ptr(), self.size) };
+        if let Some(offset) = bytes[self.object..].iter().position(|&byte| byte != REDZONE_BYTE) {
+            report(format_args!("redzone overwritten {} bytes past the allocation", offset));
+        }
+        bytes.fill(POISON_BYTE);
+    }
+}
+
+/// The general caches, one per `GENERAL_SIZES` entry.
+pub const fn general_caches() -> [SlabCache; GENERAL_CACHES] {
+    const NAMES: [&str; GENERAL_CACHES] = [
+        "size-16", "size-32", "size-64", "size-96", "size-128",
+        "size-192", "size-256", "size-512", "size-1024", "size-2048",
+    ];
+    let mut caches = [const { SlabCache::new("", 0, 0, None) }; GENERAL_CACHES];
+    let mut idx = 0;
+    while idx < GENERAL_CACHES {
+        let size = GENERAL_SIZES[idx];
+        caches[idx] = SlabCache::new(NAMES[idx], size, general_align(size), None);
+        idx += 1;
+    }
+    caches
+}
+
+/// Objects of a general cache are aligned to the largest power of two
+/// dividing their size, up to 64.
+const fn general_align(size: usize) -> usize {
+    let align = size & size.wrapping_neg();
+    if align > 64 { 64 } else { align }
+}
+
+/// The general cache serving `layout`, if it is small enough.
+pub fn size_class(layout: &Layout) -> Option<usize> {
+    GENERAL_SIZES.iter().position(|&size| {
+        layout.size() <= size && layout.align() <= general_align(size)
+    })
+}
+
+/// Slabs of the typed caches, from the kernel heap.
+struct HeapSlabs;
+
+impl SlabSource for HeapSlabs {
+    fn alloc_slab(&self, size: usize) -> Option<NonNull<u8>> {
+        let layout = Layout::from_size_align(size, size).ok()?;
+        NonNull::new(unsafe { alloc::alloc::alloc(layout) })
+    }
+
+    fn free_slab(&self, slab: NonNull<u8>, size: usize) {
+        let layout = Layout::from_size_align(size, size).unwrap();
+        unsafe { alloc::alloc::dealloc(slab.as_ptr(), layout) };
+    }
+}
+
+/// A cache for one type of object, see `create_cache`.
+pub struct ObjCache {
+    cache: SpinLock<SlabCache>,
+}
+
+impl ObjCache {
+    pub fn alloc(&self) -> Option<NonNull<u8>> {
+        self.cache.lock().get_mut().allocate(&HeapSlabs)
+    }
+
+    /// # Safety
+    /// `obj` must come from `alloc` on this cache and not be freed yet.
+    pub unsafe fn free(&self, obj: NonNull<u8>) {
+        self.cache.lock().get_mut().deallocate(obj, &HeapSlabs);
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.cache.lock().get().stats()
+    }
+}
+
+static CACHES: SpinLock<Vec<&'static ObjCache>> = SpinLock::new(Vec::new());
+
+/// A cache of `size` byte objects aligned to `align`; `ctor`, if any,
+/// initialises every object handed out. Caches live for good. `None` if
+/// `align` is not a power of two or the objects are too large for a slab.
+pub fn create_cache(name: &'static str, size: usize, align: usize, ctor: Option<fn(*mut u8)>)
+    -> Option<&'static ObjCache> {
+    if !align.is_power_of_two() {
+        return None;
+    }
+    let cache = SlabCache::new(name, size, align, ctor);
+    if !cache.is_valid() {
+        return None;
+    }
+    let cache: &'static ObjCache = Box::leak(Box::new(ObjCache { cache: SpinLock::new(cache) }));
+    CACHES.lock().get_mut().push(cache);
+    Some(cache)
+}
+
+/// Stats of the caches made by `create_cache`.
+pub fn cache_stats() -> Vec<SlabStats> {
+    let caches = CACHES.lock().get().clone();
+    caches.iter().map(|cache| cache.stats()).collect()
+}
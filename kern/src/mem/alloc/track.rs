@@ -0,0 +1,261 @@
+//! Allocation tracking, built with the `alloc-track` feature.
+//!
+//! `Allocator` records every live allocation of the kernel heap here,
+//! with a short backtrace and a sequence number, and forgets
+//! it when it is freed. What is left after a test run are the leaks:
+//! `dump` prints them, on demand (Ctrl-O on the console) and when init
+//! exits.
+//!
+//! The records sit in a fixed table, tracking must not allocate.
+//!
+//! Callers are found by following frame pointers, which the build keeps
+//! (`-Cforce-frame-pointers=yes` in `.cargo/config.toml`).
+
+use core::alloc::Layout;
+
+use crate::sync::SpinLock;
+
+/// Live allocations tracked at most, a power of two. One slot always
+/// stays empty.
+pub const MAX_TRACKED: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocRecord {
+    pub ptr   : usize,
+    pub layout: Layout,
+    pub callers: [usize; BACKTRACE],    // return addresses, from `GlobalAlloc::alloc` out
+    pub seq   : u64,        // order of the allocation since boot
+}
+
+/// Live allocations by address: an open addressing hash table with
+/// linear probing.
+pub struct AllocTable {
+    slots   : [Option<AllocRecord>; MAX_TRACKED],
+    live    : usize,
+    next_seq: u64,
+    dropped : usize,        // allocations not recorded, the table was full
+}
+
+impl Default for AllocTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AllocTable {
+    pub const fn new() -> Self {
+        Self {
+            slots   : [None; MAX_TRACKED],
+            live    : 0,
+            next_seq: 0,
+            dropped : 0,
+        }
+    }
+
+    fn home(ptr: usize) -> usize {
+        // heap blocks are at least 16 byte aligned
+        ((ptr >> 4) ^ (ptr >> 16)) & (MAX_TRACKED - 1)
+    }
+
+    /// Record an allocation, returns its sequence number.
+    pub fn insert(&mut self, ptr: usize, layout: Layout, callers: [usize; BACKTRACE]) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let mut slot = Self::home(ptr);
+        while let Some(record) = self.slots[slot] {
+            if record.ptr == ptr {
+                break;
+            }
+            slot = (slot + 1) & (MAX_TRACKED - 1);
+        }
+        if self.slots[slot].is_none() {
+            if self.live == MAX_TRACKED - 1 {
+                self.dropped += 1;
+                return seq;
+            }
+            self.live += 1;
+        }
+        self.slots[slot] = Some(AllocRecord { ptr, layout, callers, seq });
+        seq
+    }
+
+    /// The allocation at `ptr`, if recorded.
+    pub fn get(&self, ptr: usize) -> Option<&AllocRecord> {
+        let mut slot = Self::home(ptr);
+        loop {
+            match &self.slots[slot] {
+                Some(record) if record.ptr == ptr => return Some(record),
+                Some(_) => slot = (slot + 1) & (MAX_TRACKED - 1),
+                None    => return None,
+            }
+        }
+    }
+
+    /// Forget the allocation at `ptr`; `None` if it was not recorded.
+    pub fn remove(&mut self, ptr: usize) -> Option<AllocRecord> {
+        let mut hole = Self::home(ptr);
+        loop {
+            match self.slots[hole] {
+                Some(record) if record.ptr == ptr => break,
+                Some(_) => hole = (hole + 1) & (MAX_TRACKED - 1),
+                None    => return None,
+            }
+        }
+        let removed = self.slots[hole].take();
+        self.live -= 1;
+
+        // move back the records that probed past the hole
+        let mut next = (hole + 1) & (MAX_TRACKED - 1);
+        while let Some(record) = self.slots[next] {
+            let home = Self::home(record.ptr);
+            let from_home = next.wrapping_sub(home) & (MAX_TRACKED - 1);
+            let from_hole = next.wrapping_sub(hole) & (MAX_TRACKED - 1);
+            if from_home >= from_hole {
+                self.slots[hole] = self.slots[next].take();
+                hole = next;
+            }
+            next = (next + 1) & (MAX_TRACKED - 1);
+        }
+        removed
+    }
+
+    /// The live allocations, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &AllocRecord> {
+        self.slots.iter().flatten()
+    }
+
+    pub fn live(&self) -> usize {
+        self.live
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Sequence number of the next allocation.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+}
+
+static TRACKED: SpinLock<AllocTable> = SpinLock::new(AllocTable::new());
+
+pub(super) fn record(ptr: *mut u8, layout: Layout, callers: [usize; BACKTRACE]) {
+    TRACKED.lock().get_mut().insert(ptr as usize, layout, callers);
+}
+
+pub(super) fn forget(ptr: *mut u8) {
+    TRACKED.lock().get_mut().remove(ptr as usize);
+}
+
+/// The record of the live allocation at `ptr`.
+pub fn lookup(ptr: *mut u8) -> Option<AllocRecord> {
+    TRACKED.lock().get().get(ptr as usize).copied()
+}
+
+/// Words from a frame pointer to the saved frame pointer and return
+/// address of its frame.
+#[cfg(target_arch = "riscv64")]
+const FRAME_RECORD: (isize, isize) = (-2, -1);
+#[cfg(not(target_arch = "riscv64"))]
+const FRAME_RECORD: (isize, isize) = (0, 1);    // x86-64, aarch64
+
+#[inline(always)]
+fn frame_pointer() -> usize {
+    let fp: usize;
+    unsafe {
+        #[cfg(target_arch = "riscv64")]
+        core::arch::asm!("mv {}, s0", out(reg) fp);
+        #[cfg(target_arch = "x86_64")]
+        core::arch::asm!("mov {}, rbp", out(reg) fp);
+        #[cfg(target_arch = "aarch64")]
+        core::arch::asm!("mov {}, x29", out(reg) fp);
+    }
+    #[cfg(not(any(target_arch = "riscv64", target_arch = "x86_64", target_arch = "aarch64")))]
+    { fp = 0; }
+    fp
+}
+
+/// Frames recorded per allocation. `Box` and `Vec` reach
+/// `__rust_alloc` through a few frames of liballoc, the code allocating
+/// is further out.
+pub const BACKTRACE: usize = 12;
+
+/// Largest frame followed: the kernel stacks are a few pages, a bigger
+/// step means the chain of frame pointers is broken.
+const MAX_FRAME: usize = 64 * 1024;
+
+/// Return addresses of the `BACKTRACE` functions from `skip` frames
+/// above the one this is inlined into: `0` is its own, `1` its
+/// caller's. Zero past the outermost frame, or where the frame
+/// pointers stop making sense.
+///
+/// # Safety
+/// Every frame on the way must keep a frame pointer.
+#[inline(always)]
+pub unsafe fn backtrace(skip: usize) -> [usize; BACKTRACE] {
+    let mut trace = [0; BACKTRACE];
+    let mut fp = frame_pointer();
+    for depth in 0..skip + BACKTRACE {
+        if fp == 0 || !fp.is_multiple_of(core::mem::size_of::<usize>()) {
+            break;
+        }
+        if depth >= skip {
+            trace[depth - skip] = *(fp as *const usize).offset(FRAME_RECORD.1);
+        }
+        // stacks grow down: the caller's frame is above ours
+        let next = *(fp as *const usize).offset(FRAME_RECORD.0);
+        if next <= fp || next - fp > MAX_FRAME {
+            break;
+        }
+        fp = next;
+    }
+    trace
+}
+
+/// Sequence number of the next allocation, to `dump` only what was
+/// allocated from now on.
+pub fn mark() -> u64 {
+    TRACKED.lock().get().next_seq()
+}
+
+/// Print the live allocations made since `mark` returned `since`.
+///
+/// The table is copied out a few records at a time and printed with
+/// `TRACKED` unlocked, so the heap stays usable meanwhile; records
+/// moved by a concurrent free may be missed or shown twice.
+pub fn dump(since: u64) {
+    const BATCH: usize = 32;
+    let mut count = 0;
+    let mut bytes = 0;
+    kprintln!("{:>8} {:>18} {:>8} {:>6}  {}", "seq", "address", "size", "align", "callers");
+    let mut slot = 0;
+    while slot < MAX_TRACKED {
+        let mut batch = [None; BATCH];
+        let mut len = 0;
+        {
+            let guard = TRACKED.lock();
+            let slots = &guard.get().slots;
+            while slot < MAX_TRACKED && len < BATCH {
+                if let Some(record) = slots[slot].filter(|record| record.seq >= since) {
+                    batch[len] = Some(record);
+                    len += 1;
+                }
+                slot += 1;
+            }
+        }
+        for record in batch.iter().flatten() {
+            kprint!("{:>8} {:#18x} {:>8} {:>6} ", record.seq, record.ptr,
+                    record.layout.size(), record.layout.align());
+            for caller in record.callers.iter().take_while(|&&caller| caller != 0) {
+                kprint!(" {:#x}", caller);
+            }
+            kprintln!();
+            count += 1;
+            bytes += record.layout.size();
+        }
+    }
+    let dropped = TRACKED.lock().get().dropped();
+    kprintln!("alloc-track: {} live allocations, {} bytes ({} not tracked)", count, bytes, dropped);
+}
@@ -0,0 +1,244 @@
+//! Physical frame allocator: the one owner of RAM.
+//!
+//! Everything else gets its memory from here: page tables, kernel
+//! stacks and user pages one frame at a time through `virtm::pg_alloc`,
+//! the kernel heap as one contiguous run (see `mem::alloc`).
+
+use crate::platform::{MemRange, MAX_RAM_RANGES};
+use crate::sync::SpinLock;
+use crate::virtm::{page_down, page_up, PAGE_SIZE};
+
+const BITMAP_LEN: usize = 64;
+/// Reserved ranges remembered to catch frees inside them: the bitmap,
+/// the kernel image, the DTB and the initrd, with room to spare.
+const MAX_RESERVED: usize = 8;
+
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameErr {
+    NoMemory,       // no RAM, or no room for the bitmap
+}
+
+/// Frame allocator over all of RAM.
+///
+/// One bit per frame from the lowest to the highest RAM address, set for
+/// frames in use. The bitmap itself lives in the first free spot of RAM,
+/// so only about 32 KiB per GiB is spent on it whatever the RAM size.
+pub struct FrameAllocator {
+    pmap       : &'static mut [u64],
+    alloc_start: usize,     // address of frame 0
+    page_count : usize,
+    ram        : [MemRange; MAX_RAM_RANGES],  // whole frames, to catch frees in holes
+    ram_count  : usize,
+    reserved   : [MemRange; MAX_RESERVED],  // page aligned, never freed
+    reserved_count: usize,
+}
+
+impl FrameAllocator {
+    /// Manage the frames of `ram`, sorted by address, leaving out the
+    /// frames overlapping `reserved` (kernel image, DTB, initrd). Ranges
+    /// past `MAX_RAM_RANGES` are ignored.
+    pub fn new(ram: &[MemRange], reserved: &[MemRange]) -> Result<Self, FrameErr> {
+        let ram = &ram[..ram.len().min(MAX_RAM_RANGES)];
+        let first = ram.first().ok_or(FrameErr::NoMemory)?;
+        let last  = ram.last().ok_or(FrameErr::NoMemory)?;
+        let alloc_start = page_up(first.start);
+        let page_count  = page_down(last.end()).saturating_sub(alloc_start) / PAGE_SIZE;
+        let num_bitmaps = page_count.div_ceil(BITMAP_LEN);
+        let map_size    = page_up(num_bitmaps * core::mem::size_of::<u64>());
+
+        let overlaps = |start: usize, size: usize| reserved.iter()
+            .find(|r| r.size != 0 && r.start < start + size && start < r.end());
+        let mut map_mem = None;
+        'ranges: for range in ram {
+            let mut start = page_up(range.start);
+            while start + map_size <= range.end() {
+                match overlaps(start, map_size) {
+                    Some(r) => start = page_up(r.end()),
+                    None => {
+                        map_mem = Some(start);
+                        break 'ranges;
+                    }
+                }
+            }
+        }
+        let map_mem = map_mem.ok_or(FrameErr::NoMemory)?;
+
+        let pmap = unsafe {
+            core::slice::from_raw_parts_mut(map_mem as *mut u64, num_bitmaps) };
+        pmap.fill(u64::MAX);
+        let mut allocator = Self {
+            pmap,
+            alloc_start,
+            page_count,
+            ram: [MemRange { start: 0, size: 0 }; MAX_RAM_RANGES],
+            ram_count: 0,
+            reserved: [MemRange { start: 0, size: 0 }; MAX_RESERVED],
+            reserved_count: 0,
+        };
+
+        // only whole frames of RAM are free
+        for range in ram {
+            let start = page_up(range.start.max(alloc_start));
+            let end   = page_down(range.end());
+            if start < end {
+                allocator.mark(start, end, false);
+                allocator.ram[allocator.ram_count] = MemRange { start, size: end - start };
+                allocator.ram_count += 1;
+            }
+        }
+        allocator.reserve(map_mem, map_size);
+        for range in reserved {
+            allocator.reserve(range.start, range.size);
+        }
+        Ok(allocator)
+    }
+
+    fn index(&self, addr: usize) -> Option<usize> {
+        let in_range = addr >= self.alloc_start && addr.is_multiple_of(PAGE_SIZE)
+            && (addr - self.alloc_start) / PAGE_SIZE < self.page_count;
+        in_range.then(|| (addr - self.alloc_start) / PAGE_SIZE)
+    }
+
+    fn is_set(&self, idx: usize) -> bool {
+        self.pmap[idx / BITMAP_LEN] & (1u64 << (idx % BITMAP_LEN)) != 0
+    }
+
+    /// Set or clear the bits of the frames in `[start, end)`, both within
+    /// the managed range.
+    fn mark(&mut self, start: usize, end: usize, used: bool) {
+        let mut idx = (start - self.alloc_start) / PAGE_SIZE;
+        let last    = (end - self.alloc_start) / PAGE_SIZE;
+        while idx < last {
+            let mask = 1u64 << (idx % BITMAP_LEN);
+            match used {
+                true  => self.pmap[idx / BITMAP_LEN] |= mask,
+                false => self.pmap[idx / BITMAP_LEN] &= !mask,
+            }
+            idx += 1;
+        }
+    }
+
+    /// One frame, not zeroed.
+    pub fn allocate(&mut self) -> Option<usize> {
+        let (idx, map) = self.pmap.iter_mut().enumerate().find(|(_, map)| **map != u64::MAX)?;
+        let bit_idx = (!*map).trailing_zeros() as usize;
+        *map |= 1u64 << bit_idx;
+        Some(self.alloc_start + (BITMAP_LEN * idx + bit_idx) * PAGE_SIZE)
+    }
+
+    /// `count` contiguous frames starting at a multiple of `align` bytes
+    /// (a power of two), not zeroed.
+    pub fn allocate_run(&mut self, count: usize, align: usize) -> Option<usize> {
+        let align = align.max(PAGE_SIZE);
+        let mut start = (self.alloc_start + align - 1) & !(align - 1);
+        let end = self.alloc_start + self.page_count * PAGE_SIZE;
+        while start + count * PAGE_SIZE <= end {
+            let first = self.index(start)?;
+            // restart past the last frame in use, if any
+            match (first..first + count).rev().find(|&idx| self.is_set(idx)) {
+                Some(used) => {
+                    let next = self.alloc_start + (used + 1) * PAGE_SIZE;
+                    start = (next + align - 1) & !(align - 1);
+                }
+                None => {
+                    self.mark(start, start + count * PAGE_SIZE, true);
+                    return Some(start);
+                }
+            }
+        }
+        None
+    }
+
+    /// Give back one frame.
+    /// - Panics if it is not a frame of RAM or is reserved.
+    pub fn deallocate(&mut self, addr: usize) {
+        self.deallocate_run(addr, 1);
+    }
+
+    /// Give back `count` frames from `allocate_run`.
+    /// - Panics if any of them is not a frame of RAM (misaligned, outside
+    ///   the managed range or in a hole between RAM ranges) or is reserved.
+    pub fn deallocate_run(&mut self, addr: usize, count: usize) {
+        let end = addr + count * PAGE_SIZE;
+        let ram = &self.ram[..self.ram_count];
+        if let Some(bad) = (addr..end).step_by(PAGE_SIZE)
+            .find(|&frame| self.index(frame).is_none() || !ram.iter().any(|r| r.contains(frame)))
+        {
+            panic!("frame: freeing {:#x}..{:#x}, {:#x} is not a frame of RAM", addr, end, bad);
+        }
+        if let Some(r) = self.reserved[..self.reserved_count].iter()
+            .find(|r| r.start < end && addr < r.end())
+        {
+            panic!("frame: freeing {:#x}..{:#x} inside reserved {:#x}..{:#x}",
+                   addr, end, r.start, r.end());
+        }
+        self.mark(addr, end, false);
+    }
+
+    /// Mark the frames overlapping `[start, start + size)` as allocated so
+    /// they are never handed out (boot data like the DTB or initrd).
+    /// - Panics past `MAX_RESERVED` ranges.
+    pub fn reserve(&mut self, start: usize, size: usize) {
+        let alloc_end = self.alloc_start + self.page_count * PAGE_SIZE;
+        let first = page_down(start).max(self.alloc_start);
+        let last  = page_up(start + size).min(alloc_end);
+        if first < last {
+            self.mark(first, last, true);
+            let slot = self.reserved.get_mut(self.reserved_count)
+                .expect("frame: too many reserved ranges");
+            *slot = MemRange { start: first, size: last - first };
+            self.reserved_count += 1;
+        }
+    }
+
+    pub fn is_allocated(&self, addr: usize) -> bool {
+        self.index(addr).is_some_and(|idx| self.is_set(idx))
+    }
+
+    /// Number of free frames.
+    pub fn free_frames(&self) -> usize {
+        self.pmap.iter().map(|map| map.count_zeros() as usize).sum()
+    }
+}
+
+static FRAMES: SpinLock<Option<FrameAllocator>> = SpinLock::new(None);
+
+/// Hand RAM to the frame allocator.
+/// - Runs once, before paging is turned on.
+pub fn frame_init(ram: &[MemRange], reserved: &[MemRange]) -> Result<(), FrameErr> {
+    let allocator = FrameAllocator::new(ram, reserved)?;
+    *FRAMES.lock().get_mut() = Some(allocator);
+    Ok(())
+}
+
+/// One frame, not zeroed.
+pub fn frame_alloc() -> Option<usize> {
+    FRAMES.lock().get_mut().as_mut()?.allocate()
+}
+
+pub fn frame_free(addr: usize) {
+    if let Some(frames) = FRAMES.lock().get_mut().as_mut() {
+        frames.deallocate(addr);
+    }
+}
+
+/// `count` contiguous frames aligned to `align` bytes, not zeroed.
+pub fn frame_alloc_run(count: usize, align: usize) -> Option<usize> {
+    FRAMES.lock().get_mut().as_mut()?.allocate_run(count, align)
+}
+
+pub fn frame_free_run(addr: usize, count: usize) {
+    if let Some(frames) = FRAMES.lock().get_mut().as_mut() {
+        frames.deallocate_run(addr, count);
+    }
+}
+
+/// Is the frame at `addr` allocated? False outside RAM.
+pub fn frame_is_allocated(addr: usize) -> bool {
+    FRAMES.lock().get().as_ref().is_some_and(|frames| frames.is_allocated(addr))
+}
+
+pub fn free_frames() -> usize {
+    FRAMES.lock().get().as_ref().map_or(0, FrameAllocator::free_frames)
+}
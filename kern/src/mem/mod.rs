@@ -1,4 +1,6 @@
 pub mod alloc;
+pub mod frame;
+pub mod oom;
 pub mod virtm;
 
 
@@ -0,0 +1,88 @@
+//! Running out of kernel memory.
+//!
+//! When the heap cannot serve an allocation, `retry` runs the reclaim
+//! hooks, which give back memory the kernel can do without, and tries
+//! again. With the `oom-kill` feature it then kills the largest user
+//! process, waits for its parent to reap it and tries once more. An allocation that
+//! still fails ends in the `alloc_error_handler`, which prints the heap
+//! and the failing layout through `out_of_memory` and panics.
+//!
+//! Hooks take locks and killing waits, so both only run when no spinlock
+//! is held on this hart, i.e. with interrupts on. Nor does a process
+//! holding a `SleepLock` kill: the victim may be asleep on that lock and
+//! could not exit while we wait for it.
+
+use core::alloc::Layout;
+use core::ptr::null_mut;
+
+use crate::block::cache;
+use crate::{proc, riscv};
+
+/// Gives back memory, never sleeping. Returns how many objects
+/// (buffers, processes ..) it freed.
+type ReclaimHook = fn() -> usize;
+
+static RECLAIM_HOOKS: [(&str, ReclaimHook); 2] = [
+    ("buffers", cache::shrink),
+    ("zombie processes", proc::proc_reap_zombies),
+];
+
+/// Times the allocating process yields waiting for a killed process to
+/// be reaped (`oom-kill`).
+#[cfg(feature = "oom-kill")]
+const KILL_WAIT: usize = 32;
+
+/// Run the reclaim hooks. Returns the objects freed.
+pub fn reclaim() -> usize {
+    RECLAIM_HOOKS.iter().map(|(what, hook)| {
+        let freed = hook();
+        if freed != 0 {
+            kprintln!("oom: reclaimed {} {}", freed, what);
+        }
+        freed
+    }).sum()
+}
+
+/// Make room for an allocation `alloc` just failed to make, and make it
+/// again. Null if no room could be made, or if a spinlock is held.
+pub fn retry(alloc: impl Fn() -> *mut u8) -> *mut u8 {
+    if !riscv::intr_get() {
+        return null_mut();
+    }
+    reclaim_and_retry(alloc)
+}
+
+/// `retry`, for a caller that knows it holds no spinlock.
+pub fn reclaim_and_retry(alloc: impl Fn() -> *mut u8) -> *mut u8 {
+    if reclaim() != 0 {
+        let ptr = alloc();
+        if !ptr.is_null() {
+            return ptr;
+        }
+    }
+
+    // the victim's memory comes back when its parent reaps it, or the
+    // zombie hook does if it has none
+    #[cfg(feature = "oom-kill")]
+    if proc::myproc().is_none_or(|proc| proc.sleep_locks == 0) {
+        if let Some(pid) = proc::proc_kill_largest() {
+            for _ in 0..KILL_WAIT {
+                crate::sched::yield_proc();
+                if !proc::proc_exists(pid) {
+                    break;
+                }
+            }
+            reclaim();
+            return alloc();
+        }
+    }
+    null_mut()
+}
+
+/// Report an allocation that could not be made, then panic.
+pub fn out_of_memory(layout: Layout) -> ! {
+    kprintln!("oom: out of memory allocating {} bytes aligned to {}", layout.size(), layout.align());
+    super::alloc::heap_summary();
+    kprintln!("oom: {} free frames", super::frame::free_frames());
+    panic!("out of kernel memory ({:?})", layout);
+}
@@ -0,0 +1,174 @@
+//! The machine we run on, as described by the boot DTB.
+//!
+//! `platform_init` fills this in on hart 0 before any driver starts;
+//! drivers then take their MMIO bases and IRQs from `platform()` rather
+//! than from constants. Without a usable DTB the layout of QEMU's virt
+//! machine with 128M of RAM is assumed.
+
+use crate::fdt::{self, Fdt, FdtNode};
+use crate::virtm::VirtMemMap;
+
+/// RAM ranges kept, extra `/memory` ranges are ignored.
+pub const MAX_RAM_RANGES: usize = 4;
+/// virtio-mmio transports kept.
+pub const MAX_VIRTIO    : usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemRange {
+    pub start: usize,
+    pub size : usize,
+}
+
+impl MemRange {
+    pub const fn end(&self) -> usize {
+        self.start + self.size
+    }
+
+    pub const fn contains(&self, addr: usize) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+}
+
+/// A memory mapped device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mmio {
+    pub base: usize,
+    pub size: usize,
+    pub irq : u32,      // PLIC source, 0 if none
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Platform {
+    ram         : [MemRange; MAX_RAM_RANGES],
+    ram_count   : usize,
+    pub cpus    : usize,
+    pub timebase: usize,    // `time` ticks per second
+    pub uart    : Mmio,
+    pub plic    : Mmio,
+    virtio      : [Mmio; MAX_VIRTIO],
+    virtio_count: usize,
+}
+
+const NO_RAM   : MemRange = MemRange { start: 0, size: 0 };
+const NO_DEVICE: Mmio = Mmio { base: 0, size: 0, irq: 0 };
+
+const fn qemu_virtio() -> [Mmio; MAX_VIRTIO] {
+    let mut slots = [NO_DEVICE; MAX_VIRTIO];
+    let mut slot = 0;
+    while slot < MAX_VIRTIO {
+        slots[slot] = Mmio {
+            base: VirtMemMap::VIRT_VIRTIO + slot * 0x1000,
+            size: 0x1000,
+            irq : 1 + slot as u32,
+        };
+        slot += 1;
+    }
+    slots
+}
+
+impl Platform {
+    /// QEMU's virt machine, `-m 128M -smp 1`.
+    pub const QEMU_VIRT: Platform = Platform {
+        ram         : [MemRange { start: VirtMemMap::VIRT_DRAM, size: 128 * 1024 * 1024 },
+                       NO_RAM, NO_RAM, NO_RAM],
+        ram_count   : 1,
+        cpus        : 1,
+        timebase    : 10_000_000,
+        uart        : Mmio { base: VirtMemMap::VIRT_UART0, size: 0x100, irq: 10 },
+        plic        : Mmio { base: VirtMemMap::VIRT_PLIC, size: 0x600000, irq: 0 },
+        virtio      : qemu_virtio(),
+        virtio_count: MAX_VIRTIO,
+    };
+
+    /// What `fdt` describes, falling back to `QEMU_VIRT` for anything
+    /// it leaves out.
+    pub fn from_fdt(fdt: &Fdt) -> Platform {
+        let mut platform = Self::QEMU_VIRT;
+
+        let mut ram = [NO_RAM; MAX_RAM_RANGES];
+        let mut ram_count = 0;
+        let memory = fdt.nodes().filter(|node| node.is_type("memory") && node.is_enabled());
+        for (start, size) in memory.flat_map(|node| node.reg()).filter(|&(_, size)| size != 0) {
+            if ram_count < MAX_RAM_RANGES {
+                ram[ram_count] = MemRange { start, size };
+                ram_count += 1;
+            }
+        }
+        if ram_count != 0 {
+            ram[..ram_count].sort_unstable_by_key(|range| range.start);
+            platform.ram = ram;
+            platform.ram_count = ram_count;
+        }
+
+        let cpus = fdt.nodes().filter(|node| node.is_type("cpu") && node.is_enabled()).count();
+        if cpus != 0 {
+            platform.cpus = cpus;
+        }
+        if let Some(freq) = fdt.prop("/cpus", "timebase-frequency").and_then(fdt::prop_usize) {
+            platform.timebase = freq;
+        }
+
+        if let Some(uart) = fdt.find_compatible(&["ns16550a", "ns16550"]).and_then(mmio) {
+            platform.uart = uart;
+        }
+        if let Some(plic) = fdt.find_compatible(&["riscv,plic0", "sifive,plic-1.0.0"]).and_then(mmio) {
+            platform.plic = plic;
+        }
+
+        let mut virtio = [NO_DEVICE; MAX_VIRTIO];
+        let mut virtio_count = 0;
+        let transports = fdt.nodes()
+            .filter(|node| node.is_enabled() && node.is_compatible("virtio,mmio"))
+            .filter_map(mmio);
+        for transport in transports {
+            if virtio_count < MAX_VIRTIO {
+                virtio[virtio_count] = transport;
+                virtio_count += 1;
+            }
+        }
+        if virtio_count != 0 {
+            // QEMU lists them from the top; bus 0 is the lowest address
+            virtio[..virtio_count].sort_unstable_by_key(|transport| transport.base);
+            platform.virtio = virtio;
+            platform.virtio_count = virtio_count;
+        }
+        platform
+    }
+
+    /// RAM ranges, lowest first.
+    pub fn ram(&self) -> &[MemRange] {
+        &self.ram[..self.ram_count]
+    }
+
+    /// virtio-mmio transports, lowest address first.
+    pub fn virtio(&self) -> &[Mmio] {
+        &self.virtio[..self.virtio_count]
+    }
+
+    pub fn ram_size(&self) -> usize {
+        self.ram().iter().map(|range| range.size).sum()
+    }
+}
+
+fn mmio(node: FdtNode) -> Option<Mmio> {
+    let (base, size) = node.reg().next()?;
+    Some(Mmio { base, size, irq: node.irq().unwrap_or(0) })
+}
+
+static mut PLATFORM: Platform = Platform::QEMU_VIRT;
+
+/// The machine description; `QEMU_VIRT` until `platform_init` ran.
+pub fn platform() -> &'static Platform {
+    unsafe { &*core::ptr::addr_of!(PLATFORM) }
+}
+
+/// Read the machine description from the DTB at `dtb`.
+/// - Runs in M-mode on hart 0, before the other harts go on.
+pub fn platform_init(dtb: usize) {
+    unsafe {
+        fdt::BOOT_DTB = dtb;
+        if let Ok(fdt) = Fdt::from_addr(dtb) {
+            PLATFORM = Platform::from_fdt(&fdt);
+        }
+    }
+}
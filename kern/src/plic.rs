@@ -1,15 +1,20 @@
-use crate::uart::UART0_IRQ;
+use crate::uart::uart_irq;
 
-pub const PLIC          : usize = 0x0c000000;
-pub const PLIC_PRIORITY : usize = PLIC + 0x0;
-pub const PLIC_PENDING  : usize = PLIC + 0x1000;
+// register offsets from `plic_base()`
+pub const PLIC_PRIORITY : usize = 0x0;
+pub const PLIC_PENDING  : usize = 0x1000;
+
+/// Base of the PLIC registers, from the DTB.
+pub fn plic_base() -> usize {
+    crate::platform::platform().plic.base
+}
 
 #[macro_export]
 macro_rules! plic_enable {
     ($hart:expr, $value:expr) => {{
         unsafe {
             let enable_ptr = 
-                (($crate::plic::PLIC + 0x2080) + ($hart * 0x100)) 
+                (($crate::plic::plic_base() + 0x2080) + ($hart * 0x100)) 
                     as *mut u32;
             *enable_ptr = $value;
         }
@@ -21,7 +26,7 @@ macro_rules! plic_spriority {
     ($hart:expr, $value:expr) => {{
         unsafe{
             let priority_ptr = 
-                (($crate::plic::PLIC + 0x201000) + ($hart * 0x2000)) 
+                (($crate::plic::plic_base() + 0x201000) + ($hart * 0x2000)) 
                     as *mut u32;
             *priority_ptr = $value;
         }
@@ -34,7 +39,7 @@ macro_rules! plic_sclaim_r {
     ($hart:expr) => {{
         unsafe {
             let sclaim_ptr = 
-                ($crate::plic::PLIC + 0x201004 + ($hart * 0x2000)) 
+                ($crate::plic::plic_base() + 0x201004 + ($hart * 0x2000)) 
                     as *const u32;
             *sclaim_ptr
         }
@@ -46,7 +51,7 @@ macro_rules! plic_sclaim_w {
     ($hart:expr, $value:expr) => {{
         unsafe {
             let sclaim_ptr = 
-                ($crate::plic::PLIC + 0x201004 + ($hart * 0x2000)) 
+                ($crate::plic::plic_base() + 0x201004 + ($hart * 0x2000)) 
                     as *mut u32;
             *sclaim_ptr = $value;
         }
@@ -54,14 +59,24 @@ macro_rules! plic_sclaim_w {
 }
 
 
-pub fn plic_init(hart: usize)
+/// Give `irq` a non-zero priority (zero means disabled) and let it
+/// interrupt `hart` in S-mode, keeping the IRQs already enabled.
+pub fn plic_enable_irq(hart: usize, irq: u32)
 {
     unsafe {
-        // set desired IRQ priorities non-zero (otherwise disabled).
-        let ptr = 
-            (PLIC + (UART0_IRQ as usize * 4usize)) as *mut u32;
-        *ptr = 1;
+        let ptr = (plic_base() + PLIC_PRIORITY + irq as usize * 4) as *mut u32;
+        ptr.write_volatile(1);
+
+        // one enable bit per source, 32 to a word
+        let word = irq as usize / 32;
+        let enable_ptr = ((plic_base() + 0x2080) + (hart * 0x100) + word * 4) as *mut u32;
+        enable_ptr.write_volatile(enable_ptr.read_volatile() | (1 << (irq % 32)));
     };
-    plic_enable!(hart, 1 << UART0_IRQ);
+}
+
+pub fn plic_init(hart: usize)
+{
+    plic_enable!(hart, 0);
+    plic_enable_irq(hart, uart_irq());
     plic_spriority!(hart, 0);
 }
\ No newline at end of file
@@ -0,0 +1,493 @@
+//! Processes and the process table.
+
+use alloc::sync::Arc;
+
+use crate::fs::File;
+use crate::ktrap::TrapFrame;
+use crate::riscv::{RegTP, Register};
+use crate::sync::SpinLock;
+use crate::{sched, trampoline};
+use crate::virtm::{self, PTEPerms, PAGE_SIZE};
+
+pub const NPROC: usize = 16;
+/// Harts the kernel runs on, `_entry` parks any others the machine has.
+pub const NCPU : usize = 2;
+/// Open files per process.
+pub const NOFILE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcState {
+    Unused,
+    Used,       // allocated, not yet runnable
+    Runnable,
+    Running,
+    Sleeping,
+    Zombie,
+}
+
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum ProcErr {
+    NoFreeSlot,     // the process table is full
+    OutOfMemory,    // could not allocate the page table, trap frame ..
+}
+
+/// Callee-saved registers of a kernel thread.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Context {
+    pub ra : usize,
+    pub sp : usize,
+    pub s0 : usize,
+    pub s1 : usize,
+    pub s2 : usize,
+    pub s3 : usize,
+    pub s4 : usize,
+    pub s5 : usize,
+    pub s6 : usize,
+    pub s7 : usize,
+    pub s8 : usize,
+    pub s9 : usize,
+    pub s10: usize,
+    pub s11: usize,
+}
+
+impl Context {
+    pub const fn new() -> Self {
+        Self {
+            ra: 0, sp: 0,
+            s0: 0, s1: 0, s2: 0, s3: 0, s4: 0, s5: 0,
+            s6: 0, s7: 0, s8: 0, s9: 0, s10: 0, s11: 0,
+        }
+    }
+}
+
+pub struct Proc {
+    pub pid        : usize,
+    pub state      : ProcState,
+    pub chan       : usize,         // what a `Sleeping` process waits for
+    pub parent     : usize,         // pid of the parent, 0 if none
+    pub exit_status: i32,
+    pub killed     : bool,          // exits on its way back to user space
+    pub sleep_locks: usize,         // `SleepLock`s held, see `mem::oom`
+    pub page_table : *mut u64,      // user Sv39 root
+    pub trap_frame : *mut TrapFrame,
+    pub kstack     : usize,         // bottom of the kernel stack (virtual)
+    pub context    : Context,
+    pub brk        : usize,         // end of the user heap
+    pub name       : [u8; 16],
+    pub files      : [Option<Arc<File>>; NOFILE],
+}
+
+unsafe impl Send for Proc {}
+unsafe impl Sync for Proc {}
+
+impl Proc {
+    const fn new(slot: usize) -> Self {
+        Self {
+            pid        : 0,
+            state      : ProcState::Unused,
+            chan       : 0,
+            parent     : 0,
+            exit_status: 0,
+            killed     : false,
+            sleep_locks: 0,
+            page_table : core::ptr::null_mut(),
+            trap_frame : core::ptr::null_mut(),
+            kstack     : virtm::kstack_va(slot),
+            context    : Context::new(),
+            brk        : 0,
+            name       : [0; 16],
+            files      : [const { None }; NOFILE],
+        }
+    }
+
+    pub fn trap_frame(&mut self) -> &mut TrapFrame {
+        unsafe { &mut *self.trap_frame }
+    }
+
+    pub fn kstack_top(&self) -> usize {
+        self.kstack + virtm::KSTACK_SIZE
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        let len = name.len().min(self.name.len());
+        self.name = [0; 16];
+        self.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+    }
+
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|c| *c == 0).unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..len]).unwrap_or("?")
+    }
+
+    /// Bytes of user memory: the pages mapped for the image, the heap
+    /// and the stack.
+    pub fn mem_size(&self) -> usize {
+        virtm::pt_user_pages(self.page_table) * PAGE_SIZE
+    }
+
+    /// Install `file` in the lowest free fd.
+    pub fn fd_alloc(&mut self, file: Arc<File>) -> Option<usize> {
+        let fd = self.files.iter().position(|slot| slot.is_none())?;
+        self.files[fd] = Some(file);
+        Some(fd)
+    }
+
+    pub fn file(&self, fd: usize) -> Option<&Arc<File>> {
+        self.files.get(fd)?.as_ref()
+    }
+
+    /// Grow (or shrink) the heap by `incr` bytes.
+    /// Returns the previous break.
+    pub fn sbrk(&mut self, incr: isize) -> Option<usize> {
+        let old_brk = self.brk;
+        let new_brk = old_brk.checked_add_signed(incr)?;
+        if !(PAGE_SIZE..USR_LIMIT).contains(&new_brk) {
+            return None;
+        }
+
+        let page_up = |addr: usize| (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        if incr > 0 {
+            let mut page = page_up(old_brk);
+            while page < new_brk {
+                let mapped = match virtm::pg_alloc() {
+                    Some(phys) => {
+                        let mapped = virtm::pt_map(self.page_table, phys as usize, page, PAGE_SIZE,
+                                PTEPerms::READ | PTEPerms::WRITE | PTEPerms::USER, "usr heap");
+                        if !mapped {
+                            virtm::pg_free(phys);
+                        }
+                        mapped
+                    },
+                    None => false,
+                };
+                if !mapped {
+                    virtm::pt_unmap(self.page_table, page_up(old_brk), page - page_up(old_brk), true);
+                    return None;
+                }
+                page += PAGE_SIZE;
+            }
+        }else if incr < 0 {
+            let start = page_up(new_brk);
+            virtm::pt_unmap(self.page_table, start, page_up(old_brk) - start, true);
+        }
+        crate::riscv::sfence_vma();
+
+        self.brk = new_brk;
+        Some(old_brk)
+    }
+}
+
+/// User addresses stay below the user stack.
+pub const USR_LIMIT     : usize = USR_STACK_TOP - USR_STACK_SIZE;
+/// Top of the user stack. Leaves a guard page below the trap frame.
+pub const USR_STACK_TOP : usize = virtm::TRAPFRAME - PAGE_SIZE;
+pub const USR_STACK_SIZE: usize = PAGE_SIZE;
+
+pub struct ProcTable {
+    procs   : [Proc; NPROC],
+    next_pid: usize,
+}
+
+pub static PROC_TABLE: SpinLock<ProcTable> = SpinLock::new(ProcTable::new());
+
+/// Held by `wait` from looking at its children until it sleeps, and by
+/// `proc_exit` while it hands over children and wakes the parent, so an
+/// exit cannot slip in between and its wakeup be lost. Taken before
+/// `PROC_TABLE`.
+pub static WAIT_LOCK: SpinLock<()> = SpinLock::new(());
+
+impl ProcTable {
+    const fn new() -> Self {
+        let mut procs = [const { Proc::new(0) }; NPROC];
+        let mut slot = 0;
+        while slot < NPROC {
+            procs[slot].kstack = virtm::kstack_va(slot);
+            slot += 1;
+        }
+        Self { procs, next_pid: 1 }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Proc> {
+        self.procs.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Proc> {
+        self.procs.iter_mut()
+    }
+
+    pub fn slot_mut(&mut self, slot: usize) -> &mut Proc {
+        &mut self.procs[slot]
+    }
+
+    pub fn get_mut(&mut self, pid: usize) -> Option<&mut Proc> {
+        self.procs.iter_mut().find(|p| p.pid == pid && p.state != ProcState::Unused)
+    }
+}
+
+/// Per-hart state.
+pub struct Cpu {
+    pub proc      : *mut Proc,  // running process, null if none
+    pub context   : Context,    // `swtch` here to enter the scheduler
+    pub slice_left: usize,      // timer ticks left for the running process
+}
+
+pub static mut CPUS: [Cpu; NCPU] = [const {
+    Cpu { proc: core::ptr::null_mut(), context: Context::new(), slice_left: 0 }
+}; NCPU];
+
+pub fn mycpu() -> &'static mut Cpu {
+    let id = RegTP::read();
+    #[allow(static_mut_refs)]
+    unsafe { &mut CPUS[id] }
+}
+
+/// The process running on this hart.
+pub fn myproc() -> Option<&'static mut Proc> {
+    let proc = mycpu().proc;
+    if proc.is_null() {
+        return None;
+    }
+    Some(unsafe { &mut *proc })
+}
+
+/// Map a kernel stack for every process slot.
+/// Run once at boot, after the kernel page table is installed.
+pub fn proc_init() {
+    for slot in 0..NPROC {
+        let kstack = virtm::kstack_va(slot);
+        for page in 0..virtm::KSTACK_PAGES {
+            let kpage = virtm::pg_alloc().expect("proc_init: could not allocate a kernel stack");
+            virtm::vm_map(kpage as usize, kstack + page * PAGE_SIZE, PAGE_SIZE,
+                    PTEPerms::READ | PTEPerms::WRITE, "proc kstack");
+        }
+    }
+    crate::riscv::sfence_vma();
+    kprintln!("Process table initialised");
+}
+
+/// Set up a user page table with only the trampoline and the trap frame.
+pub(crate) fn proc_page_table(trap_frame: *mut TrapFrame) -> Option<*mut u64> {
+    let page_table = virtm::pt_create()?;
+    let mapped = virtm::pt_map(page_table, trampoline::trampoline_start(), virtm::TRAMPOLINE, PAGE_SIZE,
+                    PTEPerms::READ | PTEPerms::EXEC, "usr trampoline")
+        && virtm::pt_map(page_table, trap_frame as usize, virtm::TRAPFRAME, PAGE_SIZE,
+                    PTEPerms::READ | PTEPerms::WRITE, "usr trap frame");
+    if !mapped {
+        virtm::pt_destroy(page_table, false);
+        return None;
+    }
+    Some(page_table)
+}
+
+/// Allocate a process slot, a pid, a trap frame and an (almost) empty
+/// user page table. The process is returned in the `Used` state; the
+/// caller loads a program and marks it `Runnable`.
+pub fn proc_alloc() -> Result<&'static mut Proc, ProcErr> {
+    let proc = {
+        let mut guard = PROC_TABLE.lock();
+        let table = guard.get_mut();
+        let pid = table.next_pid;
+        let proc = table.procs.iter_mut()
+            .find(|p| p.state == ProcState::Unused)
+            .ok_or(ProcErr::NoFreeSlot)?;
+        proc.pid   = pid;
+        proc.state = ProcState::Used;
+        let proc = proc as *mut Proc;
+        table.next_pid += 1;
+        proc
+    };
+    let proc = unsafe { &mut *proc };
+
+    proc.parent      = 0;
+    proc.exit_status = 0;
+    proc.killed      = false;
+    proc.sleep_locks = 0;
+    proc.brk         = 0;
+    proc.context     = Context::new();
+    #[cfg(target_arch = "riscv64")]
+    { proc.context.ra = sched::proc_first_run as *const () as usize; }
+    proc.context.sp  = proc.kstack_top();
+    proc.name        = [0; 16];
+
+    let trap_frame = match virtm::pg_alloc() {
+        Some(page) => page as *mut TrapFrame,
+        None => {
+            proc_free(proc);
+            return Err(ProcErr::OutOfMemory);
+        }
+    };
+    proc.trap_frame = trap_frame;
+
+    match proc_page_table(trap_frame) {
+        Some(page_table) => proc.page_table = page_table,
+        None => {
+            proc_free(proc);
+            return Err(ProcErr::OutOfMemory);
+        }
+    }
+
+    if !proc_map_stack(proc.page_table) {
+        proc_free(proc);
+        return Err(ProcErr::OutOfMemory);
+    }
+    proc.trap_frame().sp = USR_STACK_TOP;
+
+    Ok(proc)
+}
+
+/// Map a fresh user stack below `USR_STACK_TOP`.
+pub(crate) fn proc_map_stack(page_table: *mut u64) -> bool {
+    let Some(stack) = virtm::pg_alloc() else {
+        return false;
+    };
+    if !virtm::pt_map(page_table, stack as usize, USR_STACK_TOP - USR_STACK_SIZE, USR_STACK_SIZE,
+            PTEPerms::READ | PTEPerms::WRITE | PTEPerms::USER, "usr stack") {
+        virtm::pg_free(stack);
+        return false;
+    }
+    true
+}
+
+/// Release everything a process owns and return its slot to the table.
+/// The kernel stack stays mapped for the next user of the slot.
+pub fn proc_free(proc: &mut Proc) {
+    if !proc.trap_frame.is_null() {
+        virtm::pg_free(proc.trap_frame as *mut u8);
+    }
+    virtm::pt_destroy(proc.page_table, true);
+
+    proc.trap_frame = core::ptr::null_mut();
+    proc.page_table = core::ptr::null_mut();
+    proc.brk        = 0;
+    proc.parent     = 0;
+    proc.killed     = false;
+    proc.name       = [0; 16];
+    proc.files      = [const { None }; NOFILE];
+
+    let _guard = PROC_TABLE.lock();
+    proc.pid   = 0;
+    proc.state = ProcState::Unused;
+}
+
+/// The child of `parent` to wait for: `pid`, or with `pid` 0 any child,
+/// one that has exited if there is one. `None` if there is no such child.
+pub fn proc_child(parent: usize, pid: usize) -> Option<(usize, ProcState)> {
+    let guard = PROC_TABLE.lock();
+    let mut found = None;
+    for child in guard.get().iter().filter(|p| p.state != ProcState::Unused && p.parent == parent) {
+        if pid != 0 && child.pid != pid {
+            continue;
+        }
+        if child.state == ProcState::Zombie {
+            return Some((child.pid, child.state));
+        }
+        found.get_or_insert((child.pid, child.state));
+    }
+    found
+}
+
+/// Free a `Zombie` process and return its exit status.
+/// Returns `None` if `pid` is not a zombie.
+pub fn proc_reap(pid: usize) -> Option<i32> {
+    let proc = {
+        let mut guard = PROC_TABLE.lock();
+        let proc = guard.get_mut().get_mut(pid)?;
+        if proc.state != ProcState::Zombie {
+            return None;
+        }
+        // keep other reapers away while we tear it down
+        proc.state = ProcState::Used;
+        proc as *mut Proc
+    };
+    let proc = unsafe { &mut *proc };
+    let status = proc.exit_status;
+    proc_free(proc);
+    Some(status)
+}
+
+/// Free the zombies nobody will wait for, when memory runs out: those
+/// whose parent is gone. Orphans go to init, so this is mostly what
+/// init itself left behind. Returns the processes freed.
+pub fn proc_reap_zombies() -> usize {
+    let mut zombies = [0; NPROC];
+    {
+        let guard = PROC_TABLE.lock();
+        let table = guard.get();
+        let parent_gone = |parent: usize| parent == 0
+            || !table.iter().any(|p| p.pid == parent && p.state != ProcState::Unused);
+        let pids = table.iter()
+            .filter(|p| p.state == ProcState::Zombie && parent_gone(p.parent))
+            .map(|p| p.pid);
+        for (slot, pid) in zombies.iter_mut().zip(pids) {
+            *slot = pid;
+        }
+    }
+    zombies.iter().filter(|&&pid| pid != 0 && proc_reap(pid).is_some()).count()
+}
+
+/// Whether `pid` still has a slot, running or waiting to be reaped.
+pub fn proc_exists(pid: usize) -> bool {
+    PROC_TABLE.lock().get_mut().get_mut(pid).is_some()
+}
+
+/// Kill the process using the most user memory, other than init and the
+/// caller: it exits the next time it heads back to user space. Returns
+/// its pid.
+pub fn proc_kill_largest() -> Option<usize> {
+    let me = myproc().map_or(0, |proc| proc.pid);
+    let mut guard = PROC_TABLE.lock();
+    let victim = guard.get_mut().iter_mut()
+        .filter(|p| p.pid > 1 && p.pid != me && !p.killed)
+        .filter(|p| matches!(p.state, ProcState::Runnable | ProcState::Running | ProcState::Sleeping))
+        .max_by_key(|p| p.mem_size())?;
+    victim.killed = true;
+    // sleepers re-check what they wait for, waking them early is fine
+    if victim.state == ProcState::Sleeping {
+        victim.state = ProcState::Runnable;
+    }
+    kprintln!("Killing process {} ({}), {} KiB of user memory", victim.pid, victim.name(), victim.mem_size() / 1024);
+    Some(victim.pid)
+}
+
+/// Terminate the running process. Its slot stays a `Zombie` until it
+/// is reaped. Its children are handed to init, which reaps them.
+pub fn proc_exit(status: i32) -> ! {
+    let proc = myproc().expect("proc_exit: no process");
+    kprintln!("Process {} ({}) exited with status {}", proc.pid, proc.name(), status);
+    proc.files = [const { None }; NOFILE];
+    // nothing runs after init, what it leaves allocated has leaked
+    #[cfg(feature = "alloc-track")]
+    if proc.pid == 1 {
+        crate::mem::alloc::track::dump(0);
+    }
+
+    let wait = WAIT_LOCK.lock();
+    // released by the scheduler
+    let mut guard = PROC_TABLE.lock();
+    let table = guard.get_mut();
+    let (pid, init) = (proc.pid, if proc.pid == 1 { 0 } else { 1 });
+    let mut zombies = false;
+    for child in table.iter_mut().filter(|p| p.parent == pid) {
+        child.parent = init;
+        zombies |= child.state == ProcState::Zombie;
+    }
+    // a parent in `wait` sleeps on its own `Proc`
+    if zombies {
+        if let Some(init) = table.get_mut(init) {
+            let chan = init as *const Proc as usize;
+            sched::wakeup_locked(table, chan);
+        }
+    }
+    if let Some(parent) = table.get_mut(proc.parent) {
+        let chan = parent as *const Proc as usize;
+        sched::wakeup_locked(table, chan);
+    }
+    proc.exit_status = status;
+    proc.state = ProcState::Zombie;
+    // interrupts stay off until the scheduler lets go of PROC_TABLE
+    let _ = wait.unlock_irq_off();
+    sched::sched();
+    panic!("proc_exit: zombie resumed");
+}
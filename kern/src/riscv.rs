@@ -1,9 +1,33 @@
+/// `core::arch::asm!` on the target. Host builds (the `sim` crate) have
+/// no CSRs: reads give 0 and writes are dropped.
+macro_rules! rv_asm {
+    ($tmpl:literal $(, $kind:ident(reg) $var:expr)* $(,)?) => {{
+        #[cfg(target_arch = "riscv64")]
+        core::arch::asm!($tmpl $(, $kind(reg) $var)*);
+        #[cfg(not(target_arch = "riscv64"))]
+        { $( rv_asm!(@host $kind $var); )* host_asm(); }
+    }};
+    (@host out $var:expr) => { $var = 0; };
+    (@host in $var:expr) => { let _ = $var; };
+}
+
+/// Stands in for the instruction on the host, keeps call sites `unsafe`.
+#[cfg(not(target_arch = "riscv64"))]
+#[inline(always)]
+unsafe fn host_asm() {}
+
 use crate::plic;
 use crate::virtm;
 use crate::uart;
+use crate::timer;
+use crate::platform;
+use crate::initramfs;
+
+use core::sync::atomic::{AtomicBool, Ordering};
 
-#[allow(non_upper_case_globals)]
-static mut sys_initialised: bool = false;
+/// Set by hart 0 once the kernel page table is built; the others wait
+/// for it before loading `KERN_SATP`.
+static SYS_INITIALISED: AtomicBool = AtomicBool::new(false);
 
 extern "C" {
     fn kern_exec() -> !;
@@ -11,16 +35,16 @@ extern "C" {
 }
 
 #[export_name = "sys_init"]
-pub extern "C" fn sys_init()
+pub extern "C" fn sys_init(_hartid: usize, dtb: usize)
 {
     let mut x = RegMStatus::read();
     x &= !RegMStatus::MSTATUS_MPP_MASK;
     x |=  RegMStatus::MSTATUS_MPP_S;
     RegMStatus::write(x);
 
-    RegMEPC::write(kern_exec as usize);
+    RegMEPC::write(kern_exec as *const () as usize);
 
-    RegSTVec::write(kern_trap as usize);
+    RegSTVec::write(kern_trap as *const () as usize);
 
     RegMEDeleg::write(0xffff);
     RegMIDeleg::write(0xffff);
@@ -32,7 +56,7 @@ pub extern "C" fn sys_init()
 
     RegSIE::write(intr);
     intr_on();
-    
+
     // 
     RegPmpAddr0::write(0x3fffffffffffff);
     RegPmpCfg0::write(0xf);
@@ -40,18 +64,24 @@ pub extern "C" fn sys_init()
     RegTP::write(cpu_id);
 
     if cpu_id == 0 { 
+        platform::platform_init(dtb);
         uart::uart_init();
         plic::plic_init(0); 
-        // virtm::kern_vm_init();
-        unsafe {sys_initialised = true};
+        initramfs::initramfs_probe(dtb);
+        virtm::kern_vm_init();
+        SYS_INITIALISED.store(true, Ordering::Release);
     }
 
-    while (cpu_id != 0) && !unsafe { sys_initialised } { }
+    while (cpu_id != 0) && !SYS_INITIALISED.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+
+    // the tick length comes from the DTB
+    timer::timer_init();
 
     unsafe {
-        // RegSATP::set_root_page_sv39_(virtm::KERN_SATP);
-        RegSATP::write(0);
-        core::arch::asm!("mret")
+        RegSATP::set_root_page_sv39_(virtm::KERN_SATP);
+        rv_asm!("mret")
     };
 }
 
@@ -73,6 +103,12 @@ pub fn intr_get() -> bool
     RegSStatus::intr_get()
 }
 
+/// Flush the TLB after page table updates.
+#[inline]
+pub fn sfence_vma() {
+    unsafe { rv_asm!("sfence.vma zero, zero"); };
+}
+
 
 
 pub trait Register {
@@ -85,7 +121,7 @@ impl Register for RegMHartId {
     fn read() -> usize {
         let x: usize;
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrr {}, mhartid",
                 out(reg) x
             )
@@ -107,7 +143,7 @@ impl Register for RegMStatus{
     fn read() -> usize {
         let x: usize;
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrr {}, mstatus",
                 out(reg) x,
             )
@@ -117,7 +153,7 @@ impl Register for RegMStatus{
 
     fn write(x: usize) {
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrw mstatus, {}",
                 in(reg) x
             )
@@ -132,7 +168,7 @@ impl Register for RegMEPC{
 
     fn write(x: usize) {
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrw mepc, {}",
                 in(reg) x
             )
@@ -147,7 +183,7 @@ impl Register for RegSStatus {
     fn read() -> usize {
         let x: usize;
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrr {}, sstatus",
                 out(reg) x
             )
@@ -157,7 +193,7 @@ impl Register for RegSStatus {
 
     fn write(x: usize) {
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrw sstatus, {}",
                 in(reg) x
             )
@@ -176,12 +212,12 @@ impl RegSStatus {
 
     pub fn intr_on() {
         unsafe {
-            core::arch::asm!("csrsi sstatus, 1 << 1"); 
+            rv_asm!("csrsi sstatus, 1 << 1"); 
         }
     }
     pub fn intr_off() {
         unsafe {
-            core::arch::asm!("csrci sstatus, 1 << 1"); 
+            rv_asm!("csrci sstatus, 1 << 1"); 
         }
     }
     pub fn intr_get() -> bool
@@ -199,7 +235,7 @@ impl Register for RegSIP {
     fn read() -> usize {
         let x: usize;
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrr {}, sip",
                 out(reg) x,
             )
@@ -209,7 +245,7 @@ impl Register for RegSIP {
 
     fn write(x: usize) {
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrw sip, {}",
                 in(reg) x,
             )
@@ -229,7 +265,7 @@ impl Register for RegSIE{
     fn read() -> usize {
         let x: usize;
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrr {}, sie",
                 out(reg) x,
             )
@@ -239,7 +275,7 @@ impl Register for RegSIE{
 
     fn write(x: usize) {
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrw sie, {}",
                 in(reg) x,
             )
@@ -252,7 +288,7 @@ impl Register for RegMIE{
     fn read() -> usize {
         let x: usize;
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrr {}, mie",
                 out(reg) x,
             )
@@ -262,7 +298,7 @@ impl Register for RegMIE{
 
     fn write(x: usize) {
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrw mie, {}",
                 in(reg) x,
             )
@@ -275,7 +311,7 @@ impl Register for RegSEPC{
     fn read() -> usize {
         let x: usize;
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrr {}, sepc",
                 out(reg) x,
             )
@@ -285,7 +321,7 @@ impl Register for RegSEPC{
 
     fn write(x: usize) {
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrw sepc, {}",
                 in(reg) x
             )
@@ -300,7 +336,7 @@ impl Register for RegMEDeleg{
     fn read() -> usize {
         let x: usize;
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrr {}, medeleg",
                 out(reg) x,
             )
@@ -309,7 +345,7 @@ impl Register for RegMEDeleg{
     }
     fn write(x: usize) {
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrw medeleg, {}",
                 in(reg) x
             )
@@ -325,7 +361,7 @@ impl Register for RegMIDeleg {
     fn read() -> usize {
         let x: usize;
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrr {}, mideleg",
                 out(reg) x,
             )
@@ -335,7 +371,7 @@ impl Register for RegMIDeleg {
 
     fn write(x: usize) {
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrw mideleg, {}",
                 in(reg) x
             )
@@ -354,7 +390,7 @@ impl Register for RegSTVec {
     fn read() -> usize {
         let x: usize;
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrr {}, stvec",
                 out(reg) x,
             )
@@ -364,7 +400,7 @@ impl Register for RegSTVec {
 
     fn write(x: usize) {
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrw stvec, {}",
                 in(reg) x
             )
@@ -380,7 +416,7 @@ impl Register for RegSTimeCmp{
         let x: usize;
         unsafe {
             // "csrr {}, stimecmp",
-            core::arch::asm!(
+            rv_asm!(
                 "csrr {}, 0x14d",
                 out(reg) x,
             )
@@ -391,7 +427,7 @@ impl Register for RegSTimeCmp{
     fn write(x: usize) {
         unsafe {
             // "csrr 0x14d, {}",
-            core::arch::asm!(
+            rv_asm!(
                 "csrw 0x14d, {}",
                 in(reg) x
             )
@@ -402,12 +438,15 @@ impl Register for RegSTimeCmp{
 
 /// Machine Environment Configuration Register
 pub struct RegMEnvCfg;
+impl RegMEnvCfg {
+    pub const MENVCFG_STCE: usize = 1 << 63;  // enable `stimecmp` (Sstc)
+}
 impl Register for RegMEnvCfg{
     fn read() -> usize {
         let x: usize;
         unsafe {
             // "csrr {}, menvcfg",
-            core::arch::asm!(
+            rv_asm!(
                 "csrr {}, 0x30a",
                 out(reg) x
             )
@@ -418,7 +457,7 @@ impl Register for RegMEnvCfg{
     fn write(x: usize) {
         unsafe {
             // "csrw menvcfg, {}",
-            core::arch::asm!(
+            rv_asm!(
                 "csrw 0x30a, {}",
                 in(reg) x
             )
@@ -434,7 +473,7 @@ impl Register for RegPmpCfg0 {
 
     fn write(x: usize) {
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrw pmpcfg0, {}",
                 in(reg) x
             )
@@ -449,7 +488,7 @@ impl Register for RegPmpAddr0 {
 
     fn write(x: usize) {
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrw pmpaddr0, {}",
                 in(reg) x
             )
@@ -472,7 +511,7 @@ impl RegSATP {
     pub fn read() -> usize {
         let x: usize;
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrr {}, satp",
                 out(reg) x
             )
@@ -482,18 +521,23 @@ impl RegSATP {
 
     pub fn write(x: u64) {
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrw satp, {}",
                 in(reg) x
             )
         }
     }
 
-    fn set_root_page_sv39_(addr: u64){
-        unsafe { core::arch::asm!("sfence.vma zero, zero"); };
-        let addr = (addr >> 12) | RegSATP::SV39;
+    pub fn set_root_page_sv39_(addr: u64){
+        unsafe { rv_asm!("sfence.vma zero, zero"); };
+        let addr = RegSATP::sv39(addr);
         RegSATP::write(addr);
-        unsafe { core::arch::asm!("sfence.vma zero, zero"); };
+        unsafe { rv_asm!("sfence.vma zero, zero"); };
+    }
+
+    /// `satp` value selecting the SV39 page table rooted at `addr`.
+    pub const fn sv39(addr: u64) -> u64 {
+        (addr >> 12) | RegSATP::SV39
     }
 
 }
@@ -504,7 +548,7 @@ impl Register for RegSCause {
     fn read() -> usize {
         let x: usize;
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrr {}, scause",
                 out(reg) x
             )
@@ -520,7 +564,7 @@ impl Register for RegSTVal {
     fn read() -> usize {
         let x: usize;
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrr {}, stval",
                 out(reg) x
             )
@@ -531,11 +575,14 @@ impl Register for RegSTVal {
 
 /// Machine mode counter enable
 pub struct RegMCounterEn;
+impl RegMCounterEn {
+    pub const MCOUNTEREN_TM: usize = 1 << 1;  // S-mode may read `time`
+}
 impl Register for RegMCounterEn {
     fn read() -> usize {
        let x: usize;
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrr {}, mcounteren",
                 out(reg) x
             )
@@ -545,7 +592,7 @@ impl Register for RegMCounterEn {
 
     fn write(x: usize) {
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrw mcounteren, {}",
                 in(reg) x
             )
@@ -560,7 +607,7 @@ impl Register for RegTime {
     fn read() -> usize {
         let x: usize;
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "csrr {}, time",
                 out(reg) x
             )
@@ -576,7 +623,7 @@ impl Register for RegSP{
     fn read() -> usize {
         let x: usize;
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "mv {}, sp",
                 out(reg) x
             )
@@ -590,7 +637,7 @@ impl Register for RegTP{
     fn read() -> usize {
         let x: usize;
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "mv {}, tp",
                 out(reg) x
             )
@@ -600,7 +647,7 @@ impl Register for RegTP{
 
     fn write(x: usize) {
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "mv tp, {}",
                 in(reg) x
             )
@@ -610,10 +657,13 @@ impl Register for RegTP{
 
 pub struct RegRA;
 impl Register for RegRA {
+    /// Inlined, so this is the caller's `ra`, not the return address of
+    /// `read` itself.
+    #[inline(always)]
     fn read() -> usize {
         let x: usize;
         unsafe {
-            core::arch::asm!(
+            rv_asm!(
                 "mv {}, ra",
                 out(reg) x
 
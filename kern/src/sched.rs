@@ -0,0 +1,204 @@
+//! Per-hart round-robin scheduler.
+//!
+//! Every hart runs `scheduler` on its boot stack and switches into
+//! runnable processes with `swtch`. A process gives the hart back by
+//! switching to the scheduler's context, either voluntarily (`yield_proc`,
+//! `proc_exit`) or when its time slice runs out on a timer tick.
+//!
+//! The `PROC_TABLE` lock is held across every switch: the side that
+//! resumes releases the lock the other side took.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::proc::{self, ProcState, ProcTable, PROC_TABLE};
+use crate::riscv;
+#[cfg(target_arch = "riscv64")]
+use crate::ktrap;
+#[cfg(target_arch = "riscv64")]
+use crate::proc::{Context, NPROC};
+use crate::sync::SpinLockGuard;
+
+/// Default time slice, in timer ticks.
+pub const TIME_SLICE: usize = 5;
+
+static SLICE_TICKS: AtomicUsize = AtomicUsize::new(TIME_SLICE);
+
+/// Set the number of timer ticks a process may run before it is preempted.
+pub fn set_time_slice(ticks: usize) {
+    SLICE_TICKS.store(ticks.max(1), Ordering::Relaxed);
+}
+
+pub fn time_slice() -> usize {
+    SLICE_TICKS.load(Ordering::Relaxed)
+}
+
+/// Save the callee-saved registers in `old` and load them from `new`.
+/// Returns on `new`'s stack, at `new.ra`.
+///
+/// # Safety
+/// `new` must hold a context saved by `swtch`, or one set up to start a
+/// new process, whose stack is still mapped.
+#[cfg(target_arch = "riscv64")]
+#[unsafe(naked)]
+#[export_name = "swtch"]
+pub unsafe extern "C" fn swtch(_old: *mut Context, _new: *const Context)
+{
+    core::arch::naked_asm!(
+        "
+        sd ra, 0(a0)
+        sd sp, 8(a0)
+        sd s0, 16(a0)
+        sd s1, 24(a0)
+        sd s2, 32(a0)
+        sd s3, 40(a0)
+        sd s4, 48(a0)
+        sd s5, 56(a0)
+        sd s6, 64(a0)
+        sd s7, 72(a0)
+        sd s8, 80(a0)
+        sd s9, 88(a0)
+        sd s10, 96(a0)
+        sd s11, 104(a0)
+
+        ld ra, 0(a1)
+        ld sp, 8(a1)
+        ld s0, 16(a1)
+        ld s1, 24(a1)
+        ld s2, 32(a1)
+        ld s3, 40(a1)
+        ld s4, 48(a1)
+        ld s5, 56(a1)
+        ld s6, 64(a1)
+        ld s7, 72(a1)
+        ld s8, 80(a1)
+        ld s9, 88(a1)
+        ld s10, 96(a1)
+        ld s11, 104(a1)
+        ret
+        "
+    );
+}
+
+/// Run processes on this hart, forever.
+#[cfg(target_arch = "riscv64")]
+pub fn scheduler() -> ! {
+    let cpu = proc::mycpu();
+    cpu.proc = core::ptr::null_mut();
+
+    loop {
+        // let devices interrupt us, in case every process is sleeping
+        riscv::intr_on();
+        crate::console::cons_commands();
+
+        let mut ran = false;
+        for slot in 0..NPROC {
+            let mut guard = PROC_TABLE.lock();
+            let proc = guard.get_mut().slot_mut(slot);
+            if proc.state == ProcState::Runnable {
+                proc.state     = ProcState::Running;
+                cpu.proc       = proc;
+                cpu.slice_left = time_slice();
+                unsafe { swtch(&mut cpu.context, &proc.context) };
+
+                // back from the process, which took the lock we release here
+                cpu.proc = core::ptr::null_mut();
+                ran = true;
+            }
+            drop(guard);
+        }
+
+        if !ran {
+            unsafe { core::arch::asm!("wfi") };
+        }
+    }
+}
+
+/// Switch to this hart's scheduler.
+/// The caller holds `PROC_TABLE` and has moved the process out of `Running`.
+pub fn sched() {
+    let proc = proc::myproc().expect("sched: no process");
+    if proc.state == ProcState::Running {
+        panic!("sched: process still running");
+    }
+    #[cfg(target_arch = "riscv64")]
+    unsafe { swtch(&mut proc.context, &proc::mycpu().context) };
+
+    // host builds (the `sim` crate) have no scheduler to switch to: the
+    // process is picked again right away
+    #[cfg(not(target_arch = "riscv64"))]
+    if proc.state != ProcState::Zombie {
+        proc.state = ProcState::Running;
+    }
+}
+
+/// Give up the hart for one scheduling round.
+pub fn yield_proc() {
+    if let Some(proc) = proc::myproc() {
+        let _guard = PROC_TABLE.lock();
+        proc.state = ProcState::Runnable;
+        sched();
+    }
+}
+
+/// Release `guard` and sleep until `wakeup(chan)`, then take the lock
+/// again. `chan` is any address both sides agree on.
+///
+/// Wakeups are not counted, so callers re-check what they wait for in a
+/// loop. Without a process (early boot) this only drops the lock for a
+/// moment and lets interrupts in.
+pub fn sleep<'a, T>(chan: usize, guard: SpinLockGuard<'a, T>) -> SpinLockGuard<'a, T> {
+    let Some(proc) = proc::myproc() else {
+        let (lock, irq_enabled) = guard.unlock_irq_off();
+        if irq_enabled {
+            riscv::intr_on();
+        }
+        core::hint::spin_loop();
+        return lock.relock(irq_enabled);
+    };
+
+    // take PROC_TABLE before letting go of `guard`, so a `wakeup` on
+    // another hart cannot run in between and be missed
+    let ptable = PROC_TABLE.lock();
+    let (lock, irq_enabled) = guard.unlock_irq_off();
+    proc.chan  = chan;
+    proc.state = ProcState::Sleeping;
+    sched();
+    proc.chan = 0;
+    drop(ptable);
+    lock.relock(irq_enabled)
+}
+
+/// Make every process sleeping on `chan` runnable.
+pub fn wakeup(chan: usize) {
+    wakeup_locked(PROC_TABLE.lock().get_mut(), chan);
+}
+
+/// `wakeup` for callers already holding `PROC_TABLE`.
+pub fn wakeup_locked(table: &mut ProcTable, chan: usize) {
+    for proc in table.iter_mut() {
+        if proc.state == ProcState::Sleeping && proc.chan == chan {
+            proc.state = ProcState::Runnable;
+        }
+    }
+}
+
+/// Account a timer tick to the running process and preempt it once its
+/// time slice is used up.
+pub fn tick() {
+    let cpu = proc::mycpu();
+    if cpu.proc.is_null() {
+        return;
+    }
+    cpu.slice_left = cpu.slice_left.saturating_sub(1);
+    if cpu.slice_left == 0 {
+        yield_proc();
+    }
+}
+
+/// First kernel code a new process runs, `swtch`ed to by `scheduler`.
+#[cfg(target_arch = "riscv64")]
+pub extern "C" fn proc_first_run() -> ! {
+    // still holding the lock from the scheduler
+    unsafe { PROC_TABLE.force_unlock() };
+    ktrap::usr_trap_ret()
+}
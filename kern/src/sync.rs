@@ -62,6 +62,16 @@ impl <'a, T> SpinLockGuard<'a, T>{
     pub fn get(&self) -> &T{
         self.deref()
     }
+
+    /// Release the lock but leave interrupts off. Returns the lock and
+    /// whether dropping the guard would have turned interrupts back on,
+    /// for `SpinLock::relock`.
+    pub(crate) fn unlock_irq_off(self) -> (&'a SpinLock<T>, bool) {
+        let (lock, irq_enabled) = (self.lock, self.irq_enabled);
+        core::mem::forget(self);
+        lock.key.store(0, Ordering::Release);
+        (lock, irq_enabled)
+    }
 }
 
 impl <'a, T> Drop for SpinLockGuard <'a, T>{
@@ -109,4 +119,108 @@ impl <T> SpinLock <T>{
             irq_enabled
         }
     }
-}
\ No newline at end of file
+
+    /// Take the lock again after `SpinLockGuard::unlock_irq_off`; the
+    /// new guard restores interrupts like the old one would have.
+    pub(crate) fn relock(&self, irq_enabled: bool) -> SpinLockGuard<'_, T> {
+        let mut guard = self.lock();
+        guard.irq_enabled = irq_enabled;
+        guard
+    }
+
+    /// Release the lock without going through a guard.
+    ///
+    /// # Safety
+    /// Only for locks that are handed over across a context switch,
+    /// e.g. a new process releasing the `PROC_TABLE` lock its hart's
+    /// scheduler took before switching to it. The caller must know the
+    /// lock is held and that the holder's guard will never be dropped.
+    pub unsafe fn force_unlock(&self) {
+        self.key.store(0, Ordering::Release);
+    }
+}
+/// A lock whose waiters sleep instead of spinning, for holders that
+/// may block themselves (e.g. on disk I/O). Interrupts stay on while
+/// it is held; only a process may sleep, early boot code spins.
+pub struct SleepLock <T>{
+    locked: SpinLock<bool>,
+    data:   UnsafeCell<T>,
+}
+
+unsafe impl <T: Send> Send for SleepLock<T> {}
+unsafe impl <T: Send> Sync for SleepLock<T> {}
+
+pub struct SleepLockGuard <'a, T> {
+    lock: &'a SleepLock<T>,
+}
+
+impl <T> SleepLock <T>{
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: SpinLock::new(false),
+            data:   UnsafeCell::new(data),
+        }
+    }
+
+    fn chan(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    pub fn lock(&self) -> SleepLockGuard<'_, T> {
+        let mut guard = self.locked.lock();
+        while *guard.get() {
+            guard = crate::sched::sleep(self.chan(), guard);
+        }
+        *guard.get_mut() = true;
+        Self::held(1);
+        SleepLockGuard { lock: self }
+    }
+
+    /// The lock if it is free, without sleeping.
+    pub fn try_lock(&self) -> Option<SleepLockGuard<'_, T>> {
+        let mut guard = self.locked.lock();
+        if *guard.get() {
+            return None;
+        }
+        *guard.get_mut() = true;
+        Self::held(1);
+        Some(SleepLockGuard { lock: self })
+    }
+
+    /// Count the sleep locks the running process holds, for `mem::oom`.
+    fn held(delta: isize) {
+        if let Some(proc) = crate::proc::myproc() {
+            proc.sleep_locks = proc.sleep_locks.wrapping_add_signed(delta);
+        }
+    }
+}
+
+impl <'a, T> SleepLockGuard<'a, T>{
+    pub fn get_mut(&mut self) -> &mut T{
+        self.deref_mut()
+    }
+    pub fn get(&self) -> &T{
+        self.deref()
+    }
+}
+
+impl <'a, T> Drop for SleepLockGuard <'a, T>{
+    fn drop(&mut self){
+        *self.lock.locked.lock().get_mut() = false;
+        SleepLock::<T>::held(-1);
+        crate::sched::wakeup(self.lock.chan());
+    }
+}
+
+impl <'a, T> Deref for SleepLockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe {& *(self.lock.data.get()) }
+    }
+}
+
+impl <'a, T> DerefMut for SleepLockGuard<'a, T>{
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe {&mut *(self.lock.data.get())}
+    }
+}
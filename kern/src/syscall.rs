@@ -0,0 +1,212 @@
+//! System calls.
+//!
+//! ABI (entered with `ecall` from U-mode):
+//! - `a7`      : system call number
+//! - `a0`..`a5`: arguments
+//! - `a0`      : return value. Failures return `-errno`.
+
+use crate::ktrap::TrapFrame;
+use crate::{exec, proc, sched, usr, virtm};
+use crate::mem::alloc::{self, HeapInfo};
+use crate::sysfile::{arg_path, sys_close, sys_getdents, sys_lseek, sys_mkdir, sys_open, sys_read,
+                     sys_stat, sys_unlink, sys_write};
+
+pub const SYS_WRITE   : usize = 1;
+pub const SYS_EXIT    : usize = 2;
+pub const SYS_GETPID  : usize = 3;
+pub const SYS_YIELD   : usize = 4;
+pub const SYS_SBRK    : usize = 5;
+pub const SYS_WAIT    : usize = 6;
+pub const SYS_EXEC    : usize = 7;
+pub const SYS_SPAWN   : usize = 8;
+pub const SYS_OPEN    : usize = 9;
+pub const SYS_READ    : usize = 10;
+pub const SYS_CLOSE   : usize = 11;
+pub const SYS_LSEEK   : usize = 12;
+pub const SYS_STAT    : usize = 13;
+pub const SYS_GETDENTS: usize = 14;
+pub const SYS_MKDIR   : usize = 15;
+pub const SYS_UNLINK  : usize = 16;
+pub const SYS_HEAPINFO: usize = 17;
+
+/// Error numbers handed back to user space (negated) in `a0`.
+/// Values follow the usual POSIX numbering.
+#[repr(isize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Errno {
+    EPERM        = 1,
+    ENOENT       = 2,
+    ESRCH        = 3,
+    EINTR        = 4,
+    EIO          = 5,
+    ENOEXEC      = 8,
+    EBADF        = 9,
+    ECHILD       = 10,
+    ENOMEM       = 12,
+    EFAULT       = 14,
+    EBUSY        = 16,
+    EEXIST       = 17,
+    ENOTDIR      = 20,
+    EISDIR       = 21,
+    EINVAL       = 22,
+    EMFILE       = 24,
+    ENOSPC       = 28,
+    EROFS        = 30,
+    ENAMETOOLONG = 36,
+    ENOSYS       = 38,
+    ENOTEMPTY    = 39,
+    EOPNOTSUPP   = 95,
+}
+
+pub type SysResult = Result<usize, Errno>;
+type SysCallFn = fn(&mut TrapFrame) -> SysResult;
+
+/// Dispatch table, indexed by system call number.
+static SYSCALLS: [Option<SysCallFn>; 18] = [
+    None,
+    Some(sys_write),    // SYS_WRITE
+    Some(sys_exit),     // SYS_EXIT
+    Some(sys_getpid),   // SYS_GETPID
+    Some(sys_yield),    // SYS_YIELD
+    Some(sys_sbrk),     // SYS_SBRK
+    Some(sys_wait),     // SYS_WAIT
+    Some(sys_exec),     // SYS_EXEC
+    Some(sys_spawn),    // SYS_SPAWN
+    Some(sys_open),     // SYS_OPEN
+    Some(sys_read),     // SYS_READ
+    Some(sys_close),    // SYS_CLOSE
+    Some(sys_lseek),    // SYS_LSEEK
+    Some(sys_stat),     // SYS_STAT
+    Some(sys_getdents), // SYS_GETDENTS
+    Some(sys_mkdir),    // SYS_MKDIR
+    Some(sys_unlink),   // SYS_UNLINK
+    Some(sys_heapinfo), // SYS_HEAPINFO
+];
+
+/// Argument `n` (0..=5) of the current system call.
+#[inline]
+pub fn arg(tf: &TrapFrame, n: usize) -> usize {
+    match n {
+        0 => tf.a0,
+        1 => tf.a1,
+        2 => tf.a2,
+        3 => tf.a3,
+        4 => tf.a4,
+        5 => tf.a5,
+        _ => 0,
+    }
+}
+
+/// Run the system call requested in `tf` and store its result in `a0`.
+/// The caller has already moved `sepc` past the `ecall`.
+pub fn syscall(tf: &mut TrapFrame) {
+    let num = tf.a7;
+    let ret = match SYSCALLS.get(num).copied().flatten() {
+        Some(handler) => handler(tf),
+        None => {
+            kprintln!("Unknown system call: {}", num);
+            Err(Errno::ENOSYS)
+        }
+    };
+
+    tf.a0 = match ret {
+        Ok(value) => value,
+        Err(errno) => (-(errno as isize)) as usize,
+    };
+}
+
+/// The calling process. System calls only come from user space, so
+/// there always is one.
+fn current() -> &'static mut proc::Proc {
+    proc::myproc().expect("syscall: no process")
+}
+
+/// `exit(status)`
+fn sys_exit(tf: &mut TrapFrame) -> SysResult {
+    proc::proc_exit(arg(tf, 0) as i32)
+}
+
+/// `getpid()`
+fn sys_getpid(_tf: &mut TrapFrame) -> SysResult {
+    Ok(current().pid)
+}
+
+/// `yield()`
+fn sys_yield(_tf: &mut TrapFrame) -> SysResult {
+    sched::yield_proc();
+    Ok(0)
+}
+
+/// `sbrk(incr)`, returns the previous program break.
+fn sys_sbrk(tf: &mut TrapFrame) -> SysResult {
+    current().sbrk(arg(tf, 0) as isize).ok_or(Errno::ENOMEM)
+}
+
+/// `wait(pid, status)`: wait for the child `pid`, or any child if `pid`
+/// is 0, to exit and free it. Stores its exit status (an `i32`) at
+/// `status` unless that is null. Returns the pid of the child.
+fn sys_wait(tf: &mut TrapFrame) -> SysResult {
+    let pid = arg(tf, 0);
+    let dst = arg(tf, 1);
+    let me   = current();
+    let chan = me as *const proc::Proc as usize;
+    let mut wait = proc::WAIT_LOCK.lock();
+    loop {
+        let (child, state) = proc::proc_child(me.pid, pid).ok_or(Errno::ECHILD)?;
+        if state == proc::ProcState::Zombie {
+            if let Some(status) = proc::proc_reap(child) {
+                drop(wait);
+                if dst != 0 {
+                    virtm::copy_out(me.page_table, dst, &status.to_le_bytes()).map_err(|_| Errno::EFAULT)?;
+                }
+                return Ok(child);
+            }
+        }
+        if me.killed {
+            return Err(Errno::EINTR);
+        }
+        // woken by the child's `proc_exit`
+        wait = sched::sleep(chan, wait);
+    }
+}
+
+/// `exec(path, len)`: replace the calling program with the executable
+/// at `path`. Does not return to the old program on success.
+fn sys_exec(tf: &mut TrapFrame) -> SysResult {
+    let path  = arg_path(tf, 0)?;
+    let image = usr::usr_find(&path)?;
+
+    let proc = current();
+    exec::exec(proc, &image).map_err(|err| {
+        kprintln!("exec {}: {:?}", path, err);
+        match err {
+            exec::LoadErr::OutOfMemory => Errno::ENOMEM,
+            _ => Errno::ENOEXEC,
+        }
+    })?;
+    proc.set_name(path.rsplit('/').next().unwrap_or(&path));
+    Ok(0)
+}
+
+/// `spawn(path, len)`: start the executable at `path` in a new process,
+/// a child of the caller. Returns its pid.
+fn sys_spawn(tf: &mut TrapFrame) -> SysResult {
+    let path = arg_path(tf, 0)?;
+    usr::usr_spawn(&path).ok_or(Errno::ENOENT)
+}
+
+/// `heapinfo(info)`: copy the kernel heap's `HeapInfo` to `info`, or
+/// print the heap report on the console if `info` is null.
+fn sys_heapinfo(tf: &mut TrapFrame) -> SysResult {
+    let dst = arg(tf, 0);
+    if dst == 0 {
+        alloc::heap_report();
+        return Ok(0);
+    }
+    let info  = alloc::heap_info().ok_or(Errno::ENOMEM)?;
+    let bytes = unsafe {
+        core::slice::from_raw_parts(&info as *const HeapInfo as *const u8, core::mem::size_of::<HeapInfo>())
+    };
+    virtm::copy_out(current().page_table, dst, bytes).map_err(|_| Errno::EFAULT)?;
+    Ok(0)
+}
@@ -0,0 +1,194 @@
+//! File system calls. Paths are passed as a pointer and a length.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+
+use crate::fs::{self, File, FsErr, Stat, NAME_MAX, PATH_MAX};
+use crate::ktrap::TrapFrame;
+use crate::proc;
+use crate::syscall::{arg, Errno, SysResult};
+use crate::virtm;
+
+/// Bytes moved between user memory and a file per step.
+const IO_CHUNK: usize = 512;
+
+impl From<FsErr> for Errno {
+    fn from(err: FsErr) -> Self {
+        match err {
+            FsErr::NotFound    => Errno::ENOENT,
+            FsErr::NotDir      => Errno::ENOTDIR,
+            FsErr::IsDir       => Errno::EISDIR,
+            FsErr::Exists      => Errno::EEXIST,
+            FsErr::NotEmpty    => Errno::ENOTEMPTY,
+            FsErr::ReadOnly    => Errno::EROFS,
+            FsErr::NoSpace     => Errno::ENOSPC,
+            FsErr::NameTooLong => Errno::ENAMETOOLONG,
+            FsErr::InvalidPath => Errno::ENOENT,
+            FsErr::Busy        => Errno::EBUSY,
+            FsErr::Invalid     => Errno::EINVAL,
+            FsErr::Io          => Errno::EIO,
+            FsErr::Corrupt     => Errno::EIO,
+            FsErr::Unsupported => Errno::EOPNOTSUPP,
+            FsErr::Interrupted => Errno::EINTR,
+        }
+    }
+}
+
+fn current() -> &'static mut proc::Proc {
+    proc::myproc().expect("syscall: no process")
+}
+
+/// Copy in the path whose pointer and length are arguments `n` and `n + 1`.
+pub(crate) fn arg_path(tf: &TrapFrame, n: usize) -> Result<String, Errno> {
+    let ptr = arg(tf, n);
+    let len = arg(tf, n + 1);
+    if len > PATH_MAX {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    let mut path = [0u8; PATH_MAX];
+    virtm::copy_in(current().page_table, &mut path[..len], ptr).map_err(|_| Errno::EFAULT)?;
+    core::str::from_utf8(&path[..len]).map(String::from).map_err(|_| Errno::EINVAL)
+}
+
+fn arg_file(tf: &TrapFrame, n: usize) -> Result<Arc<File>, Errno> {
+    current().file(arg(tf, n)).cloned().ok_or(Errno::EBADF)
+}
+
+/// `open(path, len, flags)`, returns the new fd.
+pub fn sys_open(tf: &mut TrapFrame) -> SysResult {
+    let path  = arg_path(tf, 0)?;
+    let flags = arg(tf, 2);
+    let file  = fs::open(&path, flags)?;
+    current().fd_alloc(file).ok_or(Errno::EMFILE)
+}
+
+/// `read(fd, buf, len)`
+pub fn sys_read(tf: &mut TrapFrame) -> SysResult {
+    let file = arg_file(tf, 0)?;
+    let buf  = arg(tf, 1);
+    let len  = arg(tf, 2);
+    let page_table = current().page_table;
+
+    let mut chunk = [0u8; IO_CHUNK];
+    let mut done = 0;
+    while done < len {
+        let want  = (len - done).min(chunk.len());
+        let count = file.read(&mut chunk[..want])?;
+        virtm::copy_out(page_table, buf + done, &chunk[..count]).map_err(|_| Errno::EFAULT)?;
+        done += count;
+        if count < want {
+            break;
+        }
+    }
+    Ok(done)
+}
+
+/// `write(fd, buf, len)`
+pub fn sys_write(tf: &mut TrapFrame) -> SysResult {
+    let file = arg_file(tf, 0)?;
+    let buf  = arg(tf, 1);
+    let len  = arg(tf, 2);
+    let page_table = current().page_table;
+
+    let mut chunk = [0u8; IO_CHUNK];
+    let mut done = 0;
+    while done < len {
+        let want = (len - done).min(chunk.len());
+        virtm::copy_in(page_table, &mut chunk[..want], buf + done).map_err(|_| Errno::EFAULT)?;
+        let count = file.write(&chunk[..want])?;
+        done += count;
+        if count < want {
+            break;
+        }
+    }
+    Ok(done)
+}
+
+/// `close(fd)`
+pub fn sys_close(tf: &mut TrapFrame) -> SysResult {
+    let slot = current().files.get_mut(arg(tf, 0)).ok_or(Errno::EBADF)?;
+    slot.take().ok_or(Errno::EBADF)?;
+    Ok(0)
+}
+
+/// `lseek(fd, offset, whence)`, returns the new position.
+pub fn sys_lseek(tf: &mut TrapFrame) -> SysResult {
+    let file = arg_file(tf, 0)?;
+    Ok(file.seek(arg(tf, 1) as isize, arg(tf, 2))?)
+}
+
+/// `stat(path, len, stat_buf)`, fills in a `fs::Stat`.
+pub fn sys_stat(tf: &mut TrapFrame) -> SysResult {
+    let path = arg_path(tf, 0)?;
+    let stat = fs::stat(&path)?;
+    let bytes = unsafe {
+        core::slice::from_raw_parts(&stat as *const Stat as *const u8, core::mem::size_of::<Stat>())
+    };
+    virtm::copy_out(current().page_table, arg(tf, 2), bytes).map_err(|_| Errno::EFAULT)?;
+    Ok(0)
+}
+
+/// `getdents(fd, buf, len)`: read directory entries. Each record is
+/// ```text
+/// ino: u64, reclen: u16, kind: u8, namelen: u8, name[namelen], NUL
+/// ```
+/// padded to 8 bytes (`reclen` includes the padding). Returns the bytes
+/// filled, 0 at the end of the directory. Names longer than `NAME_MAX`
+/// bytes are skipped.
+pub fn sys_getdents(tf: &mut TrapFrame) -> SysResult {
+    let file = arg_file(tf, 0)?;
+    let buf  = arg(tf, 1);
+    let len  = arg(tf, 2);
+    let page_table = current().page_table;
+
+    let mut record = [0u8; 12 + NAME_MAX + 1 + 8];
+    let mut done = 0;
+    while let Some(entry) = file.next_dirent()? {
+        let name = entry.name.as_bytes();
+        if name.len() > NAME_MAX {
+            // cannot be opened by name anyway
+            continue;
+        }
+        let reclen = (12 + name.len() + 1 + 7) & !7;
+        if done + reclen > len {
+            file.unread_dirent();
+            if done == 0 {
+                return Err(Errno::EINVAL);
+            }
+            break;
+        }
+
+        record[..reclen].fill(0);
+        record[0..8].copy_from_slice(&entry.ino.to_le_bytes());
+        record[8..10].copy_from_slice(&(reclen as u16).to_le_bytes());
+        record[10] = entry.kind as u8;
+        record[11] = name.len() as u8;
+        record[12..12 + name.len()].copy_from_slice(name);
+        virtm::copy_out(page_table, buf + done, &record[..reclen]).map_err(|_| Errno::EFAULT)?;
+        done += reclen;
+    }
+    Ok(done)
+}
+
+/// `mkdir(path, len)`
+pub fn sys_mkdir(tf: &mut TrapFrame) -> SysResult {
+    let path = arg_path(tf, 0)?;
+    fs::mkdir(&path)?;
+    Ok(0)
+}
+
+/// `unlink(path, len)`
+pub fn sys_unlink(tf: &mut TrapFrame) -> SysResult {
+    let path = arg_path(tf, 0)?;
+    fs::unlink(&path)?;
+    Ok(0)
+}
+
+/// Give a new process fds 0, 1 and 2 on `/dev/console`.
+pub fn open_console(proc: &mut proc::Proc) -> Result<(), FsErr> {
+    for flags in [fs::OpenFlags::RDONLY, fs::OpenFlags::WRONLY, fs::OpenFlags::WRONLY] {
+        let file = fs::open("/dev/console", flags)?;
+        proc.fd_alloc(file).ok_or(FsErr::Busy)?;
+    }
+    Ok(())
+}
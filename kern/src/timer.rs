@@ -0,0 +1,36 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::riscv::{RegMCounterEn, RegMEnvCfg, RegSTimeCmp, RegTP, RegTime, Register};
+
+/// Timer interrupts per second.
+pub const TICK_HZ: usize = 100;
+
+/// Ticks since boot, counted on hart 0.
+pub static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+/// `time` ticks between two timer interrupts, from the DTB's
+/// `timebase-frequency` (10MHz on QEMU's virt machine).
+pub fn tick_interval() -> usize {
+    crate::platform::platform().timebase / TICK_HZ
+}
+
+/// Let S-mode program its own timer through `stimecmp` (Sstc) and
+/// arm the first tick.
+/// - Runs in M-mode, once per hart, after `platform_init`.
+pub fn timer_init() {
+    RegMEnvCfg::write(RegMEnvCfg::read() | RegMEnvCfg::MENVCFG_STCE);
+    RegMCounterEn::write(RegMCounterEn::read() | RegMCounterEn::MCOUNTEREN_TM);
+    RegSTimeCmp::write(RegTime::read() + tick_interval());
+}
+
+/// Supervisor timer interrupt: count the tick and re-arm the timer,
+/// which also clears the pending interrupt.
+pub fn timer_intr() {
+    if RegTP::read() == 0 {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+    RegSTimeCmp::write(RegTime::read() + tick_interval());
+}
+
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
+}
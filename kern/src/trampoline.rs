@@ -0,0 +1,187 @@
+//! User <-> kernel transition code.
+//!
+//! Everything in here lives in the `trampsec` section, which the linker
+//! script aligns to its own page. That page is mapped at `virtm::TRAMPOLINE`
+//! in the kernel page table and in every user page table, so the code keeps
+//! running across the `satp` switch.
+//!
+//! The user's `TrapFrame` is mapped at `virtm::TRAPFRAME` in the user page
+//! table (not user accessible).
+//!
+//! Both routines are assembled without compressed instructions so their
+//! sizes are multiples of 4 and `uservec` stays aligned for `stvec`
+//! whatever order they are laid out in.
+
+#[cfg(target_arch = "riscv64")]
+use crate::virtm::{TRAMPOLINE, TRAPFRAME};
+
+/// User trap vector. `stvec` points here (at its `TRAMPOLINE` alias)
+/// while a process runs in U-mode.
+///
+/// Saves the user registers into the trap frame, loads the kernel stack,
+/// hart id and page table recorded by `usr_trap_ret` and jumps to
+/// `kernel_trap` (`ktrap::usr_trap`).
+///
+/// # Safety
+/// Only ever entered by the hardware through `stvec`, never called.
+#[cfg(target_arch = "riscv64")]
+#[unsafe(naked)]
+#[link_section = "trampsec"]
+#[export_name = "uservec"]
+pub unsafe extern "C" fn uservec()
+{
+    core::arch::naked_asm!(
+        "
+        .option push
+        .option norvc
+        csrw sscratch, a0
+        li a0, {tf}
+
+        sd ra, 0(a0)
+        sd sp, 8(a0)
+        sd gp, 16(a0)
+        sd tp, 24(a0)
+        sd t0, 32(a0)
+        sd t1, 40(a0)
+        sd t2, 48(a0)
+        sd s0, 56(a0)
+        sd s1, 64(a0)
+        sd a1, 80(a0)
+        sd a2, 88(a0)
+        sd a3, 96(a0)
+        sd a4, 104(a0)
+        sd a5, 112(a0)
+        sd a6, 120(a0)
+        sd a7, 128(a0)
+        sd s2, 136(a0)
+        sd s3, 144(a0)
+        sd s4, 152(a0)
+        sd s5, 160(a0)
+        sd s6, 168(a0)
+        sd s7, 176(a0)
+        sd s8, 184(a0)
+        sd s9, 192(a0)
+        sd s10, 200(a0)
+        sd s11, 208(a0)
+        sd t3, 216(a0)
+        sd t4, 224(a0)
+        sd t5, 232(a0)
+        sd t6, 240(a0)
+
+        csrr t0, sscratch
+        sd t0, 72(a0)
+        csrr t0, sepc
+        sd t0, 248(a0)
+        csrr t0, sstatus
+        sd t0, 256(a0)
+        csrr t0, scause
+        sd t0, 264(a0)
+        csrr t0, stval
+        sd t0, 272(a0)
+
+        ld sp, 288(a0)
+        ld tp, 304(a0)
+        ld t0, 296(a0)
+        ld t1, 280(a0)
+
+        sfence.vma zero, zero
+        csrw satp, t1
+        sfence.vma zero, zero
+
+        jr t0
+        .option pop
+        ",
+        tf = const TRAPFRAME,
+    );
+}
+
+/// Return to user space.
+///
+/// Called (at its `TRAMPOLINE` alias) by `usr_trap_ret` with the user
+/// `satp` value in `a0`. `sepc` and `sstatus` must already be set up.
+///
+/// # Safety
+/// Only to be called through the `TRAMPOLINE` mapping with interrupts
+/// off, `stvec` on `uservec` and the trap frame filled in for the program
+/// `satp` belongs to. Does not return.
+#[cfg(target_arch = "riscv64")]
+#[unsafe(naked)]
+#[link_section = "trampsec"]
+#[export_name = "userret"]
+pub unsafe extern "C" fn userret(_satp: usize) -> !
+{
+    core::arch::naked_asm!(
+        "
+        .option push
+        .option norvc
+        sfence.vma zero, zero
+        csrw satp, a0
+        sfence.vma zero, zero
+
+        li a0, {tf}
+
+        ld ra, 0(a0)
+        ld sp, 8(a0)
+        ld gp, 16(a0)
+        ld tp, 24(a0)
+        ld t0, 32(a0)
+        ld t1, 40(a0)
+        ld t2, 48(a0)
+        ld s0, 56(a0)
+        ld s1, 64(a0)
+        ld a1, 80(a0)
+        ld a2, 88(a0)
+        ld a3, 96(a0)
+        ld a4, 104(a0)
+        ld a5, 112(a0)
+        ld a6, 120(a0)
+        ld a7, 128(a0)
+        ld s2, 136(a0)
+        ld s3, 144(a0)
+        ld s4, 152(a0)
+        ld s5, 160(a0)
+        ld s6, 168(a0)
+        ld s7, 176(a0)
+        ld s8, 184(a0)
+        ld s9, 192(a0)
+        ld s10, 200(a0)
+        ld s11, 208(a0)
+        ld t3, 216(a0)
+        ld t4, 224(a0)
+        ld t5, 232(a0)
+        ld t6, 240(a0)
+        ld a0, 72(a0)
+
+        sret
+        .option pop
+        ",
+        tf = const TRAPFRAME,
+    );
+}
+
+/// Physical (and kernel virtual) address of the trampoline page.
+pub fn trampoline_start() -> usize {
+    let x: usize;
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        core::arch::asm!(
+            "la {}, _trampoline",
+            out(reg) x
+        );
+    };
+    #[cfg(not(target_arch = "riscv64"))]
+    { x = 0; }
+    x
+}
+
+/// Address of `uservec` as seen through the `TRAMPOLINE` mapping.
+#[cfg(target_arch = "riscv64")]
+pub fn uservec_va() -> usize {
+    TRAMPOLINE + (uservec as *const () as usize - trampoline_start())
+}
+
+/// Address of `userret` as seen through the `TRAMPOLINE` mapping.
+#[cfg(target_arch = "riscv64")]
+pub fn userret_va() -> usize {
+    TRAMPOLINE + (userret as *const () as usize - trampoline_start())
+}
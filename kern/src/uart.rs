@@ -24,17 +24,25 @@ pub const LSR             :usize = 5;                   // line status register
 pub const LSR_RX_READY    :u8    = 1 << 0;              // input is waiting to be read from RHR
 pub const LSR_TX_IDLE     :u8    = 1 << 5;              // THR can accept another character to send
 
-pub const UART0           : usize = 0x10000000;
-pub const UART0_IRQ       : u8 = 10;
+/// Base of the UART registers, from the DTB.
+pub fn uart_base() -> usize {
+    crate::platform::platform().uart.base
+}
+
+/// PLIC IRQ of the UART, from the DTB.
+pub fn uart_irq() -> u32 {
+    crate::platform::platform().uart.irq
+}
 
 pub static UART_RX_BUFF: SpinLock<UartBuff> = SpinLock::new(UartBuff::new());
 pub const UART_BUFF_SIZE: usize = 1024;
 
 #[macro_export]
 macro_rules! uartreg {
-    ($reg:expr) => {
-        unsafe { &mut *(($crate::uart::UART0 + $reg)  as *mut u8 ) }
-    };
+    ($reg:expr) => {{
+        let addr = $crate::uart::uart_base() + $reg;
+        unsafe { &mut *(addr as *mut u8) }
+    }};
     // used for testing
     ($reg:expr, $mock_mem:expr) => {
         &mut $mock_mem[$reg]
@@ -149,6 +157,7 @@ impl UartBuff {
                         b'\r' => b'\n',
                         _ => char
                     };
+                    crate::console::cons_intr(char);
                     if char == (8 | b'\x7f') { // backspace
                         self.push(b'\x08');
                         self.push(b' ');
@@ -241,6 +250,7 @@ pub extern "C" fn uart_isr()
         buff.send(None);
         buff_empty = buff.isempty();
     }
+    crate::sched::wakeup(crate::console::cons_chan());
 
     if buff_empty {
         uartwt!(IER, IER_RX_ENABLE)
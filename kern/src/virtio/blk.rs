@@ -0,0 +1,304 @@
+//! virtio-blk driver for the first disk found, e.g.
+//! ```text
+//! -drive file=disk.img,if=none,format=raw,id=x0
+//! -device virtio-blk-device,drive=x0,bus=virtio-mmio-bus.0
+//! ```
+//! Every request is a three descriptor chain: header, data, status.
+//! The caller sleeps until the interrupt handler sees the chain in the
+//! used ring.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use super::queue::{QueueBuf, VirtQueue, QUEUE_SIZE};
+use super::{MmioTransport, VirtioDevice, VirtioErr};
+use crate::block::{BlockDevice, BlockErr};
+use crate::sched;
+use crate::sync::SpinLock;
+use crate::{plic, virtm};
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Feature bits of block devices.
+pub struct BlkFeature;
+impl BlkFeature {
+    pub const SIZE_MAX: u64 = 1 << 1;
+    pub const SEG_MAX : u64 = 1 << 2;
+    pub const RO      : u64 = 1 << 5;
+    pub const BLK_SIZE: u64 = 1 << 6;
+    pub const FLUSH   : u64 = 1 << 9;
+}
+
+/// Request types.
+struct BlkReqType;
+impl BlkReqType {
+    const IN   : u32 = 0;   // read from the disk
+    const OUT  : u32 = 1;   // write to the disk
+    const FLUSH: u32 = 4;
+}
+
+/// Status byte the device writes back.
+const BLK_S_OK: u8 = 0;
+/// Written before a request goes out, never by the device.
+const BLK_S_PENDING: u8 = 0xff;
+
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlkErr {
+    NoDisk,
+    ReadOnly,
+    OutOfRange,     // past the end of the disk
+    BadLength,      // not a whole number of sectors
+    Io,             // the device reported an error
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct BlkReq {
+    kind    : u32,
+    reserved: u32,
+    sector  : u64,
+}
+
+/// Per descriptor head: the request header and status the device reads
+/// and writes, and whether the request has completed.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Inflight {
+    req   : BlkReq,
+    status: u8,
+    done  : bool,
+}
+
+struct Disk {
+    transport: MmioTransport,
+    queue    : VirtQueue,
+    inflight : Box<[Inflight; QUEUE_SIZE]>,
+    sectors  : u64,
+    features : u64,
+}
+
+static DISK: SpinLock<Option<Disk>> = SpinLock::new(None);
+
+/// Channel processes waiting for free descriptors sleep on.
+fn free_chan() -> usize {
+    &DISK as *const _ as usize
+}
+
+impl Disk {
+    /// Collect finished requests and wake whoever waits for them.
+    fn reap(&mut self) {
+        while let Some((head, _)) = self.queue.pop_used() {
+            let inflight = &mut self.inflight[head as usize];
+            inflight.done = true;
+            sched::wakeup(inflight as *const Inflight as usize);
+        }
+    }
+
+    fn read_only(&self) -> bool {
+        self.features & BlkFeature::RO != 0
+    }
+}
+
+/// Find the disk and set it up. Returns its size in sectors.
+pub fn blk_init() -> Result<u64, VirtioErr> {
+    let transport = unsafe { MmioTransport::find(VirtioDevice::BLOCK) }.ok_or(VirtioErr::NoDevice)?;
+    let features = transport.init(|_| BlkFeature::RO | BlkFeature::FLUSH)?;
+
+    let queue = VirtQueue::new()?;
+    transport.setup_queue(0, &queue)?;
+    let sectors = transport.config_u64(0);
+    let inflight = Box::new([Inflight { req: BlkReq::default(), status: 0, done: true }; QUEUE_SIZE]);
+    transport.driver_ok();
+
+    let irq = transport.irq();
+    *DISK.lock().get_mut() = Some(Disk { transport, queue, inflight, sectors, features });
+    plic::plic_enable_irq(0, irq);
+    Ok(sectors)
+}
+
+/// Disk size in sectors, `None` without a disk.
+pub fn blk_sectors() -> Option<u64> {
+    DISK.lock().get().as_ref().map(|disk| disk.sectors)
+}
+
+pub fn blk_read_only() -> bool {
+    DISK.lock().get().as_ref().is_some_and(Disk::read_only)
+}
+
+/// PLIC IRQ of the disk.
+pub fn blk_irq() -> Option<u32> {
+    DISK.lock().get().as_ref().map(|disk| disk.transport.irq())
+}
+
+/// Disk interrupt: acknowledge it and finish completed requests.
+pub fn blk_intr() {
+    let mut guard = DISK.lock();
+    if let Some(disk) = guard.get_mut() {
+        disk.transport.ack_interrupt();
+        disk.reap();
+    }
+}
+
+/// Physical address of `[addr, addr + len)` if it is contiguous in
+/// physical memory too.
+fn phys_range(addr: usize, len: usize) -> Option<usize> {
+    let start = virtm::kern_translate(addr)?;
+    let mut page = addr & !(virtm::PAGE_SIZE - 1);
+    while page + virtm::PAGE_SIZE < addr + len {
+        page += virtm::PAGE_SIZE;
+        if virtm::kern_translate(page)? != start + (page - addr) {
+            return None;
+        }
+    }
+    Some(start)
+}
+
+/// Run one request and sleep until it is done. `data` is the physical
+/// address and length of the data buffer, `None` for a flush.
+fn blk_request(kind: u32, sector: u64, data: Option<(usize, usize)>) -> Result<(), BlkErr> {
+    let needed = if data.is_some() { 3 } else { 2 };
+    let mut guard = DISK.lock();
+    while guard.get().as_ref().ok_or(BlkErr::NoDisk)?.queue.num_free() < needed {
+        guard = sched::sleep(free_chan(), guard);
+    }
+
+    let disk = guard.get_mut().as_mut().ok_or(BlkErr::NoDisk)?;
+    let head = disk.queue.next_head().ok_or(BlkErr::Io)?;
+    let inflight = &mut disk.inflight[head as usize];
+    inflight.req    = BlkReq { kind, reserved: 0, sector };
+    inflight.status = BLK_S_PENDING;
+    inflight.done   = false;
+    let chan   = inflight as *const Inflight as usize;
+    let header = QueueBuf { addr: &inflight.req as *const BlkReq as usize, len: 16, writable: false };
+    let status = QueueBuf { addr: &inflight.status as *const u8 as usize, len: 1, writable: true };
+
+    let pushed = match data {
+        Some((addr, len)) => {
+            let data = QueueBuf { addr, len: len as u32, writable: kind == BlkReqType::IN };
+            disk.queue.push(&[header, data, status])
+        }
+        None => disk.queue.push(&[header, status]),
+    };
+    assert_eq!(pushed, Some(head), "virtio-blk: unexpected descriptor head");
+    disk.transport.notify(0);
+
+    loop {
+        let disk = guard.get_mut().as_mut().ok_or(BlkErr::NoDisk)?;
+        // also catches completions while interrupts are off, e.g. at boot
+        disk.reap();
+        if disk.inflight[head as usize].done {
+            break;
+        }
+        guard = sched::sleep(chan, guard);
+    }
+
+    let disk = guard.get_mut().as_mut().ok_or(BlkErr::NoDisk)?;
+    let status = disk.inflight[head as usize].status;
+    disk.queue.free_chain(head);
+    drop(guard);
+    sched::wakeup(free_chan());
+
+    match status {
+        BLK_S_OK => Ok(()),
+        _ => Err(BlkErr::Io),
+    }
+}
+
+fn check_range(len: usize, sector: u64) -> Result<(), BlkErr> {
+    if !len.is_multiple_of(SECTOR_SIZE) {
+        return Err(BlkErr::BadLength);
+    }
+    let count = (len / SECTOR_SIZE) as u64;
+    let sectors = blk_sectors().ok_or(BlkErr::NoDisk)?;
+    if sector.checked_add(count).is_none_or(|end| end > sectors) {
+        return Err(BlkErr::OutOfRange);
+    }
+    Ok(())
+}
+
+/// Read `buf.len() / SECTOR_SIZE` sectors starting at `sector`.
+pub fn blk_read(sector: u64, buf: &mut [u8]) -> Result<(), BlkErr> {
+    check_range(buf.len(), sector)?;
+    if buf.is_empty() {
+        return Ok(());
+    }
+    match phys_range(buf.as_ptr() as usize, buf.len()) {
+        Some(phys) => blk_request(BlkReqType::IN, sector, Some((phys, buf.len()))),
+        None => {
+            // e.g. a buffer on a kernel stack, whose pages are scattered
+            let mut bounce: Vec<u8> = Vec::new();
+            bounce.try_reserve_exact(buf.len()).map_err(|_| BlkErr::Io)?;
+            bounce.resize(buf.len(), 0);
+            blk_request(BlkReqType::IN, sector, Some((bounce.as_mut_ptr() as usize, buf.len())))?;
+            buf.copy_from_slice(&bounce);
+            Ok(())
+        }
+    }
+}
+
+/// Write `buf` to the disk starting at `sector`.
+pub fn blk_write(sector: u64, buf: &[u8]) -> Result<(), BlkErr> {
+    check_range(buf.len(), sector)?;
+    if blk_read_only() {
+        return Err(BlkErr::ReadOnly);
+    }
+    if buf.is_empty() {
+        return Ok(());
+    }
+    match phys_range(buf.as_ptr() as usize, buf.len()) {
+        Some(phys) => blk_request(BlkReqType::OUT, sector, Some((phys, buf.len()))),
+        None => {
+            let mut bounce: Vec<u8> = Vec::new();
+            bounce.try_reserve_exact(buf.len()).map_err(|_| BlkErr::Io)?;
+            bounce.extend_from_slice(buf);
+            blk_request(BlkReqType::OUT, sector, Some((bounce.as_ptr() as usize, buf.len())))
+        }
+    }
+}
+
+/// Ask the disk to write out its own cache, if it has one.
+pub fn blk_flush() -> Result<(), BlkErr> {
+    let has_flush = DISK.lock().get().as_ref().ok_or(BlkErr::NoDisk)?.features & BlkFeature::FLUSH != 0;
+    if !has_flush {
+        return Ok(());
+    }
+    blk_request(BlkReqType::FLUSH, 0, None)
+}
+
+impl From<BlkErr> for BlockErr {
+    fn from(err: BlkErr) -> Self {
+        match err {
+            BlkErr::NoDisk     => BlockErr::NoDevice,
+            BlkErr::ReadOnly   => BlockErr::ReadOnly,
+            BlkErr::OutOfRange => BlockErr::OutOfRange,
+            BlkErr::BadLength  => BlockErr::BadLength,
+            BlkErr::Io         => BlockErr::Io,
+        }
+    }
+}
+
+/// The virtio disk as a `BlockDevice`, for the buffer cache.
+pub struct VirtioBlk;
+
+impl BlockDevice for VirtioBlk {
+    fn sectors(&self) -> u64 {
+        blk_sectors().unwrap_or(0)
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockErr> {
+        Ok(blk_read(sector, buf)?)
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), BlockErr> {
+        Ok(blk_write(sector, buf)?)
+    }
+
+    fn flush(&self) -> Result<(), BlockErr> {
+        Ok(blk_flush()?)
+    }
+
+    fn read_only(&self) -> bool {
+        blk_read_only()
+    }
+}
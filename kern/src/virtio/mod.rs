@@ -0,0 +1,281 @@
+//! virtio over MMIO, as found on QEMU's virt machine.
+//!
+//! The transport slots and their PLIC IRQs come from the `virtio,mmio`
+//! nodes of the DTB (see `platform`), lowest address first; QEMU has 8
+//! of them from `VIRT_VIRTIO` on. Slots without a device read back a
+//! device id of 0. Both the legacy (version 1) and the modern (version 2,
+//! `-global virtio-mmio.force-legacy=false`) register layouts are handled.
+
+use crate::platform::platform;
+use crate::virtm::PAGE_SIZE;
+
+pub mod queue;
+pub mod blk;
+
+pub use queue::VirtQueue;
+
+/// "virt" in little endian.
+const VIRTIO_MAGIC: u32 = 0x74726976;
+
+/// MMIO register offsets.
+pub struct VirtioReg;
+impl VirtioReg {
+    pub const MAGIC_VALUE        : usize = 0x000;
+    pub const VERSION            : usize = 0x004;
+    pub const DEVICE_ID          : usize = 0x008;
+    pub const VENDOR_ID          : usize = 0x00c;
+    pub const DEVICE_FEATURES    : usize = 0x010;
+    pub const DEVICE_FEATURES_SEL: usize = 0x014;
+    pub const DRIVER_FEATURES    : usize = 0x020;
+    pub const DRIVER_FEATURES_SEL: usize = 0x024;
+    pub const GUEST_PAGE_SIZE    : usize = 0x028;   // legacy only
+    pub const QUEUE_SEL          : usize = 0x030;
+    pub const QUEUE_NUM_MAX      : usize = 0x034;
+    pub const QUEUE_NUM          : usize = 0x038;
+    pub const QUEUE_ALIGN        : usize = 0x03c;   // legacy only
+    pub const QUEUE_PFN          : usize = 0x040;   // legacy only
+    pub const QUEUE_READY        : usize = 0x044;
+    pub const QUEUE_NOTIFY       : usize = 0x050;
+    pub const INTERRUPT_STATUS   : usize = 0x060;
+    pub const INTERRUPT_ACK      : usize = 0x064;
+    pub const STATUS             : usize = 0x070;
+    pub const QUEUE_DESC_LOW     : usize = 0x080;
+    pub const QUEUE_DESC_HIGH    : usize = 0x084;
+    pub const QUEUE_DRIVER_LOW   : usize = 0x090;
+    pub const QUEUE_DRIVER_HIGH  : usize = 0x094;
+    pub const QUEUE_DEVICE_LOW   : usize = 0x0a0;
+    pub const QUEUE_DEVICE_HIGH  : usize = 0x0a4;
+    pub const CONFIG             : usize = 0x100;
+}
+
+/// Device status bits.
+pub struct VirtioStatus;
+impl VirtioStatus {
+    pub const ACKNOWLEDGE: u32 = 1;
+    pub const DRIVER     : u32 = 2;
+    pub const DRIVER_OK  : u32 = 4;
+    pub const FEATURES_OK: u32 = 8;
+    pub const FAILED     : u32 = 128;
+}
+
+/// Device types, from the `DEVICE_ID` register.
+pub struct VirtioDevice;
+impl VirtioDevice {
+    pub const NET  : u32 = 1;
+    pub const BLOCK: u32 = 2;
+}
+
+/// Feature bits every device type shares.
+pub struct VirtioFeature;
+impl VirtioFeature {
+    pub const RING_INDIRECT_DESC: u64 = 1 << 28;
+    pub const RING_EVENT_IDX    : u64 = 1 << 29;
+    pub const VERSION_1         : u64 = 1 << 32;
+}
+
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioErr {
+    NoDevice,           // no slot holds a device of the wanted type
+    BadVersion,
+    FeaturesRejected,   // the device did not accept FEATURES_OK
+    QueueUnavailable,   // queue missing or already in use
+    QueueTooSmall,
+    OutOfMemory,
+}
+
+/// One virtio-mmio slot.
+pub struct MmioTransport {
+    base   : usize,
+    slot   : usize,
+    irq    : u32,
+    version: u32,
+}
+
+impl MmioTransport {
+    /// The transport in `slot`, if it holds a device.
+    ///
+    /// # Safety
+    /// The slots must be mapped (see `virtm::kern_vm_create_maps`).
+    pub unsafe fn probe(slot: usize) -> Option<Self> {
+        let mmio = platform().virtio().get(slot)?;
+        let transport = Self {
+            base   : mmio.base,
+            slot,
+            irq    : mmio.irq,
+            version: 0,
+        };
+        if transport.read(VirtioReg::MAGIC_VALUE) != VIRTIO_MAGIC
+            || transport.read(VirtioReg::DEVICE_ID) == 0 {
+            return None;
+        }
+        let version = transport.read(VirtioReg::VERSION);
+        Some(Self { version, ..transport })
+    }
+
+    /// The first slot holding a device of type `device_id`.
+    ///
+    /// # Safety
+    /// See `probe`.
+    pub unsafe fn find(device_id: u32) -> Option<Self> {
+        (0..platform().virtio().len())
+            .filter_map(|slot| unsafe { Self::probe(slot) })
+            .find(|transport| transport.device_id() == device_id)
+    }
+
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+
+    pub fn irq(&self) -> u32 {
+        self.irq
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn device_id(&self) -> u32 {
+        self.read(VirtioReg::DEVICE_ID)
+    }
+
+    pub fn read(&self, reg: usize) -> u32 {
+        unsafe { ((self.base + reg) as *const u32).read_volatile() }
+    }
+
+    pub fn write(&self, reg: usize, value: u32) {
+        unsafe { ((self.base + reg) as *mut u32).write_volatile(value) }
+    }
+
+    /// Byte `offset` of the device specific configuration space.
+    pub fn config_u32(&self, offset: usize) -> u32 {
+        self.read(VirtioReg::CONFIG + offset)
+    }
+
+    pub fn config_u64(&self, offset: usize) -> u64 {
+        let low  = self.config_u32(offset) as u64;
+        let high = self.config_u32(offset + 4) as u64;
+        (high << 32) | low
+    }
+
+    fn set_status(&self, bits: u32) {
+        self.write(VirtioReg::STATUS, self.read(VirtioReg::STATUS) | bits);
+    }
+
+    /// Reset the device and negotiate features: `accept` gets what the
+    /// device offers and returns what the driver wants. Returns the
+    /// negotiated set. Queues are set up next, then `driver_ok`.
+    pub fn init(&self, accept: impl FnOnce(u64) -> u64) -> Result<u64, VirtioErr> {
+        if self.version != 1 && self.version != 2 {
+            return Err(VirtioErr::BadVersion);
+        }
+        self.write(VirtioReg::STATUS, 0);
+        self.set_status(VirtioStatus::ACKNOWLEDGE);
+        self.set_status(VirtioStatus::DRIVER);
+
+        let mut offered = 0u64;
+        for half in 0..2 {
+            self.write(VirtioReg::DEVICE_FEATURES_SEL, half);
+            offered |= (self.read(VirtioReg::DEVICE_FEATURES) as u64) << (32 * half);
+        }
+        let mut features = accept(offered) & offered;
+        if self.version == 2 {
+            // modern devices refuse drivers that do not speak version 1
+            features |= offered & VirtioFeature::VERSION_1;
+        }
+        for half in 0..2 {
+            self.write(VirtioReg::DRIVER_FEATURES_SEL, half);
+            self.write(VirtioReg::DRIVER_FEATURES, (features >> (32 * half)) as u32);
+        }
+
+        if self.version == 2 {
+            self.set_status(VirtioStatus::FEATURES_OK);
+            if self.read(VirtioReg::STATUS) & VirtioStatus::FEATURES_OK == 0 {
+                self.set_status(VirtioStatus::FAILED);
+                return Err(VirtioErr::FeaturesRejected);
+            }
+        } else {
+            self.write(VirtioReg::GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+        }
+        Ok(features)
+    }
+
+    /// Hand queue `index` to the device.
+    pub fn setup_queue(&self, index: u32, queue: &VirtQueue) -> Result<(), VirtioErr> {
+        self.write(VirtioReg::QUEUE_SEL, index);
+        let in_use = match self.version {
+            1 => self.read(VirtioReg::QUEUE_PFN) != 0,
+            _ => self.read(VirtioReg::QUEUE_READY) != 0,
+        };
+        let max = self.read(VirtioReg::QUEUE_NUM_MAX);
+        if in_use || max == 0 {
+            return Err(VirtioErr::QueueUnavailable);
+        }
+        if max < queue.size() as u32 {
+            return Err(VirtioErr::QueueTooSmall);
+        }
+        self.write(VirtioReg::QUEUE_NUM, queue.size() as u32);
+
+        let (desc, driver, device) = queue.addrs();
+        if self.version == 1 {
+            // one contiguous block, the used ring on the next page
+            self.write(VirtioReg::QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(VirtioReg::QUEUE_PFN, (desc / PAGE_SIZE) as u32);
+        } else {
+            self.write(VirtioReg::QUEUE_DESC_LOW, desc as u32);
+            self.write(VirtioReg::QUEUE_DESC_HIGH, (desc >> 32) as u32);
+            self.write(VirtioReg::QUEUE_DRIVER_LOW, driver as u32);
+            self.write(VirtioReg::QUEUE_DRIVER_HIGH, (driver >> 32) as u32);
+            self.write(VirtioReg::QUEUE_DEVICE_LOW, device as u32);
+            self.write(VirtioReg::QUEUE_DEVICE_HIGH, (device >> 32) as u32);
+            self.write(VirtioReg::QUEUE_READY, 1);
+        }
+        Ok(())
+    }
+
+    /// Setup is done, the device may start using its queues.
+    pub fn driver_ok(&self) {
+        self.set_status(VirtioStatus::DRIVER_OK);
+    }
+
+    /// Tell the device queue `index` has new buffers.
+    pub fn notify(&self, index: u32) {
+        self.write(VirtioReg::QUEUE_NOTIFY, index);
+    }
+
+    /// Acknowledge a pending interrupt, returns the status bits
+    /// (1: used ring updated, 2: configuration changed).
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = self.read(VirtioReg::INTERRUPT_STATUS);
+        self.write(VirtioReg::INTERRUPT_ACK, status & 0x3);
+        status
+    }
+}
+
+/// Block device id of the virtio disk, once it is registered.
+pub static mut VIRTIO_BLK_DEV: Option<crate::block::DevId> = None;
+
+/// Probe the virtio slots and bring up the drivers we have.
+pub fn virtio_init() {
+    match blk::blk_init() {
+        Ok(sectors) => {
+            let dev = crate::block::register(alloc::sync::Arc::new(blk::VirtioBlk));
+            unsafe { VIRTIO_BLK_DEV = Some(dev) };
+            kprintln!("virtio-blk: {} sectors ({} KiB), block device {}", sectors, sectors / 2, dev);
+        }
+        Err(VirtioErr::NoDevice) => kprintln!("virtio-blk: no disk attached"),
+        Err(err) => kprintln!("virtio-blk: init failed: {:?}", err),
+    }
+}
+
+/// PLIC interrupt for one of the virtio slots.
+pub fn virtio_intr(irq: u32) {
+    if blk::blk_irq() == Some(irq) {
+        blk::blk_intr();
+    }
+}
+
+/// Is `irq` wired to a virtio slot?
+pub fn is_virtio_irq(irq: u32) -> bool {
+    platform().virtio().iter().any(|slot| slot.irq == irq)
+}
@@ -0,0 +1,216 @@
+//! Split virtqueues: a descriptor table, the available ring the driver
+//! fills and the used ring the device fills.
+//!
+//! The three parts sit in one zeroed heap block laid out the way legacy
+//! devices expect (used ring on its own page), which modern devices
+//! accept as well. Kernel heap memory is identity mapped, so the
+//! addresses handed to the device are the pointers themselves.
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use core::sync::atomic::{fence, Ordering};
+
+use super::VirtioErr;
+use crate::virtm::PAGE_SIZE;
+
+/// Descriptors per queue.
+pub const QUEUE_SIZE: usize = 8;
+
+/// Descriptor flags.
+pub struct DescFlags;
+impl DescFlags {
+    pub const NEXT    : u16 = 1;    // the chain continues in `next`
+    pub const WRITE   : u16 = 2;    // the device writes this buffer
+    pub const INDIRECT: u16 = 4;
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Desc {
+    addr : u64,
+    len  : u32,
+    flags: u16,
+    next : u16,
+}
+
+#[repr(C)]
+struct AvailRing {
+    flags     : u16,
+    idx       : u16,
+    ring      : [u16; QUEUE_SIZE],
+    used_event: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct UsedElem {
+    id : u32,   // head of the finished chain
+    len: u32,   // bytes the device wrote
+}
+
+#[repr(C)]
+struct UsedRing {
+    flags      : u16,
+    idx        : u16,
+    ring       : [UsedElem; QUEUE_SIZE],
+    avail_event: u16,
+}
+
+/// A buffer in a descriptor chain.
+#[derive(Debug, Clone, Copy)]
+pub struct QueueBuf {
+    pub addr    : usize,    // physical address
+    pub len     : u32,
+    pub writable: bool,     // the device writes it (e.g. read data, status)
+}
+
+const USED_OFFSET: usize = (core::mem::size_of::<[Desc; QUEUE_SIZE]>()
+    + core::mem::size_of::<AvailRing>()).next_multiple_of(PAGE_SIZE);
+const QUEUE_BYTES: usize = USED_OFFSET + core::mem::size_of::<UsedRing>().next_multiple_of(PAGE_SIZE);
+
+pub struct VirtQueue {
+    mem      : *mut u8,
+    desc     : *mut Desc,
+    avail    : *mut AvailRing,
+    used     : *mut UsedRing,
+    free     : [bool; QUEUE_SIZE],
+    num_free : usize,
+    last_used: u16,     // used ring entries we have seen
+}
+
+// the rings are only touched through `&mut self`
+unsafe impl Send for VirtQueue {}
+unsafe impl Sync for VirtQueue {}
+
+fn queue_layout() -> Layout {
+    Layout::from_size_align(QUEUE_BYTES, PAGE_SIZE).unwrap()
+}
+
+impl VirtQueue {
+    pub fn new() -> Result<Self, VirtioErr> {
+        let mem = unsafe { alloc_zeroed(queue_layout()) };
+        if mem.is_null() {
+            return Err(VirtioErr::OutOfMemory);
+        }
+        let desc_bytes = core::mem::size_of::<[Desc; QUEUE_SIZE]>();
+        Ok(Self {
+            mem,
+            desc     : mem as *mut Desc,
+            avail    : unsafe { mem.add(desc_bytes) } as *mut AvailRing,
+            used     : unsafe { mem.add(USED_OFFSET) } as *mut UsedRing,
+            free     : [true; QUEUE_SIZE],
+            num_free : QUEUE_SIZE,
+            last_used: 0,
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        QUEUE_SIZE
+    }
+
+    pub fn num_free(&self) -> usize {
+        self.num_free
+    }
+
+    /// Addresses of the descriptor table, the available and the used ring.
+    pub fn addrs(&self) -> (usize, usize, usize) {
+        (self.desc as usize, self.avail as usize, self.used as usize)
+    }
+
+    fn alloc_desc(&mut self) -> Option<u16> {
+        let idx = self.free.iter().position(|&free| free)?;
+        self.free[idx] = false;
+        self.num_free -= 1;
+        Some(idx as u16)
+    }
+
+    /// The head descriptor the next `push` will use: the lowest free one.
+    /// Lets drivers key per request state by head before pushing.
+    pub fn next_head(&self) -> Option<u16> {
+        self.free.iter().position(|&free| free).map(|idx| idx as u16)
+    }
+
+    /// Chain up `bufs` and offer them to the device. Returns the head
+    /// descriptor (see `next_head`), which `pop_used` reports back once
+    /// the device is done; `None` if there are not enough free
+    /// descriptors. The caller notifies the device.
+    pub fn push(&mut self, bufs: &[QueueBuf]) -> Option<u16> {
+        if bufs.is_empty() || bufs.len() > self.num_free {
+            return None;
+        }
+        let mut ids = [0u16; QUEUE_SIZE];
+        for id in ids.iter_mut().take(bufs.len()) {
+            *id = self.alloc_desc()?;
+        }
+        for (i, buf) in bufs.iter().enumerate() {
+            let mut flags = if buf.writable { DescFlags::WRITE } else { 0 };
+            let mut next = 0;
+            if i + 1 < bufs.len() {
+                flags |= DescFlags::NEXT;
+                next = ids[i + 1];
+            }
+            let desc = Desc { addr: buf.addr as u64, len: buf.len, flags, next };
+            unsafe { self.desc.add(ids[i] as usize).write_volatile(desc) };
+        }
+
+        let head = ids[0];
+        unsafe {
+            let avail = &mut *self.avail;
+            let idx = core::ptr::addr_of!(avail.idx).read_volatile();
+            core::ptr::addr_of_mut!(avail.ring[idx as usize % QUEUE_SIZE]).write_volatile(head);
+            // the device must see the ring entry before the new index
+            fence(Ordering::SeqCst);
+            core::ptr::addr_of_mut!(avail.idx).write_volatile(idx.wrapping_add(1));
+            fence(Ordering::SeqCst);
+        }
+        Some(head)
+    }
+
+    /// The next chain the device has finished with: its head and the
+    /// bytes written. The chain stays allocated until `free_chain`.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_idx = unsafe { core::ptr::addr_of!((*self.used).idx).read_volatile() };
+        if used_idx == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let slot = self.last_used as usize % QUEUE_SIZE;
+        let elem = unsafe { core::ptr::addr_of!((*self.used).ring[slot]).read_volatile() };
+        self.last_used = self.last_used.wrapping_add(1);
+        Some((elem.id as u16, elem.len))
+    }
+
+    /// Give the descriptors of the chain starting at `head` back.
+    pub fn free_chain(&mut self, head: u16) {
+        let mut idx = head as usize;
+        loop {
+            let desc = unsafe { self.desc.add(idx).read_volatile() };
+            assert!(!self.free[idx], "virtqueue: freeing a free descriptor");
+            unsafe { self.desc.add(idx).write_volatile(Desc { addr: 0, len: 0, flags: 0, next: 0 }) };
+            self.free[idx] = true;
+            self.num_free += 1;
+            if desc.flags & DescFlags::NEXT == 0 {
+                break;
+            }
+            idx = desc.next as usize;
+        }
+    }
+
+    /// Pretend to be the device: mark the chain at `head` used.
+    /// Lets the ring logic be tested without one.
+    pub fn device_complete(&mut self, head: u16, len: u32) {
+        unsafe {
+            let used = &mut *self.used;
+            let idx = core::ptr::addr_of!(used.idx).read_volatile();
+            core::ptr::addr_of_mut!(used.ring[idx as usize % QUEUE_SIZE])
+                .write_volatile(UsedElem { id: head as u32, len });
+            fence(Ordering::SeqCst);
+            core::ptr::addr_of_mut!(used.idx).write_volatile(idx.wrapping_add(1));
+        }
+    }
+}
+
+impl Drop for VirtQueue {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.mem, queue_layout()) };
+    }
+}
@@ -1,118 +1,39 @@
-use core::sync::atomic::{AtomicU64, Ordering};
 use crate::uart;
+use crate::mem::frame;
+use crate::platform::{MemRange, MAX_RAM_RANGES};
 
-const PAGE_SIZE   : usize = 4096;
-const BITMAP_LEN  : usize = 64;
+pub const PAGE_SIZE   : usize = 4096;
 const PAGE_OFFSET : usize = 12;
 const PAGE_FLAGS  : u8    = 10;
 const LEVEL_MASK  : usize = 0x1FF;
 
 
 pub const KERN_START  : usize = 0x80000000;
-pub const KERN_RESERV : usize = 128 * (1024 * 1024);
 pub const MEM_MAX : usize = 1usize << (9 + 9 + 9 + 12 - 1);
 
-pub static mut KERN_SATP: u64 = 0;
-pub static mut KERN_PG_ALLOCATOR: Option<KPageAllocator> = None;
+/// Highest page of every address space (kernel and user), holds the
+/// user <-> kernel transition code. See `trampoline`.
+pub const TRAMPOLINE  : usize = MEM_MAX - PAGE_SIZE;
+/// The user's `TrapFrame`, just below the trampoline in user page tables.
+pub const TRAPFRAME   : usize = TRAMPOLINE - PAGE_SIZE;
 
-pub struct KPageAllocator {
-    pmap:        &'static mut [AtomicU64],
-    alloc_start: usize,
-    page_count:  usize,
-}
+/// Kernel stacks are mapped below the trampoline in the kernel page
+/// table, each followed by an unmapped guard page.
+pub const KSTACK_PAGES: usize = 4;
+pub const KSTACK_SIZE : usize = KSTACK_PAGES * PAGE_SIZE;
 
-pub enum AllocErr{
-    AddrNotAligned,
-    AddrNotValid
+/// Virtual address of the kernel stack in slot `slot`.
+pub const fn kstack_va(slot: usize) -> usize {
+    TRAMPOLINE - (slot + 1) * (KSTACK_SIZE + PAGE_SIZE)
 }
 
-impl KPageAllocator {
-    pub fn new(mem_start: usize, size: usize) -> Result<Self, AllocErr> {
-        let page_count = size / PAGE_SIZE;
-        let num_bitmaps = (page_count + (BITMAP_LEN - 1)) / BITMAP_LEN;
-
-        let map_mem = mem_start as *mut AtomicU64;
-        let pmap = unsafe {
-            core::slice::from_raw_parts_mut(map_mem, num_bitmaps) };
-
-        let mut alloc_start = mem_start + (num_bitmaps * core::mem::size_of::<AtomicU64>());
-        alloc_start = (alloc_start + (PAGE_SIZE - 1)) & !(PAGE_SIZE - 1);
-        
-        Ok(Self {
-            pmap,
-            alloc_start,
-            page_count
-        })
-    }
-
-    pub fn allocate(&mut self) -> Option<*mut u8> {
-        for (idx, item) in self.pmap.iter().enumerate() {
-            let map = item.load(Ordering::Relaxed);
-            if map != u64::MAX {
-                let bit_idx = (!map).trailing_zeros() as usize;
-                if bit_idx >= BITMAP_LEN {continue;}
-                let mask = 1u64 << bit_idx;
-                
-                item.fetch_or(mask, Ordering::SeqCst);
-                
-                let offset = (BITMAP_LEN * idx) + bit_idx;
-                let page = (self.alloc_start + (PAGE_SIZE * offset)) as *mut u8;
-                // unsafe { core::ptr::write_bytes(page, 0, PAGE_SIZE); };
-                return Some(page);
-            }
-        }
-        None
-    }
-
-    // TODO: deallocate more than one page 
-    //      `pub fn deallocate(&mut self, addr: *mut u8, size: usize){` 
-    pub fn deallocate(&mut self, addr: *mut u8){
-        let addr = addr as usize;
-        let invalid_addr = addr < self.alloc_start || (addr % PAGE_SIZE) != 0;
-        if invalid_addr || addr >= (self.alloc_start + self.page_count * PAGE_SIZE) {
-            return;
-        }
-
-        let page_idx = (addr - self.alloc_start) / PAGE_SIZE;
-        let map_idx = page_idx / BITMAP_LEN;
-        let bit_idx = page_idx % BITMAP_LEN;
-        let map = &mut self.pmap[map_idx];
-
-        let mask = 1u64 << bit_idx;
-        map.fetch_and(!mask, Ordering::AcqRel);
-    }
-
-    pub fn page_allocated(&self, addr: *mut u8) -> bool{
-        let addr = addr as usize;
-        let invalid_addr = addr < self.alloc_start || (addr % PAGE_SIZE) != 0;
-        if invalid_addr || addr >= (self.alloc_start + self.page_count * PAGE_SIZE) {
-            return false;
-        }
-        
-        let page_idx = (addr - self.alloc_start) / PAGE_SIZE;
-        let map_idx = page_idx / BITMAP_LEN;
-        let bit_idx = page_idx % BITMAP_LEN;
-
-        let map = self.pmap[map_idx].load(Ordering::Relaxed);
-        let mask = 1u64 << bit_idx;
-        (map & mask) != 0
-    }
+pub static mut KERN_SATP: u64 = 0;
+pub const fn page_up(addr: usize) -> usize {
+    (addr + (PAGE_SIZE - 1)) & !(PAGE_SIZE - 1)
 }
 
-#[allow(unused)]
-fn test(){
-    const LEN: usize = (1024 * 1024) / 8;
-    let mut memory = [0u64; LEN];
-
-    if let Ok(allocator) = 
-        &mut KPageAllocator::new(memory.as_mut_ptr() as usize, LEN){
-
-        let allocated_arr = allocator.allocate().unwrap();
-        assert!(allocator.page_allocated(allocated_arr));
-
-        allocator.deallocate(allocated_arr);
-        assert!(!allocator.page_allocated(allocated_arr))
-    }
+pub const fn page_down(addr: usize) -> usize {
+    addr & !(PAGE_SIZE - 1)
 }
 
 // https://github.com/qemu/qemu/blob/master/hw/riscv/virt.c
@@ -169,15 +90,100 @@ fn vm_map_exit(pages: i32, arr_idx: usize){
 // debug-staff
 static mut addr_entries: [(usize, usize, usize, usize); 6] = [(0, 0, 0, 0),(0, 0, 0, 0),(0, 0, 0, 0),(0, 0, 0, 0),(0, 0, 0, 0),(0, 0, 0, 0),];
 
+/// Pages behind the `USER` leaf entries of the page table rooted at
+/// `root`.
+pub fn pt_user_pages(root: *mut u64) -> usize {
+    fn count_level(table: *const u64, level: usize) -> usize {
+        let entry_count = PAGE_SIZE / core::mem::size_of::<u64>();
+        let entries = unsafe { core::slice::from_raw_parts(table, entry_count) };
+        entries.iter().filter(|&&entry| (entry & PTEPerms::VALID) != 0).map(|&entry| {
+            let is_leaf = (entry & (PTEPerms::READ | PTEPerms::WRITE | PTEPerms::EXEC)) != 0;
+            if !is_leaf && level > 0 {
+                count_level(((entry >> PAGE_FLAGS) as usize * PAGE_SIZE) as *const u64, level - 1)
+            } else if (entry & PTEPerms::USER) != 0 {
+                1 << (9 * level)
+            } else {
+                0
+            }
+        }).sum()
+    }
+
+    if root.is_null() {
+        return 0;
+    }
+    count_level(root, 2)
+}
+
 /// Uses RISCV SV39 Scheme
+/// Maps into the kernel page table. See `pt_map`.
 #[unsafe(no_mangle)]
 pub fn vm_map(phys_addr: usize, vm_addr: usize, map_size: usize, perms: u64, region: &str) {
+    let root = unsafe { KERN_SATP as *mut u64 };
+    if !pt_map(root, phys_addr, vm_addr, map_size, perms, region) {
+        panic!("vm_map: could not map {}", region);
+    }
+}
+
+/// Allocate a zeroed page from the frame allocator.
+pub fn pg_alloc() -> Option<*mut u8> {
+    let page = frame::frame_alloc()? as *mut u8;
+    unsafe { core::ptr::write_bytes(page, 0, PAGE_SIZE) };
+    Some(page)
+}
+
+/// Hand a page back to the frame allocator.
+pub fn pg_free(page: *mut u8) {
+    frame::frame_free(page as usize);
+}
+
+/// Allocate a zeroed root page table.
+pub fn pt_create() -> Option<*mut u64> {
+    pg_alloc().map(|page| page as *mut u64)
+}
+
+/// Free the page table rooted at `root` and all of its intermediate
+/// tables. Pages behind `USER` leaf entries are freed as well when
+/// `free_user` is set; other leaves (trampoline, trap frame) are left
+/// to their owners.
+pub fn pt_destroy(root: *mut u64, free_user: bool) {
+    fn destroy_level(table: *mut u64, level: usize, free_user: bool) {
+        let entry_count = PAGE_SIZE / core::mem::size_of::<u64>();
+        let entries = unsafe { core::slice::from_raw_parts_mut(table, entry_count) };
+        for entry in entries.iter_mut() {
+            if (*entry & PTEPerms::VALID) == 0 {
+                continue;
+            }
+            let page = ((*entry >> PAGE_FLAGS) as usize * PAGE_SIZE) as *mut u8;
+            let is_leaf = (*entry & (PTEPerms::READ | PTEPerms::WRITE | PTEPerms::EXEC)) != 0;
+            if !is_leaf && level > 0 {
+                destroy_level(page as *mut u64, level - 1, free_user);
+                pg_free(page);
+            } else if free_user && (*entry & PTEPerms::USER) != 0 {
+                pg_free(page);
+            }
+            *entry = 0;
+        }
+    }
+
+    if root.is_null() {
+        return;
+    }
+    destroy_level(root, 2, free_user);
+    pg_free(root as *mut u8);
+}
+
+/// Uses RISCV SV39 Scheme
+/// Maps `[phys_addr, phys_addr + map_size)` at `vm_addr` in the page
+/// table rooted at `root`, allocating intermediate tables as needed.
+/// Returns `false` if it could not: the addresses are not aligned or a
+/// table could not be allocated. Pages mapped until then stay mapped.
+pub fn pt_map(root: *mut u64, phys_addr: usize, vm_addr: usize, map_size: usize, perms: u64, region: &str) -> bool {
     let kern_end = get_end();
     if (phys_addr % PAGE_SIZE) != 0 || (vm_addr % PAGE_SIZE) != 0 {
         kprintln!("Cannot map address. Not Aligned.{:#x} {:#x} {}", phys_addr, kern_end, region);
-        return;
+        return false;
     }
-    let pt_set = unsafe { KERN_SATP != 0};
+    let pt_set = !root.is_null();
     let mut num_pages = 0;
     let mut arr_idx = 0;
     
@@ -189,7 +195,7 @@ pub fn vm_map(phys_addr: usize, vm_addr: usize, map_size: usize, perms: u64, reg
 
         while curr_vm_addr < max_addr {
             num_pages += 1;
-            let mut page_table = unsafe { KERN_SATP as *mut u64 };
+            let mut page_table = root;
             for addr_level in  (1..=2).rev(){
                 let page_idx  = addr_get_page_index!(curr_vm_addr, addr_level);
                 let pt_slice  = unsafe {
@@ -199,27 +205,23 @@ pub fn vm_map(phys_addr: usize, vm_addr: usize, map_size: usize, perms: u64, reg
                     let page_valid = (*entry & PTEPerms::VALID) != 0;
                     if !page_valid { 
                         unsafe{
-                            if let Some(allocator) = &mut KERN_PG_ALLOCATOR{
-                                let allocated_addr = allocator.allocate();
-                                match allocated_addr {
-                                    Some(addr) => {
-                                        // core::ptr::write_bytes(addr, 0, PAGE_SIZE);
-                                        let pg_index  = (addr as usize) / PAGE_SIZE;
-                                        let entry_val = ((pg_index as u64) << PAGE_FLAGS) | PTEPerms::VALID ;
-                                        *entry = entry_val;
-                                        page_table = (pg_index * PAGE_SIZE) as *mut u64;
-
-                                        // debug staff
-                                        if arr_idx < 6 {
-                                            addr_entries[arr_idx] = (curr_phys_addr, curr_vm_addr, page_idx, addr_level);
-                                            arr_idx += 1;
-                                        }
-                                        continue;
-                                    },
-                                    None => {
-                                        kprintln!(" Could not allocate page. region: ");
-                                        return;
+                            match pg_alloc() {
+                                Some(addr) => {
+                                    let pg_index  = (addr as usize) / PAGE_SIZE;
+                                    let entry_val = ((pg_index as u64) << PAGE_FLAGS) | PTEPerms::VALID ;
+                                    *entry = entry_val;
+                                    page_table = (pg_index * PAGE_SIZE) as *mut u64;
+
+                                    // debug staff
+                                    if arr_idx < 6 {
+                                        addr_entries[arr_idx] = (curr_phys_addr, curr_vm_addr, page_idx, addr_level);
+                                        arr_idx += 1;
                                     }
+                                    continue;
+                                },
+                                None => {
+                                    kprintln!(" Could not allocate page. region: {}", region);
+                                    return false;
                                 }
                             }
                         };
@@ -227,7 +229,7 @@ pub fn vm_map(phys_addr: usize, vm_addr: usize, map_size: usize, perms: u64, reg
                     page_table = ((*entry >> PAGE_FLAGS) * PAGE_SIZE as u64) as *mut u64;
                 }else{
                     kprintln!("Invalid index into page table");
-                    return;
+                    return false;
                 };
             }
 
@@ -249,6 +251,102 @@ pub fn vm_map(phys_addr: usize, vm_addr: usize, map_size: usize, perms: u64, reg
         }
         vm_map_exit(num_pages, arr_idx);
     }
+    pt_set
+}
+
+
+/// Walk the page table rooted at `root` and return the leaf entry
+/// for `vm_addr`, if the intermediate tables exist.
+pub fn pt_walk(root: *mut u64, vm_addr: usize) -> Option<*mut u64> {
+    if root.is_null() || vm_addr >= MEM_MAX {
+        return None;
+    }
+    let mut table = root;
+    for level in (1..=2).rev() {
+        let entry = unsafe { *table.add(addr_get_page_index!(vm_addr, level)) };
+        if (entry & PTEPerms::VALID) == 0 {
+            return None;
+        }
+        table = ((entry >> PAGE_FLAGS) * PAGE_SIZE as u64) as *mut u64;
+    }
+    Some(unsafe { table.add(addr_get_page_index!(vm_addr, 0)) })
+}
+
+/// Physical address backing the user address `vm_addr`.
+/// Only valid, `USER` accessible mappings that grant `perms` are translated.
+pub fn pt_translate(root: *mut u64, vm_addr: usize, perms: u64) -> Option<usize> {
+    let entry = unsafe { *pt_walk(root, vm_addr)? };
+    let user_page = PTEPerms::VALID | PTEPerms::USER | perms;
+    if (entry & user_page) != user_page {
+        return None;
+    }
+    let page = ((entry >> PAGE_FLAGS) as usize) * PAGE_SIZE;
+    Some(page + (vm_addr % PAGE_SIZE))
+}
+
+/// Physical address behind the kernel address `vm_addr`, for handing
+/// buffers to devices. Most kernel memory is identity mapped, kernel
+/// stacks are not.
+pub fn kern_translate(vm_addr: usize) -> Option<usize> {
+    let entry = unsafe { *pt_walk(KERN_SATP as *mut u64, vm_addr)? };
+    if (entry & PTEPerms::VALID) == 0 {
+        return None;
+    }
+    let page = ((entry >> PAGE_FLAGS) as usize) * PAGE_SIZE;
+    Some(page + (vm_addr % PAGE_SIZE))
+}
+
+/// Remove the mappings of `[vm_addr, vm_addr + size)`. The backing
+/// pages are handed back to the page allocator if `free` is set.
+pub fn pt_unmap(root: *mut u64, vm_addr: usize, size: usize, free: bool) {
+    let mut curr_vm_addr = vm_addr & !(PAGE_SIZE - 1);
+    while curr_vm_addr < vm_addr + size {
+        if let Some(entry) = pt_walk(root, curr_vm_addr) {
+            unsafe {
+                let valid = (*entry & PTEPerms::VALID) != 0;
+                if valid && free {
+                    pg_free(((*entry >> PAGE_FLAGS) as usize * PAGE_SIZE) as *mut u8);
+                }
+                *entry = 0;
+            };
+        }
+        curr_vm_addr += PAGE_SIZE;
+    }
+}
+
+#[derive(Debug)]
+pub enum CopyErr {
+    BadAddress,   // the user range is not (fully) mapped
+}
+
+/// Copy `dst.len()` bytes from the user address `src_va`.
+pub fn copy_in(root: *mut u64, dst: &mut [u8], src_va: usize) -> Result<(), CopyErr> {
+    let mut copied = 0;
+    while copied < dst.len() {
+        let va    = src_va + copied;
+        let phys  = pt_translate(root, va, PTEPerms::READ).ok_or(CopyErr::BadAddress)?;
+        let count = (PAGE_SIZE - (va % PAGE_SIZE)).min(dst.len() - copied);
+        unsafe {
+            core::ptr::copy_nonoverlapping(phys as *const u8, dst[copied..].as_mut_ptr(), count);
+        };
+        copied += count;
+    }
+    Ok(())
+}
+
+/// Copy `src` to the user address `dst_va`.
+pub fn copy_out(root: *mut u64, dst_va: usize, src: &[u8]) -> Result<(), CopyErr> {
+    let mut copied = 0;
+    while copied < src.len() {
+        let va    = dst_va + copied;
+        let phys  = pt_translate(root, va, PTEPerms::WRITE).ok_or(CopyErr::BadAddress)?;
+        let count = (PAGE_SIZE - (va % PAGE_SIZE)).min(src.len() - copied);
+        unsafe {
+            core::ptr::copy_nonoverlapping(src[copied..].as_ptr(), phys as *mut u8, count);
+        };
+        copied += count;
+    }
+    Ok(())
 }
 
 
@@ -311,64 +409,65 @@ pub fn addr_dbg(addr: usize, page_table: *mut u64) -> AddrDebug {
 }
 
 
+/// Address of a symbol from the linker script. Host builds (the `sim`
+/// crate) are not linked with it and get 0.
+macro_rules! linker_addr {
+    ($sym:literal) => {{
+        let x: usize;
+        #[cfg(target_arch = "riscv64")]
+        unsafe { core::arch::asm!(concat!("la {}, ", $sym), out(reg) x) };
+        #[cfg(not(target_arch = "riscv64"))]
+        { x = 0; }
+        x
+    }};
+}
+
 #[inline]
 fn get_end() -> usize{
-    let x: usize;
-    unsafe {
-        core::arch::asm!(
-            "la {}, end",
-            out(reg) x
-        )
-    };
-    x
+    linker_addr!("end")
 }
 
 
 #[inline]
 fn get_txt_end() -> usize {
-    let x: usize;
-    unsafe {
-        core::arch::asm!(
-            "la {}, etext",
-            out(reg) x
-        )
-    };
-    x
+    linker_addr!("etext")
 }
 
 
 #[inline]
 fn get_kern_stack() -> usize {
-    let x: usize;
-    unsafe {
-        core::arch::asm!(
-            "la {}, stack0",
-            out(reg) x
-        )
-    };
-    x
+    linker_addr!("stack0")
 }
 
 pub fn get_data_end() -> usize {
-    let x: usize;
-    unsafe {
-        core::arch::asm!(
-            "la {}, end",
-            out(reg) x
-        );
-    };
-    x
+    linker_addr!("end")
 }
 
 fn get_data_start() -> usize {
-    let x: usize;
-    unsafe {
-        core::arch::asm!(
-            "la {}, data_start",
-            out(reg) x
-        );
-    };
-    x
+    linker_addr!("data_start")
+}
+
+/// RAM is identity mapped below this; the top of the address space
+/// holds the kernel stacks and the trampoline.
+const RAM_LIMIT: usize = MEM_MAX / 2;
+
+/// The RAM ranges of the platform, cut off at `RAM_LIMIT`.
+fn kern_ram() -> ([MemRange; MAX_RAM_RANGES], usize) {
+    let mut ram = [MemRange { start: 0, size: 0 }; MAX_RAM_RANGES];
+    let mut count = 0;
+    for range in crate::platform::platform().ram() {
+        if range.start < RAM_LIMIT {
+            ram[count] = MemRange { start: range.start, size: range.end().min(RAM_LIMIT) - range.start };
+            count += 1;
+        }
+    }
+    (ram, count)
+}
+
+fn map_device(mmio: &crate::platform::Mmio, region: &str) {
+    let base = mmio.base & !(PAGE_SIZE - 1);
+    let end  = (mmio.base + mmio.size + (PAGE_SIZE - 1)) & !(PAGE_SIZE - 1);
+    vm_map(base, base, end - base, PTEPerms::WRITE | PTEPerms::READ, region);
 }
 
 #[unsafe(no_mangle)]
@@ -380,56 +479,57 @@ pub fn kern_vm_create_maps(){
     let kern_data_size = kern_data_end - kern_data_start;
 
 
-    let mut kern_end = get_end();
-    kern_end = (kern_end + (PAGE_SIZE - 1)) & !(PAGE_SIZE - 1);
-    let mem_size = KERN_RESERV - (kern_end - KERN_START);
+    let kern_end = page_up(get_end());
 
-    vm_map(kern_end, kern_end, mem_size, 
-            PTEPerms::READ | PTEPerms::WRITE | PTEPerms::EXEC, "Free Range");
+    // all of RAM but the kernel image
+    let (ram, count) = kern_ram();
+    for range in &ram[..count] {
+        let start = page_up(range.start);
+        let start = if range.contains(KERN_START) { start.max(kern_end) } else { start };
+        let end   = page_down(range.end());
+        if start < end {
+            vm_map(start, start, end - start,
+                    PTEPerms::READ | PTEPerms::WRITE | PTEPerms::EXEC, "Free Range");
+        }
+    }
 
     vm_map(KERN_START, KERN_START, 
             kern_txt_end - KERN_START, PTEPerms::READ | PTEPerms::EXEC, "Kern Code");
 
-    vm_map(VirtMemMap::VIRT_UART0, 
-           VirtMemMap::VIRT_UART0, PAGE_SIZE,
-           PTEPerms::WRITE | PTEPerms::READ, "Uart");
-    
-    vm_map(VirtMemMap::VIRT_VIRTIO, 
-            VirtMemMap::VIRT_VIRTIO, PAGE_SIZE, 
-            PTEPerms::WRITE | PTEPerms::READ, "Virt IO");
-
-    
-    vm_map(VirtMemMap::VIRT_PLIC, 
-            VirtMemMap::VIRT_PLIC, 0x4000000, 
-            PTEPerms::WRITE | PTEPerms::READ, "PLIC");
+    let platform = crate::platform::platform();
+    map_device(&platform.uart, "Uart");
+    for transport in platform.virtio() {
+        map_device(transport, "Virt IO");
+    }
+    map_device(&platform.plic, "PLIC");
 
     // TODO: FIXME: This currently marks all the kernel data (`rodata`, `data`, `bss`) 
     //       with read and write perms.
     vm_map(kern_data_start, kern_data_start, kern_data_size, 
             PTEPerms::READ | PTEPerms::WRITE, "Data Section");
 
- }
+    vm_map(crate::trampoline::trampoline_start(), TRAMPOLINE, PAGE_SIZE,
+            PTEPerms::READ | PTEPerms::EXEC, "Trampoline");
+}
 
 
 #[unsafe(no_mangle)]
 pub fn kern_vm_init(){
     let mut satp_created = false;
     unsafe {
-        let mut kern_end = get_end();
-        kern_end = (kern_end + (PAGE_SIZE - 1)) & !(PAGE_SIZE - 1);
-        let mem_size = KERN_RESERV - (kern_end - KERN_START);
-        if let Ok(mut kallocator) = KPageAllocator::new(kern_end, mem_size){
-            {
-                let alloc_ref = &mut kallocator;
-                let page = alloc_ref.allocate();
-                match page {
-                    Some(page) => {
-                        KERN_PG_ALLOCATOR = Some(kallocator);
-                        KERN_SATP = page as u64;
-                        satp_created = true;
-                    },
-                    None => {}
-                }
+        let (initrd_start, initrd_end) = crate::initramfs::INITRD;
+        let dtb_size = crate::fdt::Fdt::from_addr(crate::fdt::BOOT_DTB)
+            .map_or(0, |dtb| dtb.total_size());
+        let reserved = [
+            MemRange { start: KERN_START, size: get_end() - KERN_START },
+            MemRange { start: initrd_start, size: initrd_end - initrd_start },
+            MemRange { start: crate::fdt::BOOT_DTB, size: dtb_size },
+        ];
+        let (ram, count) = kern_ram();
+        if frame::frame_init(&ram[..count], &reserved).is_ok() {
+            if let Some(page) = pg_alloc() {
+                KERN_SATP = page as u64;
+                satp_created = true;
             }
         }
     }
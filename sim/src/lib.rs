@@ -1,4 +1,6 @@
 #![allow(unused)]
+// `Memory` and its first tests spell out casts of usize offsets
+#![allow(clippy::unnecessary_cast)]
 
 extern crate kernel;
 use kernel::*;
@@ -31,6 +33,123 @@ impl Memory
     }
 }
 
+/// An image file on the host as a block device, e.g. one made with
+/// `mkfs.vfat` and `mcopy`.
+pub struct FileDisk
+{
+    file:  std::sync::Mutex<std::fs::File>,
+}
+
+impl FileDisk
+{
+    pub fn open(path: &std::path::Path) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Self { file: std::sync::Mutex::new(file) })
+    }
+}
+
+impl block::BlockDevice for FileDisk
+{
+    fn sectors(&self) -> u64
+    {
+        let len = self.file.lock().unwrap().metadata().map(|m| m.len()).unwrap_or(0);
+        len / block::SECTOR_SIZE as u64
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), block::BlockErr>
+    {
+        use std::io::{Read, Seek, SeekFrom};
+        block::check_range(self.sectors(), sector, buf.len())?;
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(sector * block::SECTOR_SIZE as u64))
+            .and_then(|_| file.read_exact(buf))
+            .map_err(|_| block::BlockErr::Io)
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), block::BlockErr>
+    {
+        use std::io::{Seek, SeekFrom, Write};
+        block::check_range(self.sectors(), sector, buf.len())?;
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(sector * block::SECTOR_SIZE as u64))
+            .and_then(|_| file.write_all(buf))
+            .map_err(|_| block::BlockErr::Io)
+    }
+
+    fn flush(&self) -> Result<(), block::BlockErr>
+    {
+        self.file.lock().unwrap().sync_data().map_err(|_| block::BlockErr::Io)
+    }
+}
+
+/// A ram disk that loses power after a number of sector writes: the
+/// rest are dropped without an error, as if the machine had stopped.
+pub struct CrashDisk
+{
+    data:    std::sync::Mutex<Vec<u8>>,
+    budget:  std::sync::Mutex<Option<usize>>,
+    writes:  std::sync::atomic::AtomicUsize,
+}
+
+impl CrashDisk
+{
+    /// A disk holding `data` that keeps the first `budget` sector writes,
+    /// all of them with `None`.
+    pub fn new(data: Vec<u8>, budget: Option<usize>) -> Self {
+        Self {
+            data:   std::sync::Mutex::new(data),
+            budget: std::sync::Mutex::new(budget),
+            writes: std::sync::atomic::AtomicUsize::new(0),
+        }
+    }
+
+    /// Sector writes attempted so far, kept or not.
+    pub fn writes(&self) -> usize {
+        self.writes.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// What the disk holds, e.g. after the power went out.
+    pub fn contents(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+}
+
+impl block::BlockDevice for CrashDisk
+{
+    fn sectors(&self) -> u64
+    {
+        (self.data.lock().unwrap().len() / block::SECTOR_SIZE) as u64
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), block::BlockErr>
+    {
+        let data = self.data.lock().unwrap();
+        block::check_range((data.len() / block::SECTOR_SIZE) as u64, sector, buf.len())?;
+        let start = sector as usize * block::SECTOR_SIZE;
+        buf.copy_from_slice(&data[start..start + buf.len()]);
+        Ok(())
+    }
+
+    /// Sector by sector, so the cut may fall inside a multi-sector write.
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), block::BlockErr>
+    {
+        let mut data = self.data.lock().unwrap();
+        block::check_range((data.len() / block::SECTOR_SIZE) as u64, sector, buf.len())?;
+        let mut budget = self.budget.lock().unwrap();
+        for (n, chunk) in buf.chunks(block::SECTOR_SIZE).enumerate() {
+            self.writes.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            match budget.as_mut() {
+                Some(0) => continue,
+                Some(left) => *left -= 1,
+                None => {}
+            }
+            let start = (sector as usize + n) * block::SECTOR_SIZE;
+            data[start..start + chunk.len()].copy_from_slice(chunk);
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -52,31 +171,2249 @@ mod tests {
         let val = uartrd!(RHR as usize, memory.mem);
         assert_eq!(val, 0x05);
     }
-}
 
+    use kernel::fs::tmpfs::TmpFs;
+    use kernel::fs::{FileSystem, FileType, FsErr, Inode};
+
+    #[test]
+    fn tmpfs_write_read_grow()
+    {
+        let fs = TmpFs::new();
+        let file = fs.root().create("a", FileType::Regular).unwrap();
+        assert_eq!(file.write_at(0, b"hello").unwrap(), 5);
+        // past the end: the hole reads back as zeros
+        assert_eq!(file.write_at(10_000, b"world").unwrap(), 5);
+        assert_eq!(file.stat().size, 10_005);
+
+        let mut buf = vec![0xffu8; 10_005];
+        assert_eq!(file.read_at(0, &mut buf).unwrap(), 10_005);
+        assert_eq!(&buf[..5], b"hello");
+        assert!(buf[5..10_000].iter().all(|&b| b == 0));
+        assert_eq!(&buf[10_000..], b"world");
+        assert_eq!(file.read_at(20_000, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn tmpfs_truncate()
+    {
+        let fs = TmpFs::new();
+        let file = fs.root().create("a", FileType::Regular).unwrap();
+        file.write_at(0, &[7u8; 6000]).unwrap();
+        file.truncate(4100).unwrap();
+        assert_eq!(file.stat().size, 4100);
+        // growing again must not bring back the old bytes
+        file.truncate(6000).unwrap();
+        let mut buf = [0xffu8; 6000];
+        assert_eq!(file.read_at(0, &mut buf).unwrap(), 6000);
+        assert!(buf[..4100].iter().all(|&b| b == 7));
+        assert!(buf[4100..].iter().all(|&b| b == 0));
+        file.truncate(0).unwrap();
+        assert_eq!(file.read_at(0, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn tmpfs_directories()
+    {
+        let fs = TmpFs::new();
+        let root = fs.root();
+        let dir = root.create("dir", FileType::Directory).unwrap();
+        assert_eq!(root.stat().nlink, 3);
+        dir.create("f", FileType::Regular).unwrap();
+        assert_eq!(root.create("dir", FileType::Regular).err(), Some(FsErr::Exists));
+        assert_eq!(root.lookup("dir").unwrap().lookup("f").unwrap().stat().kind, FileType::Regular);
+
+        let names: Vec<String> = (0..).map_while(|i| root.dirent(i).unwrap()).map(|e| e.name).collect();
+        assert_eq!(names, ["dir"]);
+
+        assert_eq!(root.unlink("dir").err(), Some(FsErr::NotEmpty));
+        dir.unlink("f").unwrap();
+        root.unlink("dir").unwrap();
+        assert_eq!(root.lookup("dir").err(), Some(FsErr::NotFound));
+        assert_eq!(root.stat().nlink, 2);
+    }
+
+    #[test]
+    fn tmpfs_hard_links()
+    {
+        let fs = TmpFs::new();
+        let root = fs.root();
+        let file = root.create("a", FileType::Regular).unwrap();
+        file.write_at(0, b"shared").unwrap();
+        root.link("b", &file).unwrap();
+        assert_eq!(file.stat().nlink, 2);
+
+        root.unlink("a").unwrap();
+        let other = root.lookup("b").unwrap();
+        assert_eq!(other.stat().ino, file.stat().ino);
+        assert_eq!(other.stat().nlink, 1);
+        let mut buf = [0u8; 6];
+        other.read_at(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"shared");
+
+        // no links across filesystems or to directories
+        let elsewhere = TmpFs::new();
+        assert_eq!(elsewhere.root().link("c", &other).err(), Some(FsErr::Invalid));
+        let dir = root.create("d", FileType::Directory).unwrap();
+        assert_eq!(root.link("e", &dir).err(), Some(FsErr::IsDir));
+    }
+
+    #[test]
+    fn tmpfs_symlinks()
+    {
+        let fs = TmpFs::new();
+        let link = fs.root().symlink("l", "/etc/motd").unwrap();
+        assert_eq!(link.stat().kind, FileType::Symlink);
+        assert_eq!(link.read_link().unwrap(), "/etc/motd");
+    }
+
+    use kernel::cpio::{self, CpioErr};
+
+    /// Append a newc entry to `archive`.
+    fn cpio_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8])
+    {
+        cpio_file(archive, name, mode, (0, 0), 1, 1, data);
+    }
+
+    /// Append a newc entry for inode `ino` of device `(major, minor)`
+    /// with `nlink` links.
+    fn cpio_file(archive: &mut Vec<u8>, name: &str, mode: u32, (major, minor): (u32, u32),
+                 ino: u32, nlink: u32, data: &[u8])
+    {
+        let fields = [
+            ino, mode, 0, 0, nlink, 0, data.len() as u32, major, minor, 0, 0, name.len() as u32 + 1, 0,
+        ];
+        archive.extend_from_slice(b"070701");
+        for field in fields {
+            archive.extend_from_slice(format!("{:08x}", field).as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+    }
+
+    fn cpio_archive() -> Vec<u8>
+    {
+        let mut archive = Vec::new();
+        cpio_entry(&mut archive, ".", cpio::S_IFDIR | 0o755, b"");
+        cpio_entry(&mut archive, "./bin/", cpio::S_IFDIR | 0o755, b"");
+        cpio_entry(&mut archive, "./bin/init", cpio::S_IFREG | 0o755, b"\x7fELF.");
+        cpio_entry(&mut archive, "/sh", cpio::S_IFLNK | 0o777, b"bin/init");
+        cpio_entry(&mut archive, "TRAILER!!!", 0, b"");
+        archive
+    }
+
+    #[test]
+    fn cpio_entries()
+    {
+        let mut archive = cpio_archive();
+        // anything after the trailer is ignored
+        archive.extend_from_slice(b"garbage");
+        let entries: Vec<_> = cpio::entries(&archive).map(Result::unwrap).collect();
+        let names: Vec<_> = entries.iter().map(|entry| entry.name).collect();
+        assert_eq!(names, [".", "bin", "bin/init", "sh"]);
+        assert!(entries[1].is_dir() && !entries[1].is_file());
+        assert!(entries[2].is_file() && entries[2].data == b"\x7fELF.");
+        assert!(entries[3].is_symlink() && entries[3].data == b"bin/init");
+        assert_eq!(entries[2].mode & !cpio::S_IFMT, 0o755);
+        assert_eq!(cpio::normalize("./a/b/"), "a/b");
+        assert_eq!(cpio::normalize("//a"), "a");
+        assert_eq!(cpio::entries(&[]).count(), 1);
+    }
+
+    #[test]
+    fn cpio_errors()
+    {
+        let good = cpio_archive();
+        // the first error ends the iteration
+        let first = |archive: &[u8]| {
+            let mut entries = cpio::entries(archive);
+            let err = entries.find_map(Result::err);
+            assert!(entries.next().is_none());
+            err
+        };
+        assert!(first(&good).is_none());
+
+        let mut bad_magic = good.clone();
+        bad_magic[5] = b'2';        // 070702, newc with checksums
+        assert!(matches!(first(&bad_magic), Some(CpioErr::BadMagic)));
+        let mut bad_header = good.clone();
+        bad_header[6 + 8] = b'x';   // mode
+        assert!(matches!(first(&bad_header), Some(CpioErr::BadHeader)));
+
+        // the name of the second entry loses its NUL, or is not UTF-8
+        let name = 112 + 110;
+        assert_eq!(&good[name..name + 6], b"./bin/");
+        let mut no_nul = good.clone();
+        no_nul[name + 6] = b'x';
+        assert!(matches!(first(&no_nul), Some(CpioErr::BadName)));
+        let mut not_utf8 = good.clone();
+        not_utf8[name + 2] = 0xff;
+        assert!(matches!(first(&not_utf8), Some(CpioErr::BadName)));
+
+        // the archive ends in a header, a name or a file's data, or
+        // has no trailer
+        for len in [100, name + 3, good.len() - 128, good.len() - 124] {
+            assert!(matches!(first(&good[..len]), Some(CpioErr::Truncated)), "cut at {}", len);
+        }
+    }
+
+    #[test]
+    fn initramfs_hard_links()
+    {
+        use kernel::initramfs;
+
+        let file = cpio::S_IFREG | 0o644;
+        let mut archive = Vec::new();
+        // newc puts the data on the last link only
+        cpio_file(&mut archive, "a", file, (8, 1), 7, 2, b"");
+        cpio_file(&mut archive, "b", file, (8, 1), 7, 2, b"shared");
+        // same inode number on another device, and an unlinked empty file
+        cpio_file(&mut archive, "c", file, (8, 2), 7, 2, b"");
+        cpio_file(&mut archive, "d", file, (8, 2), 9, 1, b"");
+        cpio_file(&mut archive, "e", file, (8, 2), 9, 1, b"other");
+        cpio_entry(&mut archive, "TRAILER!!!", 0, b"");
+        let archive = Vec::leak(archive);
+
+        let nodes = initramfs::index(archive).unwrap();
+        let data: Vec<_> = nodes.iter().map(|node| node.data).collect();
+        assert_eq!(data, [&b"shared"[..], b"shared", b"", b"", b"other"]);
+    }
+
+    #[test]
+    fn initramfs_unpack_keeps_links()
+    {
+        use kernel::initramfs;
+
+        let file = cpio::S_IFREG | 0o644;
+        let mut archive = Vec::new();
+        cpio_entry(&mut archive, "etc", cpio::S_IFDIR | 0o755, b"");
+        cpio_file(&mut archive, "etc/a", file, (8, 1), 7, 2, b"");
+        cpio_file(&mut archive, "etc/b", file, (8, 1), 7, 2, b"shared");
+        // a character device and a FIFO
+        cpio_entry(&mut archive, "etc/tty", 0o020000 | 0o620, b"");
+        cpio_entry(&mut archive, "etc/fifo", 0o010000 | 0o644, b"");
+        cpio_entry(&mut archive, "TRAILER!!!", 0, b"");
+        let nodes = initramfs::index(Vec::leak(archive)).unwrap();
+
+        let _kernel = kernel();
+        fs::mount("/", Arc::new(TmpFs::new())).unwrap();
+        assert_eq!(fs::unpack_initramfs(&nodes), Ok(3));
+        let (a, b) = (fs::resolve("/etc/a").unwrap(), fs::resolve("/etc/b").unwrap());
+        assert_eq!((a.stat().ino, a.stat().nlink), (b.stat().ino, 2));
+        b.write_at(0, b"S").unwrap();
+        let mut buf = [0u8; 6];
+        a.read_at(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"Shared");
+        assert!(fs::resolve("/etc/tty").is_err() && fs::resolve("/etc/fifo").is_err());
+        fs::umount("/").unwrap();
+    }
+
+    use kernel::virtio::queue::{QueueBuf, VirtQueue, QUEUE_SIZE};
+
+    #[test]
+    fn virtqueue_chains()
+    {
+        let mut queue = VirtQueue::new().unwrap();
+        let buf = QueueBuf { addr: 0x1000, len: 16, writable: false };
+
+        let first = queue.next_head().unwrap();
+        assert_eq!(queue.push(&[buf, buf, buf]), Some(first));
+        let second = queue.push(&[buf, buf]).unwrap();
+        assert_eq!(queue.num_free(), QUEUE_SIZE - 5);
+        // all or nothing
+        assert_eq!(queue.push(&[buf; QUEUE_SIZE]), None);
+        assert_eq!(queue.num_free(), QUEUE_SIZE - 5);
+
+        assert_eq!(queue.pop_used(), None);
+        queue.device_complete(second, 512);
+        queue.device_complete(first, 0);
+        assert_eq!(queue.pop_used(), Some((second, 512)));
+        assert_eq!(queue.pop_used(), Some((first, 0)));
+        assert_eq!(queue.pop_used(), None);
+
+        queue.free_chain(second);
+        queue.free_chain(first);
+        assert_eq!(queue.num_free(), QUEUE_SIZE);
+    }
+
+    use kernel::mem::frame::FrameAllocator;
+    use kernel::platform::MemRange;
+    use kernel::virtm::PAGE_SIZE;
+
+    #[test]
+    fn frame_allocator_over_ram_ranges()
+    {
+        // 64 frames of "RAM" in two ranges with a 4 frame hole, the kernel
+        // in the first 3 frames and a boot blob straddling frames 40..42
+        let mut ram = vec![0u8; 128 * PAGE_SIZE];
+        let base = (ram.as_mut_ptr() as usize).next_multiple_of(64 * PAGE_SIZE);
+        let frame = |n: usize| base + n * PAGE_SIZE;
+        let ranges = [
+            MemRange { start: base, size: 20 * PAGE_SIZE },
+            MemRange { start: frame(24), size: 40 * PAGE_SIZE },
+        ];
+        let reserved = [
+            MemRange { start: base, size: 3 * PAGE_SIZE - 100 },
+            MemRange { start: frame(40) + 8, size: 2 * PAGE_SIZE - 8 },
+            MemRange { start: 0, size: 0 },
+        ];
+        let mut frames = FrameAllocator::new(&ranges, &reserved).unwrap();
+        // one frame of bitmap at frame 3
+        assert!(frames.is_allocated(frame(3)));
+        assert_eq!(frames.free_frames(), 64 - 4 - 3 - 1 - 2);
+
+        // runs skip the hole and the reserved frames
+        assert_eq!(frames.allocate_run(16, PAGE_SIZE), Some(frame(4)));
+        assert_eq!(frames.allocate_run(4, 8 * PAGE_SIZE), Some(frame(24)));
+        assert_eq!(frames.allocate_run(12, PAGE_SIZE), Some(frame(28)));
+        assert_eq!(frames.allocate_run(23, PAGE_SIZE), None);
+        assert_eq!(frames.allocate_run(22, PAGE_SIZE), Some(frame(42)));
+        assert_eq!(frames.free_frames(), 0);
+        frames.deallocate_run(frame(4), 16);
+        frames.deallocate_run(frame(24), 4);
+        frames.deallocate_run(frame(28), 12);
+        frames.deallocate_run(frame(42), 22);
+
+        let mut taken = Vec::new();
+        while let Some(addr) = frames.allocate() {
+            taken.push((addr - base) / PAGE_SIZE);
+        }
+        assert_eq!(taken.len(), 54);
+        assert!(taken.iter().all(|&n| n > 3 && !(20..24).contains(&n) && !(40..42).contains(&n) && n < 64));
+        assert_eq!(frames.free_frames(), 0);
+
+        for &n in &taken {
+            frames.deallocate(frame(n));
+        }
+        assert_eq!(frames.free_frames(), 54);
+        assert!(FrameAllocator::new(&[], &[]).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "inside reserved")]
+    fn frame_free_reserved()
+    {
+        let mut ram = vec![0u8; 16 * PAGE_SIZE];
+        let base = (ram.as_mut_ptr() as usize).next_multiple_of(PAGE_SIZE);
+        let ranges = [MemRange { start: base, size: 8 * PAGE_SIZE }];
+        let reserved = [MemRange { start: base + 4 * PAGE_SIZE, size: 100 }];
+        let mut frames = FrameAllocator::new(&ranges, &reserved).unwrap();
+        // a run ending in the reserved frame
+        frames.deallocate_run(base + 2 * PAGE_SIZE, 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "not a frame of RAM")]
+    fn frame_free_across_hole()
+    {
+        let mut ram = vec![0u8; 16 * PAGE_SIZE];
+        let base = (ram.as_mut_ptr() as usize).next_multiple_of(PAGE_SIZE);
+        let ranges = [
+            MemRange { start: base, size: 4 * PAGE_SIZE },
+            MemRange { start: base + 6 * PAGE_SIZE, size: 4 * PAGE_SIZE },
+        ];
+        let mut frames = FrameAllocator::new(&ranges, &[]).unwrap();
+        // first and last frame are RAM, the two in the middle are not
+        frames.deallocate_run(base + 3 * PAGE_SIZE, 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "not a frame of RAM")]
+    fn frame_free_misaligned()
+    {
+        let mut ram = vec![0u8; 16 * PAGE_SIZE];
+        let base = (ram.as_mut_ptr() as usize).next_multiple_of(PAGE_SIZE);
+        let ranges = [MemRange { start: base, size: 8 * PAGE_SIZE }];
+        let mut frames = FrameAllocator::new(&ranges, &[]).unwrap();
+        frames.deallocate(base + 5 * PAGE_SIZE + 8);
+    }
+
+    use std::collections::BTreeMap as Map;
+    use kernel::mem::alloc::Allocatable;
+    use kernel::mem::alloc::alloc_buddy::{BuddyAllocator, BuddyErr};
+
+    /// xorshift64, reproducible without a crate.
+    struct Rng(u64);
+
+    impl Rng
+    {
+        fn next(&mut self) -> u64
+        {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize
+        {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    fn buddy_check(buddy: &BuddyAllocator) -> Result<(), BuddyErr>
+    {
+        buddy.check(&mut vec![0u8; buddy.scratch_len()])
+    }
+
+    fn buddy_free_counts(buddy: &BuddyAllocator) -> Vec<usize>
+    {
+        (BuddyAllocator::MIN_ORDER..=BuddyAllocator::MAX_ORDER).map(|order| buddy.free_blocks(order)).collect()
+    }
+
+    #[test]
+    fn buddy_randomized_stress()
+    {
+        // a heap that does not end on a `MAX_BLOCK_SIZE` boundary, wherever
+        // the host puts the region
+        let mut ram = vec![0u8; (1 << 20) + (200 << 10) + BuddyAllocator::MAX_BLOCK_SIZE];
+        let start = ram.as_ptr().align_offset(BuddyAllocator::MAX_BLOCK_SIZE);
+        let region = &mut ram[start..start + (1 << 20) + (200 << 10)];
+        let mut buddy = unsafe { BuddyAllocator::from_region(region) }.unwrap();
+        buddy_check(&buddy).unwrap();
+        let initial = buddy_free_counts(&buddy);
+        assert!(initial.iter().filter(|&&count| count != 0).count() > 1, "{:?}", initial);
+
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        let mut live: Map<usize, (Layout, u8)> = Map::new();
+        let (mut allocs, mut frees, mut failed, mut peak) = (0, 0, 0, 0);
+        for step in 0..20_000 {
+            if live.is_empty() || rng.below(100) < 55 {
+                let bits  = rng.below(16);
+                let size  = rng.below(1 << bits) + 1;
+                let align = 1 << rng.below(13);
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let Some(ptr) = buddy.allocate(layout) else {
+                    failed += 1;
+                    continue;
+                };
+                allocs += 1;
+                let addr = ptr.as_ptr() as usize;
+                assert_eq!(addr % layout.align(), 0);
+                assert!(addr >= region.as_ptr() as usize && addr + size <= region.as_ptr() as usize + region.len());
+                if let Some((&prev, (prev_layout, _))) = live.range(..addr).next_back() {
+                    assert!(prev + prev_layout.size() <= addr, "overlap at step {}", step);
+                }
+                if let Some((&next, _)) = live.range(addr..).next() {
+                    assert!(addr + size <= next, "overlap at step {}", step);
+                }
+                let tag = step as u8;
+                unsafe { ptr.as_ptr().write_bytes(tag, size) };
+                live.insert(addr, (layout, tag));
+            } else {
+                let addr = *live.keys().nth(rng.below(live.len())).unwrap();
+                let (layout, tag) = live.remove(&addr).unwrap();
+                let data = unsafe { std::slice::from_raw_parts(addr as *const u8, layout.size()) };
+                assert!(data.iter().all(|&b| b == tag), "allocation at {:#x} was overwritten", addr);
+                buddy.deallocate(NonNull::new(addr as *mut u8).unwrap(), layout);
+                frees += 1;
+            }
+            if step % 500 == 0 {
+                buddy_check(&buddy).unwrap_or_else(|err| panic!("step {}: {:?}", step, err));
+            }
+            let held: usize = live.values().map(|(layout, _)| buddy.block_size(layout).unwrap()).sum();
+            peak = peak.max(held);
+            let info = buddy.heap_info();
+            assert_eq!((info.allocated, info.allocs, info.frees, info.failed), (held, allocs, frees, failed));
+            assert_eq!(info.peak, peak);
+        }
+        assert!(failed > 0 && allocs > 5000, "{} allocations, {} failed", allocs, failed);
+
+        for (addr, (layout, _)) in std::mem::take(&mut live) {
+            buddy.deallocate(NonNull::new(addr as *mut u8).unwrap(), layout);
+            frees += 1;
+        }
+        buddy_check(&buddy).unwrap();
+        assert_eq!(buddy_free_counts(&buddy), initial);
+        let info = buddy.heap_info();
+        assert_eq!((info.allocated, info.allocs, info.frees, info.peak), (0, allocs, frees, peak));
+        assert_eq!(info.largest_free, BuddyAllocator::MAX_BLOCK_SIZE);
+        assert_eq!(info.free(), buddy_free_counts(&buddy).iter().enumerate()
+            .map(|(n, count)| count << (BuddyAllocator::MIN_ORDER + n)).sum::<usize>());
+    }
+
+    #[test]
+    #[cfg_attr(feature = "heap-debug", should_panic(expected = "heap: double free"))]
+    fn buddy_check_catches_double_free()
+    {
+        let mut region = vec![0u8; 1 << 20];
+        let mut buddy = unsafe { BuddyAllocator::from_region(&mut region) }.unwrap();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let first  = buddy.allocate(layout).unwrap();
+        let _second = buddy.allocate(layout).unwrap();
+        buddy_check(&buddy).unwrap();
+        buddy.deallocate(first, layout);
+        buddy_check(&buddy).unwrap();
+        buddy.deallocate(first, layout);
+        assert!(matches!(buddy_check(&buddy), Err(BuddyErr::Overlap { .. })), "{:?}", buddy_check(&buddy));
+    }
+
+    #[cfg(feature = "heap-debug")]
+    use kernel::mem::alloc::alloc_buddy::{POISON_BYTE, REDZONE_BYTE};
+
+    /// A buddy allocator over a fresh region of about 1 MiB, and an
+    /// allocation from it of `layout`. The region ends on a largest block
+    /// boundary, so the heap has no partial tail for the checks to trip on.
+    #[cfg(feature = "heap-debug")]
+    fn buddy_with_allocation(region: &mut Vec<u8>, layout: Layout) -> (BuddyAllocator, NonNull<u8>)
+    {
+        *region = vec![0u8; (1 << 20) + BuddyAllocator::MAX_BLOCK_SIZE];
+        let start = region.as_ptr() as usize;
+        let end = (start + region.len()) & !(BuddyAllocator::MAX_BLOCK_SIZE - 1);
+        let mut buddy = unsafe { BuddyAllocator::from_region(&mut region[..end - start]) }.unwrap();
+        let ptr = buddy.allocate(layout).unwrap();
+        (buddy, ptr)
+    }
+
+    #[test]
+    #[cfg(feature = "heap-debug")]
+    fn heap_debug_redzones_and_poison()
+    {
+        let layout = Layout::from_size_align(100, 64).unwrap();
+        let mut region = Vec::new();
+        let (mut buddy, ptr) = buddy_with_allocation(&mut region, layout);
+        assert_eq!(ptr.as_ptr() as usize % 64, 0);
+        let block = buddy.block_size(&layout).unwrap();
+        let start = ptr.as_ptr() as usize - 64;
+        let bytes = unsafe { std::slice::from_raw_parts(start as *const u8, block) };
+        assert!(bytes[..64].iter().chain(&bytes[164..]).all(|&b| b == REDZONE_BYTE));
+
+        unsafe { ptr.as_ptr().write_bytes(0, 100) };
+        buddy.deallocate(ptr, layout);
+        let bytes = unsafe { std::slice::from_raw_parts(start as *const u8, block) };
+        // the free list link is kept in the first word
+        assert!(bytes[8..].iter().all(|&b| b == POISON_BYTE));
+        buddy_check(&buddy).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "heap-debug")]
+    #[should_panic(expected = "heap: double free")]
+    fn heap_debug_double_free()
+    {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let mut region = Vec::new();
+        let (mut buddy, ptr) = buddy_with_allocation(&mut region, layout);
+        let _other = buddy.allocate(layout).unwrap();
+        buddy.deallocate(ptr, layout);
+        buddy.deallocate(ptr, layout);
+    }
+
+    #[test]
+    #[cfg(feature = "heap-debug")]
+    #[should_panic(expected = "freed with a larger layout than allocated")]
+    fn heap_debug_free_with_larger_layout()
+    {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let mut region = Vec::new();
+        let (mut buddy, ptr) = buddy_with_allocation(&mut region, layout);
+        buddy.deallocate(ptr, Layout::from_size_align(400, 8).unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "heap-debug")]
+    #[should_panic(expected = "heap: invalid free, freed with a smaller layout")]
+    fn heap_debug_free_with_smaller_layout()
+    {
+        let layout = Layout::from_size_align(400, 8).unwrap();
+        let mut region = Vec::new();
+        let (mut buddy, ptr) = buddy_with_allocation(&mut region, layout);
+        buddy.deallocate(ptr, Layout::from_size_align(100, 8).unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "heap-debug")]
+    #[should_panic(expected = "heap: invalid free, not a heap allocation")]
+    fn heap_debug_free_outside_heap()
+    {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let mut region = Vec::new();
+        let (mut buddy, _ptr) = buddy_with_allocation(&mut region, layout);
+        let mut local = [0u8; 256];
+        buddy.deallocate(NonNull::new(local[16..].as_mut_ptr()).unwrap(), layout);
+    }
+
+    #[test]
+    #[cfg(feature = "heap-debug")]
+    #[should_panic(expected = "heap: redzone overwritten 3 bytes past the allocation")]
+    fn heap_debug_overflow()
+    {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let mut region = Vec::new();
+        let (mut buddy, ptr) = buddy_with_allocation(&mut region, layout);
+        unsafe { ptr.as_ptr().add(103).write(0) };
+        buddy.deallocate(ptr, layout);
+    }
+
+    #[test]
+    #[cfg(feature = "heap-debug")]
+    #[should_panic(expected = "heap: redzone overwritten 1 bytes before the allocation")]
+    fn heap_debug_underflow()
+    {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let mut region = Vec::new();
+        let (mut buddy, ptr) = buddy_with_allocation(&mut region, layout);
+        unsafe { ptr.as_ptr().sub(1).write(0) };
+        buddy.deallocate(ptr, layout);
+    }
+
+    #[cfg(feature = "alloc-track")]
+    use kernel::mem::alloc::track::{AllocTable, BACKTRACE, MAX_TRACKED};
+
+    #[test]
+    #[cfg(feature = "alloc-track")]
+    fn alloc_track_table()
+    {
+        let callers = |caller: usize| {
+            let mut callers = [0; BACKTRACE];
+            callers[0] = caller;
+            callers
+        };
+        let mut table = Box::new(AllocTable::new());
+        let layout = Layout::from_size_align(64, 16).unwrap();
+        // addresses 64 KiB apart share a home slot, so probing and
+        // deletion are exercised as well
+        let ptrs: Vec<usize> = (0..3000).map(|n| 0x8000_0000 + (n % 7) * 0x10000 + (n / 7) * 64).collect();
+        for (n, &ptr) in ptrs.iter().enumerate() {
+            assert_eq!(table.insert(ptr, layout, callers(0x1000 + n)), n as u64);
+        }
+        assert_eq!(table.live(), ptrs.len());
+
+        let mut rng = Rng(42);
+        let mut live = ptrs.clone();
+        while live.len() > 1000 {
+            let ptr = live.swap_remove(rng.below(live.len()));
+            let record = table.remove(ptr).unwrap();
+            assert_eq!((record.ptr, record.layout), (ptr, layout));
+            assert_eq!(record.callers, callers(0x1000 + record.seq as usize));
+            assert!(table.remove(ptr).is_none());
+        }
+        assert_eq!(table.live(), live.len());
+        let mut tracked: Vec<usize> = table.iter().map(|record| record.ptr).collect();
+        tracked.sort_unstable();
+        live.sort_unstable();
+        assert_eq!(tracked, live);
+        for &ptr in &live {
+            assert!(table.remove(ptr).is_some(), "{:#x} lost", ptr);
+        }
+
+        // one slot stays empty, the rest is counted as dropped
+        for n in 0..MAX_TRACKED + 10 {
+            table.insert(0x9000_0000 + n * 16, layout, callers(0));
+        }
+        assert_eq!((table.live(), table.dropped()), (MAX_TRACKED - 1, 11));
+        assert_eq!(table.next_seq(), (ptrs.len() + MAX_TRACKED + 10) as u64);
+    }
+
+    use kernel::mem::alloc::{large_alloc, large_dealloc};
+
+    #[test]
+    fn heap_large_allocations()
+    {
+        let _kernel = kernel();
+        let free = frame::free_frames();
+
+        let layouts = [
+            Layout::from_size_align(300 << 10, 8).unwrap(),
+            Layout::from_size_align(1, 1 << 20).unwrap(),
+            Layout::from_size_align((1 << 20) + 1, 64 << 10).unwrap(),
+        ];
+        let runs: Vec<_> = layouts.iter().map(|&layout| large_alloc(layout).unwrap()).collect();
+        for (run, layout) in runs.iter().zip(&layouts) {
+            assert_eq!(run.as_ptr() as usize % layout.align().max(PAGE_SIZE), 0);
+            unsafe { run.as_ptr().write_bytes(0x5a, layout.size()) };
+        }
+        // the heap-debug redzone after the first run takes another frame
+        let redzone = usize::from(cfg!(feature = "heap-debug"));
+        assert_eq!(frame::free_frames(), free - 75 - redzone - 1 - 257);
+        assert!(large_alloc(Layout::from_size_align(KERNEL_RAM, 8).unwrap()).is_none());
+
+        for (run, layout) in runs.into_iter().zip(layouts) {
+            unsafe { large_dealloc(run, layout) };
+        }
+        assert_eq!(frame::free_frames(), free);
+    }
+
+    use std::alloc::GlobalAlloc;
+    use kernel::mem::alloc::Allocator;
 
+    #[test]
+    fn heap_info_counts_every_path()
+    {
+        let _kernel = kernel();
+        let mut region = vec![0u8; 1 << 20];
+        let mut heap = Allocator::<BuddyAllocator>::new();
+        heap.set_allocator(unsafe { BuddyAllocator::from_region(&mut region) }.unwrap());
+        let info = |heap: &Allocator<BuddyAllocator>| heap.heap_info().unwrap();
+        let empty = info(&heap);
+        assert_eq!((empty.allocs, empty.frees, empty.failed, empty.allocated), (0, 0, 0, 0));
 
+        // what a `Box<[u64; 8]>` asks for, from a slab
+        let boxed = unsafe { heap.alloc(Layout::new::<[u64; 8]>()) };
+        assert!(!boxed.is_null());
+        let slabbed = info(&heap);
+        assert_eq!(slabbed.allocs, 1);
+        assert!(slabbed.allocated > 0);
 
+        // a block from the buddy and a run of frames
+        let block = Layout::from_size_align(8 << 10, 8).unwrap();
+        let run = Layout::from_size_align(BuddyAllocator::MAX_LAYOUT + 1, 8).unwrap();
+        let (block_ptr, run_ptr) = unsafe { (heap.alloc(block), heap.alloc(run)) };
+        assert!(!block_ptr.is_null() && !run_ptr.is_null());
+        let all = info(&heap);
+        assert_eq!(all.allocs, 3);
+        let run_bytes = (BuddyAllocator::MAX_LAYOUT + 1).next_multiple_of(PAGE_SIZE);
+        // heap-debug redzones may take the block up an order
+        assert!(all.allocated >= slabbed.allocated + (8 << 10) + run_bytes);
+        assert_eq!(all.peak, all.allocated);
 
+        // the frames, then the buddy run out
+        let huge = Layout::from_size_align(KERNEL_RAM, 8).unwrap();
+        assert!(unsafe { heap.alloc(huge) }.is_null());
+        assert_eq!(info(&heap).failed, 1);
+        let most = Layout::from_size_align(BuddyAllocator::MAX_LAYOUT, 8).unwrap();
+        let filler: Vec<_> = std::iter::from_fn(|| NonNull::new(unsafe { heap.alloc(most) })).collect();
+        let full = info(&heap);
+        assert_eq!((full.allocs, full.failed), (3 + filler.len(), 2));
+
+        unsafe {
+            for ptr in &filler {
+                heap.dealloc(ptr.as_ptr(), most);
+            }
+            heap.dealloc(run_ptr, run);
+            heap.dealloc(block_ptr, block);
+            heap.dealloc(boxed, Layout::new::<[u64; 8]>());
+        }
+        let freed = info(&heap);
+        assert_eq!((freed.allocs, freed.frees, freed.failed), (full.allocs, full.allocs, 2));
+        assert_eq!(freed.allocated, slabbed.allocated);
+        assert_eq!(freed.peak, full.allocated);
+    }
 
+    /// `Allocator` over a fresh 1 MiB buddy heap in `region`.
+    #[cfg(feature = "heap-debug")]
+    fn heap_with_region(region: &mut Vec<u8>) -> Allocator<BuddyAllocator>
+    {
+        *region = vec![0u8; 1 << 20];
+        let mut heap = Allocator::<BuddyAllocator>::new();
+        heap.set_allocator(unsafe { BuddyAllocator::from_region(region) }.unwrap());
+        heap
+    }
 
+    #[test]
+    #[cfg(feature = "heap-debug")]
+    #[should_panic(expected = "heap: double free")]
+    fn heap_debug_slab_double_free()
+    {
+        let _kernel = kernel();
+        let mut region = Vec::new();
+        let heap = heap_with_region(&mut region);
+        let layout = Layout::new::<[u64; 8]>();
+        unsafe {
+            let ptr = heap.alloc(layout);
+            assert!(!ptr.is_null());
+            heap.dealloc(ptr, layout);
+            assert!((0..64).all(|idx| idx < 8 || *ptr.add(idx) == POISON_BYTE));
+            heap.dealloc(ptr, layout);
+        }
+    }
 
+    #[test]
+    #[cfg(feature = "heap-debug")]
+    #[should_panic(expected = "heap: redzone overwritten 0 bytes past the allocation")]
+    fn heap_debug_slab_overflow()
+    {
+        let _kernel = kernel();
+        let mut region = Vec::new();
+        let heap = heap_with_region(&mut region);
+        let layout = Layout::new::<[u64; 8]>();
+        unsafe {
+            let ptr = heap.alloc(layout);
+            *ptr.add(64) = 0;
+            heap.dealloc(ptr, layout);
+        }
+    }
 
+    #[test]
+    #[cfg(feature = "heap-debug")]
+    #[should_panic(expected = "heap: redzone overwritten 2 bytes past the allocation")]
+    fn heap_debug_large_overflow()
+    {
+        let _kernel = kernel();
+        let mut region = Vec::new();
+        let heap = heap_with_region(&mut region);
+        let layout = Layout::from_size_align(BuddyAllocator::MAX_LAYOUT + 1, 8).unwrap();
+        unsafe {
+            let ptr = heap.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(*ptr.add(layout.size()), REDZONE_BYTE);
+            *ptr.add(layout.size() + 2) = 0;
+            heap.dealloc(ptr, layout);
+        }
+    }
 
+    #[test]
+    #[cfg(feature = "heap-debug")]
+    #[should_panic(expected = "heap: double free, or not a run of frames")]
+    fn heap_debug_large_double_free()
+    {
+        let _kernel = kernel();
+        let mut region = Vec::new();
+        let heap = heap_with_region(&mut region);
+        let layout = Layout::from_size_align(BuddyAllocator::MAX_LAYOUT + 1, 8).unwrap();
+        unsafe {
+            let ptr = heap.alloc(layout);
+            assert!(!ptr.is_null());
+            heap.dealloc(ptr, layout);
+            heap.dealloc(ptr, layout);
+        }
+    }
 
+    #[cfg(feature = "alloc-track")]
+    use kernel::mem::alloc::track;
+    #[cfg(feature = "alloc-track")]
+    use std::alloc::System;
 
+    /// The host allocator of the test binary. The allocations of a
+    /// thread with `ROUTED` set go to a kernel `Allocator` instead, so
+    /// tracking sees real `__rust_alloc` calls from `Box` and `Vec`.
+    #[cfg(feature = "alloc-track")]
+    struct RoutedAlloc;
 
+    #[cfg(feature = "alloc-track")]
+    #[global_allocator]
+    static ROUTED_ALLOC: RoutedAlloc = RoutedAlloc;
 
+    #[cfg(feature = "alloc-track")]
+    const ROUTED_SIZE: usize = 1 << 20;
+    #[cfg(feature = "alloc-track")]
+    static mut ROUTED_REGION: [u8; ROUTED_SIZE] = [0; ROUTED_SIZE];
+    #[cfg(feature = "alloc-track")]
+    static mut ROUTED_HEAP: Allocator<BuddyAllocator> = Allocator::new();
+    #[cfg(feature = "alloc-track")]
+    static ROUTED_INIT: std::sync::Once = std::sync::Once::new();
 
+    #[cfg(feature = "alloc-track")]
+    thread_local! {
+        static ROUTED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+    }
 
+    #[cfg(feature = "alloc-track")]
+    unsafe impl GlobalAlloc for RoutedAlloc {
+        // no frame of its own, as with the kernel's `Allocator`
+        #[inline(always)]
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            match ROUTED.with(|routed| routed.get()) {
+                true  => (*std::ptr::addr_of!(ROUTED_HEAP)).alloc(layout),
+                false => System.alloc(layout),
+            }
+        }
 
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            let region = std::ptr::addr_of!(ROUTED_REGION) as usize;
+            match (region..region + ROUTED_SIZE).contains(&(ptr as usize)) {
+                true  => (*std::ptr::addr_of!(ROUTED_HEAP)).dealloc(ptr, layout),
+                false => System.dealloc(ptr, layout),
+            }
+        }
+    }
 
+    /// Run `f` with the allocations of this thread going to the kernel
+    /// heap.
+    #[cfg(feature = "alloc-track")]
+    fn on_kernel_heap<R>(f: impl FnOnce() -> R) -> R
+    {
+        ROUTED_INIT.call_once(|| unsafe {
+            let region = &mut *std::ptr::addr_of_mut!(ROUTED_REGION);
+            let heap = &mut *std::ptr::addr_of_mut!(ROUTED_HEAP);
+            heap.set_allocator(BuddyAllocator::from_region(region).unwrap());
+        });
+        ROUTED.with(|routed| routed.set(true));
+        let result = f();
+        ROUTED.with(|routed| routed.set(false));
+        result
+    }
 
+    #[inline(never)]
+    #[cfg(feature = "alloc-track")]
+    fn box_site() -> Box<u64>
+    {
+        Box::new(7)
+    }
 
+    #[inline(never)]
+    #[cfg(feature = "alloc-track")]
+    fn vec_site(vec: &mut Vec<u64>)
+    {
+        vec.push(7);
+    }
 
+    #[test]
+    #[cfg(feature = "alloc-track")]
+    fn alloc_track_records_the_callers()
+    {
+        // a return address inside `site`, past the liballoc frames
+        let within = |callers: &[usize], site: usize| {
+            callers.iter().any(|&caller| (site + 1..site + 0x200).contains(&caller))
+        };
 
+        let boxed = on_kernel_heap(box_site);
+        let ptr = &*boxed as *const u64 as *mut u8;
+        let record = track::lookup(ptr).unwrap();
+        assert!(within(&record.callers, box_site as *const () as usize));
+        drop(boxed);
+        assert!(track::lookup(ptr).is_none());
 
+        let mut vec = Vec::new();
+        on_kernel_heap(|| vec_site(&mut vec));
+        let ptr = vec.as_mut_ptr() as *mut u8;
+        let record = track::lookup(ptr).unwrap();
+        assert!(within(&record.callers, vec_site as *const () as usize));
+        drop(vec);
+        assert!(track::lookup(ptr).is_none());
+    }
 
+    use std::cell::Cell;
+    use std::alloc::Layout;
+    use std::ptr::NonNull;
+    use kernel::mem::alloc::slab::{self, SlabCache, SlabSource};
 
+    /// Slabs from the host heap, counting the live ones.
+    #[derive(Default)]
+    struct HostSlabs
+    {
+        live: Cell<usize>,
+    }
 
+    impl SlabSource for HostSlabs
+    {
+        fn alloc_slab(&self, size: usize) -> Option<NonNull<u8>>
+        {
+            self.live.set(self.live.get() + 1);
+            NonNull::new(unsafe { std::alloc::alloc(Layout::from_size_align(size, size).unwrap()) })
+        }
 
+        fn free_slab(&self, slab: NonNull<u8>, size: usize)
+        {
+            self.live.set(self.live.get() - 1);
+            unsafe { std::alloc::dealloc(slab.as_ptr(), Layout::from_size_align(size, size).unwrap()) };
+        }
+    }
+
+    #[test]
+    fn slab_cache_objects()
+    {
+        let src = HostSlabs::default();
+        let mut cache = SlabCache::new("test-40", 40, 8, None);
+        // heap-debug puts a redzone after every object
+        let stride = if cfg!(feature = "heap-debug") { 56 } else { 40 };
+        let per_slab = 4096 / stride - 1;
+
+        let mut objects: Vec<usize> = (0..300)
+            .map(|_| cache.allocate(&src).unwrap().as_ptr() as usize)
+            .collect();
+        let stats = cache.stats();
+        assert_eq!((stats.object_size, stats.slab_size, stats.inuse), (stride, 4096, 300));
+        assert_eq!(stats.slabs, 300usize.div_ceil(per_slab));
+        assert_eq!(stats.objects, stats.slabs * per_slab);
+        assert_eq!(src.live.get(), stats.slabs);
+
+        objects.sort();
+        assert!(objects.iter().all(|&obj| obj % 8 == 0));
+        assert!(objects.windows(2).all(|pair| pair[1] - pair[0] >= stride));
+
+        // every other object, then the rest: all slabs but one go back
+        for &obj in objects.iter().step_by(2).chain(objects.iter().skip(1).step_by(2)) {
+            unsafe { cache.deallocate(NonNull::new(obj as *mut u8).unwrap(), &src) };
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.slabs, stats.inuse, stats.allocs, stats.frees), (1, 0, 300, 300));
+        assert_eq!(src.live.get(), 1);
+
+        // the kept slab is reused
+        let again = cache.allocate(&src).unwrap();
+        assert_eq!(src.live.get(), 1);
+        unsafe { cache.deallocate(again, &src) };
+    }
+
+    #[test]
+    fn slab_typed_caches()
+    {
+        fn fill(obj: *mut u8)
+        {
+            unsafe { obj.write_bytes(0xab, 24) };
+        }
+        let cache = slab::create_cache("test-typed", 24, 32, Some(fill)).unwrap();
+        let objects: Vec<_> = (0..200).map(|_| cache.alloc().unwrap()).collect();
+        for obj in &objects {
+            assert_eq!(obj.as_ptr() as usize % 32, 0);
+            assert_eq!(unsafe { std::slice::from_raw_parts(obj.as_ptr(), 24) }, &[0xab; 24]);
+        }
+        let stats = cache.stats();
+        let stride = if cfg!(feature = "heap-debug") { 64 } else { 32 };
+        let slabs = 200usize.div_ceil((4096 - 32) / stride);
+        assert_eq!((stats.object_size, stats.inuse), (stride, 200));
+        assert_eq!((stats.slabs, stats.utilisation()), (slabs, 200 * stride * 100 / (slabs * 4096)));
+        assert!(slab::cache_stats().iter().any(|s| s.name == "test-typed"));
+        for obj in objects {
+            unsafe { cache.free(obj) };
+        }
+        assert_eq!(cache.stats().inuse, 0);
+
+        assert!(slab::create_cache("bad-align", 24, 24, None).is_none());
+        assert!(slab::create_cache("too-big", 1 << 20, 8, None).is_none());
+
+        let class = |size, align| slab::size_class(&Layout::from_size_align(size, align).unwrap())
+            .map(|class| slab::GENERAL_SIZES[class]);
+        assert_eq!(class(1, 1), Some(16));
+        assert_eq!(class(80, 8), Some(96));
+        assert_eq!(class(80, 64), Some(128));
+        assert_eq!(class(2048, 8), Some(2048));
+        assert_eq!(class(2049, 8), None);
+        assert_eq!(class(16, 128), None);
+    }
+
+    use std::sync::Arc;
+    use kernel::block::{self, cache, BlockDevice, BlockErr, RamDisk};
+
+    /// A registered ram disk of `blocks` 512 byte blocks, block `n` filled with `n`.
+    fn ram_disk(blocks: usize) -> (Arc<RamDisk>, block::DevId)
+    {
+        let data = (0..blocks).flat_map(|n| [n as u8; 512]).collect();
+        let disk = Arc::new(RamDisk::new(data));
+        let dev = block::register(disk.clone());
+        (disk, dev)
+    }
+
+    #[test]
+    fn bcache_read_write_back()
+    {
+        let (disk, dev) = ram_disk(16);
+        {
+            let mut buf = block::bread(dev, 3).unwrap();
+            assert!(buf.data().iter().all(|&b| b == 3));
+            buf.data_mut()[0] = 0xaa;
+            buf.write_back();
+        }
+        // cached, not on the disk yet
+        assert_eq!(block::bread(dev, 3).unwrap().data()[0], 0xaa);
+        assert_eq!(disk.contents()[3 * 512], 3);
+        block::sync(dev).unwrap();
+        assert_eq!(disk.contents()[3 * 512], 0xaa);
+
+        let mut buf = block::bread(dev, 4).unwrap();
+        buf.data_mut()[1] = 0xbb;
+        buf.write_through().unwrap();
+        assert_eq!(disk.contents()[4 * 512 + 1], 0xbb);
+        drop(buf);
+
+        assert_eq!(block::bread(dev, 16).err(), Some(BlockErr::OutOfRange));
+        block::unregister(dev).unwrap();
+    }
+
+    #[test]
+    fn bcache_evicts_dirty_buffers()
+    {
+        let blocks = cache::NBUF * 2;
+        let (disk, dev) = ram_disk(blocks);
+        for n in 0..blocks as u64 {
+            let mut buf = block::bread(dev, n).unwrap();
+            buf.data_mut()[0] = !(n as u8);
+            buf.write_back();
+        }
+        // the early blocks were recycled, and written back on the way
+        let contents = disk.contents();
+        assert_eq!(contents[0], !0);
+        assert_eq!(contents[512], !1);
+        for n in 0..blocks as u64 {
+            assert_eq!(block::bread(dev, n).unwrap().data()[0], !(n as u8));
+        }
+        block::unregister(dev).unwrap();
+    }
+
+    #[test]
+    fn bcache_shrink()
+    {
+        let (disk, dev) = ram_disk(8);
+        for n in 0..4 {
+            drop(block::bread(dev, n).unwrap());
+        }
+        let mut dirty = block::bread(dev, 4).unwrap();
+        dirty.data_mut()[0] = 0xdd;
+        dirty.write_back();
+        drop(dirty);
+        let held = block::bread(dev, 5).unwrap();
+
+        // change the disk under the cache: only dropped buffers see it
+        disk.write_sectors(0, &[0xee; 512 * 4]).unwrap();
+        cache::shrink();
+        for n in 0..4 {
+            assert_eq!(block::bread(dev, n).unwrap().data()[0], 0xee);
+        }
+        assert_eq!(block::bread(dev, 4).unwrap().data()[0], 0xdd);
+        assert_eq!(held.data()[0], 5);
+        drop(held);
+        block::sync(dev).unwrap();
+        block::unregister(dev).unwrap();
+    }
+
+    #[test]
+    fn bcache_block_size()
+    {
+        let (_disk, dev) = ram_disk(16);
+        {
+            let _held = block::bread(dev, 0).unwrap();
+            assert_eq!(block::set_block_size(dev, 1024), Err(BlockErr::Busy));
+        }
+        assert_eq!(block::set_block_size(dev, 1000), Err(BlockErr::BadBlockSize));
+        block::set_block_size(dev, 1024).unwrap();
+        assert_eq!(block::blocks(dev).unwrap(), 8);
+        let buf = block::bread(dev, 1).unwrap();
+        assert_eq!(buf.data().len(), 1024);
+        assert_eq!((buf.data()[0], buf.data()[1023]), (2, 3));
+        drop(buf);
+        block::unregister(dev).unwrap();
+    }
+
+    use std::path::{Path, PathBuf};
+    use kernel::fs::fat32::Fat32Fs;
+    use super::FileDisk;
+
+    /// Write a FAT32 image to a sparse temporary file, laid out the way
+    /// `mkfs.vfat -F 32 -s 1` and `mcopy` leave it: a volume label, a file
+    /// "Hello World.txt" with a long name holding "hello, world" and an
+    /// empty "readme.txt" that only has a lower case 8.3 name. It has the
+    /// 65525 clusters a volume needs at least to be FAT32 rather than
+    /// FAT16, about 33 MiB with 512 byte clusters.
+    fn fat32_image(name: &str) -> PathBuf
+    {
+        const CLUSTERS: usize = 65536;
+        const FAT_SECTORS: usize = ((CLUSTERS + 2) * 4).div_ceil(512);
+        const DATA: usize = 32 + 2 * FAT_SECTORS;
+        const SECTORS: usize = DATA + CLUSTERS;
+        // everything past the file's cluster stays a hole
+        let mut image = vec![0u8; (DATA + 2) * 512];
+        let put16 = |image: &mut Vec<u8>, at: usize, v: u16| image[at..at + 2].copy_from_slice(&v.to_le_bytes());
+        let put32 = |image: &mut Vec<u8>, at: usize, v: u32| image[at..at + 4].copy_from_slice(&v.to_le_bytes());
+
+        // boot sector
+        image[..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+        image[3..11].copy_from_slice(b"mkfs.fat");
+        put16(&mut image, 11, 512);
+        image[13] = 1;
+        put16(&mut image, 14, 32);
+        image[16] = 2;
+        image[21] = 0xf8;
+        put32(&mut image, 32, SECTORS as u32);
+        put32(&mut image, 36, FAT_SECTORS as u32);
+        put32(&mut image, 44, 2);
+        put16(&mut image, 48, 1);
+        put16(&mut image, 50, 6);
+        image[66] = 0x29;
+        image[71..82].copy_from_slice(b"LULA       ");
+        image[82..90].copy_from_slice(b"FAT32   ");
+        image[510..512].copy_from_slice(&[0x55, 0xaa]);
+        // FSInfo
+        put32(&mut image, 512, 0x41615252);
+        put32(&mut image, 512 + 484, 0x61417272);
+        put32(&mut image, 512 + 488, 0xffff_ffff);
+        put32(&mut image, 512 + 492, 4);
+        image[512 + 510..512 + 512].copy_from_slice(&[0x55, 0xaa]);
+        // both FATs: media, reserved, root directory and the file
+        for fat in 0..2 {
+            let at = (32 + fat * FAT_SECTORS) * 512;
+            for (n, v) in [0x0fff_fff8u32, 0x0fff_ffff, 0x0fff_ffff, 0x0fff_ffff].iter().enumerate() {
+                put32(&mut image, at + n * 4, *v);
+            }
+        }
+
+        // root directory in cluster 2
+        let root = DATA * 512;
+        image[root..root + 11].copy_from_slice(b"LULA       ");
+        image[root + 11] = 0x08;
+        let short = *b"HELLOW~1TXT";
+        let sum = short.iter().fold(0u8, |s, &b| s.rotate_right(1).wrapping_add(b));
+        let units: Vec<u16> = "Hello World.txt".encode_utf16().collect();
+        let offsets = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+        for (slot, order) in [(1usize, 2usize), (2, 1)] {
+            let at = root + slot * 32;
+            image[at] = order as u8 | if order == 2 { 0x40 } else { 0 };
+            image[at + 11] = 0x0f;
+            image[at + 13] = sum;
+            for (i, off) in offsets.iter().enumerate() {
+                let n = (order - 1) * 13 + i;
+                let unit = if n < units.len() { units[n] } else if n == units.len() { 0 } else { 0xffff };
+                put16(&mut image, at + off, unit);
+            }
+        }
+        let at = root + 3 * 32;
+        image[at..at + 11].copy_from_slice(&short);
+        image[at + 11] = 0x20;
+        put16(&mut image, at + 26, 3);
+        put32(&mut image, at + 28, 12);
+        let at = root + 4 * 32;
+        image[at..at + 11].copy_from_slice(b"README  TXT");
+        image[at + 11] = 0x20;
+        image[at + 12] = 0x18;
+        // the file in cluster 3
+        image[(DATA + 1) * 512..(DATA + 1) * 512 + 12].copy_from_slice(b"hello, world");
+
+        let path = std::env::temp_dir().join(format!("lula-{}-{}.img", name, std::process::id()));
+        std::fs::write(&path, image).unwrap();
+        std::fs::File::options().write(true).open(&path).unwrap().set_len((SECTORS * 512) as u64).unwrap();
+        path
+    }
+
+    fn fat32_mount(path: &Path) -> (Arc<Fat32Fs>, block::DevId)
+    {
+        let dev = block::register(Arc::new(FileDisk::open(path).unwrap()));
+        (Fat32Fs::mount(dev).unwrap(), dev)
+    }
+
+    fn fat32_unmount(fs: Arc<Fat32Fs>, dev: block::DevId)
+    {
+        fs.sync().unwrap();
+        drop(fs);
+        block::unregister(dev).unwrap();
+    }
+
+    #[test]
+    fn fat32_reads_volume()
+    {
+        let path = fat32_image("read");
+        let (fs, dev) = fat32_mount(&path);
+        let root = fs.root();
+
+        let names: Vec<String> = (0..).map_while(|n| root.dirent(n).unwrap()).map(|e| e.name).collect();
+        assert_eq!(names, ["Hello World.txt", "readme.txt"]);
+
+        let hello = root.lookup("hello world.TXT").unwrap();
+        assert_eq!(hello.stat().size, 12);
+        let mut buf = [0u8; 32];
+        assert_eq!(hello.read_at(0, &mut buf).unwrap(), 12);
+        assert_eq!(&buf[..12], b"hello, world");
+        assert_eq!(hello.read_at(7, &mut buf).unwrap(), 5);
+        // the 8.3 alias finds the same file
+        assert_eq!(root.lookup("HELLOW~1.TXT").unwrap().stat().ino, hello.stat().ino);
+        assert_eq!(root.lookup("README.TXT").unwrap().stat().size, 0);
+        assert_eq!(root.lookup("missing").err(), Some(FsErr::NotFound));
+
+        fat32_unmount(fs, dev);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn fat32_create_append_delete()
+    {
+        let path = fat32_image("write");
+        let (fs, dev) = fat32_mount(&path);
+        let root = fs.root();
+
+        let file = root.create("A rather long file name.data", FileType::Regular).unwrap();
+        let data: Vec<u8> = (0..3000u32).map(|n| (n * 7) as u8).collect();
+        for chunk in data.chunks(700) {
+            let end = file.stat().size as usize;
+            assert_eq!(file.write_at(end, chunk).unwrap(), chunk.len());
+        }
+        assert_eq!(file.stat().size, 3000);
+        let mut back = vec![0u8; 4000];
+        assert_eq!(file.read_at(0, &mut back).unwrap(), 3000);
+        assert_eq!(&back[..3000], &data[..]);
+        assert_eq!(root.create("a RATHER long file name.data", FileType::Regular).err(), Some(FsErr::Exists));
+        assert_eq!(root.create("bad:name", FileType::Regular).err(), Some(FsErr::InvalidPath));
+
+        // a write past the end leaves zeros
+        let short = root.create("NOTES.TXT", FileType::Regular).unwrap();
+        short.write_at(1000, b"end").unwrap();
+        assert_eq!(short.read_at(0, &mut back).unwrap(), 1003);
+        assert!(back[..1000].iter().all(|&b| b == 0));
+        short.truncate(10).unwrap();
+        assert_eq!(short.stat().size, 10);
+
+        root.unlink("a rather long file name.DATA").unwrap();
+        assert_eq!(root.lookup("A rather long file name.data").err(), Some(FsErr::NotFound));
+        assert_eq!(file.stat().nlink, 0);
+        let names: Vec<String> = (0..).map_while(|n| root.dirent(n).unwrap()).map(|e| e.name).collect();
+        assert_eq!(names, ["Hello World.txt", "readme.txt", "NOTES.TXT"]);
+
+        fat32_unmount(fs, dev);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn fat32_directories()
+    {
+        let path = fat32_image("dirs");
+        let (fs, dev) = fat32_mount(&path);
+        let root = fs.root();
+
+        let sub = root.create("Sub directory", FileType::Directory).unwrap();
+        let deeper = sub.create("deeper", FileType::Directory).unwrap();
+        deeper.create("leaf.txt", FileType::Regular).unwrap().write_at(0, b"leaf").unwrap();
+        let leaf = root.lookup("sub directory").unwrap().lookup("DEEPER").unwrap().lookup("leaf.txt").unwrap();
+        assert_eq!(leaf.stat().size, 4);
+        assert_eq!(root.lookup("Sub directory").unwrap().stat().kind, FileType::Directory);
+        assert_eq!(leaf.lookup("x").err(), Some(FsErr::NotDir));
+        assert_eq!(sub.unlink("deeper"), Err(FsErr::NotEmpty));
+
+        // more entries than one cluster holds
+        for n in 0..40 {
+            sub.create(&format!("file number {}", n), FileType::Regular).unwrap();
+        }
+        let names: Vec<String> = (0..).map_while(|n| sub.dirent(n).unwrap()).map(|e| e.name).collect();
+        assert_eq!(names.len(), 41);
+        assert_eq!(names[40], "file number 39");
+
+        deeper.unlink("leaf.txt").unwrap();
+        sub.unlink("deeper").unwrap();
+        assert_eq!(sub.lookup("deeper").err(), Some(FsErr::NotFound));
+
+        fat32_unmount(fs, dev);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn fat32_survives_remount()
+    {
+        let path = fat32_image("remount");
+        let (fs, dev) = fat32_mount(&path);
+        let dir = fs.root().create("saved", FileType::Directory).unwrap();
+        let file = dir.create("Persistent data.bin", FileType::Regular).unwrap();
+        let data = vec![0x5au8; 2000];
+        file.write_at(0, &data).unwrap();
+        fs.root().lookup("Hello World.txt").unwrap().write_at(12, b", again").unwrap();
+        drop((dir, file));
+        fat32_unmount(fs, dev);
+
+        let (fs, dev) = fat32_mount(&path);
+        let file = fs.root().lookup("saved").unwrap().lookup("persistent DATA.bin").unwrap();
+        let mut back = vec![0u8; 2100];
+        assert_eq!(file.read_at(0, &mut back).unwrap(), 2000);
+        assert!(back[..2000].iter().all(|&b| b == 0x5a));
+        let hello = fs.root().lookup("hello world.txt").unwrap();
+        assert_eq!(hello.read_at(0, &mut back).unwrap(), 19);
+        assert_eq!(&back[..19], b"hello, world, again");
+        drop((file, hello));
+        fat32_unmount(fs, dev);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn fat32_long_non_ascii_name()
+    {
+        let path = fat32_image("long-name");
+        let (fs, dev) = fat32_mount(&path);
+        assert!(matches!(fs.root().create(&"é".repeat(128), FileType::Regular), Err(FsErr::NameTooLong)));
+        fs.root().create(&"a".repeat(255), FileType::Regular).unwrap().write_at(0, b"long").unwrap();
+        fat32_unmount(fs, dev);
+
+        // another system's 255 character name, 510 bytes in UTF-8
+        let mut image = std::fs::read(&path).unwrap();
+        let mut units = 0;
+        for slot in image.chunks_mut(32).filter(|slot| slot[11] == 0x0f) {
+            for off in [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30] {
+                if slot[off..off + 2] == *b"a\0" {
+                    slot[off] = 0xe9;
+                    units += 1;
+                }
+            }
+        }
+        assert_eq!(units, 255);
+        std::fs::write(&path, image).unwrap();
+
+        // it goes by its 8.3 name
+        let (fs, dev) = fat32_mount(&path);
+        let names: Vec<_> = (0..).map_while(|n| fs.root().dirent(n).unwrap()).map(|entry| entry.name).collect();
+        assert_eq!(names.len(), 3);
+        let short = names.iter().find(|name| name.starts_with("AAAAAA~")).unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(fs.root().lookup(short).unwrap().read_at(0, &mut buf).unwrap(), 4);
+
+        {
+            let _kernel = kernel();
+            let proc = run_proc(1);
+            let fd = proc.fd_alloc(Arc::new(fs::File::new(fs.root(), fs::OpenFlags::RDONLY))).unwrap();
+            let len = call(syscall::SYS_GETDENTS, &[fd, USER_HEAP, PAGE_SIZE]);
+            let mut records = vec![0u8; len as usize];
+            virtm::copy_in(proc.page_table, &mut records, USER_HEAP).unwrap();
+            let mut listed = Vec::new();
+            while !records.is_empty() {
+                let reclen = u16::from_le_bytes([records[8], records[9]]) as usize;
+                let namelen = records[11] as usize;
+                listed.push(String::from_utf8(records[12..12 + namelen].to_vec()).unwrap());
+                records.drain(..reclen);
+            }
+            assert_eq!(listed, names);
+            exit_proc(proc);
+        }
+
+        // and unlinking it frees its long name entries too
+        fs.root().unlink(short).unwrap();
+        fat32_unmount(fs, dev);
+        let image = std::fs::read(&path).unwrap();
+        assert!(!image.chunks(32).any(|slot| slot[11] == 0x0f && slot[0] != 0xe5 && slot[1] == 0xe9));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    use kernel::fs::ext2::Ext2Fs;
+
+    /// Build an ext2 image of `blocks` blocks of `block_size` bytes with
+    /// `mke2fs -d` from a tree holding small, large and sparse files,
+    /// nested directories and symlinks.
+    fn ext2_image(name: &str, block_size: usize, blocks: usize) -> PathBuf
+    {
+        let tmp = std::env::temp_dir().join(format!("lula-{}-{}", name, std::process::id()));
+        let tree = tmp.join("tree");
+        std::fs::create_dir_all(tree.join("etc/deep/deeper")).unwrap();
+        std::fs::write(tree.join("hello.txt"), "hello, ext2\n").unwrap();
+        std::fs::write(tree.join("etc/deep/deeper/leaf"), "leaf").unwrap();
+        // reaches the double indirect blocks with 1 KiB blocks
+        let big: Vec<u8> = (0..400 * 1024u32).map(|n| (n % 251) as u8).collect();
+        std::fs::write(tree.join("big.bin"), &big).unwrap();
+        let sparse = std::fs::File::create(tree.join("sparse")).unwrap();
+        sparse.set_len(64 * 1024).unwrap();
+        std::os::unix::fs::FileExt::write_at(&sparse, b"tail", 64 * 1024 - 4).unwrap();
+        std::os::unix::fs::symlink("hello.txt", tree.join("short-link")).unwrap();
+        let long = format!("etc/deep/deeper/{}/../leaf", "x".repeat(80));
+        std::os::unix::fs::symlink(&long, tree.join("long-link")).unwrap();
+
+        let image = tmp.join("ext2.img");
+        let status = std::process::Command::new("mke2fs")
+            .args(["-q", "-F", "-t", "ext2", "-b", &block_size.to_string(), "-d"])
+            .arg(&tree)
+            .arg(&image)
+            .arg(blocks.to_string())
+            .stdout(std::process::Stdio::null())
+            .status()
+            .expect("mke2fs not found");
+        assert!(status.success());
+        image
+    }
+
+    fn ext2_check(block_size: usize, blocks: usize)
+    {
+        let image = ext2_image(&format!("ext2-{}", block_size), block_size, blocks);
+        let dev = block::register(Arc::new(FileDisk::open(&image).unwrap()));
+        let fs = Ext2Fs::mount(dev).unwrap();
+        let root = fs.root();
+
+        let mut names: Vec<String> = (0..).map_while(|n| root.dirent(n).unwrap()).map(|e| e.name).collect();
+        names.sort();
+        assert_eq!(names, ["big.bin", "etc", "hello.txt", "long-link", "lost+found", "short-link", "sparse"]);
+
+        let mut buf = vec![0u8; 500 * 1024];
+        let hello = root.lookup("hello.txt").unwrap();
+        assert_eq!(hello.read_at(0, &mut buf).unwrap(), 12);
+        assert_eq!(&buf[..12], b"hello, ext2\n");
+        assert_eq!(hello.stat().kind, FileType::Regular);
+
+        let big = root.lookup("big.bin").unwrap();
+        assert_eq!(big.stat().size, 400 * 1024);
+        assert_eq!(big.read_at(0, &mut buf).unwrap(), 400 * 1024);
+        assert!(buf[..400 * 1024].iter().enumerate().all(|(n, &b)| b == (n % 251) as u8));
+        assert_eq!(big.read_at(300 * 1024 + 5, &mut buf[..3]).unwrap(), 3);
+        assert_eq!(buf[..3], [(300 * 1024 + 5) % 251, (300 * 1024 + 6) % 251, (300 * 1024 + 7) % 251].map(|b| b as u8));
+
+        let sparse = root.lookup("sparse").unwrap();
+        assert_eq!(sparse.read_at(0, &mut buf).unwrap(), 64 * 1024);
+        assert!(buf[..64 * 1024 - 4].iter().all(|&b| b == 0));
+        assert_eq!(&buf[64 * 1024 - 4..64 * 1024], b"tail");
+
+        let leaf = root.lookup("etc").unwrap().lookup("deep").unwrap().lookup("deeper").unwrap().lookup("leaf").unwrap();
+        assert_eq!(leaf.read_at(0, &mut buf).unwrap(), 4);
+        assert_eq!(root.lookup("etc").unwrap().stat().kind, FileType::Directory);
+
+        let short = root.lookup("short-link").unwrap();
+        assert_eq!(short.stat().kind, FileType::Symlink);
+        assert_eq!(short.read_link().unwrap(), "hello.txt");
+        let long = root.lookup("long-link").unwrap().read_link().unwrap();
+        assert_eq!(long, format!("etc/deep/deeper/{}/../leaf", "x".repeat(80)));
+
+        assert_eq!(root.create("new", FileType::Regular).err(), Some(FsErr::ReadOnly));
+        assert_eq!(hello.write_at(0, b"x").err(), Some(FsErr::ReadOnly));
+        assert_eq!(root.lookup("missing").err(), Some(FsErr::NotFound));
+
+        drop((root, hello, big, sparse, leaf, short, fs));
+        block::unregister(dev).unwrap();
+        std::fs::remove_dir_all(image.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn ext2_reads_mke2fs_image()
+    {
+        ext2_check(1024, 2048);
+    }
+
+    #[test]
+    fn ext2_reads_4k_blocks()
+    {
+        ext2_check(4096, 1024);
+    }
+
+    use std::collections::BTreeMap;
+    use kernel::fs::xv6fs::{self, Xv6Fs};
+    use kernel::fs::InodeRef;
+    use super::CrashDisk;
+
+    /// A freshly made xv6fs image of `blocks` KiB.
+    fn xv6fs_image(blocks: usize) -> Vec<u8>
+    {
+        let disk = Arc::new(RamDisk::new(vec![0; blocks * 1024]));
+        let dev = block::register(disk.clone());
+        xv6fs::mkfs(dev, 64).unwrap();
+        block::unregister(dev).unwrap();
+        disk.contents()
+    }
+
+    /// Every path below `dir` with its type and contents (the target for
+    /// symlinks).
+    fn xv6fs_view(dir: &InodeRef, prefix: &str, view: &mut BTreeMap<String, (FileType, Vec<u8>)>)
+    {
+        for entry in (0..).map_while(|n| dir.dirent(n).unwrap()) {
+            let path = format!("{}/{}", prefix, entry.name);
+            let inode = dir.lookup(&entry.name).unwrap();
+            let contents = match entry.kind {
+                FileType::Directory => {
+                    xv6fs_view(&inode, &path, view);
+                    Vec::new()
+                }
+                FileType::Symlink => inode.read_link().unwrap().into_bytes(),
+                _ => {
+                    let mut buf = vec![0u8; inode.stat().size as usize];
+                    inode.read_at(0, &mut buf).unwrap();
+                    buf
+                }
+            };
+            view.insert(path, (entry.kind, contents));
+        }
+    }
+
+    #[test]
+    fn xv6fs_files_and_directories()
+    {
+        let disk = Arc::new(RamDisk::new(xv6fs_image(1024)));
+        let dev = block::register(disk.clone());
+        let fs = Xv6Fs::mount(dev).unwrap();
+        let root = fs.root();
+
+        // through the indirect block
+        let big = root.create("big", FileType::Regular).unwrap();
+        let data: Vec<u8> = (0..200 * 1024u32).map(|n| (n % 253) as u8).collect();
+        assert_eq!(big.write_at(0, &data).unwrap(), data.len());
+        let mut back = vec![0u8; data.len() + 10];
+        assert_eq!(big.read_at(0, &mut back).unwrap(), data.len());
+        assert_eq!(&back[..data.len()], &data[..]);
+        assert_eq!(big.write_at(268 * 1024, b"x").err(), Some(FsErr::NoSpace));
+        big.truncate(5000).unwrap();
+        big.truncate(6000).unwrap();
+        assert_eq!(big.read_at(0, &mut back).unwrap(), 6000);
+        assert_eq!(&back[..5000], &data[..5000]);
+        assert!(back[5000..6000].iter().all(|&b| b == 0));
+
+        let dir = root.create("dir", FileType::Directory).unwrap();
+        let file = dir.create("file", FileType::Regular).unwrap();
+        file.write_at(0, b"contents").unwrap();
+        dir.link("other", &file).unwrap();
+        assert_eq!(file.stat().nlink, 2);
+        root.symlink("link", "dir/file").unwrap();
+        assert_eq!(root.lookup("link").unwrap().read_link().unwrap(), "dir/file");
+        assert_eq!(root.create("a-name-longer-than-28-bytes-x", FileType::Regular).err(), Some(FsErr::NameTooLong));
+        assert_eq!(root.create("dir", FileType::Regular).err(), Some(FsErr::Exists));
+        assert_eq!(root.unlink("dir"), Err(FsErr::NotEmpty));
+        assert_eq!(root.link("dir2", &dir).err(), Some(FsErr::IsDir));
+
+        // unlinked while open: readable until dropped, then freed
+        dir.unlink("file").unwrap();
+        dir.unlink("other").unwrap();
+        assert_eq!(file.stat().nlink, 0);
+        assert_eq!(file.read_at(0, &mut back).unwrap(), 8);
+        let before = fs.fsck().unwrap_err();
+        assert!(matches!(before, xv6fs::FsckErr::Unreachable(_)), "{:?}", before);
+        drop(file);
+        root.unlink("dir").unwrap();
+        drop(dir);
+        let stats = fs.fsck().unwrap();
+        assert_eq!((stats.inodes, stats.dirs), (3, 1));
+
+        drop((root, big, fs));
+        block::unregister(dev).unwrap();
+
+        // still there after a remount
+        let dev = block::register(Arc::new(RamDisk::new(disk.contents())));
+        let fs = Xv6Fs::mount(dev).unwrap();
+        let mut view = BTreeMap::new();
+        xv6fs_view(&fs.root(), "", &mut view);
+        assert_eq!(view.keys().collect::<Vec<_>>(), ["/big", "/link"]);
+        assert_eq!(view["/big"].1.len(), 6000);
+        fs.fsck().unwrap();
+        drop(fs);
+        block::unregister(dev).unwrap();
+    }
+
+    /// One step of `xv6fs_workload`.
+    type Step = Box<dyn Fn(&InodeRef) -> Result<(), FsErr>>;
+
+    /// Steps that each are one logged operation.
+    fn xv6fs_workload() -> Vec<Step>
+    {
+        vec![
+            Box::new(|root| root.create("a", FileType::Directory).map(drop)),
+            Box::new(|root| root.lookup("a")?.create("f", FileType::Regular).map(drop)),
+            Box::new(|root| root.lookup("a")?.lookup("f")?.write_at(0, &[0xf0; 3000]).map(drop)),
+            Box::new(|root| root.create("g", FileType::Regular).map(drop)),
+            Box::new(|root| root.lookup("g")?.write_at(0, b"g").map(drop)),
+            Box::new(|root| root.symlink("l", "a/f").map(drop)),
+            Box::new(|root| root.lookup("a")?.link("h", &root.lookup("a")?.lookup("f")?)),
+            Box::new(|root| root.lookup("a")?.unlink("f")),
+            Box::new(|root| root.lookup("a")?.create("d", FileType::Directory).map(drop)),
+            Box::new(|root| root.lookup("a")?.lookup("d")?.create("x", FileType::Regular).map(drop)),
+            Box::new(|root| root.lookup("a")?.lookup("d")?.lookup("x")?.write_at(2000, b"x").map(drop)),
+            Box::new(|root| root.lookup("a")?.lookup("h")?.write_at(2500, &[0x0f; 1500]).map(drop)),
+            Box::new(|root| root.unlink("g")),
+            Box::new(|root| root.lookup("a")?.lookup("h")?.truncate(10)),
+            Box::new(|root| root.lookup("a")?.lookup("d")?.unlink("x")),
+            Box::new(|root| root.lookup("a")?.unlink("d")),
+        ]
+    }
+
+    #[test]
+    fn xv6fs_crash_at_every_write()
+    {
+        let image = xv6fs_image(256);
+        let ops = xv6fs_workload();
+
+        // what the tree looks like after each operation
+        let disk = Arc::new(CrashDisk::new(image.clone(), None));
+        let dev = block::register(disk.clone());
+        let fs = Xv6Fs::mount(dev).unwrap();
+        let mut views = vec![BTreeMap::new()];
+        for op in &ops {
+            op(&fs.root()).unwrap();
+            let mut view = BTreeMap::new();
+            xv6fs_view(&fs.root(), "", &mut view);
+            views.push(view);
+        }
+        drop(fs);
+        block::unregister(dev).unwrap();
+        let total = disk.writes();
+
+        let mut seen = vec![false; views.len()];
+        for cut in 0..=total {
+            let disk = Arc::new(CrashDisk::new(image.clone(), Some(cut)));
+            let dev = block::register(disk.clone());
+            let fs = Xv6Fs::mount(dev).unwrap();
+            for op in &ops {
+                // reads of blocks whose writes were lost may fail
+                let _ = op(&fs.root());
+            }
+            drop(fs);
+            block::unregister(dev).unwrap();
+
+            // reboot
+            let dev = block::register(Arc::new(RamDisk::new(disk.contents())));
+            let fs = Xv6Fs::mount(dev).unwrap();
+            if let Err(err) = fs.fsck() {
+                panic!("inconsistent after a crash at write {} of {}: {:?}", cut, total, err);
+            }
+            let mut view = BTreeMap::new();
+            xv6fs_view(&fs.root(), "", &mut view);
+            let Some(state) = views.iter().position(|v| *v == view) else {
+                let sizes: Vec<_> = view.iter().map(|(path, (_, data))| (path, data.len())).collect();
+                panic!("crash at write {} of {} left a state no operation sequence produced: {:?}", cut, total, sizes);
+            };
+            seen[state] = true;
+            drop(fs);
+            block::unregister(dev).unwrap();
+        }
+        // every prefix of the workload shows up for some cut
+        assert!(seen.iter().all(|&s| s), "{:?}", seen);
+    }
+
+    use kernel::fdt::Fdt;
+    use kernel::platform::{Mmio, Platform};
+
+    /// Writes a flattened device tree, version 17.
+    #[derive(Default)]
+    struct FdtBuilder
+    {
+        structs: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl FdtBuilder
+    {
+        fn token(&mut self, token: u32)
+        {
+            self.structs.extend_from_slice(&token.to_be_bytes());
+        }
+
+        fn pad(&mut self)
+        {
+            while !self.structs.len().is_multiple_of(4) {
+                self.structs.push(0);
+            }
+        }
+
+        fn begin(&mut self, name: &str) -> &mut Self
+        {
+            self.token(1);
+            self.structs.extend_from_slice(name.as_bytes());
+            self.structs.push(0);
+            self.pad();
+            self
+        }
+
+        fn end(&mut self) -> &mut Self
+        {
+            self.token(2);
+            self
+        }
+
+        fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self
+        {
+            let nameoff = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.token(3);
+            self.token(value.len() as u32);
+            self.token(nameoff);
+            self.structs.extend_from_slice(value);
+            self.pad();
+            self
+        }
+
+        fn cells(&mut self, name: &str, cells: &[u32]) -> &mut Self
+        {
+            let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+            self.prop(name, &value)
+        }
+
+        fn string(&mut self, name: &str, value: &str) -> &mut Self
+        {
+            self.prop(name, format!("{}\0", value).as_bytes())
+        }
+
+        fn finish(&mut self) -> Vec<u8>
+        {
+            self.token(9);
+            let reserve = 40;
+            let structs = reserve + 16;     // one empty reservation entry
+            let strings = structs + self.structs.len();
+            let total = strings + self.strings.len();
+            let header = [0xd00dfeed, total, structs, strings, reserve, 17, 16, 0,
+                          self.strings.len(), self.structs.len()];
+            let mut blob: Vec<u8> = header.iter().flat_map(|&w| (w as u32).to_be_bytes()).collect();
+            blob.extend_from_slice(&[0; 16]);
+            blob.extend_from_slice(&self.structs);
+            blob.extend_from_slice(&self.strings);
+            blob
+        }
+    }
+
+    /// Like the tree QEMU's virt machine passes with `-m 3G -smp 2`,
+    /// RAM split in two ranges.
+    fn virt_dtb() -> Vec<u8>
+    {
+        let mut fdt = FdtBuilder::default();
+        fdt.begin("").cells("#address-cells", &[2]).cells("#size-cells", &[2]);
+        fdt.begin("chosen").string("bootargs", "quiet").end();
+        fdt.begin("memory@c0000000").string("device_type", "memory")
+            .cells("reg", &[0, 0xc0000000, 1, 0]).end();
+        fdt.begin("memory@80000000").string("device_type", "memory")
+            .cells("reg", &[0, 0x80000000, 0, 0x40000000]).end();
+        fdt.begin("cpus").cells("#address-cells", &[1]).cells("#size-cells", &[0])
+            .cells("timebase-frequency", &[1_000_000]);
+        for hart in 0..3 {
+            fdt.begin(&format!("cpu@{}", hart)).string("device_type", "cpu").cells("reg", &[hart]);
+            if hart == 2 {
+                fdt.string("status", "disabled");
+            }
+            fdt.begin("interrupt-controller").string("compatible", "riscv,cpu-intc").end();
+            fdt.end();
+        }
+        fdt.end();
+        fdt.begin("soc").cells("#address-cells", &[2]).cells("#size-cells", &[2]);
+        for slot in (0..4u32).rev() {
+            let base = 0x10001000 + slot * 0x1000;
+            fdt.begin(&format!("virtio_mmio@{:x}", base)).string("compatible", "virtio,mmio")
+                .cells("reg", &[0, base, 0, 0x1000]).cells("interrupts", &[1 + slot]).end();
+        }
+        fdt.begin("serial@10000000").string("compatible", "ns16550a")
+            .cells("reg", &[0, 0x10000000, 0, 0x100]).cells("interrupts", &[10]).end();
+        fdt.begin("plic@c000000").prop("compatible", b"sifive,plic-1.0.0\0riscv,plic0\0")
+            .cells("reg", &[0, 0xc000000, 0, 0x600000]).end();
+        fdt.end();
+        fdt.end().finish()
+    }
+
+    #[test]
+    fn fdt_discovers_platform()
+    {
+        let blob = virt_dtb();
+        let fdt = Fdt::from_bytes(&blob).unwrap();
+        assert_eq!(fdt.total_size(), blob.len());
+        assert_eq!(fdt.prop("/chosen", "bootargs"), Some(&b"quiet\0"[..]));
+        assert_eq!(fdt.prop("/cpus/cpu", "reg"), Some(&[0, 0, 0, 0][..]));
+
+        let names: Vec<_> = fdt.nodes().map(|node| (node.depth(), node.name())).collect();
+        assert_eq!(names.len(), 18);
+        assert_eq!(names[0], (1, ""));
+        assert_eq!(names[6], (4, "interrupt-controller"));
+        assert_eq!(names[14], (3, "virtio_mmio@10002000"));
+        let serial = fdt.find_compatible(&["ns16550a"]).unwrap();
+        assert_eq!(serial.reg().collect::<Vec<_>>(), vec![(0x10000000, 0x100)]);
+        assert!(fdt.find_compatible(&["riscv,plic0"]).unwrap().is_compatible("sifive,plic-1.0.0"));
+
+        let platform = Platform::from_fdt(&fdt);
+        assert_eq!(platform.ram(), &[
+            MemRange { start: 0x80000000, size: 1 << 30 },
+            MemRange { start: 0xc0000000, size: 1 << 32 },
+        ]);
+        assert_eq!(platform.ram_size(), 5 << 30);
+        assert_eq!(platform.cpus, 2);
+        assert_eq!(platform.timebase, 1_000_000);
+        assert_eq!(platform.uart, Mmio { base: 0x10000000, size: 0x100, irq: 10 });
+        assert_eq!(platform.plic, Mmio { base: 0xc000000, size: 0x600000, irq: 0 });
+        let virtio: Vec<_> = platform.virtio().iter().map(|mmio| (mmio.base, mmio.irq)).collect();
+        assert_eq!(virtio, vec![(0x10001000, 1), (0x10002000, 2), (0x10003000, 3), (0x10004000, 4)]);
+
+        // the defaults stand in for whatever the tree leaves out
+        let mut fdt = FdtBuilder::default();
+        let blob = fdt.begin("").begin("chosen").end().end().finish();
+        let platform = Platform::from_fdt(&Fdt::from_bytes(&blob).unwrap());
+        assert_eq!(platform.ram(), Platform::QEMU_VIRT.ram());
+        assert_eq!(platform.virtio(), Platform::QEMU_VIRT.virtio());
+        assert_eq!(platform.uart, Platform::QEMU_VIRT.uart);
+
+        assert!(Fdt::from_bytes(&blob[4..]).is_err());
+        assert!(Fdt::from_bytes(&blob[..blob.len() - 1]).is_err());
+    }
+
+    use std::sync::{Mutex, MutexGuard, Once};
+    use kernel::ktrap::TrapFrame;
+    use kernel::mem::frame;
+    use kernel::proc::Proc;
+    use kernel::syscall::{self, Errno};
+
+    /// Guards the kernel globals tests share: the frame allocator (tests
+    /// count frames) and the running process.
+    static KERNEL: Mutex<()> = Mutex::new(());
+
+    /// Frames backing the global frame allocator.
+    const KERNEL_RAM: usize = 16 << 20;
+
+    /// Hold the kernel globals. The first caller hands the frame allocator
+    /// `KERNEL_RAM` of host memory and points the UART at a host buffer,
+    /// so `kprintln!` has somewhere to go.
+    fn kernel() -> MutexGuard<'static, ()>
+    {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            let ram = Vec::leak(vec![0u8; KERNEL_RAM + (1 << 20)]);
+            let base = (ram.as_mut_ptr() as usize).next_multiple_of(1 << 20);
+            frame::frame_init(&[MemRange { start: base, size: KERNEL_RAM }], &[]).unwrap();
+
+            // transmitter always idle
+            let regs = Vec::leak(vec![0u8; 0x100]);
+            regs[uart::LSR] = uart::LSR_TX_IDLE;
+            let regs = regs.as_ptr() as usize;
+            let mut fdt = FdtBuilder::default();
+            fdt.begin("").cells("#address-cells", &[2]).cells("#size-cells", &[2]);
+            fdt.begin("soc").cells("#address-cells", &[2]).cells("#size-cells", &[2]);
+            fdt.begin("serial").string("compatible", "ns16550a")
+                .cells("reg", &[(regs >> 32) as u32, regs as u32, 0, 0x100]).end();
+            fdt.end();
+            let dtb = Vec::leak(fdt.end().finish());
+            platform::platform_init(dtb.as_ptr() as usize);
+            assert_eq!(platform::platform().uart.base, regs);
+        });
+        KERNEL.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Where `run_proc` puts the heap.
+    const USER_HEAP: usize = 0x10000;
+
+    /// A new process with `pages` of heap at `USER_HEAP`, made the
+    /// running one: system calls act on it. Needs `kernel`.
+    fn run_proc(pages: usize) -> &'static mut Proc
+    {
+        let proc = proc::proc_alloc().unwrap();
+        proc.brk = USER_HEAP;
+        proc.sbrk((pages * PAGE_SIZE) as isize).unwrap();
+        proc::mycpu().proc = proc;
+        proc
+    }
+
+    fn exit_proc(proc: &mut Proc)
+    {
+        proc::mycpu().proc = std::ptr::null_mut();
+        proc::proc_free(proc);
+    }
+
+    /// Make system call `num` with `args`, returns `a0`.
+    fn call(num: usize, args: &[usize]) -> isize
+    {
+        let mut tf = TrapFrame::new();
+        tf.a7 = num;
+        for (n, &arg) in args.iter().enumerate() {
+            *[&mut tf.a0, &mut tf.a1, &mut tf.a2, &mut tf.a3][n] = arg;
+        }
+        syscall::syscall(&mut tf);
+        tf.a0 as isize
+    }
+
+    fn err(errno: Errno) -> isize
+    {
+        -(errno as isize)
+    }
+
+    #[test]
+    fn syscall_unknown_numbers()
+    {
+        let _kernel = kernel();
+        for num in [0, syscall::SYS_HEAPINFO + 1, 1000, usize::MAX] {
+            assert_eq!(call(num, &[1, 2, 3]), err(Errno::ENOSYS), "system call {}", num);
+        }
+    }
+
+    #[test]
+    fn syscall_yield_keeps_running()
+    {
+        let _kernel = kernel();
+        let proc = run_proc(0);
+        proc.state = proc::ProcState::Running;
+        // nothing else to run on the host
+        assert_eq!(call(syscall::SYS_YIELD, &[]), 0);
+        assert_eq!(proc.state, proc::ProcState::Running);
+        exit_proc(proc);
+    }
+
+    #[test]
+    fn syscall_bad_user_pointers()
+    {
+        let _kernel = kernel();
+        let proc = run_proc(1);
+        let path = USER_HEAP + PAGE_SIZE - 4;
+        virtm::copy_out(proc.page_table, path, b"/tmp").unwrap();
+        assert_eq!(call(syscall::SYS_GETPID, &[]), proc.pid as isize);
+
+        // unmapped, crossing into an unmapped page, mapped but not user
+        for ptr in [0, USER_HEAP - 1, path + 1, virtm::TRAPFRAME, usize::MAX - 1] {
+            assert_eq!(call(syscall::SYS_OPEN, &[ptr, 4, 0]), err(Errno::EFAULT), "path at {:#x}", ptr);
+            assert_eq!(call(syscall::SYS_STAT, &[ptr, 4, path]), err(Errno::EFAULT));
+            assert_eq!(call(syscall::SYS_EXEC, &[ptr, 4]), err(Errno::EFAULT));
+        }
+        assert_eq!(call(syscall::SYS_MKDIR, &[path, fs::PATH_MAX + 1]), err(Errno::ENAMETOOLONG));
+        assert_eq!(call(syscall::SYS_WAIT, &[proc.pid + 100, path]), err(Errno::ECHILD));
+        assert_eq!(call(syscall::SYS_READ, &[99, path, 4]), err(Errno::EBADF));
+        exit_proc(proc);
+    }
+
+    #[test]
+    fn syscall_wait_any_child()
+    {
+        let _kernel = kernel();
+        let proc = run_proc(1);
+        let status = USER_HEAP;
+        let child = |state| {
+            let child = proc::proc_alloc().unwrap();
+            child.parent = proc.pid;
+            child.state = state;
+            child.exit_status = child.pid as i32 + 100;
+            child.pid
+        };
+        let running = child(proc::ProcState::Runnable);
+        let exited = child(proc::ProcState::Zombie);
+
+        // one that has exited, whichever comes first in the table
+        assert_eq!(call(syscall::SYS_WAIT, &[0, status]), exited as isize);
+        let mut bytes = [0u8; 4];
+        virtm::copy_in(proc.page_table, &mut bytes, status).unwrap();
+        assert_eq!(i32::from_le_bytes(bytes), exited as i32 + 100);
+        assert_eq!(call(syscall::SYS_WAIT, &[exited, status]), err(Errno::ECHILD));
+
+        proc::PROC_TABLE.lock().get_mut().get_mut(running).unwrap().state = proc::ProcState::Zombie;
+        assert_eq!(call(syscall::SYS_WAIT, &[0, 0]), running as isize);
+        assert_eq!(call(syscall::SYS_WAIT, &[0, status]), err(Errno::ECHILD));
+        exit_proc(proc);
+    }
+
+    use kernel::exec::{self, LoadErr};
+
+    /// A `PT_LOAD` segment for `elf_image`: vaddr, file bytes, memsz, flags.
+    struct Seg(usize, usize, usize, u32);
+
+    const PF_X: u32 = 1;
+    const PF_W: u32 = 2;
+    const PF_R: u32 = 4;
+
+    /// A RISC-V ELF64 executable of `segments`, their data after the
+    /// program headers, filled with the low byte of their vaddr page.
+    fn elf_image(entry: usize, segments: &[Seg]) -> Vec<u8>
+    {
+        let mut elf = Vec::new();
+        elf.extend_from_slice(b"\x7fELF\x02\x01\x01");
+        elf.resize(16, 0);
+        elf.extend_from_slice(&2u16.to_le_bytes());           // ET_EXEC
+        elf.extend_from_slice(&243u16.to_le_bytes());         // EM_RISCV
+        elf.extend_from_slice(&1u32.to_le_bytes());
+        elf.extend_from_slice(&(entry as u64).to_le_bytes());
+        elf.extend_from_slice(&64u64.to_le_bytes());          // e_phoff
+        elf.extend_from_slice(&0u64.to_le_bytes());           // e_shoff
+        elf.extend_from_slice(&0u32.to_le_bytes());
+        for half in [64, 56, segments.len() as u16, 64, 0, 0] {
+            elf.extend_from_slice(&u16::to_le_bytes(half));
+        }
+
+        let mut offset = 64 + 56 * segments.len();
+        for Seg(vaddr, filesz, memsz, flags) in segments {
+            elf.extend_from_slice(&1u32.to_le_bytes());       // PT_LOAD
+            elf.extend_from_slice(&flags.to_le_bytes());
+            for field in [offset, *vaddr, *vaddr, *filesz, *memsz, PAGE_SIZE] {
+                elf.extend_from_slice(&(field as u64).to_le_bytes());
+            }
+            offset += filesz;
+        }
+        for Seg(vaddr, filesz, ..) in segments {
+            elf.resize(elf.len() + filesz, (vaddr / PAGE_SIZE) as u8);
+        }
+        elf
+    }
+
+    fn load(image: &[u8]) -> Result<usize, LoadErr>
+    {
+        let proc = proc::proc_alloc().unwrap();
+        let result = exec::load_elf(proc, image);
+        proc::proc_free(proc);
+        result
+    }
+
+    #[test]
+    fn elf_load_segments()
+    {
+        let _kernel = kernel();
+        let image = elf_image(0x10010, &[
+            Seg(0x10000, 0x1800, 0x1800, PF_R | PF_X),
+            Seg(0x11800, 0x100, 0x2000, PF_R | PF_W),      // shares a page, then .bss
+        ]);
+        let proc = proc::proc_alloc().unwrap();
+        assert_eq!(exec::load_elf(proc, &image).unwrap(), 0x10010);
+        assert_eq!(proc.brk, 0x14000);
+
+        let mut bytes = vec![0u8; 0x3800];
+        virtm::copy_in(proc.page_table, &mut bytes, 0x10000).unwrap();
+        assert!(bytes[..0x1800].iter().all(|&b| b == 0x10));
+        assert!(bytes[0x1800..0x1900].iter().all(|&b| b == 0x11));
+        assert!(bytes[0x1900..].iter().all(|&b| b == 0));
+        let writable = |va| virtm::pt_translate(proc.page_table, va, virtm::PTEPerms::WRITE).is_some();
+        assert!(!writable(0x10000) && writable(0x11000) && writable(0x13000));
+        proc::proc_free(proc);
+    }
+
+    #[test]
+    fn elf_load_errors()
+    {
+        let _kernel = kernel();
+        let text = || Seg(0x10000, 0x100, 0x100, PF_R | PF_X);
+        let good = elf_image(0x10000, &[text()]);
+        assert!(load(&good).is_ok());
+
+        let mut bad_magic = good.clone();
+        bad_magic[1] = b'F';
+        assert!(matches!(load(&bad_magic), Err(LoadErr::Parse(_))));
+        let mut machine = good.clone();
+        machine[18] = 62;       // x86-64
+        assert!(matches!(load(&machine), Err(LoadErr::WrongMachine)));
+        let mut shared = good.clone();
+        shared[16] = 3;         // ET_DYN
+        assert!(matches!(load(&shared), Err(LoadErr::NotExecutable)));
+
+        // the program headers, or the data of a segment, end early
+        assert!(matches!(load(&good[..64 + 30]), Err(LoadErr::Parse(_))));
+        assert!(matches!(load(&good[..good.len() - 1]), Err(LoadErr::Parse(_))));
+        assert!(matches!(load(&elf_image(0x10000, &[])), Err(LoadErr::NoLoadSegments)));
+
+        let bad_segments = [
+            vec![Seg(0x10000, 0x200, 0x100, PF_R | PF_X)],                // filesz > memsz
+            vec![Seg(0, 0x100, 0x100, PF_R | PF_X)],                      // the null page
+            vec![text(), Seg(proc::USR_LIMIT - 0x100, 0, 0x200, PF_R)],   // past the user range
+            vec![text(), Seg(usize::MAX - 0xff, 0, 0x200, PF_R)],         // wraps around
+            vec![text(), Seg(0x10080, 0x100, 0x100, PF_R | PF_W)],        // overlapping
+            vec![Seg(0x20000, 0, 0x100, PF_R), Seg(0x1f000, 0, 0x2000, PF_R), text()],
+        ];
+        for segments in &bad_segments {
+            assert!(matches!(load(&elf_image(0x10000, segments)), Err(LoadErr::BadSegment)));
+        }
+        // the entry point is not in an executable segment
+        let data = elf_image(0x10000, &[Seg(0x10000, 0x100, 0x100, PF_R | PF_W)]);
+        assert!(matches!(load(&data), Err(LoadErr::BadSegment)));
+        assert!(matches!(load(&elf_image(0x20000, &[text()])), Err(LoadErr::BadSegment)));
+    }
+
+    #[test]
+    fn elf_load_out_of_memory()
+    {
+        let _kernel = kernel();
+        let image = elf_image(0x10000, &[Seg(0x10000, 0x100, 0x100, PF_R | PF_X)]);
+        let before = frame::free_frames();
+        let proc = proc::proc_alloc().unwrap();
+        let free = frame::free_frames();
+
+        // a frame for the segment but none for the page tables it needs
+        let held: Vec<_> = (1..free).map(|_| frame::frame_alloc().unwrap()).collect();
+        assert!(matches!(exec::load_elf(proc, &image), Err(LoadErr::OutOfMemory)));
+        assert_eq!(frame::free_frames(), 1);
+
+        for frame in held {
+            frame::frame_free(frame);
+        }
+        assert_eq!(exec::load_elf(proc, &image).unwrap(), 0x10000);
+        proc::proc_free(proc);
+        assert_eq!(frame::free_frames(), before);
+    }
+
+    #[test]
+    fn exec_resolves_through_vfs()
+    {
+        let _kernel = kernel();
+        let root = Arc::new(TmpFs::new());
+        let bin = root.root().create("bin", FileType::Directory).unwrap();
+        let image = elf_image(0x10000, &[Seg(0x10000, 0x100, 0x100, PF_R | PF_X)]);
+        // longer than the old 64 byte limit on exec paths
+        let prog = "p".repeat(70);
+        bin.create(&prog, FileType::Regular).unwrap().write_at(0, &image).unwrap();
+        fs::mount("/", root).unwrap();
+
+        let proc = run_proc(1);
+        let path = |name: &str| {
+            virtm::copy_out(proc.page_table, USER_HEAP, name.as_bytes()).unwrap();
+            [USER_HEAP, name.len()]
+        };
+        assert_eq!(call(syscall::SYS_EXEC, &path("/bin/none")), err(Errno::ENOENT));
+        assert_eq!(call(syscall::SYS_EXEC, &path("/bin")), err(Errno::EISDIR));
+        assert_eq!(call(syscall::SYS_EXEC, &[USER_HEAP, fs::PATH_MAX + 1]), err(Errno::ENAMETOOLONG));
+        assert_eq!(call(syscall::SYS_EXEC, &path(&format!("/bin/{}", prog))), 0);
+        assert!(prog.starts_with(proc.name()));
+        assert_eq!(proc.trap_frame().sepc, 0x10000);
+
+        exit_proc(proc);
+        fs::umount("/").unwrap();
+    }
+
+    use kernel::mem::oom;
+    use kernel::proc::ProcState;
+
+    #[test]
+    fn oom_retry_shrinks_the_buffer_cache()
+    {
+        let _kernel = kernel();
+        let (disk, dev) = ram_disk(8);
+        for n in 0..4 {
+            drop(block::bread(dev, n).unwrap());
+        }
+        // block 0 reads 0xee once its buffer has been freed
+        disk.write_sectors(0, &[0xee; 512]).unwrap();
+        let mut room = 0u64;
+        let ptr = &mut room as *mut u64 as *mut u8;
+        let alloc = || match block::bread(dev, 0).unwrap().data()[0] {
+            0xee => ptr,
+            _    => std::ptr::null_mut(),
+        };
+        assert!(alloc().is_null());
+        assert_eq!(oom::reclaim_and_retry(alloc), ptr);
+        block::unregister(dev).unwrap();
+    }
+
+    #[test]
+    fn oom_reaps_zombies_without_a_parent()
+    {
+        let _kernel = kernel();
+        let parent = proc::proc_alloc().unwrap();
+        let zombie = |parent| {
+            let zombie = proc::proc_alloc().unwrap();
+            zombie.parent = parent;
+            zombie.state = ProcState::Zombie;
+            zombie.pid
+        };
+        let waited = zombie(parent.pid);
+        let orphan = zombie(0);
+        let stray = zombie(parent.pid + 1000);
+
+        assert!(proc::proc_reap_zombies() >= 2);
+        assert!(!proc::proc_exists(orphan) && !proc::proc_exists(stray));
+        // its parent may still wait for it
+        assert!(proc::proc_exists(waited));
+        assert!(proc::proc_reap(waited).is_some());
+        proc::proc_free(parent);
+    }
+
+    #[test]
+    fn oom_kills_the_largest_process()
+    {
+        let _kernel = kernel();
+        // the largest, but the one allocating is spared
+        let me = run_proc(8);
+        let sleeper = |pages: usize| {
+            let proc = proc::proc_alloc().unwrap();
+            proc.brk = USER_HEAP;
+            proc.sbrk((pages * PAGE_SIZE) as isize).unwrap();
+            proc.state = ProcState::Sleeping;
+            proc
+        };
+        let small = sleeper(1);
+        let large = sleeper(4);
+
+        // the pages mapped, not how far `brk` is from 0
+        assert_eq!(large.mem_size(), 4 * PAGE_SIZE + proc::USR_STACK_SIZE);
+        assert_eq!(proc::proc_kill_largest(), Some(large.pid));
+        assert!(large.killed && large.state == ProcState::Runnable);
+        assert!(!small.killed && !me.killed);
+        // the killed one is on its way out, the next largest goes next
+        assert_eq!(proc::proc_kill_largest(), Some(small.pid));
+        assert_eq!(proc::proc_kill_largest(), None);
+        proc::proc_free(small);
+        proc::proc_free(large);
+        exit_proc(me);
+    }
+
+    #[test]
+    fn killed_process_stops_waiting()
+    {
+        let _kernel = kernel();
+        let me = run_proc(1);
+        let child = proc::proc_alloc().unwrap();
+        child.parent = me.pid;
+        child.state = ProcState::Runnable;
+        me.killed = true;
+
+        // a child that will not exit, and no console input
+        assert_eq!(call(syscall::SYS_WAIT, &[0, 0]), err(Errno::EINTR));
+        assert_eq!(kernel::console::cons_read(&mut [0u8; 8]), Err(FsErr::Interrupted));
+        proc::proc_free(child);
+        exit_proc(me);
+    }
+
+    #[test]
+    #[cfg(feature = "oom-kill")]
+    fn oom_no_kill_under_a_sleep_lock()
+    {
+        let _kernel = kernel();
+        let me = run_proc(1);
+        let victim = proc::proc_alloc().unwrap();
+        victim.state = ProcState::Sleeping;
+        let lock = kernel::sync::SleepLock::new(());
+
+        let guard = lock.lock();
+        assert_eq!(me.sleep_locks, 1);
+        assert!(oom::reclaim_and_retry(std::ptr::null_mut).is_null());
+        assert!(!victim.killed);
+        drop(guard);
+        assert_eq!(me.sleep_locks, 0);
+        assert!(oom::reclaim_and_retry(std::ptr::null_mut).is_null());
+        assert!(victim.killed);
+        proc::proc_free(victim);
+        exit_proc(me);
+    }
+}
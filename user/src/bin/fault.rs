@@ -0,0 +1,16 @@
+#![no_std]
+#![no_main]
+
+use user::*;
+
+/// In the first page, which is never mapped.
+const BAD_ADDR: usize = 0x8;
+
+/// Store to an unmapped address: the kernel kills us with status -1.
+#[unsafe(no_mangle)]
+pub fn main() -> i32 {
+    println!("fault: writing to {:#x}", BAD_ADDR);
+    unsafe { core::ptr::write_volatile(BAD_ADDR as *mut u8, 1) };
+    println!("fault: still running");
+    0
+}
@@ -0,0 +1,10 @@
+#![no_std]
+#![no_main]
+
+use user::*;
+
+#[unsafe(no_mangle)]
+pub fn main() -> i32 {
+    println!("hello from pid {}", getpid());
+    0
+}
@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+
+use user::*;
+
+/// Print the entries of the directory at `path`.
+fn list(path: &str) {
+    let fd = open(path, O_RDONLY | O_DIRECTORY);
+    if fd < 0 {
+        println!("init: cannot open {}: {}", path, fd);
+        return;
+    }
+    let mut buf = [0u8; 512];
+    loop {
+        let count = getdents(fd as usize, &mut buf);
+        if count <= 0 {
+            break;
+        }
+        for entry in Dirents::new(&buf[..count as usize]) {
+            let suffix = if entry.kind == T_DIR { "/" } else { "" };
+            println!("  {}{}", entry.name, suffix);
+        }
+    }
+    close(fd as usize);
+}
+
+/// Copy the file at `path` to stdout.
+fn cat(path: &str) {
+    let fd = open(path, O_RDONLY);
+    if fd < 0 {
+        return;
+    }
+    let mut buf = [0u8; 128];
+    loop {
+        let count = read(fd as usize, &mut buf);
+        if count <= 0 {
+            break;
+        }
+        write(1, &buf[..count as usize]);
+    }
+    close(fd as usize);
+}
+
+#[unsafe(no_mangle)]
+pub fn main() -> i32 {
+    println!("init: started as pid {}", getpid());
+    cat("/etc/motd");
+
+    println!("init: /");
+    list("/");
+
+    // a program that faults is killed, not the system
+    let pid = spawn("/bin/fault");
+    let mut status = 0;
+    if pid < 0 || wait(pid as usize, &mut status) != pid {
+        println!("init: running fault failed: {}", pid);
+    }else {
+        println!("init: fault exited with status {}", status);
+    }
+
+    if spawn("/bin/hello") < 0 {
+        println!("init: running hello failed");
+    }
+
+    // reap the children, and those of processes that exited before them
+    loop {
+        let pid = wait(0, &mut status);
+        if pid < 0 {
+            break;
+        }
+        println!("init: process {} exited with status {}", pid, status);
+    }
+    0
+}
@@ -0,0 +1,61 @@
+#![no_std]
+
+//! Runtime for user programs: entry point, system call wrappers and
+//! console printing.
+//!
+//! A program is a `src/bin/*.rs` file built with `#![no_std]` and
+//! `#![no_main]` that defines
+//! ```ignore
+//! #[unsafe(no_mangle)]
+//! pub fn main() -> i32 { .. }
+//! ```
+
+pub mod syscall;
+
+use core::fmt::Write;
+pub use syscall::*;
+
+pub struct Stdout;
+
+impl Write for Stdout {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if write(1, s.as_bytes()) < 0 {
+            return Err(core::fmt::Error);
+        }
+        Ok(())
+    }
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {{
+        use core::fmt::Write;
+        let _ = write!(&mut $crate::Stdout, $($arg)*);
+    }};
+}
+
+#[macro_export]
+macro_rules! println {
+    () => { $crate::print!("\n"); };
+    ($($arg:tt)*) => {{
+        $crate::print!($($arg)*);
+        $crate::print!("\n");
+    }};
+}
+
+extern "Rust" {
+    fn main() -> i32;
+}
+
+#[unsafe(no_mangle)]
+#[link_section = ".text.entry"]
+pub extern "C" fn _start() -> ! {
+    let status = unsafe { main() };
+    exit(status)
+}
+
+#[panic_handler]
+fn panic_handler(info: &core::panic::PanicInfo) -> ! {
+    println!("panic: {}", info.message());
+    exit(-1)
+}
@@ -0,0 +1,200 @@
+//! System call wrappers. Numbers and ABI match the kernel's `syscall` module.
+
+pub const SYS_WRITE   : usize = 1;
+pub const SYS_EXIT    : usize = 2;
+pub const SYS_GETPID  : usize = 3;
+pub const SYS_YIELD   : usize = 4;
+pub const SYS_SBRK    : usize = 5;
+pub const SYS_WAIT    : usize = 6;
+pub const SYS_EXEC    : usize = 7;
+pub const SYS_SPAWN   : usize = 8;
+pub const SYS_OPEN    : usize = 9;
+pub const SYS_READ    : usize = 10;
+pub const SYS_CLOSE   : usize = 11;
+pub const SYS_LSEEK   : usize = 12;
+pub const SYS_STAT    : usize = 13;
+pub const SYS_GETDENTS: usize = 14;
+pub const SYS_MKDIR   : usize = 15;
+pub const SYS_UNLINK  : usize = 16;
+pub const SYS_HEAPINFO: usize = 17;
+
+/// `open` flags, same values as Linux.
+pub const O_RDONLY   : usize = 0;
+pub const O_WRONLY   : usize = 1;
+pub const O_RDWR     : usize = 2;
+pub const O_CREAT    : usize = 0o100;
+pub const O_EXCL     : usize = 0o200;
+pub const O_TRUNC    : usize = 0o1000;
+pub const O_APPEND   : usize = 0o2000;
+pub const O_DIRECTORY: usize = 0o200000;
+
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+/// File types in `Stat::kind` and directory entries.
+pub const T_FILE   : u32 = 1;
+pub const T_DIR    : u32 = 2;
+pub const T_SYMLINK: u32 = 3;
+pub const T_DEVICE : u32 = 4;
+
+/// Filled in by `stat`, matches the kernel's `fs::Stat`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stat {
+    pub ino  : u64,
+    pub size : u64,
+    pub dev  : u32,
+    pub nlink: u32,
+    pub kind : u32,
+    pub mode : u32,
+}
+
+/// Kernel heap block orders, 32 bytes to 128 KiB.
+pub const HEAP_MIN_ORDER: usize = 5;
+pub const HEAP_ORDERS   : usize = 13;
+
+/// Filled in by `heapinfo`, matches the kernel's `mem::alloc::HeapInfo`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HeapInfo {
+    pub free_bytes  : [usize; HEAP_ORDERS],     // in free blocks of `1 << (HEAP_MIN_ORDER + n)` bytes
+    pub largest_free: usize,
+    pub allocated   : usize,
+    pub allocs      : usize,
+    pub frees       : usize,
+    pub peak        : usize,
+    pub failed      : usize,
+}
+
+#[inline]
+fn syscall(num: usize, a0: usize, a1: usize, a2: usize) -> isize {
+    let ret: isize;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") a0 => ret,
+            in("a1") a1,
+            in("a2") a2,
+            in("a7") num,
+        )
+    };
+    ret
+}
+
+pub fn open(path: &str, flags: usize) -> isize {
+    syscall(SYS_OPEN, path.as_ptr() as usize, path.len(), flags)
+}
+
+pub fn read(fd: usize, buf: &mut [u8]) -> isize {
+    syscall(SYS_READ, fd, buf.as_mut_ptr() as usize, buf.len())
+}
+
+pub fn close(fd: usize) -> isize {
+    syscall(SYS_CLOSE, fd, 0, 0)
+}
+
+pub fn lseek(fd: usize, offset: isize, whence: usize) -> isize {
+    syscall(SYS_LSEEK, fd, offset as usize, whence)
+}
+
+pub fn stat(path: &str, stat: &mut Stat) -> isize {
+    syscall(SYS_STAT, path.as_ptr() as usize, path.len(), stat as *mut Stat as usize)
+}
+
+/// Fill `buf` with directory entries, see `Dirents`.
+pub fn getdents(fd: usize, buf: &mut [u8]) -> isize {
+    syscall(SYS_GETDENTS, fd, buf.as_mut_ptr() as usize, buf.len())
+}
+
+pub fn mkdir(path: &str) -> isize {
+    syscall(SYS_MKDIR, path.as_ptr() as usize, path.len(), 0)
+}
+
+pub fn unlink(path: &str) -> isize {
+    syscall(SYS_UNLINK, path.as_ptr() as usize, path.len(), 0)
+}
+
+pub fn heapinfo(info: &mut HeapInfo) -> isize {
+    syscall(SYS_HEAPINFO, info as *mut HeapInfo as usize, 0, 0)
+}
+
+/// Have the kernel print its heap report on the console.
+pub fn heap_report() -> isize {
+    syscall(SYS_HEAPINFO, 0, 0, 0)
+}
+
+pub fn write(fd: usize, buf: &[u8]) -> isize {
+    syscall(SYS_WRITE, fd, buf.as_ptr() as usize, buf.len())
+}
+
+pub fn exit(status: i32) -> ! {
+    syscall(SYS_EXIT, status as usize, 0, 0);
+    unreachable!()
+}
+
+pub fn getpid() -> isize {
+    syscall(SYS_GETPID, 0, 0, 0)
+}
+
+pub fn yield_() -> isize {
+    syscall(SYS_YIELD, 0, 0, 0)
+}
+
+pub fn sbrk(incr: isize) -> isize {
+    syscall(SYS_SBRK, incr as usize, 0, 0)
+}
+
+/// Replace the calling program with the one called `name`.
+/// Only returns on failure.
+pub fn exec(name: &str) -> isize {
+    syscall(SYS_EXEC, name.as_ptr() as usize, name.len(), 0)
+}
+
+/// Start the executable at `path` in a new child process.
+/// Returns its pid.
+pub fn spawn(path: &str) -> isize {
+    syscall(SYS_SPAWN, path.as_ptr() as usize, path.len(), 0)
+}
+
+/// Wait for the child `pid`, or any child if `pid` is 0, to exit and
+/// store its exit status in `status`. Returns the pid of the child.
+pub fn wait(pid: usize, status: &mut i32) -> isize {
+    syscall(SYS_WAIT, pid, status as *mut i32 as usize, 0)
+}
+
+/// A directory entry decoded from a `getdents` buffer.
+pub struct Dirent<'a> {
+    pub ino : u64,
+    pub kind: u32,
+    pub name: &'a str,
+}
+
+/// Walks the records `getdents` put in a buffer:
+/// `ino: u64, reclen: u16, kind: u8, namelen: u8, name, NUL`, padded to 8.
+pub struct Dirents<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Dirents<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+}
+
+impl<'a> Iterator for Dirents<'a> {
+    type Item = Dirent<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.len() < 12 {
+            return None;
+        }
+        let ino     = u64::from_le_bytes(self.buf[0..8].try_into().ok()?);
+        let reclen  = u16::from_le_bytes([self.buf[8], self.buf[9]]) as usize;
+        let kind    = self.buf[10] as u32;
+        let namelen = self.buf[11] as usize;
+        let name    = core::str::from_utf8(self.buf.get(12..12 + namelen)?).ok()?;
+        self.buf = self.buf.get(reclen..)?;
+        Some(Dirent { ino, kind, name })
+    }
+}